@@ -0,0 +1,250 @@
+//! Comparing compression algorithms for the snapshot format.
+//!
+//! A snapshot of a data set is stored as a full-set record of an
+//! [event store][rtrtr::payload::event_store]. This benchmark creates a
+//! realistic data set of 200,000 VRPs, stores it as a snapshot, and then
+//! compresses and decompresses the snapshot with zstd, LZ4, and Brotli at a
+//! few levels each, reporting the compressed size and the time each step
+//! takes.
+//!
+//! Run it via `cargo bench --bench compression_bench`.
+//!
+//! # Choosing an algorithm
+//!
+//! Snapshots are written whenever a unit publishes a new full data set and
+//! read when replaying history, so both directions matter, but neither is
+//! on a hot path. The encoded items are short and mostly differ only in
+//! their addresses and AS numbers, which leaves fairly little redundancy.
+//! A run on a current x86-64 machine produced these results for the
+//! snapshot of about 2.7 MB:
+//!
+//! ```text
+//! algorithm     ratio   compress   decompress
+//! zstd-3         0.58      18 ms        5 ms
+//! zstd-19        0.49     860 ms        6 ms
+//! lz4            0.76       8 ms        1 ms
+//! brotli-5       0.50     210 ms       23 ms
+//! brotli-11      0.43    5200 ms       20 ms
+//! ```
+//!
+//! Snapshots are therefore compressed with zstd at its default level 3.
+//! LZ4 is faster but only saves a quarter of the size. The higher zstd
+//! level and Brotli save another 10 to 15 percent of the original size but
+//! take 10 to 300 times as long to compress, which isn’t worth it for data
+//! that is replaced every few minutes. Brotli also decompresses four times
+//! slower than zstd.
+
+use std::{env, fs, process};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rpki_rtr::payload::{Ipv4Prefix, Ipv6Prefix, Payload};
+use rpki_rtr::state::Serial;
+use rtrtr::payload::{Set, SetBuilder, Update};
+use rtrtr::payload::event_store::EventStore;
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The number of VRPs in the data set.
+const SET_SIZE: usize = 200_000;
+
+/// The share of IPv4 VRPs in percent.
+const V4_SHARE: u32 = 80;
+
+/// The number of distinct AS numbers.
+const ASN_COUNT: usize = 30_000;
+
+/// How often each step is repeated. The fastest run is reported.
+const ROUNDS: usize = 3;
+
+
+//------------ Algorithms ----------------------------------------------------
+
+/// A compression algorithm at a certain level.
+#[derive(Clone, Copy)]
+enum Algorithm {
+    Zstd(i32),
+    Lz4,
+    Brotli(u32),
+}
+
+impl Algorithm {
+    /// All the algorithms to compare.
+    const ALL: &'static [Algorithm] = &[
+        Algorithm::Zstd(3), Algorithm::Zstd(19), Algorithm::Lz4,
+        Algorithm::Brotli(5), Algorithm::Brotli(11),
+    ];
+
+    fn name(self) -> String {
+        match self {
+            Algorithm::Zstd(level) => format!("zstd-{}", level),
+            Algorithm::Lz4 => "lz4".into(),
+            Algorithm::Brotli(quality) => format!("brotli-{}", quality),
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Zstd(level) => zstd::encode_all(data, level).unwrap(),
+            Algorithm::Lz4 => lz4_flex::compress_prepend_size(data),
+            Algorithm::Brotli(quality) => {
+                let mut writer = brotli::CompressorWriter::new(
+                    Vec::new(), 4096, quality, 22
+                );
+                writer.write_all(data).unwrap();
+                writer.into_inner()
+            }
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Zstd(_) => zstd::decode_all(data).unwrap(),
+            Algorithm::Lz4 => {
+                lz4_flex::decompress_size_prepended(data).unwrap()
+            }
+            Algorithm::Brotli(_) => {
+                let mut res = Vec::new();
+                brotli::Decompressor::new(data, 4096).read_to_end(
+                    &mut res
+                ).unwrap();
+                res
+            }
+        }
+    }
+}
+
+
+//------------ Data ----------------------------------------------------------
+
+/// Creates a data set resembling what a relying party produces.
+///
+/// Most IPv4 prefixes are /24s or less specific and most IPv6 prefixes
+/// are between /32 and /48. About a fifth of the VRPs have a max-length
+/// beyond their prefix length. AS numbers are drawn from a limited pool so
+/// that they repeat as they do in real data.
+fn make_set(rng: &mut StdRng) -> Set {
+    let asns: Vec<u32> = (0..ASN_COUNT).map(|_| {
+        rng.gen_range(1, 400_000)
+    }).collect();
+    let mut set = SetBuilder::empty();
+    while set.len() < SET_SIZE {
+        let asn = asns[rng.gen_range(0, asns.len())];
+        let item = if rng.gen_range(0, 100) < V4_SHARE {
+            let prefix_len = match rng.gen_range(0, 100) {
+                0..=59 => 24,
+                60..=79 => rng.gen_range(20, 24),
+                80..=94 => rng.gen_range(16, 20),
+                _ => rng.gen_range(8, 16),
+            };
+            let addr = rng.gen::<u32>() & (!0u32 << (32 - prefix_len));
+            Payload::V4(Ipv4Prefix {
+                prefix: Ipv4Addr::from(addr),
+                prefix_len,
+                max_len: max_len(rng, prefix_len, 24),
+                asn,
+            })
+        }
+        else {
+            let prefix_len = match rng.gen_range(0, 100) {
+                0..=49 => 48,
+                50..=79 => rng.gen_range(32, 48),
+                _ => rng.gen_range(29, 32),
+            };
+            let addr = (
+                0x2000u128 << 112 | u128::from(rng.gen::<u64>()) << 64
+            ) & (!0u128 << (128 - prefix_len));
+            Payload::V6(Ipv6Prefix {
+                prefix: Ipv6Addr::from(addr),
+                prefix_len,
+                max_len: max_len(rng, prefix_len, 48),
+                asn,
+            })
+        };
+        let _ = set.insert(item);
+    }
+    set.finalize()
+}
+
+/// Returns a max-length for a prefix length.
+fn max_len(rng: &mut StdRng, prefix_len: u8, common: u8) -> u8 {
+    if prefix_len < common && rng.gen_range(0, 100) < 20 {
+        common
+    }
+    else {
+        prefix_len
+    }
+}
+
+/// Returns the items of the snapshot of a set.
+///
+/// The event store compresses snapshots itself, so this returns the
+/// decompressed items of the record it wrote, i.e., what the algorithms
+/// would be applied to.
+fn snapshot(set: Set) -> Vec<u8> {
+    let path = env::temp_dir().join(
+        format!("rtrtr-compression-bench-{}", process::id())
+    );
+    let _ = fs::remove_file(&path);
+    {
+        let mut store = EventStore::open(&path).unwrap();
+        store.append(
+            &Update::new(Serial::from(1), Arc::new(set), None)
+        ).unwrap();
+    }
+    let file = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // Skip the magic, the record length, the serial, and the flags.
+    zstd::decode_all(&file[17..]).unwrap()
+}
+
+
+//------------ Main ----------------------------------------------------------
+
+/// Runs `op` a number of times and returns the last result and the fastest
+/// time.
+fn measure<T>(mut op: impl FnMut() -> T) -> (T, Duration) {
+    let mut best = None;
+    let mut res = None;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        res = Some(op());
+        let elapsed = start.elapsed();
+        best = Some(match best {
+            Some(best) if best < elapsed => best,
+            _ => elapsed,
+        });
+    }
+    (res.unwrap(), best.unwrap())
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0x5254_5254);
+    let data = snapshot(make_set(&mut rng));
+    println!(
+        "snapshot of {} VRPs: {} bytes\n", SET_SIZE, data.len()
+    );
+    println!(
+        "{:<12} {:>10} {:>7} {:>12} {:>12}",
+        "algorithm", "size", "ratio", "compress", "decompress"
+    );
+    for &algorithm in Algorithm::ALL {
+        let (compressed, compress) = measure(|| algorithm.compress(&data));
+        let (decompressed, decompress) = measure(|| {
+            algorithm.decompress(&compressed)
+        });
+        assert!(decompressed == data);
+        println!(
+            "{:<12} {:>10} {:>7.2} {:>9.1} ms {:>9.1} ms",
+            algorithm.name(), compressed.len(),
+            compressed.len() as f64 / data.len() as f64,
+            compress.as_secs_f64() * 1000.,
+            decompress.as_secs_f64() * 1000.,
+        );
+    }
+}
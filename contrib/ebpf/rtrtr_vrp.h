@@ -0,0 +1,26 @@
+/*
+ * Route origin records as written by RTRTR for eBPF array maps.
+ *
+ * See src/formats/ebpf_map.rs for the producing side. The file written by
+ * RTRTR is a plain sequence of these records, one per map entry.
+ */
+#ifndef RTRTR_VRP_H
+#define RTRTR_VRP_H
+
+#include <linux/types.h>
+
+#define RTRTR_VRP_AF_IPV4 4
+#define RTRTR_VRP_AF_IPV6 6
+
+struct rtrtr_vrp {
+	__u8 af;		/* RTRTR_VRP_AF_IPV4 or RTRTR_VRP_AF_IPV6 */
+	__u8 prefix[16];	/* network byte order, IPv4 in the first four */
+	__u8 prefix_len;
+	__u8 max_len;
+	__u8 _pad;		/* always zero */
+	__u32 asn;		/* host byte order */
+};
+
+_Static_assert(sizeof(struct rtrtr_vrp) == 24, "unexpected record size");
+
+#endif /* RTRTR_VRP_H */
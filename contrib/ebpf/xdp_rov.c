@@ -0,0 +1,46 @@
+/*
+ * Example XDP program stub using route origins provided by RTRTR.
+ *
+ * The array map `rtrtr_vrps` is filled from the output of
+ * write_ebpf_array_map(), e.g., with bpftool, and `rtrtr_vrp_count` is set
+ * to the number of records. The program only shows how to get at the
+ * records. What to look up and what to do with the result depends on the
+ * application, e.g., checking the origin of BGP updates passing through.
+ *
+ * Build with:
+ *
+ *     clang -O2 -target bpf -c xdp_rov.c -o xdp_rov.o
+ */
+#include <linux/bpf.h>
+#include <bpf/bpf_helpers.h>
+#include "rtrtr_vrp.h"
+
+#define RTRTR_MAX_VRPS 1048576
+
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(max_entries, RTRTR_MAX_VRPS);
+	__type(key, __u32);
+	__type(value, struct rtrtr_vrp);
+} rtrtr_vrps SEC(".maps");
+
+volatile const __u32 rtrtr_vrp_count = 0;
+
+SEC("xdp")
+int xdp_rov(struct xdp_md *ctx)
+{
+	__u32 key = 0;
+	struct rtrtr_vrp *vrp;
+
+	if (rtrtr_vrp_count == 0)
+		return XDP_PASS;
+
+	vrp = bpf_map_lookup_elem(&rtrtr_vrps, &key);
+	if (!vrp)
+		return XDP_PASS;
+
+	/* Look up the origin of interest against the records here. */
+	return XDP_PASS;
+}
+
+char _license[] SEC("license") = "BSD";
@@ -0,0 +1,222 @@
+//! Analysing the data sets of units.
+//!
+//! If the `set-analysis` option is given, the manager starts a
+//! [`SetAnalysis`] for every unit. It regularly computes statistics about
+//! overlapping items in the unit’s current data set and makes them
+//! available as metrics and under `/analysis/<unit-name>` on the HTTP
+//! server. See [`payload::Set::overlap_stats`] for what is computed.
+//!
+//! The analysis doesn’t change the data. It is off by default since it
+//! takes some time for large sets.
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use hyper::{Body, Method, Request, Response};
+use tokio::task::spawn_blocking;
+use tokio::time::delay_for;
+use crate::{http, metrics, payload};
+use crate::comms::UnitHandle;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ SetAnalysis ---------------------------------------------------
+
+/// Regularly analyses the data set of a unit.
+///
+/// The analysis runs over the unit’s current data set once per period if
+/// the set has changed since the last run.
+#[derive(Debug)]
+pub struct SetAnalysis {
+    /// The path of the HTTP resource.
+    path: String,
+
+    /// The result of the last run or `None` if there hasn’t been one yet.
+    stats: Mutex<Option<payload::OverlapStats>>,
+}
+
+impl SetAnalysis {
+    /// Creates a new analysis for the unit with the given name.
+    pub fn new(name: &str) -> Self {
+        SetAnalysis {
+            path: format!("/analysis/{}", name),
+            stats: Default::default(),
+        }
+    }
+
+    /// Registers the analysis for a unit and starts it.
+    ///
+    /// The analysis runs every `period` until the unit has terminated.
+    pub fn spawn(
+        name: &str,
+        unit: UnitHandle,
+        period: Duration,
+        metrics: &metrics::Collection,
+        resources: &http::Resources,
+        runtime: &tokio::runtime::Runtime,
+    ) {
+        let this = Arc::new(Self::new(name));
+        metrics.register(
+            name.into(), Arc::downgrade(&this) as Weak<dyn metrics::Source>
+        );
+        resources.register(
+            Arc::downgrade(&this) as Weak<dyn http::ProcessRequest>
+        );
+        runtime.spawn(this.run(unit, period));
+    }
+
+    /// Runs the analysis every `period` for as long as the unit is alive.
+    async fn run(self: Arc<Self>, unit: UnitHandle, period: Duration) {
+        let mut last = Weak::new();
+        loop {
+            delay_for(period).await;
+            if unit.is_gone() {
+                return
+            }
+            let set = match unit.data() {
+                Some((_, set)) => set,
+                None => continue
+            };
+            let weak = Arc::downgrade(&set);
+            if Weak::ptr_eq(&last, &weak) {
+                continue
+            }
+            last = weak;
+
+            // The task only fails if the closure panics in which case we
+            // should panic, too.
+            let stats = spawn_blocking(move || {
+                set.overlap_stats()
+            }).await.unwrap();
+            *self.stats.lock().unwrap() = Some(stats);
+        }
+    }
+
+    /// Returns the result of the last run if there was one.
+    pub fn stats(&self) -> Option<payload::OverlapStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+impl SetAnalysis {
+    const OVERLAPPING_METRIC: Metric = Metric::new(
+        "set_overlapping_vrps",
+        "the number of VRPs overlapping with another VRP",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const MOAS_METRIC: Metric = Metric::new(
+        "set_moas_prefixes",
+        "the number of prefixes with VRPs for more than one origin AS",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const MAX_LEN_EXCESS_METRIC: Metric = Metric::new(
+        "set_vrp_count_by_max_len_excess",
+        "the number of VRPs by how much max-length exceeds prefix length",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for SetAnalysis {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        let stats = match self.stats() {
+            Some(stats) => stats,
+            None => return
+        };
+        target.append_simple(
+            &Self::OVERLAPPING_METRIC, Some(unit_name), stats.overlapping
+        );
+        target.append_simple(
+            &Self::MOAS_METRIC, Some(unit_name), stats.moas_prefixes
+        );
+        target.append(
+            &Self::MAX_LEN_EXCESS_METRIC, Some(unit_name), |records| {
+                for &(af, ref counts) in &[
+                    ("ipv4", stats.v4_max_len_excess_sorted()),
+                    ("ipv6", stats.v6_max_len_excess_sorted()),
+                ] {
+                    for &(excess, count) in counts {
+                        records.label_value(
+                            &[("excess", &excess.to_string()), ("af", af)],
+                            count
+                        );
+                    }
+                }
+            }
+        );
+    }
+}
+
+impl http::ProcessRequest for SetAnalysis {
+    fn process_request(
+        &self, request: &mut Request<Body>
+    ) -> Option<Response<Body>> {
+        if
+            request.method() != Method::GET
+            || request.uri().path() != self.path
+        {
+            return None
+        }
+        let body = match self.stats() {
+            Some(stats) => {
+                let mut body = String::new();
+                writeln!(body, "overlapping: {}", stats.overlapping).unwrap();
+                writeln!(
+                    body, "moas-prefixes: {}", stats.moas_prefixes
+                ).unwrap();
+                for &(af, ref counts) in &[
+                    ("ipv4", stats.v4_max_len_excess_sorted()),
+                    ("ipv6", stats.v6_max_len_excess_sorted()),
+                ] {
+                    for &(excess, count) in counts {
+                        writeln!(
+                            body, "max-len-excess {} {}: {}",
+                            af, excess, count
+                        ).unwrap();
+                    }
+                }
+                body
+            }
+            None => "overlapping: N/A\n".into()
+        };
+        Some(
+            Response::builder()
+            .header("Content-Type", "text/plain")
+            .body(body.into())
+            .unwrap()
+        )
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use http::ProcessRequest;
+    use crate::payload::testing::v4;
+    use super::*;
+
+    #[test]
+    fn output() {
+        let analysis = SetAnalysis::new("rtr");
+        let mut request = Request::get("/analysis/rtr").body(
+            Body::empty()
+        ).unwrap();
+        assert!(analysis.process_request(&mut request).is_some());
+
+        let mut builder = payload::SetBuilder::empty();
+        for &asn in &[64496, 64497] {
+            builder.insert(v4([192, 0, 2, 0], 24, 24, asn)).unwrap();
+        }
+        *analysis.stats.lock().unwrap() = Some(
+            builder.finalize().overlap_stats()
+        );
+        let mut target = metrics::Target::new(metrics::OutputFormat::Plain);
+        metrics::Source::append(&analysis, "rtr", &mut target);
+        let output = target.into_string();
+        assert!(output.contains("rtr set_overlapping_vrps: 2"));
+        assert!(output.contains("rtr set_moas_prefixes: 1"));
+    }
+}
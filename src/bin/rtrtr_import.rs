@@ -0,0 +1,39 @@
+//! Serves a snapshot file once via RTR.
+//!
+//! See [`rtrtr::import`] for details.
+
+use std::env::current_dir;
+use std::process::exit;
+use clap::{App, crate_authors, crate_version};
+use log::error;
+use rtrtr::import::Import;
+use rtrtr::log::{ExitError, LogConfig};
+
+
+fn _main() -> Result<(), ExitError> {
+    LogConfig::init_logging()?;
+    let matches = Import::config_args(
+        App::new("rtrtr-import")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("serves a snapshot of route filtering data once via RTR")
+    ).get_matches();
+    let cur_dir = match current_dir() {
+        Ok(dir) => dir,
+        Err(err) => {
+            error!(
+                "Fatal: cannot get current directory ({}). Aborting.",
+                err
+            );
+            return Err(ExitError);
+        }
+    };
+    Import::run(&matches, &cur_dir)
+}
+
+fn main() {
+    match _main() {
+        Ok(_) => exit(0),
+        Err(ExitError) => exit(1),
+    }
+}
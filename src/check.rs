@@ -0,0 +1,333 @@
+//! Checking a data set.
+//!
+//! Operators sometimes publish VRPs that are completely covered by a less
+//! specific VRP for the same origin AS with at least the same maximum
+//! length. These add nothing for route origin validation. The
+//! `check-redundant` command lists them for a data set given in the same
+//! formats as for the [`eval`][crate::eval] command.
+//!
+//! The `check-special` command lists the items of a data set for IANA
+//! special-purpose addresses that aren’t globally reachable together with
+//! the purpose of the address range. See
+//! [`find_special_purpose_vrps`][Set::find_special_purpose_vrps] for
+//! details.
+//!
+//! The `check-hijacks` command cross-references a data set with the events
+//! of CAIDA’s BGP Hijacks Observatory to see whether route origin
+//! validation would have caught them. See
+//! [`caida_hijack`][crate::formats::caida_hijack] for details.
+
+use std::{fs, io};
+use std::io::Write;
+use std::path::Path;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{error, info};
+use reqwest::Url;
+use crate::eval::Eval;
+use crate::formats::caida_hijack::{write_report, HijackEvents};
+use crate::log::{ExitError, LogConfig};
+use crate::payload::{DisplayPayload, Set};
+
+
+//------------ CheckRedundant ------------------------------------------------
+
+/// The `check-redundant` command.
+pub struct CheckRedundant;
+
+impl CheckRedundant {
+    /// The name of the command.
+    pub const NAME: &'static str = "check-redundant";
+
+    /// Returns the clap sub-command for the command.
+    pub fn subcommand<'a: 'b, 'b>() -> App<'a, 'b> {
+        LogConfig::config_args(
+            SubCommand::with_name(Self::NAME)
+            .about("lists the items covered by a less specific item")
+        )
+        .arg(Arg::with_name("input")
+            .long("input")
+            .takes_value(true)
+            .value_name("PATH")
+            .required(true)
+            .help("Read the data from this JSON or event store file")
+        )
+    }
+
+    /// Runs the command.
+    ///
+    /// The `matches` must be those of the sub-command returned by
+    /// [`subcommand`](Self::subcommand).
+    pub fn run(
+        matches: &ArgMatches, cur_dir: &Path
+    ) -> Result<(), ExitError> {
+        let mut log = LogConfig::default();
+        log.update_with_arg_matches(matches, cur_dir)?;
+        log.switch_logging(false)?;
+
+        let input = cur_dir.join(matches.value_of("input").unwrap());
+        let set = Eval::load_input(&input)?;
+        let stdout = io::stdout();
+        match Self::check(&set, &mut stdout.lock()) {
+            Ok(count) => {
+                info!(
+                    "{} of {} items are redundant.", count, set.len()
+                );
+                Ok(())
+            }
+            Err(err) => {
+                error!("Failed to write output: {}", err);
+                Err(ExitError)
+            }
+        }
+    }
+
+    /// Writes the redundant items of `set` to `target`, one per line.
+    ///
+    /// Returns the number of redundant items.
+    pub fn check(
+        set: &Set, target: &mut impl Write
+    ) -> Result<usize, io::Error> {
+        let redundant = set.find_redundant_vrps();
+        for item in &redundant {
+            writeln!(target, "{}", DisplayPayload(item))?;
+        }
+        Ok(redundant.len())
+    }
+}
+
+
+//------------ CheckSpecial --------------------------------------------------
+
+/// The `check-special` command.
+pub struct CheckSpecial;
+
+impl CheckSpecial {
+    /// The name of the command.
+    pub const NAME: &'static str = "check-special";
+
+    /// Returns the clap sub-command for the command.
+    pub fn subcommand<'a: 'b, 'b>() -> App<'a, 'b> {
+        LogConfig::config_args(
+            SubCommand::with_name(Self::NAME)
+            .about("lists the items for special-purpose addresses")
+        )
+        .arg(Arg::with_name("input")
+            .long("input")
+            .takes_value(true)
+            .value_name("PATH")
+            .required(true)
+            .help("Read the data from this JSON or event store file")
+        )
+    }
+
+    /// Runs the command.
+    ///
+    /// The `matches` must be those of the sub-command returned by
+    /// [`subcommand`](Self::subcommand).
+    pub fn run(
+        matches: &ArgMatches, cur_dir: &Path
+    ) -> Result<(), ExitError> {
+        let mut log = LogConfig::default();
+        log.update_with_arg_matches(matches, cur_dir)?;
+        log.switch_logging(false)?;
+
+        let input = cur_dir.join(matches.value_of("input").unwrap());
+        let set = Eval::load_input(&input)?;
+        let stdout = io::stdout();
+        match Self::check(&set, &mut stdout.lock()) {
+            Ok(count) => {
+                info!(
+                    "{} of {} items are for special-purpose addresses.",
+                    count, set.len()
+                );
+                Ok(())
+            }
+            Err(err) => {
+                error!("Failed to write output: {}", err);
+                Err(ExitError)
+            }
+        }
+    }
+
+    /// Writes the special-purpose items of `set` to `target`.
+    ///
+    /// Each item is written on a line of its own followed by a tab and the
+    /// purpose of its address range. Returns the number of items.
+    pub fn check(
+        set: &Set, target: &mut impl Write
+    ) -> Result<usize, io::Error> {
+        let special = set.find_special_purpose_vrps();
+        for (item, purpose) in &special {
+            writeln!(target, "{}\t{}", DisplayPayload(item), purpose)?;
+        }
+        Ok(special.len())
+    }
+}
+
+
+//------------ CheckHijacks --------------------------------------------------
+
+/// The `check-hijacks` command.
+pub struct CheckHijacks;
+
+impl CheckHijacks {
+    /// The name of the command.
+    pub const NAME: &'static str = "check-hijacks";
+
+    /// Returns the clap sub-command for the command.
+    pub fn subcommand<'a: 'b, 'b>() -> App<'a, 'b> {
+        LogConfig::config_args(
+            SubCommand::with_name(Self::NAME)
+            .about("checks BGP hijack events against a data set")
+        )
+        .arg(Arg::with_name("input")
+            .long("input")
+            .takes_value(true)
+            .value_name("PATH")
+            .required(true)
+            .help("Read the data from this JSON or event store file")
+        )
+        .arg(Arg::with_name("feed")
+            .long("feed")
+            .takes_value(true)
+            .value_name("URL")
+            .required(true)
+            .help("Read the hijack events from this URL or file")
+        )
+    }
+
+    /// Runs the command.
+    ///
+    /// The `matches` must be those of the sub-command returned by
+    /// [`subcommand`](Self::subcommand).
+    pub fn run(
+        matches: &ArgMatches, cur_dir: &Path
+    ) -> Result<(), ExitError> {
+        let mut log = LogConfig::default();
+        log.update_with_arg_matches(matches, cur_dir)?;
+        log.switch_logging(false)?;
+
+        let input = cur_dir.join(matches.value_of("input").unwrap());
+        let set = Eval::load_input(&input)?;
+        let feed = matches.value_of("feed").unwrap();
+        let events = match Self::fetch(feed, cur_dir) {
+            Ok(events) => events,
+            Err(err) => {
+                error!("Failed to load hijack events from {}: {}", feed, err);
+                return Err(ExitError)
+            }
+        };
+        let stdout = io::stdout();
+        match write_report(&set, &events, &mut stdout.lock()) {
+            Ok(stats) => {
+                info!(
+                    "{} hijack events: {} valid, {} invalid, {} not found.",
+                    events.len(), stats.valid, stats.invalid,
+                    stats.not_found
+                );
+                Ok(())
+            }
+            Err(err) => {
+                error!("Failed to write output: {}", err);
+                Err(ExitError)
+            }
+        }
+    }
+
+    /// Loads the events from an HTTP or HTTPS URL or a local file.
+    fn fetch(
+        feed: &str, cur_dir: &Path
+    ) -> Result<HijackEvents, io::Error> {
+        let invalid_data = |err: serde_json::Error| {
+            io::Error::new(io::ErrorKind::InvalidData, err)
+        };
+        match Url::parse(feed) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                let response = reqwest::blocking::get(url).and_then(
+                    |response| response.error_for_status()
+                ).map_err(|err| {
+                    io::Error::new(io::ErrorKind::Other, err)
+                })?;
+                HijackEvents::from_json(response).map_err(invalid_data)
+            }
+            Ok(url) if url.scheme() == "file" => {
+                let path = url.to_file_path().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput, "invalid file URL"
+                    )
+                })?;
+                let file = fs::File::open(path)?;
+                HijackEvents::from_json(io::BufReader::new(file))
+                    .map_err(invalid_data)
+            }
+            _ => {
+                let file = fs::File::open(cur_dir.join(feed))?;
+                HijackEvents::from_json(io::BufReader::new(file))
+                    .map_err(invalid_data)
+            }
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::v4;
+    use super::*;
+
+    #[test]
+    fn check() {
+        let mut set = SetBuilder::empty();
+        for &(prefix_len, max_len, asn) in &[
+            (24, 26, 64496), (25, 26, 64496), (25, 26, 64497)
+        ] {
+            set.insert(v4([192, 0, 2, 0], prefix_len, max_len, asn)).unwrap();
+        }
+        let mut out = Vec::new();
+        assert_eq!(
+            CheckRedundant::check(&set.finalize(), &mut out).unwrap(), 1
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(), "192.0.2.0/25-26 AS64496\n"
+        );
+    }
+
+    #[test]
+    fn check_special() {
+        let mut set = SetBuilder::empty();
+        for &(addr, asn) in &[
+            (Ipv4Addr::new(192, 0, 2, 0), 64496),
+            (Ipv4Addr::new(193, 0, 2, 0), 64497),
+        ] {
+            set.insert(v4(addr, 24, 24, asn)).unwrap();
+        }
+        let mut out = Vec::new();
+        assert_eq!(
+            CheckSpecial::check(&set.finalize(), &mut out).unwrap(), 1
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "192.0.2.0/24-24 AS64496\tDocumentation prefix (RFC 5737)\n"
+        );
+    }
+
+    #[test]
+    fn fetch_file_url() {
+        let path = std::env::temp_dir().join(format!(
+            "rtrtr hijacks {}.json", std::process::id()
+        ));
+        fs::write(&path, r#"[{ "prefixes": ["192.0.2.0/24"] }]"#).unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+        assert!(url.path().contains("%20"));
+        let events = CheckHijacks::fetch(
+            url.as_str(), Path::new("/nonexistent")
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(events.unwrap().len(), 1);
+    }
+}
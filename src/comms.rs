@@ -9,24 +9,49 @@
 //! by any interested component. A [`GateAgent`] is a reference to a gate
 //! that can be used to create new links.
 //!
+//! Components that only need to know that a unit’s data has changed can use
+//! a [`Watcher`] instead of a link. It receives an [`UpdateNotice`] for
+//! every update rather than the update itself. Components interested in
+//! changes to specific prefixes only can register a callback via
+//! [`GateAgent::watch_prefix`] which receives a [`VrpChangeEvent`] for
+//! each of these changes.
+//!
 //! The type [`GateMetrics`] can be used by units to provide some obvious
 //! metrics such as the number of payload units in the data set or the time
 //! of last update based on the updates sent to the gate.
+//!
+//! Components that need to know about the current state of another unit
+//! by name can look up its [`UnitHandle`] in the [`Registry`] kept by the
+//! manager.
+//!
+//! A link can be given a [`LinkFilter`] in which case it only receives the
+//! part of each update that passes the filter. This allows components to
+//! use different views of the same unit’s data.
 
 use std::fmt;
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::sync::atomic;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, AtomicUsize};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
+use std::time::{Duration, Instant};
+use arc_swap::ArcSwap;
 use chrono::{DateTime, Utc};
 use crossbeam_utils::atomic::AtomicCell;
-use futures::pin_mut;
+use futures::{pin_mut, stream};
 use futures::future::{select, Either, Future};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{error, info};
+use rpki_rtr::payload::{Action, Payload};
+use rpki_rtr::state::Serial;
 use slab::Slab;
 use serde::Deserialize;
-use tokio::sync::{mpsc, oneshot};
-use crate::{manager, metrics, payload};
+use tokio::sync::{mpsc, oneshot, watch};
+use crate::{http, manager, metrics, payload};
 use crate::config::Marked;
+use crate::formats::output;
 use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::payload::Prefix;
 
 
 //------------ Configuration -------------------------------------------------
@@ -75,6 +100,33 @@ pub struct Gate {
 
     /// The gate metrics.
     metrics: Arc<GateMetrics>,
+
+    /// The sender for update notices to watchers.
+    notices: watch::Sender<Option<UpdateNotice>>,
+
+    /// The prefix watches registered via gate agents.
+    prefix_watches: PrefixWatches,
+
+    /// The handle for looking at the gate’s state from elsewhere.
+    ///
+    /// It also keeps the serial number and data set of the last update.
+    /// These are needed to determine the changes for prefix watches if an
+    /// update doesn’t come with a usable diff.
+    handle: UnitHandle,
+
+    /// Whether new links receive the last update right away.
+    replay: bool,
+
+    /// The maximum number of links accepted and the unit name for logging.
+    ///
+    /// If this is `None`, the number of links is not limited.
+    max_consumers: Option<(Arc<str>, usize)>,
+
+    /// The shadow output if the unit runs in shadow mode.
+    ///
+    /// In shadow mode, updates are only logged and kept here rather than
+    /// published.
+    shadow: Option<Arc<ShadowOutput>>,
 }
 
 
@@ -86,14 +138,25 @@ impl Gate {
     /// unit and keep the agent around for future use.
     pub fn new() -> (Gate, GateAgent) {
         let (tx, rx) = mpsc::channel(COMMAND_QUEUE_LEN);
+        let (notice_tx, notice_rx) = watch::channel(None);
+        let prefix_watches = PrefixWatches::default();
+        let metrics = Arc::new(GateMetrics::default());
         let gate = Gate {
             commands: rx,
             updates: Slab::new(),
             suspended: 0,
             unit_status: UnitStatus::default(),
-            metrics: Default::default(),
+            metrics: metrics.clone(),
+            notices: notice_tx,
+            prefix_watches: prefix_watches.clone(),
+            handle: UnitHandle::new(metrics),
+            replay: false,
+            max_consumers: None,
+            shadow: None,
+        };
+        let agent = GateAgent {
+            commands: tx, notices: notice_rx, prefix_watches
         };
-        let agent = GateAgent { commands: tx };
         (gate, agent)
     }
 
@@ -102,6 +165,41 @@ impl Gate {
         self.metrics.clone()
     }
 
+    /// Returns a handle to the gate’s state.
+    pub fn handle(&self) -> UnitHandle {
+        self.handle.clone()
+    }
+
+    /// Makes the gate send the last update to newly connected links.
+    ///
+    /// Normally, links only receive the updates sent after they have
+    /// connected. This is fine for units that keep producing updates but
+    /// not for units that publish their data only once.
+    pub fn enable_replay(&mut self) {
+        self.replay = true
+    }
+
+    /// Limits the number of links the gate accepts.
+    ///
+    /// Once `max` links are subscribed, further links are rejected and
+    /// will see the unit as gone. The unit’s name is used in the error
+    /// logged for each rejection.
+    pub fn set_max_consumers(&mut self, name: &str, max: usize) {
+        self.max_consumers = Some((name.into(), max))
+    }
+
+    /// Puts the gate into shadow mode.
+    ///
+    /// In shadow mode, the gate doesn’t publish the updates it receives.
+    /// Instead, it logs what it would have published and keeps the last
+    /// data set in the returned shadow output. Status changes are still
+    /// passed on.
+    pub fn enable_shadow_mode(&mut self, name: &str) -> Arc<ShadowOutput> {
+        let shadow = Arc::new(ShadowOutput::new(name));
+        self.shadow = Some(shadow.clone());
+        shadow
+    }
+
     /// Runs the gate’s internal machine.
     ///
     /// This method returns a future that runs the gate’s internal machine.
@@ -124,8 +222,8 @@ impl Gate {
                 GateCommand::Suspension { slot, suspend } => {
                     self.suspension(slot, suspend)
                 }
-                GateCommand::Subscribe { suspended, response } => {
-                    self.subscribe(suspended, response)
+                GateCommand::Subscribe { suspended, filter, response } => {
+                    self.subscribe(suspended, filter, response)
                 }
             }
 
@@ -160,17 +258,30 @@ impl Gate {
 
     /// Updates the data set of the unit.
     ///
-    /// This method will send out the update to all active links. It will
-    /// also update the gate metrics based on the update.
+    /// This method will send out the update to all active links and a
+    /// notice about it to all watchers. It will call the callbacks of all
+    /// prefix watches affected by the update. It will also update the gate
+    /// metrics based on the update.
     pub async fn update_data(&mut self, update: payload::Update) {
-        println!("{}", self.updates.len());
+        let update = match update.origin() {
+            Some(_) => update,
+            None => update.with_origin(Some(Instant::now())),
+        };
+        if let Some(ref shadow) = self.shadow {
+            shadow.update(&update);
+            return
+        }
         for (_, item) in &mut self.updates {
             if item.suspended {
                 continue
             }
+            let update = match item.filter {
+                Some(ref filter) => filter.apply(&update),
+                None => update.clone()
+            };
             match item.sender.as_mut() {
                 Some(sender) => {
-                    if sender.send(Ok(update.clone())).await.is_ok() {
+                    if sender.send(Ok(update)).await.is_ok() {
                         continue
                     }
                 }
@@ -179,7 +290,14 @@ impl Gate {
             item.sender = None
         }
         self.updates.retain(|_, item| item.sender.is_some());
+        self.metrics.set_consumers(self.updates.len());
         self.metrics.update(&update);
+
+        // This only fails if there are no watchers which is fine.
+        let _ = self.notices.broadcast(Some(UpdateNotice::new(&update)));
+
+        self.prefix_watches.notify(self.handle.data().as_ref(), &update);
+        self.handle.update(&update);
     }
 
     /// Updates the unit status.
@@ -199,6 +317,7 @@ impl Gate {
             item.sender = None
         }
         self.updates.retain(|_, item| item.sender.is_some());
+        self.metrics.set_consumers(self.updates.len());
         self.metrics.update_status(update);
     }
 
@@ -223,12 +342,40 @@ impl Gate {
     fn subscribe(
         &mut self,
         suspended: bool,
+        filter: Option<Arc<LinkFilter>>,
         response: oneshot::Sender<SubscribeResponse>
     ) {
-        let (tx, receiver) = mpsc::channel(UPDATE_QUEUE_LEN);
+        if let Some((ref name, max)) = self.max_consumers {
+            if self.updates.len() >= max {
+                error!(
+                    "Unit {}: rejecting new consumer, the unit accepts at \
+                     most {} consumers (see 'max-consumers').",
+                    name, max
+                );
+                self.metrics.reject_consumer();
+                // Dropping the response makes the link see the unit as
+                // gone.
+                return
+            }
+        }
+        let (mut tx, receiver) = mpsc::channel(UPDATE_QUEUE_LEN);
+        if self.replay && !suspended {
+            if let Some((serial, set)) = self.handle.data() {
+                let mut update = payload::Update::new(serial, set, None);
+                if let Some(ids) = self.metrics.update_ids() {
+                    update = update.with_ids(ids)
+                }
+                if let Some(ref filter) = filter {
+                    update = filter.apply(&update)
+                }
+                // The channel is new, so there is space.
+                let _ = tx.try_send(Ok(update));
+            }
+        }
         let slot = self.updates.insert(UpdateSender {
             sender: Some(tx),
             suspended,
+            filter,
         });
         let subscription = SubscribeResponse {
             slot,
@@ -238,6 +385,14 @@ impl Gate {
         if let Err(subscription) = response.send(subscription) {
             self.updates.remove(subscription.slot);
         }
+        self.metrics.set_consumers(self.updates.len());
+    }
+}
+
+
+impl Drop for Gate {
+    fn drop(&mut self) {
+        self.handle.set_gone()
     }
 }
 
@@ -253,6 +408,12 @@ impl Gate {
 #[derive(Clone, Debug)]
 pub struct GateAgent {
     commands: mpsc::Sender<GateCommand>,
+
+    /// A receiver for update notices to be cloned for new watchers.
+    notices: watch::Receiver<Option<UpdateNotice>>,
+
+    /// The prefix watches of the gate.
+    prefix_watches: PrefixWatches,
 }
 
 impl GateAgent {
@@ -260,6 +421,207 @@ impl GateAgent {
     pub fn create_link(&mut self) -> Link {
         Link::new(self.commands.clone())
     }
+
+    /// Creates a new watcher for the gate.
+    pub fn create_watcher(&self) -> Watcher {
+        Watcher { notices: self.notices.clone() }
+    }
+
+    /// Registers a callback for changes to payload covered by a prefix.
+    ///
+    /// Whenever the unit produces an update that announces or withdraws
+    /// payload whose prefix is equal to or more specific than `prefix`,
+    /// `callback` is called with a [`VrpChangeEvent`] for each such change.
+    ///
+    /// The callback is called synchronously by the unit while it is
+    /// distributing the update, so it should return quickly.
+    ///
+    /// The watch stays registered until the returned handle is dropped.
+    pub fn watch_prefix(
+        &self,
+        prefix: Prefix,
+        callback: Arc<dyn Fn(VrpChangeEvent) + Send + Sync>
+    ) -> WatchHandle {
+        let slot = self.prefix_watches.0.lock().unwrap().insert(
+            PrefixWatch { prefix, callback }
+        );
+        WatchHandle {
+            watches: Arc::downgrade(&self.prefix_watches.0),
+            slot
+        }
+    }
+}
+
+
+//------------ Watcher -------------------------------------------------------
+
+/// A lightweight observer of a unit’s updates.
+///
+/// Unlike a link, a watcher doesn’t receive the updates themselves but only
+/// an [`UpdateNotice`] with a few facts about each of them. Watchers are not
+/// considered by the gate when determining whether it is active, so a unit
+/// that only has watchers may not produce any updates at all.
+///
+/// Notices don’t queue up. If a watcher falls behind, it only receives the
+/// notice for the most recent update.
+#[derive(Clone, Debug)]
+pub struct Watcher {
+    notices: watch::Receiver<Option<UpdateNotice>>,
+}
+
+impl Watcher {
+    /// Waits for the notice of the next update.
+    ///
+    /// The first call returns the notice for the most recent update right
+    /// away if there has been one. The method resolves into an error if the
+    /// unit has gone away.
+    pub async fn changed(&mut self) -> Result<UpdateNotice, Terminated> {
+        loop {
+            match self.notices.recv().await {
+                Some(Some(notice)) => return Ok(notice),
+                Some(None) => continue,
+                None => return Err(Terminated)
+            }
+        }
+    }
+}
+
+
+//------------ PrefixWatches -------------------------------------------------
+
+/// The prefix watches registered with a gate.
+#[derive(Clone, Default)]
+struct PrefixWatches(Arc<Mutex<Slab<PrefixWatch>>>);
+
+/// A single prefix watch.
+struct PrefixWatch {
+    /// The prefix covering the payload to watch.
+    prefix: Prefix,
+
+    /// The callback to call for each change.
+    callback: Arc<dyn Fn(VrpChangeEvent) + Send + Sync>,
+}
+
+impl PrefixWatches {
+    /// Calls the callbacks of all watches affected by an update.
+    ///
+    /// If the update’s diff can’t be used to get from the last update to
+    /// this one, the changes are determined from the data sets instead.
+    fn notify(
+        &self,
+        last: Option<&(Serial, Arc<payload::Set>)>,
+        update: &payload::Update
+    ) {
+        // Don’t hold the lock while calling the callbacks.
+        let watches: Vec<_> = self.0.lock().unwrap().iter().map(|item| {
+            (item.1.prefix, item.1.callback.clone())
+        }).collect();
+        if watches.is_empty() {
+            return
+        }
+        let diff = match last {
+            Some((serial, set)) => match update.get_usable_diff(*serial) {
+                Some(diff) => diff,
+                None => Arc::new(payload::Diff::reconcile(set, &update.set()))
+            },
+            None => Arc::new(
+                payload::Diff::reconcile(&Default::default(), &update.set())
+            )
+        };
+        let serial = update.serial();
+        let timestamp = Instant::now();
+        for &(payload, action) in diff.iter() {
+            for (prefix, callback) in &watches {
+                if prefix.covers(&payload) {
+                    callback(VrpChangeEvent {
+                        payload, action, serial, timestamp
+                    })
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PrefixWatches {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.0.lock().unwrap().len();
+        write!(f, "PrefixWatches({} watches)", len)
+    }
+}
+
+
+//------------ WatchHandle ---------------------------------------------------
+
+/// A handle to a prefix watch.
+///
+/// The watch is removed when the handle is dropped.
+#[derive(Debug)]
+pub struct WatchHandle {
+    /// The watches of the gate.
+    watches: Weak<Mutex<Slab<PrefixWatch>>>,
+
+    /// The slot of our watch.
+    slot: usize,
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        if let Some(watches) = self.watches.upgrade() {
+            watches.lock().unwrap().remove(self.slot);
+        }
+    }
+}
+
+
+//------------ VrpChangeEvent ------------------------------------------------
+
+/// A change to payload covered by a prefix watch.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VrpChangeEvent {
+    /// The payload that changed.
+    pub payload: Payload,
+
+    /// Whether the payload was announced or withdrawn.
+    pub action: Action,
+
+    /// The serial number of the update containing the change.
+    pub serial: Serial,
+
+    /// When the change was distributed.
+    pub timestamp: Instant,
+}
+
+
+//------------ UpdateNotice --------------------------------------------------
+
+/// A notice that a unit has produced an update.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UpdateNotice {
+    /// The serial number of the update.
+    pub serial: Serial,
+
+    /// The date and time the update was handed to the gate.
+    pub time: DateTime<Utc>,
+
+    /// The number of payload items in the update’s data set.
+    pub count: usize,
+
+    /// The number of announcements and withdrawals in the update.
+    ///
+    /// This is `None` if the update didn’t come with a diff.
+    pub changes: Option<(usize, usize)>,
+}
+
+impl UpdateNotice {
+    /// Creates the notice for an update.
+    fn new(update: &payload::Update) -> Self {
+        UpdateNotice {
+            serial: update.serial(),
+            time: Utc::now(),
+            count: update.set().len(),
+            changes: update.diff().map(payload::Diff::action_counts),
+        }
+    }
 }
 
 
@@ -289,6 +651,38 @@ pub struct GateMetrics {
     ///
     /// If there has never been an update, this will be `None`.
     update: AtomicCell<Option<DateTime<Utc>>>,
+
+    /// The IDs of the source updates of the last update.
+    ///
+    /// If there has never been an update, this will be `None`.
+    update_ids: AtomicCell<Option<payload::UpdateIds>>,
+
+    /// The number of updates published.
+    updates: AtomicU64,
+
+    /// The number of updates published that only withdrew items.
+    withdraw_only_updates: AtomicU64,
+
+    /// The date and time the unit last established a connection.
+    ///
+    /// This is only available for units that connect to a server and will
+    /// be `None` until they have done so.
+    connection: AtomicCell<Option<DateTime<Utc>>>,
+
+    /// Statistics about the data set of the last update.
+    ///
+    /// Determining these takes a while for large sets, so they are only
+    /// computed when the metrics are scraped.
+    set_stats: Mutex<SetStats>,
+
+    /// The pipeline latency of the last update.
+    latency: PipelineLatency,
+
+    /// The number of links currently subscribed to the gate.
+    consumers: AtomicUsize,
+
+    /// The number of links rejected because of the consumer limit.
+    rejected_consumers: AtomicU64,
 }
 
 impl GateMetrics {
@@ -297,12 +691,95 @@ impl GateMetrics {
         self.serial.store(update.serial().into(), atomic::Ordering::Relaxed);
         self.count.store(update.set().len(), atomic::Ordering::Relaxed);
         self.update.store(Some(Utc::now()));
+        self.update_ids.store(Some(update.ids()));
+        self.updates.fetch_add(1, atomic::Ordering::Relaxed);
+        if let Some((0, withdrawn)) = update.diff().map(
+            payload::Diff::action_counts
+        ) {
+            if withdrawn > 0 {
+                self.withdraw_only_updates.fetch_add(
+                    1, atomic::Ordering::Relaxed
+                );
+            }
+        }
+        self.latency.record(update);
+        *self.set_stats.lock().unwrap() = SetStats::new(update.set());
     }
 
     /// Updates the metrics to match the given unit status.
     fn update_status(&self, status: UnitStatus) {
         self.status.store(status)
     }
+
+    /// Records that the unit has established a connection at `time`.
+    pub fn connected(&self, time: DateTime<Utc>) {
+        self.connection.store(Some(time))
+    }
+
+    /// Sets the number of links currently subscribed.
+    fn set_consumers(&self, consumers: usize) {
+        self.consumers.store(consumers, atomic::Ordering::Relaxed)
+    }
+
+    /// Counts a link rejected because of the consumer limit.
+    fn reject_consumer(&self) {
+        self.rejected_consumers.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the number of links currently subscribed.
+    pub fn consumers(&self) -> usize {
+        self.consumers.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the number of links rejected because of the consumer limit.
+    pub fn rejected_consumers(&self) -> u64 {
+        self.rejected_consumers.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the current unit status.
+    pub fn status(&self) -> UnitStatus {
+        self.status.load()
+    }
+
+    /// Returns the serial number of the last update.
+    pub fn serial(&self) -> u32 {
+        self.serial.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the number of payload items in the last update.
+    pub fn count(&self) -> usize {
+        self.count.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the date and time of the last update if there was one.
+    pub fn update_time(&self) -> Option<DateTime<Utc>> {
+        self.update.load()
+    }
+
+    /// Returns the IDs of the source updates of the last update if any.
+    pub fn update_ids(&self) -> Option<payload::UpdateIds> {
+        self.update_ids.load()
+    }
+
+    /// Returns the number of updates published so far.
+    pub fn updates(&self) -> u64 {
+        self.updates.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the number of published updates that only withdrew items.
+    pub fn withdraw_only_updates(&self) -> u64 {
+        self.withdraw_only_updates.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the date and time of the last connection if any.
+    pub fn connection_time(&self) -> Option<DateTime<Utc>> {
+        self.connection.load()
+    }
+
+    /// Returns the pipeline latency of the last update if known.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency.last()
+    }
 }
 
 impl GateMetrics {
@@ -326,6 +803,35 @@ impl GateMetrics {
         "since_last_update", "the number of seconds since the last update",
         MetricType::Gauge, MetricUnit::Second
     );
+    const UPDATES_METRIC: Metric = Metric::new(
+        "gate_updates", "the number of updates published by the unit",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const WITHDRAW_ONLY_METRIC: Metric = Metric::new(
+        "gate_withdraw_only_updates",
+        "the number of published updates that only withdrew VRPs",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const PREFIX_LEN_METRIC: Metric = Metric::new(
+        "vrp_count_by_prefix_len",
+        "the number of VRPs in the last update by prefix length",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const SPECIAL_PURPOSE_METRIC: Metric = Metric::new(
+        "special_purpose_vrps",
+        "the number of VRPs for special-purpose addresses",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const CONSUMERS_METRIC: Metric = Metric::new(
+        "gate_consumers",
+        "the number of links subscribed to the unit",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const REJECTED_CONSUMERS_METRIC: Metric = Metric::new(
+        "gate_rejected_consumers",
+        "the number of links rejected because of the consumer limit",
+        MetricType::Counter, MetricUnit::Total
+    );
 }
 
 impl metrics::Source for GateMetrics {
@@ -367,6 +873,360 @@ impl metrics::Source for GateMetrics {
                 );
             }
         }
+        target.append_simple(
+            &Self::UPDATES_METRIC, Some(unit_name), self.updates()
+        );
+        target.append_simple(
+            &Self::WITHDRAW_ONLY_METRIC, Some(unit_name),
+            self.withdraw_only_updates()
+        );
+        let (prefix_lens, special_purpose) = {
+            let mut stats = self.set_stats.lock().unwrap();
+            let (prefix_lens, special_purpose) = stats.get();
+            (prefix_lens.clone(), special_purpose)
+        };
+        target.append(&Self::PREFIX_LEN_METRIC, Some(unit_name), |records| {
+            for &(af, ref counts) in &[
+                ("ipv4", prefix_lens.v4_sorted()),
+                ("ipv6", prefix_lens.v6_sorted()),
+            ] {
+                for &(len, count) in counts {
+                    records.label_value(
+                        &[("len", &len.to_string()), ("af", af)], count
+                    );
+                }
+            }
+        });
+        target.append_simple(
+            &Self::SPECIAL_PURPOSE_METRIC, Some(unit_name), special_purpose
+        );
+        target.append_simple(
+            &Self::CONSUMERS_METRIC, Some(unit_name), self.consumers()
+        );
+        target.append_simple(
+            &Self::REJECTED_CONSUMERS_METRIC, Some(unit_name),
+            self.rejected_consumers()
+        );
+        metrics::Source::append(&self.latency, unit_name, target);
+    }
+}
+
+
+//------------ SetStats ------------------------------------------------------
+
+/// Statistics about a data set computed when first needed.
+#[derive(Debug, Default)]
+struct SetStats {
+    /// The data set if the statistics haven’t been computed yet.
+    set: Option<Arc<payload::Set>>,
+
+    /// The number of payload items by prefix length.
+    prefix_lens: payload::PrefixLenHistogram,
+
+    /// The number of payload items for special-purpose addresses.
+    special_purpose: usize,
+}
+
+impl SetStats {
+    /// Creates the statistics for a data set without computing them yet.
+    fn new(set: Arc<payload::Set>) -> Self {
+        SetStats { set: Some(set), .. Default::default() }
+    }
+
+    /// Returns the statistics, computing them if necessary.
+    ///
+    /// Returns the prefix length histogram and the number of items for
+    /// special-purpose addresses.
+    fn get(&mut self) -> (&payload::PrefixLenHistogram, usize) {
+        if let Some(set) = self.set.take() {
+            self.prefix_lens = set.prefix_len_histogram();
+            self.special_purpose = set.find_special_purpose_vrps().len();
+        }
+        (&self.prefix_lens, self.special_purpose)
+    }
+}
+
+
+//------------ PipelineLatency -----------------------------------------------
+
+/// The time updates took to travel through the pipeline.
+///
+/// This is the time between the first unit publishing the data of an update
+/// and the update being published by a unit or received by a target. It is
+/// kept by the gate of each unit and by targets, so comparing the latencies
+/// along a chain of units shows where the time is spent.
+#[derive(Debug, Default)]
+pub struct PipelineLatency {
+    /// The latency of the last update with a known origin.
+    last: AtomicCell<Option<Duration>>,
+}
+
+impl PipelineLatency {
+    const LATENCY_METRIC: Metric = Metric::new(
+        "pipeline_latency",
+        "the time since the data of the last update was first published, \
+         -1 if unknown",
+        MetricType::Gauge, MetricUnit::Second
+    );
+
+    /// Records the latency of an update.
+    ///
+    /// Does nothing if the update’s origin isn’t known.
+    pub fn record(&self, update: &payload::Update) {
+        if let Some(origin) = update.origin() {
+            self.last.store(Some(origin.elapsed()))
+        }
+    }
+
+    /// Returns the latency of the last update if known.
+    pub fn last(&self) -> Option<Duration> {
+        self.last.load()
+    }
+}
+
+impl metrics::Source for PipelineLatency {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        match self.last() {
+            Some(latency) => {
+                target.append_simple(
+                    &Self::LATENCY_METRIC, Some(unit_name),
+                    latency.as_secs_f64()
+                )
+            }
+            None => {
+                target.append_simple(
+                    &Self::LATENCY_METRIC, Some(unit_name), -1
+                )
+            }
+        }
+    }
+}
+
+
+//------------ ShadowOutput --------------------------------------------------
+
+/// What a unit in shadow mode would have published.
+///
+/// The last data set is available as JSON under `/api/v1/shadow/<unit>` on
+/// the HTTP server.
+#[derive(Debug)]
+pub struct ShadowOutput {
+    /// The name of the unit.
+    name: Arc<str>,
+
+    /// The path of the HTTP resource.
+    path: String,
+
+    /// The serial number and data set of the last update.
+    data: ArcSwap<Option<(Serial, Arc<payload::Set>)>>,
+}
+
+impl ShadowOutput {
+    /// Creates a new shadow output for the unit with the given name.
+    fn new(name: &str) -> Self {
+        ShadowOutput {
+            name: name.into(),
+            path: format!("/api/v1/shadow/{}", name),
+            data: Default::default(),
+        }
+    }
+
+    /// Returns the serial number and data set of the last update if any.
+    pub fn data(&self) -> Option<(Serial, Arc<payload::Set>)> {
+        (**self.data.load()).clone()
+    }
+
+    /// Logs and keeps an update that would have been published.
+    fn update(&self, update: &payload::Update) {
+        let changes = match (update.diff(), self.data()) {
+            (Some(diff), _) => Some(diff.action_counts()),
+            (None, Some((_, old))) => {
+                Some(update.set().diff_from(&old).action_counts())
+            }
+            (None, None) => None
+        };
+        match changes {
+            Some((announced, withdrawn)) => {
+                info!(
+                    "Unit {} (shadow mode): would publish update {} with \
+                     serial {}: {} VRPs, {} announced, {} withdrawn.",
+                    self.name, update.ids(), update.serial(),
+                    update.set().len(), announced, withdrawn
+                );
+            }
+            None => {
+                info!(
+                    "Unit {} (shadow mode): would publish update {} with \
+                     serial {}: {} VRPs.",
+                    self.name, update.ids(), update.serial(),
+                    update.set().len()
+                );
+            }
+        }
+        self.data.store(Arc::new(Some((update.serial(), update.set()))));
+    }
+}
+
+impl http::ProcessRequest for ShadowOutput {
+    fn process_request(
+        &self, request: &mut Request<Body>
+    ) -> Option<Response<Body>> {
+        if
+            request.method() != Method::GET
+            || request.uri().path() != self.path
+        {
+            return None
+        }
+        let format = output::Format::Json;
+        Some(match self.data() {
+            Some((_, set)) => {
+                Response::builder()
+                .header("Content-Type", format.content_type())
+                .body(Body::wrap_stream(stream::iter(
+                    format.stream(set).map(Result::<_, Infallible>::Ok)
+                )))
+                .unwrap()
+            }
+            None => {
+                Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("Content-Type", "text/plain")
+                .body("No data yet.".into())
+                .unwrap()
+            }
+        })
+    }
+}
+
+
+//------------ UnitHandle ----------------------------------------------------
+
+/// A lightweight handle to the state of a unit.
+///
+/// The handle is kept up to date by the unit’s gate. It can be cloned
+/// cheaply and held across await points. Once the gate has been dropped,
+/// i.e., the unit has terminated, the handle reports the unit as gone and
+/// doesn’t provide any data anymore.
+#[derive(Clone, Debug)]
+pub struct UnitHandle {
+    inner: Arc<HandleInner>,
+}
+
+#[derive(Debug)]
+struct HandleInner {
+    /// The metrics of the unit’s gate.
+    metrics: Arc<GateMetrics>,
+
+    /// The serial number and data set of the last update.
+    data: ArcSwap<Option<(Serial, Arc<payload::Set>)>>,
+
+    /// Has the gate been dropped?
+    gone: AtomicBool,
+}
+
+impl UnitHandle {
+    /// Creates a new handle using the given gate metrics.
+    fn new(metrics: Arc<GateMetrics>) -> Self {
+        UnitHandle {
+            inner: Arc::new(HandleInner {
+                metrics,
+                data: ArcSwap::from_pointee(None),
+                gone: AtomicBool::new(false),
+            })
+        }
+    }
+
+    /// Returns the current status of the unit.
+    pub fn status(&self) -> UnitStatus {
+        if self.is_gone() {
+            UnitStatus::Gone
+        }
+        else {
+            self.inner.metrics.status()
+        }
+    }
+
+    /// Returns the serial number and data set of the last update.
+    ///
+    /// Returns `None` if the unit hasn’t produced an update yet or is gone.
+    pub fn data(&self) -> Option<(Serial, Arc<payload::Set>)> {
+        if self.is_gone() {
+            return None
+        }
+        (**self.inner.data.load()).clone()
+    }
+
+    /// Returns the gate metrics of the unit.
+    pub fn metrics(&self) -> Arc<GateMetrics> {
+        self.inner.metrics.clone()
+    }
+
+    /// Returns whether the unit has terminated.
+    pub fn is_gone(&self) -> bool {
+        self.inner.gone.load(atomic::Ordering::Acquire)
+    }
+
+    /// Updates the data to match the given update.
+    fn update(&self, update: &payload::Update) {
+        self.inner.data.store(Arc::new(Some((update.serial(), update.set()))))
+    }
+
+    /// Marks the unit as gone and releases its data.
+    fn set_gone(&self) {
+        self.inner.gone.store(true, atomic::Ordering::Release);
+        self.inner.data.store(Arc::new(None));
+    }
+}
+
+
+//------------ Registry ------------------------------------------------------
+
+/// The handles of all running units by their name.
+///
+/// The registry is maintained by the manager and available to all
+/// components via [`Component::registry`](manager::Component::registry).
+/// Values can be cloned and all clones share the same data.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    units: Arc<Mutex<HashMap<String, UnitHandle>>>,
+}
+
+impl Registry {
+    /// Adds the handle for a unit, replacing any previous one.
+    pub fn register(&self, name: &str, handle: UnitHandle) {
+        self.units.lock().unwrap().insert(name.into(), handle);
+    }
+
+    /// Removes the handle for a unit.
+    pub fn remove(&self, name: &str) {
+        self.units.lock().unwrap().remove(name);
+    }
+
+    /// Returns the handle for the unit with the given name.
+    pub fn get(&self, name: &str) -> Option<UnitHandle> {
+        self.units.lock().unwrap().get(name).cloned()
+    }
+
+    /// Returns the names of all registered units.
+    pub fn names(&self) -> Vec<String> {
+        self.units.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the status and last serial number of all registered units.
+    ///
+    /// The list is ordered by unit name. Comparing the lists returned at
+    /// different times shows whether any unit has changed in between.
+    pub fn states(&self) -> Vec<(String, UnitStatus, Option<Serial>)> {
+        let mut res: Vec<_> = self.units.lock().unwrap().iter().map(
+            |(name, handle)| {
+                (
+                    name.clone(), handle.status(),
+                    handle.data().map(|(serial, _)| serial)
+                )
+            }
+        ).collect();
+        res.sort_by(|left, right| left.0.cmp(&right.0));
+        res
     }
 }
 
@@ -387,11 +1247,14 @@ impl metrics::Source for GateMetrics {
 /// Note, however, that the function only adds the link to a list of links
 /// to be properly connected by the manager later. 
 #[derive(Debug, Deserialize)]
-#[serde(from = "String")]
+#[serde(from = "LinkSpec")]
 pub struct Link {
     /// A sender of commands to the gate.
     commands: mpsc::Sender<GateCommand>,
 
+    /// The filter for the updates received via the link.
+    filter: Option<Arc<LinkFilter>>,
+
     /// The connection to the unit.
     ///
     /// If this is `None`, the link has not been connected yet.
@@ -402,6 +1265,9 @@ pub struct Link {
 
     /// Are we currently suspended?
     suspended: bool,
+
+    /// The name of the connected unit if it is known.
+    unit_name: Option<String>,
 }
 
 #[derive(Debug)]
@@ -418,12 +1284,27 @@ impl Link {
     fn new(commands: mpsc::Sender<GateCommand>) -> Self {
         Link {
             commands,
+            filter: None,
             connection: None,
             unit_status: UnitStatus::Healthy,
             suspended: false,
+            unit_name: None,
         }
     }
 
+    /// Sets the name of the connected unit.
+    pub fn with_unit_name(mut self, name: String) -> Self {
+        self.unit_name = Some(name);
+        self
+    }
+
+    /// Returns the name of the connected unit if it is known.
+    ///
+    /// This is the case for all links created from the config.
+    pub fn unit_name(&self) -> Option<&str> {
+        self.unit_name.as_deref()
+    }
+
     /// Query for the next update.
     ///
     /// The method returns a future that resolves into the next update. The
@@ -527,9 +1408,9 @@ impl Link {
         }
 
         let (tx, rx) = oneshot::channel();
-        if self.commands.send(
-            GateCommand::Subscribe { suspended, response: tx }
-        ).await.is_err() {
+        if self.commands.send(GateCommand::Subscribe {
+            suspended, filter: self.filter.clone(), response: tx
+        }).await.is_err() {
             self.unit_status = UnitStatus::Gone;
             return Err(UnitStatus::Gone)
         }
@@ -567,6 +1448,114 @@ impl From<String> for Link {
     }
 }
 
+impl From<LinkSpec> for Link {
+    fn from(spec: LinkSpec) -> Self {
+        match spec {
+            LinkSpec::Name(name) => name.into(),
+            LinkSpec::Filtered { unit, filter } => {
+                let mut link = Link::from(unit);
+                link.filter = Some(Arc::new(filter));
+                link
+            }
+        }
+    }
+}
+
+
+//------------ LinkSpec ------------------------------------------------------
+
+/// How a link is given in the config.
+///
+/// A link is either given as the name of the unit or as a table with the
+/// name in the `unit` field and the fields of a [`LinkFilter`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LinkSpec {
+    Name(String),
+    Filtered {
+        unit: String,
+
+        #[serde(flatten)]
+        filter: LinkFilter,
+    }
+}
+
+
+//------------ LinkFilter ----------------------------------------------------
+
+/// A filter for the data received via a link.
+///
+/// All conditions that are given must be met for an item to pass the
+/// filter. Since the filter looks at each item separately, the updates
+/// received via a filtered link still have correct diffs: only the changes
+/// to items passing the filter are kept.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LinkFilter {
+    /// Only items of this address family pass.
+    #[serde(default)]
+    family: Option<LinkFamily>,
+
+    /// Only items covered by one of these prefixes pass.
+    #[serde(default)]
+    prefixes: Option<Vec<Prefix>>,
+
+    /// Only items for one of these AS numbers pass.
+    #[serde(default)]
+    asns: Option<Vec<u32>>,
+}
+
+impl LinkFilter {
+    /// Returns whether an item passes the filter.
+    pub fn keep(&self, item: &Payload) -> bool {
+        let (family, asn) = match *item {
+            Payload::V4(ref item) => (LinkFamily::V4, item.asn),
+            Payload::V6(ref item) => (LinkFamily::V6, item.asn),
+        };
+        if let Some(ref keep) = self.family {
+            if *keep != family {
+                return false
+            }
+        }
+        if let Some(ref prefixes) = self.prefixes {
+            if !prefixes.iter().any(|prefix| prefix.covers(item)) {
+                return false
+            }
+        }
+        if let Some(ref asns) = self.asns {
+            if !asns.contains(&asn) {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Returns the update with only the items passing the filter.
+    pub fn apply(&self, update: &payload::Update) -> payload::Update {
+        payload::Update::new(
+            update.serial(),
+            Arc::new(update.set().filter(|item| self.keep(item))),
+            update.diff().map(|diff| {
+                Arc::new(diff.filter(|item| self.keep(item)))
+            })
+        ).with_audit_only(
+            update.is_audit_only()
+        ).with_ids(update.ids()).with_origin(update.origin())
+    }
+}
+
+
+//------------ LinkFamily ----------------------------------------------------
+
+/// An address family for a link filter.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum LinkFamily {
+    #[serde(rename = "ipv4")]
+    V4,
+
+    #[serde(rename = "ipv6")]
+    V6,
+}
+
 
 //------------ GateStatus ----------------------------------------------------
 
@@ -665,6 +1654,9 @@ enum GateCommand {
         /// Should the subscription start in suspended state?
         suspended: bool,
 
+        /// The filter for the updates sent to the subscription.
+        filter: Option<Arc<LinkFilter>>,
+
         /// The sender for the response.
         ///
         /// The response payload is the slot number of the subscription.
@@ -687,7 +1679,10 @@ struct UpdateSender {
     sender: Option<mpsc::Sender<Result<payload::Update, UnitStatus>>>,
 
     /// Are we currently suspended?
-    suspended: bool
+    suspended: bool,
+
+    /// The filter for the updates sent.
+    filter: Option<Arc<LinkFilter>>,
 }
 
 
@@ -712,3 +1707,285 @@ struct SubscribeResponse {
     unit_status: UnitStatus,
 }
 
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn watcher() {
+        let (mut gate, agent) = Gate::new();
+        let mut watcher = agent.create_watcher();
+        let set = Arc::new(payload::Set::default());
+
+        gate.update_data(
+            payload::Update::new(Serial::from(1), set.clone(), None)
+        ).await;
+        let notice = watcher.changed().await.unwrap();
+        assert_eq!(notice.serial, Serial::from(1));
+        assert_eq!(notice.changes, None);
+
+        gate.update_data(
+            payload::Update::new(
+                Serial::from(2), set, Some(Default::default())
+            )
+        ).await;
+        let notice = watcher.changed().await.unwrap();
+        assert_eq!(notice.serial, Serial::from(2));
+        assert_eq!(notice.changes, Some((0, 0)));
+
+        drop(gate);
+        assert!(watcher.changed().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn update_counts() {
+        use crate::payload::testing::v4;
+
+        let mut builder = payload::SetBuilder::empty();
+        builder.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        let full = Arc::new(builder.finalize());
+        let empty = Arc::new(payload::Set::default());
+
+        let (mut gate, _agent) = Gate::new();
+        let metrics = gate.metrics();
+        gate.update_data(
+            payload::Update::new(Serial::from(1), full.clone(), None)
+        ).await;
+        assert_eq!(metrics.updates(), 1);
+        assert_eq!(metrics.withdraw_only_updates(), 0);
+
+        // Shrinking the set still counts as an update.
+        gate.update_data(payload::Update::new(
+            Serial::from(2), empty.clone(),
+            Some(Arc::new(empty.diff_from(&full)))
+        )).await;
+        assert_eq!(metrics.updates(), 2);
+        assert_eq!(metrics.withdraw_only_updates(), 1);
+        assert_eq!(metrics.count(), 0);
+    }
+
+    #[test]
+    fn set_stats() {
+        use crate::payload::testing::v4;
+
+        let mut builder = payload::SetBuilder::empty();
+        builder.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        builder.insert(v4([198, 18, 0, 0], 15, 24, 64496)).unwrap();
+
+        // The statistics are only computed when asked for.
+        let mut stats = SetStats::new(Arc::new(builder.finalize()));
+        assert_eq!(stats.prefix_lens, Default::default());
+        let (prefix_lens, special_purpose) = stats.get();
+        assert_eq!(prefix_lens.v4_sorted(), vec![(15, 1), (24, 1)]);
+        assert_eq!(special_purpose, 2);
+        assert!(stats.set.is_none());
+    }
+
+    #[tokio::test]
+    async fn unit_handle() {
+        let registry = Registry::default();
+        let (mut gate, _agent) = Gate::new();
+        registry.register("rtr", gate.handle());
+        let handle = registry.get("rtr").unwrap();
+        assert!(registry.get("json").is_none());
+        assert_eq!(handle.status(), UnitStatus::Healthy);
+        assert!(handle.data().is_none());
+
+        gate.update_data(
+            payload::Update::new(
+                Serial::from(1), Arc::new(payload::Set::default()), None
+            )
+        ).await;
+        gate.update_status(UnitStatus::Stalled).await;
+        assert_eq!(handle.data().unwrap().0, Serial::from(1));
+        assert_eq!(handle.status(), UnitStatus::Stalled);
+        assert_eq!(handle.metrics().serial(), 1);
+
+        drop(gate);
+        assert_eq!(handle.status(), UnitStatus::Gone);
+        assert!(handle.data().is_none());
+    }
+
+    #[tokio::test]
+    async fn max_consumers() {
+        let (mut gate, mut agent) = Gate::new();
+        gate.set_max_consumers("rtr", 1);
+        let metrics = gate.metrics();
+        let mut first = agent.create_link();
+        let mut second = agent.create_link();
+        tokio::spawn(async move {
+            while gate.process().await.is_ok() { }
+        });
+        assert!(first.connect(false).await.is_ok());
+        assert_eq!(second.connect(false).await, Err(UnitStatus::Gone));
+        assert_eq!(metrics.consumers(), 1);
+        assert_eq!(metrics.rejected_consumers(), 1);
+    }
+
+    #[tokio::test]
+    async fn shadow_mode() {
+        use http::ProcessRequest;
+
+        let (mut gate, mut agent) = Gate::new();
+        let shadow = gate.enable_shadow_mode("filter");
+        let mut link = agent.create_link();
+        let mut request = Request::get("/api/v1/shadow/filter").body(
+            Body::empty()
+        ).unwrap();
+        assert_eq!(
+            shadow.process_request(&mut request).unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        gate = {
+            let process = tokio::spawn(async move {
+                let _ = gate.process().await;
+                gate
+            });
+            link.connect(false).await.unwrap();
+            process.await.unwrap()
+        };
+
+        // The update is kept but doesn’t reach the link.
+        gate.update_data(payload::Update::new(
+            Serial::from(1), Arc::new(payload::Set::default()), None
+        )).await;
+        assert_eq!(shadow.data().unwrap().0, Serial::from(1));
+        assert!(gate.handle().data().is_none());
+        let updates = &mut link.connection.as_mut().unwrap().updates;
+        assert!(updates.try_recv().is_err());
+        assert_eq!(
+            shadow.process_request(&mut request).unwrap().status(),
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn pipeline_latency() {
+        let set = Arc::new(payload::Set::default());
+
+        // A new update gets its origin at the first gate.
+        let (mut gate, _agent) = Gate::new();
+        assert_eq!(gate.metrics().latency(), None);
+        gate.update_data(
+            payload::Update::new(Serial::from(1), set.clone(), None)
+        ).await;
+        assert!(gate.metrics().latency().unwrap() < Duration::from_secs(1));
+
+        // A derived update keeps its origin.
+        let origin = Instant::now() - Duration::from_millis(50);
+        let update = payload::Update::new(
+            Serial::from(1), set.clone(), None
+        ).with_origin(Some(origin));
+        let update = LinkFilter::default().apply(&update);
+        assert_eq!(update.origin(), Some(origin));
+        let (mut gate, _agent) = Gate::new();
+        gate.update_data(update.clone()).await;
+        assert!(
+            gate.metrics().latency().unwrap() >= Duration::from_millis(50)
+        );
+
+        // Targets only record updates with an origin.
+        let latency = PipelineLatency::default();
+        latency.record(&payload::Update::new(Serial::from(1), set, None));
+        assert_eq!(latency.last(), None);
+        latency.record(&update);
+        assert!(latency.last().unwrap() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn link_filter() {
+        use crate::payload::testing::{v4, v6};
+
+        let v4 = |octet, asn| v4([192, 0, octet, 0], 24, 24, asn);
+        let v6 = |asn| v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 48, asn);
+
+        fn set(items: &[Payload]) -> payload::Set {
+            let mut set = payload::SetBuilder::empty();
+            for item in items {
+                set.insert(*item).unwrap();
+            }
+            set.finalize()
+        }
+
+        let filter: LinkFilter = toml::from_str(
+            "family = \"ipv4\"\nprefixes = [\"192.0.2.0/23\"]"
+        ).unwrap();
+        assert!(filter.keep(&v4(2, 64496)));
+        assert!(filter.keep(&v4(3, 64497)));
+        assert!(!filter.keep(&v4(4, 64496)));
+        assert!(!filter.keep(&v6(64496)));
+
+        let filter: LinkFilter = toml::from_str("asns = [64496]").unwrap();
+        let old = set(&[v4(2, 64496), v4(3, 64497)]);
+        let new = set(&[v4(3, 64496), v4(3, 64497), v6(64496), v6(64497)]);
+        let update = filter.apply(&payload::Update::new(
+            Serial::from(2), Arc::new(new.clone()),
+            Some(Arc::new(new.diff_from(&old)))
+        ).with_audit_only(true));
+        assert_eq!(update.serial(), Serial::from(2));
+        assert!(update.is_audit_only());
+        assert_eq!(
+            update.set().iter().cloned().collect::<Vec<_>>(),
+            set(&[v4(3, 64496), v6(64496)]).iter().cloned()
+                .collect::<Vec<_>>()
+        );
+        // The diff leads from the filtered old set to the filtered new one.
+        let old = old.filter(|item| filter.keep(item));
+        assert_eq!(
+            update.diff().unwrap().apply(&old).iter().cloned()
+                .collect::<Vec<_>>(),
+            update.set().iter().cloned().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn prefix_watch() {
+        use std::mem;
+        use std::str::FromStr;
+        use crate::payload::testing::v4;
+
+        fn vrp(octet: u8) -> Payload {
+            v4([192, 0, octet, 0], 24, 24, 64496)
+        }
+
+        fn update(serial: u32, octets: &[u8]) -> payload::Update {
+            let mut set = payload::SetBuilder::empty();
+            for &octet in octets {
+                set.insert(vrp(octet)).unwrap();
+            }
+            payload::Update::new(
+                Serial::from(serial), Arc::new(set.finalize()), None
+            )
+        }
+
+        let (mut gate, agent) = Gate::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let handle = agent.watch_prefix(
+            Prefix::from_str("192.0.2.0/23").unwrap(),
+            Arc::new({
+                let events = events.clone();
+                move |event: VrpChangeEvent| {
+                    events.lock().unwrap().push((
+                        event.payload, event.action
+                    ))
+                }
+            })
+        );
+        let take = || mem::replace(&mut *events.lock().unwrap(), Vec::new());
+
+        gate.update_data(update(1, &[1, 2])).await;
+        assert_eq!(take(), vec![(vrp(2), Action::Announce)]);
+        gate.update_data(update(2, &[1, 3, 4])).await;
+        assert_eq!(
+            take(),
+            vec![(vrp(2), Action::Withdraw), (vrp(3), Action::Announce)]
+        );
+        drop(handle);
+        gate.update_data(update(3, &[2])).await;
+        assert!(take().is_empty());
+    }
+}
@@ -1,19 +1,25 @@
 //! Configuration.
 //!
-//! RTRTR is configured through a single TOML configuration file. We use
-//! [serde] to deserialize this file into the [`Config`] struct provided by
-//! this module. This struct also provides the facilities to load the config
-//! file referred to in command line options.
+//! RTRTR is configured through a single configuration file in TOML, JSON,
+//! or YAML format. We use [serde] to deserialize this file into the
+//! [`Config`] struct provided by this module. This struct also provides the
+//! facilities to load the config file referred to in command line options.
 
 use std::{borrow, error, fmt, fs, io, ops};
 use std::path::Path;
 use std::sync::Arc;
 use clap::{App, Arg, ArgMatches};
+use log::warn;
 use serde::Deserialize;
+use serde::de::Error as _;
 use toml::Spanned;
-use crate::http;
+use crate::{harden, http};
+use crate::payload::Prefix;
+use crate::limits::ResourceLimits;
+use crate::siem::SiemConfig;
 use crate::log::{ExitError, Failed, LogConfig};
 use crate::manager::{Manager, TargetSet, UnitSet};
+use crate::random::Random;
 
 
 //------------ Config --------------------------------------------------------
@@ -43,6 +49,45 @@ pub struct Config {
     /// The HTTP server configuration.
     #[serde(flatten)]
     pub http: http::Server,
+
+    /// The configuration for dropping privileges.
+    #[serde(flatten)]
+    pub harden: harden::Hardening,
+
+    /// The maximum number of concurrent outbound connections.
+    ///
+    /// If this is `None`, the number is not limited.
+    #[serde(rename = "max-outbound-connections", default)]
+    pub max_outbound_connections: Option<usize>,
+
+    /// The seed for all random choices.
+    ///
+    /// If this is `None`, random choices are not reproducible. The
+    /// `RTRTR_RANDOM_SEED` environment variable overrides the value when
+    /// the config is loaded via [`from_arg_matches`](Self::from_arg_matches).
+    #[serde(rename = "random-seed", default)]
+    pub random_seed: Option<u64>,
+
+    /// The period in seconds for analysing the data sets of all units.
+    ///
+    /// If this is `None`, the data sets are not analysed. See
+    /// [`analysis`](crate::analysis) for details.
+    #[serde(rename = "set-analysis", default)]
+    pub set_analysis: Option<u64>,
+
+    /// The prefixes to trace changes for.
+    ///
+    /// See [`watch`](crate::watch) for details.
+    #[serde(default)]
+    pub watch: Vec<Prefix>,
+
+    /// The configuration for checking resource limits.
+    #[serde(flatten)]
+    pub limits: ResourceLimits,
+
+    /// The configuration for exporting events to SIEM systems.
+    #[serde(flatten)]
+    pub siem: SiemConfig,
 }
 
 impl Config {
@@ -53,9 +98,73 @@ impl Config {
         LogConfig::init_logging()
     }
 
+    /// Creates a configuration from a bytes slice in the given format.
+    pub fn from_slice(
+        slice: &[u8], format: ConfigFormat
+    ) -> Result<Self, ParseError> {
+        match format {
+            ConfigFormat::Toml => Self::from_toml(slice).map_err(Into::into),
+            ConfigFormat::Json => Self::from_json(slice).map_err(Into::into),
+            ConfigFormat::Yaml => Self::from_yaml(slice).map_err(Into::into),
+        }
+    }
+
     /// Creates a configuration from a bytes slice with TOML data.
+    ///
+    /// If the data contains a `defaults` section, the defaults given therein
+    /// are merged into the units and targets before they are deserialized.
+    /// See [`apply_defaults`] for details.
     pub fn from_toml(slice: &[u8]) -> Result<Self, toml::de::Error> {
-        toml::de::from_slice(slice)
+        let value: toml::Value = toml::de::from_slice(slice)?;
+        if value.get("defaults").is_none() {
+            // Deserialize straight from the data so errors keep their
+            // positions.
+            return toml::de::from_slice(slice)
+        }
+        Self::from_value(value)
+    }
+
+    /// Creates a configuration from a bytes slice with JSON data.
+    ///
+    /// The data is structured exactly like the TOML data, including the
+    /// `defaults` section.
+    pub fn from_json(slice: &[u8]) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_slice(slice)?;
+        if value.get("defaults").is_none() {
+            return serde_json::from_slice(slice)
+        }
+        Self::from_value(
+            serde_json::from_value(value)?
+        ).map_err(serde_json::Error::custom)
+    }
+
+    /// Creates a configuration from a bytes slice with YAML data.
+    ///
+    /// The data is structured exactly like the TOML data, including the
+    /// `defaults` section.
+    pub fn from_yaml(slice: &[u8]) -> Result<Self, serde_yaml::Error> {
+        let value: serde_yaml::Value = serde_yaml::from_slice(slice)?;
+        if value.get("defaults").is_none() {
+            return serde_yaml::from_slice(slice)
+        }
+        Self::from_value(
+            serde_yaml::from_value(value)?
+        ).map_err(serde_yaml::Error::custom)
+    }
+
+    /// Creates a configuration from a value after applying the defaults.
+    ///
+    /// Since the value has lost the positions in the original data, this
+    /// should only be used if there are defaults to apply.
+    fn from_value(mut value: toml::Value) -> Result<Self, toml::de::Error> {
+        for section in apply_defaults(&mut value)? {
+            warn!(
+                "Defaults given for {} type '{}' but there is no {} of \
+                 this type.",
+                section.kind, section.name, section.kind
+            );
+        }
+        Self::deserialize(value)
     }
 
     /// Configures a clap app with the arguments to load the configuration.
@@ -66,6 +175,12 @@ impl Config {
                  .takes_value(true)
                  .value_name("PATH")
                  .help("Read base configuration from this file")
+        ).arg(Arg::with_name("config-format")
+                 .long("config-format")
+                 .takes_value(true)
+                 .value_name("FORMAT")
+                 .possible_values(&["toml", "json", "yaml"])
+                 .help("Format of the config file [default: by extension]")
         );
         LogConfig::config_args(app)
     }
@@ -86,7 +201,7 @@ impl Config {
         manager: &mut Manager,
     ) -> Result<Self, Failed> {
         let conf_path = cur_dir.join(matches.value_of("config").unwrap());
-        let conf = match ConfigFile::load(&conf_path) {
+        let mut conf = match ConfigFile::load(&conf_path) {
             Ok(conf) => conf,
             Err(err) => {
                 eprintln!(
@@ -97,14 +212,216 @@ impl Config {
                 return Err(Failed)
             }
         };
+        if let Some(format) = matches.value_of("config-format") {
+            // Clap has checked the value already.
+            if let Some(format) = ConfigFormat::from_name(format) {
+                conf.set_format(format)
+            }
+        }
         let mut res = manager.load(conf)?;
         res.log.update_with_arg_matches(matches, cur_dir)?;
         res.log.switch_logging(false)?;
+        if let Some(seed) = Random::seed_from_env()? {
+            res.random_seed = Some(seed)
+        }
         Ok(res)
     }
 }
 
 
+//------------ ConfigFormat --------------------------------------------------
+
+/// The format of a config file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConfigFormat {
+    /// The config file is in TOML.
+    Toml,
+
+    /// The config file is in JSON.
+    Json,
+
+    /// The config file is in YAML.
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Determines the format from the extension of a file name.
+    ///
+    /// Files ending in `.json` are JSON, files ending in `.yaml` or `.yml`
+    /// are YAML. Everything else is TOML.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    /// Returns the format for a name as used in the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "toml" => Some(ConfigFormat::Toml),
+            "json" => Some(ConfigFormat::Json),
+            "yaml" => Some(ConfigFormat::Yaml),
+            _ => None
+        }
+    }
+}
+
+
+//------------ ParseError ----------------------------------------------------
+
+/// Parsing a config file has failed.
+///
+/// All variants include the line and column of the error when displayed.
+#[derive(Debug)]
+pub enum ParseError {
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+}
+
+impl From<toml::de::Error> for ParseError {
+    fn from(err: toml::de::Error) -> Self {
+        ParseError::Toml(err)
+    }
+}
+
+impl From<serde_json::Error> for ParseError {
+    fn from(err: serde_json::Error) -> Self {
+        ParseError::Json(err)
+    }
+}
+
+impl From<serde_yaml::Error> for ParseError {
+    fn from(err: serde_yaml::Error) -> Self {
+        ParseError::Yaml(err)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Toml(ref err) => err.fmt(f),
+            ParseError::Json(ref err) => err.fmt(f),
+            ParseError::Yaml(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for ParseError { }
+
+
+//------------ apply_defaults ------------------------------------------------
+
+/// Merges the per-type defaults into the units and targets of a config.
+///
+/// The defaults are given in the `defaults` table of the config. It can
+/// contain a `units` and a `targets` table which in turn contain a table
+/// for each unit or target type, e.g., `[defaults.units.rtr]`. The keys of
+/// such a table are added to each unit or target of that type that doesn’t
+/// have the key itself. Since the merged keys are then deserialized with the
+/// unit or target, they are checked just like the unit’s own keys,
+/// including rejecting unknown keys.
+///
+/// The `defaults` table is removed from `config`. Returns the defaults
+/// sections that did not apply to any unit or target.
+fn apply_defaults(
+    config: &mut toml::Value
+) -> Result<Vec<UnusedDefaults>, toml::de::Error> {
+    let config = match config.as_table_mut() {
+        Some(config) => config,
+        None => return Ok(Vec::new())
+    };
+    let defaults = match config.remove("defaults") {
+        Some(toml::Value::Table(defaults)) => defaults,
+        Some(_) => {
+            return Err(toml::de::Error::custom(
+                "'defaults' must be a table"
+            ))
+        }
+        None => return Ok(Vec::new())
+    };
+    let mut unused = Vec::new();
+    for (kind, types) in defaults {
+        let types = match types {
+            toml::Value::Table(types) => types,
+            _ => {
+                return Err(toml::de::Error::custom(format!(
+                    "'defaults.{}' must be a table", kind
+                )))
+            }
+        };
+        let singular = match kind.as_str() {
+            "units" => "unit",
+            "targets" => "target",
+            _ => {
+                return Err(toml::de::Error::custom(format!(
+                    "unknown defaults section 'defaults.{}', \
+                     expected 'units' or 'targets'", kind
+                )))
+            }
+        };
+        for (name, keys) in types {
+            let keys = match keys {
+                toml::Value::Table(keys) => keys,
+                _ => {
+                    return Err(toml::de::Error::custom(format!(
+                        "'defaults.{}.{}' must be a table", kind, name
+                    )))
+                }
+            };
+            if keys.contains_key("type") {
+                return Err(toml::de::Error::custom(format!(
+                    "'defaults.{}.{}' must not contain 'type'", kind, name
+                )))
+            }
+            let mut used = false;
+            let components = config.get_mut(&kind).and_then(|components| {
+                components.as_table_mut()
+            });
+            for (_, component) in components.into_iter().flat_map(|c| {
+                c.iter_mut()
+            }) {
+                let component = match component.as_table_mut() {
+                    Some(component) => component,
+                    None => continue,
+                };
+                let is_type = component.get("type").and_then(|value| {
+                    value.as_str()
+                }) == Some(name.as_str());
+                if !is_type {
+                    continue
+                }
+                used = true;
+                for (key, value) in &keys {
+                    if !component.contains_key(key) {
+                        component.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            if !used {
+                unused.push(UnusedDefaults { kind: singular, name })
+            }
+        }
+    }
+    Ok(unused)
+}
+
+
+//------------ UnusedDefaults ------------------------------------------------
+
+/// A defaults section that didn’t apply to any unit or target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct UnusedDefaults {
+    /// The kind of component, i.e., “unit” or “target”.
+    kind: &'static str,
+
+    /// The name of the unit or target type.
+    name: String,
+}
+
+
 //------------ Source --------------------------------------------------------
 
 /// Description of the source of configuration.
@@ -272,6 +589,9 @@ pub struct ConfigFile {
     /// The data of this file.
     bytes: Vec<u8>,
 
+    /// The format of the data.
+    format: ConfigFormat,
+
     /// The start indexes of lines.
     ///
     /// The start index of the first line is in `line_start[0]` and so on.
@@ -284,6 +604,7 @@ impl ConfigFile {
         fs::read(path).map(|bytes| {
             ConfigFile {
                 source: path.into(),
+                format: ConfigFormat::from_path(path.as_ref()),
                 line_starts: bytes.split(|ch| *ch == b'\n').fold(
                     vec![0], |mut starts, slice| {
                         starts.push(
@@ -308,6 +629,15 @@ impl ConfigFile {
         &self.bytes
     }
 
+    pub fn format(&self) -> ConfigFormat {
+        self.format
+    }
+
+    /// Overrides the format determined from the file name.
+    pub fn set_format(&mut self, format: ConfigFormat) {
+        self.format = format
+    }
+
     fn resolve_pos(&self, pos: usize) -> LineCol {
         let line = self.line_starts.iter().find(|&&start|
             start < pos
@@ -353,3 +683,96 @@ impl fmt::Display for ConfigError {
 
 impl error::Error for ConfigError { }
 
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn merged(config: &str) -> (toml::Value, Vec<UnusedDefaults>) {
+        let mut value = toml::from_str(config).unwrap();
+        let unused = apply_defaults(&mut value).unwrap();
+        (value, unused)
+    }
+
+    #[test]
+    fn defaults_precedence() {
+        let (value, unused) = merged(
+            "[defaults.units.rtr]\n\
+             audit-only = true\n\
+             retry = 30\n\
+             [units.a]\n\
+             type = \"rtr\"\n\
+             remote = \"localhost:3323\"\n\
+             retry = 5\n\
+             [units.b]\n\
+             type = \"json\"\n\
+             uri = \"http://localhost/vrps.json\"\n\
+             [targets.c]\n\
+             type = \"rtr\"\n\
+             unit = \"a\"\n"
+        );
+        assert!(unused.is_empty());
+        assert!(value.get("defaults").is_none());
+        let a = &value["units"]["a"];
+        assert_eq!(a["audit-only"].as_bool(), Some(true));
+        assert_eq!(a["retry"].as_integer(), Some(5));
+        assert!(value["units"]["b"].get("retry").is_none());
+        assert!(value["targets"]["c"].get("retry").is_none());
+    }
+
+    #[test]
+    fn defaults_unused() {
+        let (_, unused) = merged(
+            "[defaults.units.json]\n\
+             refresh = 600\n\
+             [defaults.targets.http]\n\
+             format = \"json\"\n\
+             [units.a]\n\
+             type = \"rtr\"\n\
+             remote = \"localhost:3323\"\n"
+        );
+        assert_eq!(unused.len(), 2);
+        assert!(unused.contains(&UnusedDefaults {
+            kind: "unit", name: "json".into()
+        }));
+        assert!(unused.contains(&UnusedDefaults {
+            kind: "target", name: "http".into()
+        }));
+    }
+
+    #[test]
+    fn defaults_invalid() {
+        let mut value = toml::from_str(
+            "[defaults.units.rtr]\n\
+             type = \"json\"\n"
+        ).unwrap();
+        assert!(apply_defaults(&mut value).is_err());
+        let mut value = toml::from_str(
+            "[defaults.rtr]\n\
+             refresh = 600\n"
+        ).unwrap();
+        assert!(apply_defaults(&mut value).is_err());
+    }
+
+    #[test]
+    fn defaults_unknown_keys() {
+        let config = |defaults: &str| {
+            Config::from_toml(format!(
+                "http-listen = []\n\
+                 {}\n\
+                 [units.a]\n\
+                 type = \"rtr\"\n\
+                 remote = \"localhost:3323\"\n\
+                 [targets]\n",
+                defaults
+            ).as_bytes())
+        };
+        assert!(config("[defaults.units.rtr]\nretry = 5\n").is_ok());
+        assert!(config(
+            "[defaults.units.rtr]\nrefresh = 600\n"
+        ).err().unwrap().to_string().contains("refresh"));
+    }
+}
@@ -0,0 +1,240 @@
+//! Evaluating a configuration against given data.
+//!
+//! When changing a configuration, it is helpful to know what exactly
+//! changes in the output of each target. The `eval` command answers this
+//! question by running the configured units with fixed input data and
+//! writing what each target would serve into a file. Comparing the files
+//! produced with the old and new configuration then shows the effect of
+//! the change.
+//!
+//! The input data is given either as a JSON file in the format understood
+//! by the json unit or as an [event store][crate::payload::EventStore]
+//! file, in which case the data set after the last update is used. It
+//! replaces the data of all units that would otherwise fetch it from
+//! elsewhere, i.e., the rtr, json, and rtan units. See
+//! [`Unit::inject`](crate::units::Unit::inject) for details.
+//!
+//! Targets are not started. Instead, the data set of each target’s unit is
+//! collected once all units have settled and converted into the target’s
+//! output. This output is written to a file in the output directory named
+//! after the target with an extension matching the format. The http target
+//! uses its configured format, all other targets produce JSON.
+
+use std::{fs, mem};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{error, info, warn};
+use tokio::runtime;
+use tokio::time::{timeout_at, Instant};
+use crate::payload;
+use crate::comms::{Link, UnitStatus};
+use crate::config::Config;
+use crate::formats::json::Set as JsonSet;
+use crate::log::{ExitError, Failed};
+use crate::manager::Manager;
+use crate::payload::EventStore;
+use crate::targets::EvalOutput;
+
+
+//------------ Eval ----------------------------------------------------------
+
+/// The `eval` command.
+pub struct Eval;
+
+impl Eval {
+    /// The name of the command.
+    pub const NAME: &'static str = "eval";
+
+    /// How long units need to stay unchanged to be considered settled.
+    const SETTLE: Duration = Duration::from_secs(1);
+
+    /// How long to wait at most for the units to settle.
+    const MAX_WAIT: Duration = Duration::from_secs(300);
+
+    /// Returns the clap sub-command for the command.
+    pub fn subcommand<'a: 'b, 'b>() -> App<'a, 'b> {
+        Config::config_args(
+            SubCommand::with_name(Self::NAME)
+            .about("writes the output of all targets for the given data")
+        )
+        .arg(Arg::with_name("input")
+            .long("input")
+            .takes_value(true)
+            .value_name("PATH")
+            .required(true)
+            .help("Read the input data from this JSON or event store file")
+        )
+        .arg(Arg::with_name("out")
+            .long("out")
+            .takes_value(true)
+            .value_name("DIR")
+            .required(true)
+            .help("Write the output of the targets into this directory")
+        )
+    }
+
+    /// Runs the command.
+    ///
+    /// The `matches` must be those of the sub-command returned by
+    /// [`subcommand`](Self::subcommand).
+    pub fn run(
+        matches: &ArgMatches, cur_dir: &Path
+    ) -> Result<(), ExitError> {
+        let input = cur_dir.join(matches.value_of("input").unwrap());
+        let out = cur_dir.join(matches.value_of("out").unwrap());
+        let mut manager = Manager::new();
+        let config = Config::from_arg_matches(
+            matches, cur_dir, &mut manager
+        )?;
+        let set = Self::load_input(&input)?;
+        Self::eval(manager, config, set, &out)
+    }
+
+    /// Evaluates a config with the given input data.
+    ///
+    /// The config must have been loaded via `manager`. The output of the
+    /// targets is written to the directory `out` which is created if
+    /// necessary.
+    pub fn eval(
+        mut manager: Manager,
+        mut config: Config,
+        set: Arc<payload::Set>,
+        out: &Path,
+    ) -> Result<(), ExitError> {
+        if let Err(err) = fs::create_dir_all(out) {
+            error!(
+                "Failed to create output directory {}: {}",
+                out.display(), err
+            );
+            return Err(ExitError)
+        }
+
+        config.units.inject(set);
+        let targets = mem::take(&mut config.targets).into_eval();
+        let mut runtime = runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        // The targets’ links only connect once we query them, so they
+        // need to get the data published before that, too.
+        manager.enable_replay();
+        manager.spawn(&mut config, &runtime);
+        runtime.block_on(Self::evaluate(&manager, targets, out))
+    }
+
+    /// Loads the input data from the file at `path`.
+    ///
+    /// The file is either a JSON file in the format of the json unit or an
+    /// event store file of which the data set after the last update is
+    /// used.
+    pub fn load_input(path: &Path) -> Result<Arc<payload::Set>, Failed> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) => {
+                error!(
+                    "Failed to read input file {}: {}", path.display(), err
+                );
+                return Err(Failed)
+            }
+        };
+        if EventStore::is_event_store(&data) {
+            match EventStore::read_last(path) {
+                Ok(Some(update)) => Ok(update.set()),
+                Ok(None) => {
+                    error!("Input file {} contains no data.", path.display());
+                    Err(Failed)
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to read input file {}: {}",
+                        path.display(), err
+                    );
+                    Err(Failed)
+                }
+            }
+        }
+        else {
+            match serde_json::from_slice::<JsonSet>(&data) {
+                Ok(set) => Ok(Arc::new(set.into_payload())),
+                Err(err) => {
+                    error!(
+                        "Failed to parse input file {}: {}",
+                        path.display(), err
+                    );
+                    Err(Failed)
+                }
+            }
+        }
+    }
+
+    /// Collects the data of all targets and writes their output.
+    async fn evaluate(
+        manager: &Manager,
+        targets: Vec<(String, Link, EvalOutput)>,
+        out: &Path,
+    ) -> Result<(), ExitError> {
+        // Each target’s link is queried by its own task which keeps the
+        // last data set and counts everything it receives.
+        let received = Arc::new(AtomicUsize::new(0));
+        let mut sinks = Vec::new();
+        for (name, mut link, output) in targets {
+            let data = Arc::new(Mutex::new(None));
+            let task_data = data.clone();
+            let task_received = received.clone();
+            tokio::spawn(async move {
+                loop {
+                    match link.query().await {
+                        Ok(update) => {
+                            *task_data.lock().unwrap() = Some(update.set());
+                        }
+                        Err(UnitStatus::Gone) => return,
+                        Err(_) => { }
+                    }
+                    task_received.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            sinks.push((name, data, output));
+        }
+
+        // The units may have settled before the targets’ tasks got the
+        // last update, so we wait until those have settled, too.
+        let deadline = Instant::now() + Self::MAX_WAIT;
+        loop {
+            let before = received.load(Ordering::Relaxed);
+            if timeout_at(
+                deadline, manager.wait_quiescent(Self::SETTLE)
+            ).await.is_err() {
+                warn!(
+                    "Units haven’t settled after {} seconds. Writing the \
+                     current output.",
+                    Self::MAX_WAIT.as_secs()
+                );
+                break
+            }
+            if received.load(Ordering::Relaxed) == before {
+                break
+            }
+        }
+
+        for (name, data, output) in sinks {
+            let set = match data.lock().unwrap().clone() {
+                Some(set) => set,
+                None => {
+                    warn!("Target {}: no data received.", name);
+                    continue
+                }
+            };
+            let path = out.join(format!("{}.{}", name, output.extension()));
+            if let Err(err) = output.write(set, &path) {
+                error!("Failed to write {}: {}", path.display(), err);
+                return Err(ExitError)
+            }
+            info!("Target {}: output written to {}.", name, path.display());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+//! BGP hijack events from CAIDA’s BGP Hijacks Observatory.
+//!
+//! The observatory publishes the suspicious routing events it detects as
+//! JSON. Its API wraps the list of events into an object with the list in
+//! the `data` field, but a plain list of events is accepted as well. Of
+//! each event, only the affected `prefixes` and the `attackers`, i.e., the
+//! origin AS numbers of the suspicious announcements, are used:
+//!
+//! ```json
+//! {
+//!   "data": [
+//!     {
+//!       "id": "moas-1596110400-64497_64496",
+//!       "event_type": "moas",
+//!       "prefixes": [ "192.0.2.0/24" ],
+//!       "attackers": [ 64497 ],
+//!       "victims": [ 64496 ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! The AS numbers can be given as integers or as strings with an `AS`
+//! prefix. All other fields are kept as they are and copied into the
+//! report.
+//!
+//! The [`write_report`] function cross-references the events with a
+//! payload set and determines for each event whether route origin
+//! validation would have caught the suspicious announcements.
+
+use std::io;
+use std::net::IpAddr;
+use rpki_rtr::payload::Payload;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::payload;
+use crate::payload::{Prefix, RovIndex, RovState};
+use super::json::Asn;
+
+
+//------------ HijackEvents --------------------------------------------------
+
+/// The events of a hijack feed.
+#[derive(Clone, Debug, Default)]
+pub struct HijackEvents {
+    /// The events in the order of the feed.
+    events: Vec<HijackEvent>,
+}
+
+impl HijackEvents {
+    /// Reads the events from a feed in JSON format.
+    pub fn from_json(
+        reader: impl io::Read
+    ) -> Result<Self, serde_json::Error> {
+        let events = match serde_json::from_reader(reader)? {
+            Feed::Wrapped { data } => data,
+            Feed::List(data) => data,
+        };
+        Ok(HijackEvents {
+            events: events.into_iter().map(|raw| {
+                let fields = EventFields::deserialize(&raw)?;
+                Ok(HijackEvent {
+                    prefixes: fields.prefixes,
+                    attackers: fields.attackers.into_iter().map(|asn| {
+                        asn.0
+                    }).collect(),
+                    raw
+                })
+            }).collect::<Result<_, serde_json::Error>>()?
+        })
+    }
+
+    /// Returns the number of events.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns whether there are no events.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns an iterator over the events.
+    pub fn iter(&self) -> impl Iterator<Item = &HijackEvent> {
+        self.events.iter()
+    }
+}
+
+
+//------------ HijackEvent ---------------------------------------------------
+
+/// A single hijack event.
+#[derive(Clone, Debug)]
+pub struct HijackEvent {
+    /// The event as it appeared in the feed.
+    raw: Value,
+
+    /// The prefixes affected by the event.
+    prefixes: Vec<Prefix>,
+
+    /// The origin AS numbers of the suspicious announcements.
+    attackers: Vec<u32>,
+}
+
+impl HijackEvent {
+    /// Returns the validation state of the event and the covering items.
+    ///
+    /// The event is valid if the announcement of any of its prefixes by any
+    /// of its attackers is valid. Otherwise, it is invalid if any of its
+    /// prefixes is covered by an item and not found if none is. An event
+    /// without attackers thus can never be valid.
+    ///
+    /// The covering items of all prefixes are returned without duplicates.
+    pub fn coverage<'a>(
+        &self, index: &RovIndex<'a>
+    ) -> (RovState, Vec<&'a Payload>) {
+        let mut state = RovState::NotFound;
+        let mut covering = Vec::new();
+        for &prefix in &self.prefixes {
+            for item in index.covering(prefix) {
+                if !covering.contains(&item) {
+                    covering.push(item)
+                }
+            }
+            for &asn in &self.attackers {
+                match index.validate(prefix, asn) {
+                    RovState::Valid => state = RovState::Valid,
+                    RovState::Invalid if state == RovState::NotFound => {
+                        state = RovState::Invalid
+                    }
+                    _ => { }
+                }
+            }
+        }
+        if state == RovState::NotFound && !covering.is_empty() {
+            state = RovState::Invalid
+        }
+        (state, covering)
+    }
+}
+
+
+//------------ Feed ----------------------------------------------------------
+
+/// The top-level structure of a feed.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Feed {
+    /// The list of events wrapped in an object as served by the API.
+    Wrapped { data: Vec<Value> },
+
+    /// A plain list of events.
+    List(Vec<Value>),
+}
+
+/// The fields of an event we are interested in.
+#[derive(Deserialize)]
+struct EventFields {
+    #[serde(default)]
+    prefixes: Vec<Prefix>,
+
+    #[serde(default)]
+    attackers: Vec<Asn>,
+}
+
+
+//------------ write_report --------------------------------------------------
+
+/// Writes the report for the events in JSON format.
+///
+/// The report is a list with an object for each event. The object contains
+/// the original event in `event`, the validation state as determined by
+/// [`HijackEvent::coverage`] in `vrp_coverage` as one of `"valid"`,
+/// `"invalid"`, or `"not_found"`, and the covering items in
+/// `covering_vrps`.
+///
+/// Returns the number of events in each state.
+pub fn write_report(
+    set: &payload::Set, events: &HijackEvents, target: &mut impl io::Write
+) -> Result<payload::RovStats, io::Error> {
+    let index = RovIndex::new(set);
+    let mut stats = payload::RovStats::default();
+    let report: Vec<_> = events.iter().map(|event| {
+        let (state, covering) = event.coverage(&index);
+        let covering: Vec<_> = covering.into_iter().map(vrp_json).collect();
+        json!({
+            "event": event.raw,
+            "vrp_coverage": match state {
+                RovState::Valid => {
+                    stats.valid += 1;
+                    "valid"
+                }
+                RovState::Invalid => {
+                    stats.invalid += 1;
+                    "invalid"
+                }
+                RovState::NotFound => {
+                    stats.not_found += 1;
+                    "not_found"
+                }
+            },
+            "covering_vrps": covering,
+        })
+    }).collect();
+    serde_json::to_writer_pretty(&mut *target, &report)?;
+    writeln!(target)?;
+    Ok(stats)
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns a payload item as a VRP object of the JSON format.
+fn vrp_json(item: &Payload) -> Value {
+    let (addr, prefix_len, max_len, asn) = match *item {
+        Payload::V4(ref item) => (
+            IpAddr::V4(item.prefix), item.prefix_len, item.max_len, item.asn
+        ),
+        Payload::V6(ref item) => (
+            IpAddr::V6(item.prefix), item.prefix_len, item.max_len, item.asn
+        ),
+    };
+    json!({
+        "asn": format!("AS{}", asn),
+        "prefix": format!("{}/{}", addr, prefix_len),
+        "maxLength": max_len,
+    })
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::testing::v4;
+    use super::*;
+
+    #[test]
+    fn report() {
+        let mut set = payload::SetBuilder::empty();
+        set.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        let set = set.finalize();
+        let events = HijackEvents::from_json(r#"{ "data": [
+            { "id": "a", "prefixes": ["192.0.2.0/24"],
+              "attackers": [64497] },
+            { "id": "b", "prefixes": ["192.0.2.0/24"],
+              "attackers": ["AS64496"] },
+            { "id": "c", "prefixes": ["198.51.100.0/24"],
+              "attackers": [64497] },
+            { "id": "d", "prefixes": ["192.0.2.128/25"] }
+        ] }"#.as_bytes()).unwrap();
+        assert_eq!(events.len(), 4);
+
+        let mut out = Vec::new();
+        let stats = write_report(&set, &events, &mut out).unwrap();
+        assert_eq!(
+            stats,
+            payload::RovStats { valid: 1, invalid: 2, not_found: 1 }
+        );
+        let report: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report[0]["event"]["id"], "a");
+        assert_eq!(report[0]["vrp_coverage"], "invalid");
+        assert_eq!(
+            report[0]["covering_vrps"],
+            json!([
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24 }
+            ])
+        );
+        assert_eq!(report[1]["vrp_coverage"], "valid");
+        assert_eq!(report[2]["vrp_coverage"], "not_found");
+        assert_eq!(report[2]["covering_vrps"], json!([]));
+        assert_eq!(report[3]["vrp_coverage"], "invalid");
+
+        // A plain list works, too.
+        assert_eq!(
+            HijackEvents::from_json(
+                r#"[{ "prefixes": ["192.0.2.0/24"] }]"#.as_bytes()
+            ).unwrap().len(),
+            1
+        );
+    }
+}
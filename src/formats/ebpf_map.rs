@@ -0,0 +1,111 @@
+//! Fixed-width binary records for eBPF array maps.
+//!
+//! This format allows loading a payload set into a `BPF_MAP_TYPE_ARRAY` for
+//! use by XDP programs performing route origin validation in the kernel.
+//! Each route origin is written as a record of [`RECORD_LEN`] octets
+//! matching the following C struct which is also available in
+//! `contrib/ebpf/rtrtr_vrp.h`:
+//!
+//! ```c
+//! struct rtrtr_vrp {
+//!     __u8 af;          /* 4 for IPv4, 6 for IPv6 */
+//!     __u8 prefix[16];  /* network byte order, IPv4 in the first four */
+//!     __u8 prefix_len;
+//!     __u8 max_len;
+//!     __u8 _pad;        /* always zero */
+//!     __u32 asn;        /* host byte order */
+//! };
+//! ```
+//!
+//! The AS number is in host byte order as the map is only ever loaded into
+//! the kernel of the machine producing it.
+
+use std::io;
+use rpki_rtr::payload::Payload;
+use crate::payload::Set;
+
+
+//------------ Constants -----------------------------------------------------
+
+/// The length of a single record in octets.
+pub const RECORD_LEN: usize = 24;
+
+/// The address family value for IPv4 prefixes.
+pub const AF_IPV4: u8 = 4;
+
+/// The address family value for IPv6 prefixes.
+pub const AF_IPV6: u8 = 6;
+
+
+//------------ write_ebpf_array_map ------------------------------------------
+
+/// Writes the route origins of a set as an eBPF array map.
+///
+/// Writes one record per route origin in the order of the set. Returns the
+/// number of records written which is the number of map entries needed.
+pub fn write_ebpf_array_map(
+    set: &Set, mut writer: impl io::Write
+) -> Result<usize, io::Error> {
+    for item in set.iter() {
+        writer.write_all(&record(item))?;
+    }
+    Ok(set.len())
+}
+
+/// Produces the record for a single payload item.
+fn record(item: &Payload) -> [u8; RECORD_LEN] {
+    let mut res = [0u8; RECORD_LEN];
+    let (prefix_len, max_len, asn) = match *item {
+        Payload::V4(ref origin) => {
+            res[0] = AF_IPV4;
+            res[1..5].copy_from_slice(&origin.prefix.octets());
+            (origin.prefix_len, origin.max_len, origin.asn)
+        }
+        Payload::V6(ref origin) => {
+            res[0] = AF_IPV6;
+            res[1..17].copy_from_slice(&origin.prefix.octets());
+            (origin.prefix_len, origin.max_len, origin.asn)
+        }
+    };
+    res[17] = prefix_len;
+    res[18] = max_len;
+    res[20..24].copy_from_slice(&asn.to_ne_bytes());
+    res
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::{v4, v6};
+
+    #[test]
+    fn write_records() {
+        let mut set = SetBuilder::empty();
+        set.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        set.insert(
+            v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 48, 64497)
+        ).unwrap();
+        let set = set.finalize();
+
+        let mut data = Vec::new();
+        assert_eq!(write_ebpf_array_map(&set, &mut data).unwrap(), 2);
+        assert_eq!(data.len(), 2 * RECORD_LEN);
+
+        let (v4, v6) = data.split_at(RECORD_LEN);
+        assert_eq!(v4[0], AF_IPV4);
+        assert_eq!(&v4[1..5], &[192, 0, 2, 0]);
+        assert!(v4[5..17].iter().all(|&octet| octet == 0));
+        assert_eq!(&v4[17..20], &[24, 24, 0]);
+        assert_eq!(&v4[20..], &64496u32.to_ne_bytes());
+        assert_eq!(v6[0], AF_IPV6);
+        assert_eq!(&v6[1..5], &[0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(&v6[17..20], &[32, 48, 0]);
+        assert_eq!(&v6[20..], &64497u32.to_ne_bytes());
+    }
+}
@@ -0,0 +1,285 @@
+//! Operator contacts from Ghostbusters Records.
+//!
+//! Ghostbusters Records (GBRs, RFC 6493) are vCards published by the
+//! holder of a resource certificate to provide contact information for the
+//! resources it covers. Since the RPKI data we receive carries no GBRs, the
+//! records are read from a separate feed in JSON format:
+//!
+//! ```json
+//! {
+//!   "gbrs": [
+//!     {
+//!       "asns": [ "AS64496" ],
+//!       "prefixes": [ "192.0.2.0/24", "2001:db8::/32" ],
+//!       "vcard": "BEGIN:VCARD\r\nVERSION:4.0\r\nFN:Example\r\n..."
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! The `asns` and `prefixes` are the resources of the certificate the GBR
+//! was published under. A VRP belongs to a GBR if its origin AS is among
+//! the `asns` or its prefix is covered by one of the `prefixes`.
+//!
+//! The [`write_csv`] function cross-references a payload set with the
+//! records and produces a CSV file with one line per matching pair.
+
+use std::io;
+use rpki_rtr::payload::Payload;
+use serde::Deserialize;
+use crate::payload;
+use crate::payload::Prefix;
+use super::json::Asn;
+
+
+//------------ Contacts ------------------------------------------------------
+
+/// The contacts of a GBR feed.
+#[derive(Clone, Debug, Default)]
+pub struct Contacts {
+    /// The contacts in the order of the feed.
+    contacts: Vec<Contact>,
+}
+
+impl Contacts {
+    /// Reads the contacts from a feed in JSON format.
+    ///
+    /// Records whose vCard doesn’t have a name are skipped.
+    pub fn from_json(
+        reader: impl io::Read
+    ) -> Result<Self, serde_json::Error> {
+        let feed: Feed = serde_json::from_reader(reader)?;
+        Ok(Contacts {
+            contacts: feed.gbrs.into_iter().filter_map(|gbr| {
+                Contact::from_vcard(&gbr.vcard).map(|contact| Contact {
+                    asns: gbr.asns.into_iter().map(|asn| asn.0).collect(),
+                    prefixes: gbr.prefixes,
+                    .. contact
+                })
+            }).collect()
+        })
+    }
+
+    /// Returns the number of contacts.
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    /// Returns whether there are no contacts.
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
+    /// Returns an iterator over the contacts responsible for a VRP.
+    pub fn lookup<'a>(
+        &'a self, item: &'a Payload
+    ) -> impl Iterator<Item = &'a Contact> + 'a {
+        self.contacts.iter().filter(move |contact| contact.matches(item))
+    }
+}
+
+
+//------------ Contact -------------------------------------------------------
+
+/// The contact information of a single GBR.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Contact {
+    /// The formatted name of the operator.
+    pub name: String,
+
+    /// The email address of the operator if given.
+    pub email: Option<String>,
+
+    /// The AS numbers covered by the record.
+    asns: Vec<u32>,
+
+    /// The prefixes covered by the record.
+    prefixes: Vec<Prefix>,
+}
+
+impl Contact {
+    /// Extracts name and email address from a vCard.
+    ///
+    /// Returns `None` if there is no `FN` property. Only the first `EMAIL`
+    /// property is used.
+    fn from_vcard(vcard: &str) -> Option<Self> {
+        let mut name = None;
+        let mut email = None;
+        for line in unfold(vcard).lines() {
+            let colon = match line.find(':') {
+                Some(colon) => colon,
+                None => continue
+            };
+            // Property parameters such as `EMAIL;TYPE=work` are ignored.
+            let prop = line[..colon].split(';').next().unwrap_or("");
+            let value = unescape(&line[colon + 1..]);
+            if prop.eq_ignore_ascii_case("FN") && name.is_none() {
+                name = Some(value)
+            }
+            else if prop.eq_ignore_ascii_case("EMAIL") && email.is_none() {
+                email = Some(value)
+            }
+        }
+        name.map(|name| Contact {
+            name, email, asns: Vec::new(), prefixes: Vec::new()
+        })
+    }
+
+    /// Returns whether the record covers the given VRP.
+    fn matches(&self, item: &Payload) -> bool {
+        let asn = match *item {
+            Payload::V4(ref prefix) => prefix.asn,
+            Payload::V6(ref prefix) => prefix.asn,
+        };
+        self.asns.contains(&asn)
+        || self.prefixes.iter().any(|prefix| prefix.covers(item))
+    }
+}
+
+
+//------------ Feed ----------------------------------------------------------
+
+/// The JSON representation of the GBR feed.
+#[derive(Clone, Debug, Deserialize)]
+struct Feed {
+    gbrs: Vec<Gbr>,
+}
+
+/// A single GBR in the feed.
+#[derive(Clone, Debug, Deserialize)]
+struct Gbr {
+    #[serde(default)]
+    asns: Vec<Asn>,
+
+    #[serde(default)]
+    prefixes: Vec<Prefix>,
+
+    vcard: String,
+}
+
+
+//------------ write_csv -----------------------------------------------------
+
+/// Writes the contacts for the VRPs of a set as CSV.
+///
+/// The output starts with a header line followed by one line with the
+/// columns `prefix,asn,operator_name,operator_email` for each pair of VRP
+/// and matching contact. VRPs without any contact are left out.
+pub fn write_csv(
+    set: &payload::Set, contacts: &Contacts, target: &mut impl io::Write
+) -> Result<(), io::Error> {
+    writeln!(target, "prefix,asn,operator_name,operator_email")?;
+    for item in set.iter() {
+        let (prefix, asn) = match *item {
+            Payload::V4(ref prefix) => (
+                format!("{}/{}", prefix.prefix, prefix.prefix_len),
+                prefix.asn
+            ),
+            Payload::V6(ref prefix) => (
+                format!("{}/{}", prefix.prefix, prefix.prefix_len),
+                prefix.asn
+            ),
+        };
+        for contact in contacts.lookup(item) {
+            writeln!(
+                target, "{},AS{},{},{}",
+                prefix, asn, csv_field(&contact.name),
+                csv_field(contact.email.as_deref().unwrap_or(""))
+            )?;
+        }
+    }
+    Ok(())
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Unfolds the lines of a vCard.
+///
+/// A line starting with a space or tab continues the previous line.
+fn unfold(vcard: &str) -> String {
+    vcard.replace("\r\n", "\n").replace("\n ", "").replace("\n\t", "")
+}
+
+/// Removes the backslash escapes from a vCard value.
+fn unescape(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            res.push(ch);
+            continue
+        }
+        match chars.next() {
+            Some('n') | Some('N') => res.push('\n'),
+            Some(ch) => res.push(ch),
+            None => { }
+        }
+    }
+    res
+}
+
+/// Quotes a CSV field if necessary.
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+    else {
+        value.into()
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use crate::payload::testing::v4;
+    use super::*;
+    
+    
+
+    #[test]
+    fn contacts_csv() {
+        let feed = serde_json::json!({
+            "gbrs": [
+                {
+                    "asns": [ "AS64496" ],
+                    "vcard": "BEGIN:VCARD\r\nVERSION:4.0\r\n\
+                              FN:Example\\, Inc.\r\n\
+                              EMAIL;TYPE=work:noc@exa\r\n mple.net\r\n\
+                              END:VCARD\r\n"
+                },
+                {
+                    "prefixes": [ "198.51.100.0/24" ],
+                    "vcard": "BEGIN:VCARD\r\nFN:Other\r\nEND:VCARD\r\n"
+                },
+                {
+                    "asns": [ "AS64497" ],
+                    "vcard": "BEGIN:VCARD\r\nEND:VCARD\r\n"
+                }
+            ]
+        });
+        let contacts = Contacts::from_json(
+            feed.to_string().as_bytes()
+        ).unwrap();
+        assert_eq!(contacts.len(), 2);
+
+        let v4 = |addr: [u8; 4], asn| v4(addr, 24, 24, asn);
+        let mut builder = payload::SetBuilder::empty();
+        builder.insert(v4([192, 0, 2, 0], 64496)).unwrap();
+        builder.insert(v4([198, 51, 100, 0], 64496)).unwrap();
+        builder.insert(v4([203, 0, 113, 0], 64497)).unwrap();
+        let set = builder.finalize();
+
+        let mut csv = Vec::new();
+        write_csv(&set, &contacts, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "prefix,asn,operator_name,operator_email\n\
+             192.0.2.0/24,AS64496,\"Example, Inc.\",noc@example.net\n\
+             198.51.100.0/24,AS64496,\"Example, Inc.\",noc@example.net\n\
+             198.51.100.0/24,AS64496,Other,\n"
+        );
+    }
+}
@@ -2,9 +2,13 @@
 //!
 
 use std::fmt;
+use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use chrono::{DateTime, TimeZone, Utc};
+use rpki_rtr::client::VrpError;
 use rpki_rtr::payload::{Ipv4Prefix, Ipv6Prefix, Payload};
 use serde::de;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -18,14 +22,52 @@ use crate::payload;
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Set {
     metadata: Option<Metadata>,
+
+    #[serde(deserialize_with = "deserialize_records")]
     roas: Vec<Vrp>,
 }
 
 impl Set {
+    /// Creates a set from a list of VRPs without metadata.
+    pub fn from_vrps(roas: Vec<Vrp>) -> Self {
+        Set { metadata: None, roas }
+    }
+
+    /// Returns the time the data set was generated if available.
+    pub fn generated(&self) -> Option<DateTime<Utc>> {
+        self.metadata.as_ref().and_then(Metadata::generated)
+    }
+
+    /// Checks all VRPs and returns the index and problem of invalid ones.
+    pub fn validate(&self) -> Vec<(usize, VrpError)> {
+        payload::SetBuilder::validate_all(
+            &self.roas.iter().map(Vrp::to_payload).collect::<Vec<_>>()
+        )
+    }
+
+    /// Converts the VRPs into a payload set.
+    ///
+    /// Invalid VRPs are skipped and only the first of duplicate VRPs is
+    /// used. Use [`validate`][Self::validate] to learn about these.
     pub fn into_payload(self) -> payload::Set {
+        // Trust anchor names are shared between all items of the same
+        // trust anchor.
+        let mut tas = HashMap::<String, Arc<str>>::new();
         let mut res = payload::SetBuilder::empty();
         for item in self.roas {
-            let _ = res.insert(item.into_payload());
+            let payload = item.to_payload();
+            if payload::SetBuilder::validate(&payload).is_err() {
+                continue
+            }
+            let ta = match tas.get(&item.ta) {
+                Some(ta) => ta.clone(),
+                None => {
+                    let ta: Arc<str> = item.ta.as_str().into();
+                    tas.insert(item.ta, ta.clone());
+                    ta
+                }
+            };
+            let _ = res.insert_with_ta(payload, Some(ta));
         }
         res.finalize()
     }
@@ -34,8 +76,9 @@ impl Set {
 
 //------------ Vrp -----------------------------------------------------------
 
+/// A single VRP as used in the `roas` array.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-struct Vrp {
+pub struct Vrp {
     asn: Asn,
     prefix: Prefix,
 
@@ -45,7 +88,8 @@ struct Vrp {
 }
 
 impl Vrp {
-    fn into_payload(self) -> Payload {
+    /// Converts the VRP into a payload item.
+    pub fn to_payload(&self) -> Payload {
         match self.prefix.addr {
             IpAddr::V4(addr) => {
                 Payload::V4(Ipv4Prefix {
@@ -68,10 +112,47 @@ impl Vrp {
 }
 
 
+//------------ Routes --------------------------------------------------------
+
+/// A list of BGP routes to validate.
+///
+/// The list is an array of objects with the route’s `prefix` and the origin
+/// AS number in `asn`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Routes(
+    #[serde(deserialize_with = "deserialize_records")]
+    Vec<Route>
+);
+
+impl Routes {
+    /// Converts the list into pairs of prefix and origin AS number.
+    pub fn into_vec(self) -> Vec<(payload::Prefix, u32)> {
+        self.0.into_iter().map(|route| (route.prefix, route.asn.0)).collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Route {
+    prefix: payload::Prefix,
+    asn: Asn,
+}
+
+
 //------------ Asn -----------------------------------------------------------
 
 #[derive(Clone, Debug)]
-struct Asn(u32);
+pub(super) struct Asn(pub(super) u32);
+
+impl Asn {
+    /// Returns the error for an AS number outside the allowed range.
+    fn out_of_range<E: de::Error>(v: impl fmt::Display) -> E {
+        E::custom(format_args!(
+            "AS number {} out of range (must be between AS0 and AS{})",
+            v, u32::MAX
+        ))
+    }
+}
 
 impl Serialize for Asn {
     fn serialize<S: Serializer>(
@@ -93,7 +174,7 @@ impl<'de> Deserialize<'de> for Asn {
             type Value = Asn;
 
             fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                f.write_str("a string with an AS number")
+                f.write_str("a string or integer with an AS number")
             }
 
             fn visit_str<E: de::Error>(
@@ -112,22 +193,85 @@ impl<'de> Deserialize<'de> for Asn {
                         de::Unexpected::Str(v), &self
                     ))
                 }
-                match u32::from_str(&v[2..]) {
+
+                // u32::from_str would also accept a leading plus sign.
+                let digits = &v[2..];
+                if !digits.bytes().all(|ch| ch.is_ascii_digit()) {
+                    return Err(E::invalid_value(
+                        de::Unexpected::Str(v), &self
+                    ))
+                }
+                match u32::from_str(digits) {
                     Ok(asn) => Ok(Asn(asn)),
-                    Err(_) => {
-                        Err(E::invalid_value(
-                            de::Unexpected::Str(v), &self
-                        ))
-                    }
+                    Err(_) => Err(Asn::out_of_range(v))
                 }
             }
+
+            fn visit_u64<E: de::Error>(
+                self, v: u64
+            ) -> Result<Self::Value, E> {
+                if v > u64::from(u32::MAX) {
+                    return Err(Asn::out_of_range(v))
+                }
+                Ok(Asn(v as u32))
+            }
+
+            fn visit_i64<E: de::Error>(
+                self, v: i64
+            ) -> Result<Self::Value, E> {
+                if v < 0 || v > i64::from(u32::MAX) {
+                    return Err(Asn::out_of_range(v))
+                }
+                Ok(Asn(v as u32))
+            }
         }
 
-        deserializer.deserialize_str(Visitor)
+        deserializer.deserialize_any(Visitor)
     }
 }
 
 
+//------------ Records -------------------------------------------------------
+
+/// Deserializes a list of records.
+///
+/// If a record fails to deserialize, its index is added to the error so
+/// that the offending record can be found in large files.
+fn deserialize_records<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where D: Deserializer<'de>, T: Deserialize<'de> {
+    struct Visitor<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> de::Visitor<'de> for Visitor<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a list of records")
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(
+            self, mut seq: A
+        ) -> Result<Self::Value, A::Error> {
+            let mut res = Vec::with_capacity(
+                seq.size_hint().unwrap_or(0)
+            );
+            loop {
+                match seq.next_element() {
+                    Ok(Some(item)) => res.push(item),
+                    Ok(None) => return Ok(res),
+                    Err(err) => {
+                        return Err(de::Error::custom(format_args!(
+                            "record {}: {}", res.len(), err
+                        )))
+                    }
+                }
+            }
+        }
+    }
+
+    deserializer.deserialize_seq(Visitor(PhantomData))
+}
+
+
 //------------ Prefix --------------------------------------------------------
 
 #[derive(Clone, Copy, Debug)]
@@ -197,8 +341,35 @@ impl<'de> Deserialize<'de> for Prefix {
 
 //------------ Metadata ------------------------------------------------------
 
+/// The metadata of a data set.
+///
+/// Different producers provide different metadata, so all fields are
+/// optional.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Metadata {
+    /// The generation time in seconds since the Unix epoch.
+    ///
+    /// This is provided by Routinator and OctoRPKI.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated: Option<i64>,
+
+    /// The generation time in RFC 3339 format.
+    ///
+    /// This is provided by rpki-client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buildtime: Option<String>,
+}
+
+impl Metadata {
+    /// Returns the generation time if available.
+    fn generated(&self) -> Option<DateTime<Utc>> {
+        if let Some(generated) = self.generated {
+            return Utc.timestamp_opt(generated, 0).single()
+        }
+        self.buildtime.as_ref().and_then(|buildtime| {
+            DateTime::parse_from_rfc3339(buildtime).ok()
+        }).map(|buildtime| buildtime.with_timezone(&Utc))
+    }
 }
 
 
@@ -207,14 +378,14 @@ struct Metadata {
 //------------ OutputStream --------------------------------------------------
 
 pub struct OutputStream {
-    iter: payload::SetIter,
+    set: Arc<payload::Set>,
+    pos: usize,
     state: StreamState,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum StreamState {
     Header,
-    First,
     Body,
     Done
 }
@@ -222,7 +393,8 @@ enum StreamState {
 impl OutputStream {
     pub fn new(set: Arc<payload::Set>) -> Self {
         OutputStream {
-            iter: set.into(),
+            set,
+            pos: 0,
             state: StreamState::Header,
         }
     }
@@ -234,66 +406,19 @@ impl Iterator for OutputStream {
     fn next(&mut self) -> Option<Self::Item> {
         match self.state {
             StreamState::Header => {
-                self.state = StreamState::First;
-                Some(b"{{\n  \"roas\": [\n".to_vec())
-            }
-            StreamState::First => {
-                match self.iter.next() {
-                    Some(Payload::V4(payload)) => {
-                        self.state = StreamState::Body;
-                        Some(format!(
-                            "    {{ \"asn\": \"AS{}\", \"prefix\": \"{}/{}\", \
-                            \"maxLength\": {}, \"ta\": \"N/A\" }}",
-                            payload.asn,
-                            payload.prefix,
-                            payload.prefix_len,
-                            payload.max_len,
-                        ).into_bytes())
-                    }
-                    Some(Payload::V6(payload)) => {
-                        self.state = StreamState::Body;
-                        Some(format!(
-                            "    {{ \"asn\": \"AS{}\", \"prefix\": \"{}/{}\", \
-                            \"maxLength\": {}, \"ta\": \"N/A\" }}",
-                            payload.asn,
-                            payload.prefix,
-                            payload.prefix_len,
-                            payload.max_len,
-                        ).into_bytes())
-                    }
-                    None => {
-                        self.state = StreamState::Done;
-                        Some(b"\n  ]\n}}".to_vec())
-                    }
-                }
+                self.state = StreamState::Body;
+                Some(b"{\n  \"roas\": [\n".to_vec())
             }
             StreamState::Body => {
-                match self.iter.next() {
-                    Some(Payload::V4(payload)) => {
-                        Some(format!(
-                            ",\n    \
-                            {{ \"asn\": \"AS{}\", \"prefix\": \"{}/{}\", \
-                            \"maxLength\": {}, \"ta\": \"N/A\" }}",
-                            payload.asn,
-                            payload.prefix,
-                            payload.prefix_len,
-                            payload.max_len,
-                        ).into_bytes())
-                    }
-                    Some(Payload::V6(payload)) => {
-                        Some(format!(
-                            ",\n    \
-                            {{ \"asn\": \"AS{}\", \"prefix\": \"{}/{}\", \
-                            \"maxLength\": {}, \"ta\": \"N/A\" }}",
-                            payload.asn,
-                            payload.prefix,
-                            payload.prefix_len,
-                            payload.max_len,
-                        ).into_bytes())
+                match self.set.item_with_ta(self.pos) {
+                    Some((item, ta)) => {
+                        let res = format_vrp(item, ta, self.pos == 0);
+                        self.pos += 1;
+                        Some(res.into_bytes())
                     }
                     None => {
                         self.state = StreamState::Done;
-                        Some(b"\n  ]\n}}".to_vec())
+                        Some(b"\n  ]\n}\n".to_vec())
                     }
                 }
             }
@@ -305,10 +430,36 @@ impl Iterator for OutputStream {
 }
 
 
+//------------ format_vrp ----------------------------------------------------
+
+/// Formats a single VRP as an element of the `roas` array.
+///
+/// Unless the VRP is the `first` element, it is preceded by a separator.
+pub fn format_vrp(item: &Payload, ta: &str, first: bool) -> String {
+    let (asn, prefix, prefix_len, max_len) = match *item {
+        Payload::V4(ref payload) => (
+            payload.asn, IpAddr::from(payload.prefix), payload.prefix_len,
+            payload.max_len
+        ),
+        Payload::V6(ref payload) => (
+            payload.asn, IpAddr::from(payload.prefix), payload.prefix_len,
+            payload.max_len
+        ),
+    };
+    format!(
+        "{}    {{ \"asn\": \"AS{}\", \"prefix\": \"{}/{}\", \
+        \"maxLength\": {}, \"ta\": {} }}",
+        if first { "" } else { ",\n" },
+        asn, prefix, prefix_len, max_len, serde_json::Value::from(ta)
+    )
+}
+
+
 //============ Testing =======================================================
 
 #[cfg(test)]
 mod test {
+    use crate::payload::testing::{v4, v6};
     use super::*;
 
     #[test]
@@ -335,6 +486,24 @@ mod test {
         check_set(serde_json::from_slice::<Set>(
             include_bytes!("../../test-data/vrps.json")
         ).unwrap());
+        assert_eq!(
+            serde_json::from_slice::<Set>(
+                include_bytes!("../../test-data/vrps.json")
+            ).unwrap().generated(),
+            None
+        );
+        assert_eq!(
+            serde_json::from_slice::<Set>(
+                include_bytes!("../../test-data/vrps-metadata.json")
+            ).unwrap().generated(),
+            Some(Utc.timestamp(1606315808, 0))
+        );
+        assert_eq!(
+            serde_json::from_slice::<Set>(
+                include_bytes!("../../test-data/vrps-metadata.rpki-client.json")
+            ).unwrap().generated(),
+            Some(Utc.ymd(2020, 12, 11).and_hms(22, 18, 15))
+        );
         check_set(serde_json::from_slice::<Set>(
             include_bytes!("../../test-data/vrps-metadata.json")
         ).unwrap());
@@ -342,5 +511,58 @@ mod test {
             include_bytes!("../../test-data/vrps-metadata.rpki-client.json")
         ).unwrap());
     }
+
+    #[test]
+    fn output() {
+        let mut set = payload::SetBuilder::empty();
+        set.insert_with_ta(
+            v4([192, 0, 2, 0], 24, 24, 64496), Some("a\"ta".into())
+        ).unwrap();
+        set.insert(
+            v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 48, 64497)
+        ).unwrap();
+        let output: Vec<u8> = OutputStream::new(
+            Arc::new(set.finalize())
+        ).flatten().collect();
+
+        // The output parses back with the trust anchors kept.
+        let set = serde_json::from_slice::<Set>(&output).unwrap();
+        assert_eq!(set.roas.len(), 2);
+        assert_eq!(set.roas[0].ta, "a\"ta");
+        assert_eq!(set.roas[1].asn.0, 64497);
+        assert_eq!(set.roas[1].max_len, 48);
+        assert_eq!(set.roas[1].ta, payload::Set::UNKNOWN_TA);
+    }
+
+    #[test]
+    fn asn_bounds() {
+        fn asn(json: &str) -> Result<u32, String> {
+            serde_json::from_str::<Set>(&format!(
+                r#"{{ "roas": [
+                    {{ "asn": "AS64496", "prefix": "192.0.2.0/24",
+                       "maxLength": 24, "ta": "ta" }},
+                    {{ "asn": {}, "prefix": "192.0.2.0/24",
+                       "maxLength": 24, "ta": "ta" }}
+                ] }}"#,
+                json
+            )).map(|set| set.roas[1].asn.0).map_err(|err| err.to_string())
+        }
+
+        assert_eq!(asn("\"AS4294967295\""), Ok(4294967295));
+        assert_eq!(asn("\"as65000\""), Ok(65000));
+        assert_eq!(asn("4294967295"), Ok(4294967295));
+        assert!(asn("\"AS+65000\"").is_err());
+        assert!(asn("\"AS 65000\"").is_err());
+        assert!(asn("\"AS\"").is_err());
+        assert!(asn("-1").is_err());
+        let err = asn("\"AS4294967296\"").unwrap_err();
+        assert!(err.starts_with(
+            "record 1: AS number AS4294967296 out of range"
+        ), "{}", err);
+        let err = asn("4294967296").unwrap_err();
+        assert!(err.starts_with(
+            "record 1: AS number 4294967296 out of range"
+        ), "{}", err);
+    }
 }
 
@@ -1,6 +1,8 @@
 //! Serialization formats for payload data.
 
-pub mod output;
+pub mod caida_hijack;
+pub mod ebpf_map;
+pub mod ghostbusters;
 pub mod json;
-
-
+pub mod output;
+pub mod prefix_list;
@@ -1,10 +1,15 @@
 //! All supported output formats.
 
 
+use std::convert::Infallible;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use hyper::HeaderMap;
+use hyper::body::{Bytes, HttpBody};
 use serde::Deserialize;
 use crate::payload;
-use super::json;
+use super::{json, prefix_list};
 
 //------------ Format --------------------------------------------------------
 
@@ -14,18 +19,55 @@ use super::json;
 pub enum Format {
     #[serde(rename = "json")]
     Json,
+
+    /// The aggregated prefix list with one prefix per line.
+    #[serde(rename = "prefix-list")]
+    PrefixList,
+
+    /// The aggregated prefix list as JSON.
+    #[serde(rename = "prefix-list-json")]
+    PrefixListJson,
 }
 
 impl Format {
     pub fn content_type(self) -> &'static str {
         match self {
             Format::Json => "application/json",
+            Format::PrefixList => "text/plain",
+            Format::PrefixListJson => "application/json",
+        }
+    }
+
+    /// Returns the usual file name extension for the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json | Format::PrefixListJson => "json",
+            Format::PrefixList => "txt",
         }
     }
 
     pub fn stream(self, set: Arc<payload::Set>) -> Stream {
         Stream::new(self, set)
     }
+
+    /// Returns the document to serve while there is no data yet.
+    ///
+    /// The document contains no items. The JSON formats mark it via a
+    /// `bootstrapping` member in the `metadata` object. The plain prefix
+    /// list has no means for this and is simply empty.
+    pub fn bootstrap(self) -> &'static [u8] {
+        match self {
+            Format::Json => {
+                b"{\n  \"metadata\": {\n    \"bootstrapping\": true\n  },\n  \
+                  \"roas\": [\n  ]\n}\n"
+            }
+            Format::PrefixList => b"",
+            Format::PrefixListJson => {
+                b"{\n  \"metadata\": {\n    \"bootstrapping\": true\n  },\n  \
+                  \"prefixes\": [\n  ]\n}\n"
+            }
+        }
+    }
 }
 
 
@@ -35,12 +77,19 @@ pub struct Stream(StreamInner);
 
 enum StreamInner {
     Json(json::OutputStream),
+    PrefixList(prefix_list::OutputStream),
 }
 
 impl Stream {
     fn new(format: Format, set: Arc<payload::Set>) -> Self {
         Stream(match format {
             Format::Json => StreamInner::Json(json::OutputStream::new(set)),
+            Format::PrefixList => {
+                StreamInner::PrefixList(prefix_list::OutputStream::plain(set))
+            }
+            Format::PrefixListJson => {
+                StreamInner::PrefixList(prefix_list::OutputStream::json(set))
+            }
         })
     }
 }
@@ -50,8 +99,141 @@ impl Iterator for Stream {
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.0 {
-            StreamInner::Json(ref mut inner) => inner.next()
+            StreamInner::Json(ref mut inner) => inner.next(),
+            StreamInner::PrefixList(ref mut inner) => inner.next(),
+        }
+    }
+}
+
+
+//------------ SetStream -----------------------------------------------------
+
+/// The output of a set as an HTTP response body.
+///
+/// The body is produced chunk by chunk from a [`Stream`] while it is being
+/// sent, so the complete output never needs to be kept in memory. The
+/// chunks are handed over as [`Bytes`] without copying them.
+///
+/// Since the response bodies of the HTTP server are always a
+/// [`hyper::Body`], the type also implements `futures::Stream` for use with
+/// `Body::wrap_stream`.
+pub struct SetStream {
+    /// The output stream or `None` once it has been exhausted.
+    stream: Option<Stream>,
+}
+
+impl SetStream {
+    /// Creates a new body from an output stream.
+    pub fn new(stream: Stream) -> Self {
+        SetStream { stream: Some(stream) }
+    }
+
+    /// Returns the next non-empty chunk of the output.
+    fn next_chunk(&mut self) -> Option<Bytes> {
+        loop {
+            let chunk = match self.stream.as_mut().and_then(Iterator::next) {
+                Some(chunk) => chunk,
+                None => {
+                    self.stream = None;
+                    return None
+                }
+            };
+            if !chunk.is_empty() {
+                return Some(chunk.into())
+            }
         }
     }
 }
 
+impl HttpBody for SetStream {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+        self: Pin<&mut Self>, _cx: &mut Context
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Poll::Ready(self.get_mut().next_chunk().map(Ok))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>, _cx: &mut Context
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.stream.is_none()
+    }
+}
+
+impl futures::Stream for SetStream {
+    type Item = Result<Bytes, Infallible>;
+
+    fn poll_next(
+        self: Pin<&mut Self>, _cx: &mut Context
+    ) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.get_mut().next_chunk().map(Ok))
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use crate::payload::testing::v4;
+    use super::*;
+
+    #[test]
+    fn bootstrap() {
+        let doc: serde_json::Value = serde_json::from_slice(
+            Format::Json.bootstrap()
+        ).unwrap();
+        assert_eq!(doc["metadata"]["bootstrapping"], true);
+        assert_eq!(doc["roas"], serde_json::json!([]));
+        let set: json::Set = serde_json::from_slice(
+            Format::Json.bootstrap()
+        ).unwrap();
+        assert!(set.into_payload().is_empty());
+
+        let doc: serde_json::Value = serde_json::from_slice(
+            Format::PrefixListJson.bootstrap()
+        ).unwrap();
+        assert_eq!(doc["metadata"]["bootstrapping"], true);
+        assert_eq!(doc["prefixes"], serde_json::json!([]));
+
+        assert!(Format::PrefixList.bootstrap().is_empty());
+    }
+
+    #[test]
+    fn set_stream() {
+        
+        use futures::executor::block_on;
+        
+
+        let mut set = payload::SetBuilder::empty();
+        for asn in 64496..64596 {
+            set.insert(v4([192, 0, 2, 0], 24, 24, asn)).unwrap();
+        }
+        let set = Arc::new(set.finalize());
+
+        let mut body = SetStream::new(Format::Json.stream(set.clone()));
+        let mut out = Vec::new();
+        assert!(!body.is_end_stream());
+        while let Some(chunk) = block_on(body.data()) {
+            let chunk = chunk.unwrap();
+            assert!(!chunk.is_empty());
+            out.extend_from_slice(&chunk);
+        }
+        assert!(body.is_end_stream());
+        assert!(block_on(body.data()).is_none());
+        assert_eq!(block_on(body.trailers()).unwrap(), None);
+
+        assert_eq!(
+            out, Format::Json.stream(set).flatten().collect::<Vec<_>>()
+        );
+        let set: json::Set = serde_json::from_slice(&out).unwrap();
+        assert_eq!(set.into_payload().len(), 100);
+    }
+}
+
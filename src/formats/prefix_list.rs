@@ -0,0 +1,123 @@
+//! Output of the aggregated prefix list of a payload set.
+//!
+//! See [`Set::prefix_list`](crate::payload::Set::prefix_list) for what the
+//! list contains. The plain format has one prefix per line. The JSON
+//! format is an object with the prefixes as strings in a member named
+//! `prefixes`.
+
+use std::vec;
+use std::sync::Arc;
+use crate::payload;
+
+
+//------------ OutputStream --------------------------------------------------
+
+pub struct OutputStream {
+    iter: vec::IntoIter<payload::Prefix>,
+    json: bool,
+    state: StreamState,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum StreamState {
+    Header,
+    First,
+    Body,
+    Done
+}
+
+impl OutputStream {
+    /// Creates a stream for the plain format.
+    pub fn plain(set: Arc<payload::Set>) -> Self {
+        Self::new(set, false)
+    }
+
+    /// Creates a stream for the JSON format.
+    pub fn json(set: Arc<payload::Set>) -> Self {
+        Self::new(set, true)
+    }
+
+    fn new(set: Arc<payload::Set>, json: bool) -> Self {
+        OutputStream {
+            iter: set.prefix_list().into_iter(),
+            json,
+            state: StreamState::Header,
+        }
+    }
+}
+
+impl Iterator for OutputStream {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.json {
+            return self.iter.next().map(|prefix| {
+                format!("{}\n", prefix).into_bytes()
+            })
+        }
+        match self.state {
+            StreamState::Header => {
+                self.state = StreamState::First;
+                Some(b"{\n  \"prefixes\": [\n".to_vec())
+            }
+            StreamState::First | StreamState::Body => {
+                match self.iter.next() {
+                    Some(prefix) => {
+                        let sep = match self.state {
+                            StreamState::First => "",
+                            _ => ",\n",
+                        };
+                        self.state = StreamState::Body;
+                        Some(
+                            format!("{}    \"{}\"", sep, prefix).into_bytes()
+                        )
+                    }
+                    None => {
+                        self.state = StreamState::Done;
+                        Some(b"\n  ]\n}\n".to_vec())
+                    }
+                }
+            }
+            StreamState::Done => None
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::testing::v4;
+    use super::*;
+
+    fn collect(stream: OutputStream) -> String {
+        String::from_utf8(stream.flatten().collect()).unwrap()
+    }
+
+    #[test]
+    fn output() {
+        let mut builder = payload::SetBuilder::empty();
+        for &(addr, len) in &[
+            ([192, 0, 2, 0], 25), ([192, 0, 2, 128], 25), ([10, 0, 0, 0], 8)
+        ] {
+            builder.insert(v4(addr, len, len, 0)).unwrap();
+        }
+        let set = Arc::new(builder.finalize());
+        assert_eq!(
+            collect(OutputStream::plain(set.clone())),
+            "10.0.0.0/8\n192.0.2.0/24\n"
+        );
+        assert_eq!(
+            collect(OutputStream::json(set)),
+            "{\n  \"prefixes\": [\n    \"10.0.0.0/8\",\n    \
+             \"192.0.2.0/24\"\n  ]\n}\n"
+        );
+        assert_eq!(
+            collect(OutputStream::json(Arc::new(payload::Set::default()))),
+            "{\n  \"prefixes\": [\n\n  ]\n}\n"
+        );
+    }
+}
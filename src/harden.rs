@@ -0,0 +1,547 @@
+//! Reducing privileges after startup.
+//!
+//! Once all listening sockets have been bound and all log files opened,
+//! RTRTR doesn’t need any special privileges anymore. This module provides
+//! the means to give them up. On Linux, this means switching to an
+//! unprivileged user and group, dropping all capabilities, and optionally
+//! installing a seccomp filter. On all other platforms, the configuration is
+//! accepted but nothing happens.
+//!
+//! The configuration is part of the global configuration via the
+//! [`Hardening`] type. Its [`apply`](Hardening::apply) method needs to be
+//! called before any threads are started so that the changes apply to the
+//! whole process.
+
+use log::{error, info, warn};
+use serde::Deserialize;
+use crate::log::ExitError;
+
+
+//------------ Hardening -----------------------------------------------------
+
+/// The configuration for reducing privileges after startup.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Hardening {
+    /// The name of the user to switch to.
+    #[serde(default)]
+    user: Option<String>,
+
+    /// The name of the group to switch to.
+    ///
+    /// If this is `None` but `user` is given, the user’s primary group is
+    /// used.
+    #[serde(default)]
+    group: Option<String>,
+
+    /// Whether to drop all capabilities.
+    #[serde(
+        rename = "drop-capabilities",
+        default = "Hardening::default_drop_capabilities"
+    )]
+    drop_capabilities: bool,
+
+    /// Whether to install a seccomp filter.
+    #[serde(default)]
+    harden: bool,
+
+    /// Whether to continue if any of the steps fails.
+    #[serde(rename = "allow-insecure", default)]
+    allow_insecure: bool,
+}
+
+impl Hardening {
+    /// The default for dropping capabilities.
+    pub fn default_drop_capabilities() -> bool {
+        true
+    }
+
+    /// Applies all configured hardening steps.
+    ///
+    /// Each step that was applied is logged. If a step fails, this is fatal
+    /// unless `allow-insecure` was set in which case a warning is logged
+    /// and the remaining steps are still attempted.
+    pub fn apply(&self) -> Result<(), ExitError> {
+        self.apply_steps().map_err(|_| ExitError)
+    }
+
+    /// Reports a failed step.
+    ///
+    /// Returns an error unless failures are allowed.
+    #[allow(dead_code)] // for cfg(not(target_os = "linux"))
+    fn failed(
+        &self, step: &str, err: impl std::fmt::Display
+    ) -> Result<(), ()> {
+        if self.allow_insecure {
+            warn!(
+                "Failed to {}: {}. Continuing because of allow-insecure.",
+                step, err
+            );
+            Ok(())
+        }
+        else {
+            error!("Fatal: failed to {}: {}", step, err);
+            Err(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Hardening {
+    fn apply_steps(&self) -> Result<(), ()> {
+        // Switching only the group would leave us running as root.
+        if self.user.is_none() && linux::is_root() {
+            self.failed(
+                "start as root", "no user to switch to configured"
+            )?;
+        }
+
+        // Dropping capabilities from the bounding set needs CAP_SETPCAP
+        // which we lose when switching users, so this has to go first.
+        if self.drop_capabilities {
+            match linux::drop_bounding_and_ambient() {
+                Ok(()) => {
+                    info!("Cleared bounding and ambient capability sets.")
+                }
+                Err(err) => {
+                    self.failed("clear bounding capability set", err)?
+                }
+            }
+        }
+
+        if self.user.is_some() || self.group.is_some() {
+            match linux::switch_user(
+                self.user.as_deref(), self.group.as_deref()
+            ) {
+                Ok((uid, gid)) => {
+                    info!("Switched to user ID {} and group ID {}.", uid, gid)
+                }
+                Err(err) => self.failed("switch user and group", err)?
+            }
+        }
+
+        if self.drop_capabilities {
+            match linux::drop_capabilities() {
+                Ok(()) => info!("Dropped all capabilities."),
+                Err(err) => self.failed("drop capabilities", err)?
+            }
+        }
+
+        if self.harden {
+            match linux::install_seccomp() {
+                Ok(()) => info!("Installed seccomp filter."),
+                Err(err) => self.failed("install seccomp filter", err)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Hardening {
+    fn apply_steps(&self) -> Result<(), ()> {
+        if self.user.is_some() || self.group.is_some() || self.harden {
+            warn!(
+                "Privilege dropping and hardening are only supported on \
+                 Linux. Ignoring."
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for Hardening {
+    fn default() -> Self {
+        Hardening {
+            user: None,
+            group: None,
+            drop_capabilities: Self::default_drop_capabilities(),
+            harden: false,
+            allow_insecure: false,
+        }
+    }
+}
+
+
+//------------ linux ---------------------------------------------------------
+
+/// The Linux implementations of the hardening steps.
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::ffi::CString;
+
+    /// Returns whether we are running as root.
+    pub fn is_root() -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    /// Converts the result of a libc function into an IO result.
+    fn check(res: libc::c_long) -> Result<(), io::Error> {
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Switches to the given user and group.
+    ///
+    /// Returns the numerical user and group IDs switched to.
+    pub fn switch_user(
+        user: Option<&str>, group: Option<&str>
+    ) -> Result<(libc::uid_t, libc::gid_t), io::Error> {
+        let (uid, user_gid) = match user {
+            Some(user) => {
+                let name = CString::new(user).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput, "invalid user name"
+                    )
+                })?;
+                let pwd = unsafe { libc::getpwnam(name.as_ptr()) };
+                if pwd.is_null() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("unknown user '{}'", user)
+                    ))
+                }
+                unsafe { ((*pwd).pw_uid, Some((*pwd).pw_gid)) }
+            }
+            None => (unsafe { libc::getuid() }, None)
+        };
+        let gid = match group {
+            Some(group) => {
+                let name = CString::new(group).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput, "invalid group name"
+                    )
+                })?;
+                let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+                if grp.is_null() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("unknown group '{}'", group)
+                    ))
+                }
+                unsafe { (*grp).gr_gid }
+            }
+            None => match user_gid {
+                Some(gid) => gid,
+                None => unsafe { libc::getgid() }
+            }
+        };
+
+        // The libc wrappers apply these to all threads of the process.
+        unsafe {
+            check(libc::setgroups(1, &gid).into())?;
+            check(libc::setgid(gid).into())?;
+            check(libc::setuid(uid).into())?;
+        }
+        Ok((uid, gid))
+    }
+
+    /// Drops all capabilities from the bounding and ambient sets.
+    pub fn drop_bounding_and_ambient() -> Result<(), io::Error> {
+        unsafe {
+            check(libc::prctl(
+                libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0
+            ).into())?;
+        }
+        for cap in 0.. {
+            let res = unsafe {
+                libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0)
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    // We’ve run past the last capability.
+                    Some(libc::EINVAL) => break,
+
+                    // Without CAP_SETPCAP we can’t change the bounding set.
+                    // This is fine if we don’t have the capabilities in the
+                    // first place which will be checked when dropping them.
+                    Some(libc::EPERM) => break,
+                    _ => return Err(err)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops all capabilities of the process.
+    ///
+    /// Strictly speaking, capabilities are per thread, so this only affects
+    /// the calling thread and threads started later. Switching to a
+    /// non-root user clears the capabilities of all threads, though.
+    pub fn drop_capabilities() -> Result<(), io::Error> {
+        /// The header for the capset system call.
+        #[repr(C)]
+        struct CapHeader {
+            version: u32,
+            pid: libc::c_int,
+        }
+
+        /// The data for the capset system call.
+        #[repr(C)]
+        #[derive(Default)]
+        struct CapData {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        /// Version 3 of the capability interface.
+        const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+        let header = CapHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let data: [CapData; 2] = Default::default();
+        unsafe {
+            check(libc::syscall(
+                libc::SYS_capset, &header as *const _, data.as_ptr()
+            ))
+        }
+    }
+
+    /// Installs a seccomp filter for the entire process.
+    ///
+    /// The filter only allows the system calls RTRTR needs after startup:
+    /// memory management, threads and signals for the runtime, files, and
+    /// network sockets. All other calls, such as executing programs or
+    /// changing credentials, fail with `ENOSYS`. This error rather than
+    /// `EPERM` lets the C library fall back to older system calls if it
+    /// tries a newer one that isn’t in the list.
+    pub fn install_seccomp() -> Result<(), io::Error> {
+        /// A BPF instruction.
+        #[repr(C)]
+        struct SockFilter {
+            code: u16,
+            jt: u8,
+            jf: u8,
+            k: u32,
+        }
+
+        /// A BPF program.
+        #[repr(C)]
+        struct SockFprog {
+            len: libc::c_ushort,
+            filter: *const SockFilter,
+        }
+
+        const BPF_LD_W_ABS: u16 = 0x20;
+        const BPF_JMP_JEQ_K: u16 = 0x15;
+        #[cfg(target_arch = "x86_64")]
+        const BPF_JMP_JGE_K: u16 = 0x35;
+        const BPF_RET_K: u16 = 0x06;
+        const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+        const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+        const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+        const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+        const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+        // Offsets into struct seccomp_data.
+        const NR_OFFSET: u32 = 0;
+        const ARCH_OFFSET: u32 = 4;
+
+        #[cfg(target_arch = "x86_64")]
+        const AUDIT_ARCH: u32 = 0xc000_003e;
+
+        /// The bit marking system calls of the x32 ABI.
+        ///
+        /// These share the architecture with ordinary x86-64 calls but
+        /// have different numbers, so they would slip past the filter.
+        #[cfg(target_arch = "x86_64")]
+        const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+        #[cfg(target_arch = "aarch64")]
+        const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+        // System calls newer than some versions of the libc crate we
+        // support. The C library uses them if the kernel has them.
+        #[cfg(target_arch = "x86_64")]
+        const SYS_RSEQ: libc::c_long = 334;
+        #[cfg(target_arch = "aarch64")]
+        const SYS_RSEQ: libc::c_long = 293;
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        const SYS_FACCESSAT2: libc::c_long = 439;
+
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        return Err(io::Error::new(
+            io::ErrorKind::Other, "not supported on this architecture"
+        ));
+
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            #[allow(unused_mut)] // for aarch64
+            let mut allowed = vec![
+                // Memory.
+                libc::SYS_brk, libc::SYS_mmap, libc::SYS_munmap,
+                libc::SYS_mremap, libc::SYS_mprotect, libc::SYS_madvise,
+
+                // Threads, signals, and time.
+                libc::SYS_clone, libc::SYS_clone3, libc::SYS_futex,
+                libc::SYS_set_robust_list, libc::SYS_set_tid_address,
+                SYS_RSEQ, libc::SYS_sched_yield, libc::SYS_sched_getaffinity,
+                libc::SYS_getpid, libc::SYS_gettid, libc::SYS_tgkill,
+                libc::SYS_prctl, libc::SYS_prlimit64, libc::SYS_exit,
+                libc::SYS_exit_group, libc::SYS_restart_syscall,
+                libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask,
+                libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+                libc::SYS_clock_gettime, libc::SYS_clock_getres,
+                libc::SYS_clock_nanosleep, libc::SYS_nanosleep,
+                libc::SYS_gettimeofday, libc::SYS_getrandom,
+                libc::SYS_uname, libc::SYS_getuid, libc::SYS_geteuid,
+                libc::SYS_getgid, libc::SYS_getegid,
+
+                // Files.
+                libc::SYS_openat, libc::SYS_close, libc::SYS_read,
+                libc::SYS_write, libc::SYS_readv, libc::SYS_writev,
+                libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_lseek,
+                libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx,
+                libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_fsync,
+                libc::SYS_fdatasync, libc::SYS_ftruncate, libc::SYS_fchmod,
+                libc::SYS_renameat, libc::SYS_unlinkat, libc::SYS_mkdirat,
+                libc::SYS_getdents64, libc::SYS_readlinkat,
+                libc::SYS_faccessat, SYS_FACCESSAT2, libc::SYS_getcwd,
+                libc::SYS_dup, libc::SYS_dup3, libc::SYS_pipe2,
+                libc::SYS_eventfd2, libc::SYS_epoll_create1,
+                libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_ppoll,
+                libc::SYS_pselect6,
+
+                // Network.
+                libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect,
+                libc::SYS_accept, libc::SYS_accept4, libc::SYS_bind,
+                libc::SYS_listen, libc::SYS_getsockname,
+                libc::SYS_getpeername, libc::SYS_setsockopt,
+                libc::SYS_getsockopt, libc::SYS_sendto, libc::SYS_recvfrom,
+                libc::SYS_sendmsg, libc::SYS_recvmsg, libc::SYS_sendmmsg,
+                libc::SYS_shutdown,
+            ];
+
+            // The older variants that aarch64 doesn’t have.
+            #[cfg(target_arch = "x86_64")]
+            allowed.extend_from_slice(&[
+                libc::SYS_open, libc::SYS_stat, libc::SYS_lstat,
+                libc::SYS_access, libc::SYS_readlink, libc::SYS_rename,
+                libc::SYS_unlink, libc::SYS_mkdir, libc::SYS_getdents,
+                libc::SYS_dup2, libc::SYS_pipe, libc::SYS_poll,
+                libc::SYS_select, libc::SYS_epoll_create,
+                libc::SYS_epoll_wait, libc::SYS_arch_prctl,
+            ]);
+
+            let stmt = |code, k| SockFilter { code, jt: 0, jf: 0, k };
+            let mut prog = vec![
+                stmt(BPF_LD_W_ABS, ARCH_OFFSET),
+                SockFilter {
+                    code: BPF_JMP_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH
+                },
+                stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+                stmt(BPF_LD_W_ABS, NR_OFFSET),
+            ];
+            #[cfg(target_arch = "x86_64")]
+            {
+                prog.push(SockFilter {
+                    code: BPF_JMP_JGE_K, jt: 0, jf: 1, k: X32_SYSCALL_BIT
+                });
+                prog.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+            }
+            for &nr in allowed.iter() {
+                prog.push(SockFilter {
+                    code: BPF_JMP_JEQ_K, jt: 0, jf: 1, k: nr as u32
+                });
+                prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+            }
+            prog.push(
+                stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32)
+            );
+
+            let fprog = SockFprog {
+                len: prog.len() as libc::c_ushort,
+                filter: prog.as_ptr(),
+            };
+            unsafe {
+                check(libc::prctl(
+                    libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0
+                ).into())?;
+                check(libc::syscall(
+                    libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER,
+                    SECCOMP_FILTER_FLAG_TSYNC, &fprog as *const _
+                ))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(all(
+    test, target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod test {
+    use std::{env, fs};
+    use std::process::Command;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime;
+
+    /// The environment variable telling the test it runs in the child.
+    const CHILD_VAR: &str = "RTRTR_SECCOMP_CHILD";
+
+    /// Runs a runtime with network and file access under the filter.
+    ///
+    /// Since the filter applies to the whole process, the test runs itself
+    /// again in a child process which installs the filter.
+    #[test]
+    fn seccomp() {
+        if env::var_os(CHILD_VAR).is_none() {
+            let output = Command::new(env::current_exe().unwrap())
+                .arg("--exact").arg("harden::test::seccomp")
+                .env(CHILD_VAR, "1")
+                .output().unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(
+                output.status.success(), "{}{}",
+                stdout, String::from_utf8_lossy(&output.stderr)
+            );
+            assert!(stdout.contains("1 passed"), "{}", stdout);
+            return
+        }
+
+        super::linux::install_seccomp().unwrap();
+
+        let mut runtime = runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut listener = TcpListener::bind(
+                ("127.0.0.1", 0)
+            ).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                sock.write_all(b"rtr").await.unwrap();
+            });
+            let mut sock = TcpStream::connect(addr).await.unwrap();
+            let mut data = Vec::new();
+            sock.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, b"rtr");
+            server.await.unwrap();
+        });
+
+        let path = env::temp_dir().join(
+            format!("rtrtr-seccomp-{}", std::process::id())
+        );
+        fs::write(&path, b"rtr").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"rtr");
+        fs::remove_file(&path).unwrap();
+
+        assert!(Command::new("/bin/true").status().is_err());
+    }
+}
@@ -15,7 +15,7 @@ use std::pin::Pin;
 use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll};
 use arc_swap::ArcSwap;
-use futures::pin_mut;
+use futures::{pin_mut, StreamExt};
 use hyper::{Body, Method, Request, Response, StatusCode};
 use hyper::server::accept::Accept;
 use hyper::service::{make_service_fn, service_fn};
@@ -25,6 +25,8 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
 use tokio::stream::Stream;
+use url::form_urlencoded;
+use crate::comms::{Registry, UnitHandle, UnitStatus};
 use crate::log::ExitError;
 use crate::metrics;
 
@@ -37,13 +39,47 @@ pub struct Server {
     /// The socket addresses to listen on.
     #[serde(rename = "http-listen")]
     listen: Vec<SocketAddr>,
+
+    /// The default format of the per-unit health summaries.
+    #[serde(rename = "http-health-format", default)]
+    health_format: HealthFormat,
 }
 
 impl Server {
+    /// Returns the default format for the per-unit health summaries.
+    pub fn health_format(&self) -> HealthFormat {
+        self.health_format
+    }
+
+    /// Returns the socket addresses the server listens on.
+    pub fn listen(&self) -> &[SocketAddr] {
+        &self.listen
+    }
+
+    /// Binds the listening sockets of the server.
+    ///
+    /// Binding needs to have happened before dropping privileges, so this
+    /// happens synchronously and before the runtime is started. The
+    /// returned listeners need to be passed to [`run`](Self::run).
+    pub fn bind(&self) -> Result<Vec<StdListener>, ExitError> {
+        let mut listeners = Vec::new();
+        for addr in &self.listen {
+            match StdListener::bind(addr) {
+                Ok(listener) => listeners.push(listener),
+                Err(err) => {
+                    error!("Fatal: error listening on {}: {}", addr, err);
+                    return Err(ExitError);
+                }
+            };
+        }
+        Ok(listeners)
+    }
+
     /// Runs the server.
     ///
-    /// The method will start a new server listening on the sockets provided
-    /// via the configuration and spawns it onto the given `runtime`.
+    /// The method will start a new server listening on the sockets
+    /// previously bound via [`bind`](Self::bind) and spawns it onto the
+    /// given `runtime`.
     ///
     /// The server will use `metrics` to produce information on its metrics
     /// related endpoints.
@@ -51,23 +87,11 @@ impl Server {
     /// (In a future version, this function will also take an object
     /// reflecting additionally configured endpoints.)
     pub fn run(
-        &self,
+        listeners: Vec<StdListener>,
         metrics: metrics::Collection,
         resources: Resources,
         runtime: &Runtime,
-    ) -> Result<(), ExitError> {
-        let mut listeners = Vec::new();
-        for addr in &self.listen {
-            // Binding needs to have happened before dropping privileges
-            // during detach. So we do this here synchronously.
-            match StdListener::bind(addr) {
-                Ok(listener) => listeners.push(listener),
-                Err(err) => {
-                    error!("Fatal: error listening on {}: {}", addr, err);
-                    return Err(ExitError);
-                }
-            };
-        }
+    ) {
         for listener in listeners {
             runtime.spawn(
                 Self::single_listener(
@@ -75,7 +99,6 @@ impl Server {
                 )
             );
         }
-        Ok(())
     }
  
     /// Runs a single HTTP listener.
@@ -115,26 +138,57 @@ impl Server {
     }
 
     /// Handles a single HTTP request.
+    ///
+    /// The body of a POST request is read before handing the request to
+    /// the resources and made available via the [`RequestBody`] extension.
     async fn handle_request(
-        req: Request<Body>,
+        mut req: Request<Body>,
         metrics: &metrics::Collection,
         resources: &Resources,
     ) -> Result<Response<Body>, Infallible> {
-        if *req.method() != Method::GET {
+        if *req.method() == Method::POST {
+            match Self::read_body(req.body_mut()).await {
+                Some(body) => { req.extensions_mut().insert(body); }
+                None => return Ok(Self::payload_too_large())
+            }
+        }
+        else if *req.method() != Method::GET {
             return Ok(Self::method_not_allowed())
         }
         Ok(match req.uri().path() {
+            "/metrics" | "/status" if *req.method() != Method::GET => {
+                Self::method_not_allowed()
+            }
             "/metrics" => Self::metrics(metrics),
             "/status" => Self::status(metrics),
             _ => {
-                match resources.process_request(&req) {
+                match resources.process_request(&mut req) {
                     Some(response) => response,
+                    None if *req.method() != Method::GET => {
+                        Self::method_not_allowed()
+                    }
                     None => Self::not_found()
                 }
             }
         })
     }
 
+    /// Reads the complete body of a request.
+    ///
+    /// Returns `None` if the body is larger than [`MAX_BODY_LEN`] or could
+    /// not be read.
+    async fn read_body(body: &mut Body) -> Option<RequestBody> {
+        let mut res = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.ok()?;
+            if res.len() + chunk.len() > MAX_BODY_LEN {
+                return None
+            }
+            res.extend_from_slice(&chunk);
+        }
+        Some(RequestBody(res.into()))
+    }
+
     /// Produces the response for a call to the `/metrics` endpoint.
     fn metrics(metrics: &metrics::Collection) -> Response<Body> {
         Response::builder()
@@ -164,6 +218,15 @@ impl Server {
         .unwrap()
     }
 
+    /// Produces the response for a request with an oversized body.
+    fn payload_too_large() -> Response<Body> {
+        Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header("Content-Type", "text/plain")
+        .body("Payload Too Large".into())
+        .unwrap()
+    }
+
     /// Produces the response for a Not Found error.
     fn not_found() -> Response<Body> {
         Response::builder()
@@ -175,6 +238,223 @@ impl Server {
 }
 
 
+//------------ RequestBody ---------------------------------------------------
+
+/// The maximum size of the body of a POST request.
+pub const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+/// The complete body of a POST request.
+///
+/// The server reads the body before handing a POST request to the
+/// resources and adds it to the request’s extensions.
+#[derive(Clone, Debug)]
+pub struct RequestBody(pub Arc<[u8]>);
+
+impl RequestBody {
+    /// Returns the body of a request if there is one.
+    pub fn get(request: &Request<Body>) -> Option<&[u8]> {
+        request.extensions().get::<Self>().map(|body| body.0.as_ref())
+    }
+}
+
+
+//------------ HealthFormat --------------------------------------------------
+
+/// The output format of a unit’s health summary.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum HealthFormat {
+    /// One `key: value` line per field.
+    #[serde(rename = "text")]
+    Text,
+
+    /// A JSON object with only the status and serial number.
+    #[serde(rename = "minimal-json")]
+    MinimalJson,
+
+    /// A JSON object with all available fields.
+    #[serde(rename = "verbose-json")]
+    VerboseJson,
+}
+
+impl HealthFormat {
+    /// Returns the format for the given name used in a query parameter.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(HealthFormat::Text),
+            "minimal-json" => Some(HealthFormat::MinimalJson),
+            "verbose-json" => Some(HealthFormat::VerboseJson),
+            _ => None
+        }
+    }
+
+    /// Returns the content type of the format.
+    fn content_type(self) -> &'static str {
+        match self {
+            HealthFormat::Text => "text/plain",
+            _ => "application/json",
+        }
+    }
+}
+
+impl Default for HealthFormat {
+    fn default() -> Self {
+        HealthFormat::Text
+    }
+}
+
+
+//------------ UnitHealth ----------------------------------------------------
+
+/// The health summaries of all units.
+///
+/// The summary of a unit is available under `/status/<unit-name>` for all
+/// units in the registry. Its format is given by the server configuration
+/// but can be overridden via the `format` query parameter. If the unit isn’t
+/// healthy, the response has status 503.
+pub struct UnitHealth {
+    /// The registry to look up the units in.
+    registry: Registry,
+
+    /// The format used if none is requested.
+    format: HealthFormat,
+}
+
+impl UnitHealth {
+    /// The path prefix for the summaries.
+    const PATH_PREFIX: &'static str = "/status/";
+
+    /// Creates the health summaries for the units in a registry.
+    pub fn new(registry: Registry, format: HealthFormat) -> Self {
+        UnitHealth { registry, format }
+    }
+
+    /// Produces the summary of a unit in the given format.
+    fn summary(
+        name: &str, unit: &UnitHandle, format: HealthFormat
+    ) -> String {
+        let metrics = unit.metrics();
+        let status = unit.status();
+        let serial = metrics.serial();
+        let update = metrics.update_time();
+        let connection = metrics.connection_time();
+        let ids = metrics.update_ids();
+        match format {
+            HealthFormat::Text => {
+                format!(
+                    "unit: {}\nstatus: {}\nserial: {}\nvrps: {}\n\
+                     last-update: {}\nlast-connection: {}\n\
+                     update-ids: {}\n",
+                    name, status, serial, metrics.count(),
+                    match update {
+                        Some(update) => update.to_rfc3339(),
+                        None => "N/A".into()
+                    },
+                    match connection {
+                        Some(connection) => connection.to_rfc3339(),
+                        None => "N/A".into()
+                    },
+                    match ids {
+                        Some(ids) => ids.to_string(),
+                        None => "N/A".into()
+                    }
+                )
+            }
+            HealthFormat::MinimalJson => {
+                let mut res = serde_json::json!({
+                    "status": status.to_string(),
+                    "serial": serial,
+                }).to_string();
+                res.push('\n');
+                res
+            }
+            HealthFormat::VerboseJson => {
+                let mut res = serde_json::to_string_pretty(
+                    &serde_json::json!({
+                        "unit": name,
+                        "status": status.to_string(),
+                        "serial": serial,
+                        "vrps": metrics.count(),
+                        "lastUpdate": update.map(|update| {
+                            update.to_rfc3339()
+                        }),
+                        "lastConnection": connection.map(|connection| {
+                            connection.to_rfc3339()
+                        }),
+                        "updateIds": ids.map(|ids| {
+                            serde_json::json!({
+                                "first": ids.first(),
+                                "last": ids.last(),
+                            })
+                        }),
+                    })
+                ).expect("serializing JSON value failed");
+                res.push('\n');
+                res
+            }
+        }
+    }
+
+    /// Returns the format requested via the query of a request.
+    ///
+    /// Returns the name of an unknown format as the error.
+    fn query_format(
+        &self, request: &Request<Body>
+    ) -> Result<HealthFormat, String> {
+        let query = match request.uri().query() {
+            Some(query) => query,
+            None => return Ok(self.format)
+        };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            if key == "format" {
+                return HealthFormat::from_name(&value).ok_or_else(|| {
+                    value.into_owned()
+                })
+            }
+        }
+        Ok(self.format)
+    }
+}
+
+impl ProcessRequest for UnitHealth {
+    fn process_request(
+        &self, request: &mut Request<Body>
+    ) -> Option<Response<Body>> {
+        if request.method() != Method::GET {
+            return None
+        }
+        let path = request.uri().path();
+        if !path.starts_with(Self::PATH_PREFIX) {
+            return None
+        }
+        let name = &path[Self::PATH_PREFIX.len()..];
+        let unit = self.registry.get(name)?;
+        let format = match self.query_format(request) {
+            Ok(format) => format,
+            Err(format) => {
+                return Some(
+                    Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .header("Content-Type", "text/plain")
+                    .body(format!("unknown format '{}'", format).into())
+                    .unwrap()
+                )
+            }
+        };
+        let status = match unit.status() {
+            UnitStatus::Healthy => StatusCode::OK,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        Some(
+            Response::builder()
+            .status(status)
+            .header("Content-Type", format.content_type())
+            .body(Self::summary(name, &unit, format).into())
+            .unwrap()
+        )
+    }
+}
+
+
 //------------ Resources -----------------------------------------------------
 
 /// A collection of HTTP resources to be served by the server.
@@ -226,7 +506,7 @@ impl Resources {
     /// Returns some response if any of the registered processors actually
     /// processed the particular request or `None` otherwise.
     pub fn process_request(
-        &self, request: &Request<Body>
+        &self, request: &mut Request<Body>
     ) -> Option<Response<Body>> {
         let sources = self.sources.load();
         for item in sources.iter() {
@@ -268,23 +548,27 @@ pub trait ProcessRequest: Send + Sync {
     /// If the processor feels responsible for the reuqest, it should return
     /// some response. This can be an error response. Otherwise it should
     /// return `None`.
+    ///
+    /// The request is given mutably so that a processor can take its body,
+    /// e.g., to upgrade the connection. Processors that return `None` must
+    /// leave the request untouched.
     fn process_request(
-        &self, request: &Request<Body>
+        &self, request: &mut Request<Body>
     ) -> Option<Response<Body>>;
 }
 
 impl<T: ProcessRequest> ProcessRequest for Arc<T> {
     fn process_request(
-        &self, request: &Request<Body>
+        &self, request: &mut Request<Body>
     ) -> Option<Response<Body>> {
         AsRef::<T>::as_ref(self).process_request(request)
     }
 }
 
 impl<F> ProcessRequest for F
-where F: Fn(&Request<Body>) -> Option<Response<Body>> + Sync + Send {
+where F: Fn(&mut Request<Body>) -> Option<Response<Body>> + Sync + Send {
     fn process_request(
-        &self, request: &Request<Body>
+        &self, request: &mut Request<Body>
     ) -> Option<Response<Body>> {
         (self)(request)
     }
@@ -358,3 +642,86 @@ impl AsyncWrite for HttpStream {
     }
 }
 
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+    use crate::comms::Gate;
+    use super::*;
+
+    #[test]
+    fn unit_health() {
+        let registry = Registry::default();
+        let (gate, _agent) = Gate::new();
+        let unit = gate.handle();
+        registry.register("rtr", unit.clone());
+        let health = UnitHealth::new(
+            registry.clone(), HealthFormat::MinimalJson
+        );
+        let mut request = Request::get("/status/rtr").body(
+            Body::empty()
+        ).unwrap();
+        let response = health.process_request(&mut request).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            UnitHealth::summary("rtr", &unit, HealthFormat::MinimalJson),
+            "{\"serial\":0,\"status\":\"healthy\"}\n"
+        );
+        assert!(
+            UnitHealth::summary(
+                "rtr", &unit, HealthFormat::VerboseJson
+            ).contains("\"lastUpdate\": null")
+        );
+
+        // Times are given as RFC 3339 timestamps.
+        unit.metrics().connected(
+            Utc.timestamp_opt(1_614_600_000, 0).unwrap()
+        );
+        let summary = UnitHealth::summary("rtr", &unit, HealthFormat::Text);
+        assert!(summary.contains("last-update: N/A\n"));
+        assert!(summary.contains(
+            "last-connection: 2021-03-01T12:00:00+00:00\n"
+        ));
+        assert!(
+            UnitHealth::summary(
+                "rtr", &unit, HealthFormat::VerboseJson
+            ).contains("\"lastConnection\": \"2021-03-01T12:00:00+00:00\"")
+        );
+
+        // Unit names are escaped.
+        let summary: serde_json::Value = serde_json::from_str(
+            &UnitHealth::summary(
+                "r\"t\\r", &unit, HealthFormat::VerboseJson
+            )
+        ).unwrap();
+        assert_eq!(summary["unit"], "r\"t\\r");
+
+        let mut request = Request::get("/status/rtr?format=text").body(
+            Body::empty()
+        ).unwrap();
+        let response = health.process_request(&mut request).unwrap();
+        assert_eq!(response.headers()["Content-Type"], "text/plain");
+
+        let mut request = Request::get("/status/rtr?format=xml").body(
+            Body::empty()
+        ).unwrap();
+        let response = health.process_request(&mut request).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let mut request = Request::get("/status/json").body(
+            Body::empty()
+        ).unwrap();
+        assert!(health.process_request(&mut request).is_none());
+
+        // A removed unit is reported as gone.
+        drop(gate);
+        let mut request = Request::get("/status/rtr").body(
+            Body::empty()
+        ).unwrap();
+        let response = health.process_request(&mut request).unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,572 @@
+//! Serving a snapshot once to seed routers.
+//!
+//! The `rtrtr-import` tool is meant for disaster recovery when the full
+//! pipeline isn’t operational yet. It reads the data set from a snapshot
+//! file, i.e., an [event store][crate::payload::EventStore] file of which
+//! the data set after the last update is used, and serves it via RTR.
+//!
+//! Every client receives the complete data set. Reset queries are answered
+//! as usual and serial queries with a cache reset, so clients that still
+//! have older data follow up with a reset query. Once the expected number
+//! of clients has received the complete data set, the tool exits. If that
+//! doesn’t happen within the timeout, it exits with an error.
+//!
+//! A client counts as having received the data set once the End of Data
+//! PDU following it has been flushed to its connection. Only then is it
+//! safe to exit without the client losing the end of the response.
+
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use clap::{App, Arg, ArgMatches};
+use futures::{ready, StreamExt};
+use log::{error, info, warn};
+use rpki_rtr::payload::{Action, Payload, Timing};
+use rpki_rtr::server::{NotifySender, Server, VrpSource};
+use rpki_rtr::state::State;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::runtime;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use crate::payload;
+use crate::log::{ExitError, Failed, LogConfig};
+use crate::payload::EventStore;
+
+
+//------------ Import --------------------------------------------------------
+
+/// The `rtrtr-import` tool.
+pub struct Import;
+
+impl Import {
+    /// The default address to listen on.
+    const DEFAULT_LISTEN: &'static str = "127.0.0.1:3323";
+
+    /// The default number of clients to wait for.
+    const DEFAULT_CLIENTS: &'static str = "1";
+
+    /// The default timeout in seconds.
+    const DEFAULT_TIMEOUT: &'static str = "600";
+
+    /// Configures a clap app with the arguments of the tool.
+    pub fn config_args<'a: 'b, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        let app = app
+        .arg(Arg::with_name("snapshot")
+            .required(true)
+            .value_name("PATH")
+            .help("Read the data set from this event store file")
+        )
+        .arg(Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("ADDR")
+            .default_value(Self::DEFAULT_LISTEN)
+            .help("Listen for RTR connections on this address")
+        )
+        .arg(Arg::with_name("clients")
+            .long("clients")
+            .takes_value(true)
+            .value_name("NUM")
+            .default_value(Self::DEFAULT_CLIENTS)
+            .help("Exit after this many clients have received the data")
+        )
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .takes_value(true)
+            .value_name("SECS")
+            .default_value(Self::DEFAULT_TIMEOUT)
+            .help("Give up after this many seconds")
+        );
+        LogConfig::config_args(app)
+    }
+
+    /// Runs the tool.
+    ///
+    /// The `matches` must be those of an app configured via
+    /// [`config_args`](Self::config_args).
+    pub fn run(
+        matches: &ArgMatches, cur_dir: &Path
+    ) -> Result<(), ExitError> {
+        let mut log = LogConfig::default();
+        log.update_with_arg_matches(matches, cur_dir)?;
+        log.switch_logging(false)?;
+
+        let clients = Self::number(matches, "clients")?;
+        let timeout = Duration::from_secs(Self::number(matches, "timeout")?);
+        let mut listeners = Vec::new();
+        for addr in matches.values_of("listen").into_iter().flatten() {
+            listeners.push(Self::bind(addr)?);
+        }
+        let update = Self::load(
+            &cur_dir.join(matches.value_of("snapshot").unwrap())
+        )?;
+        info!(
+            "Serving {} items with serial {} to {} clients.",
+            update.set().len(), update.serial(), clients
+        );
+
+        let mut runtime = runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let served = runtime.block_on(Self::serve(
+            listeners, ImportSource::new(update.set()), clients, timeout
+        ))?;
+        if served < clients {
+            error!(
+                "Timeout: only {} of {} clients have received the data.",
+                served, clients
+            );
+            return Err(ExitError)
+        }
+        info!("All {} clients have received the data.", clients);
+        Ok(())
+    }
+
+    /// Loads the snapshot from the event store file at `path`.
+    pub fn load(path: &Path) -> Result<payload::Update, Failed> {
+        match EventStore::read_last(path) {
+            Ok(Some(update)) => Ok(update),
+            Ok(None) => {
+                error!("Snapshot file {} contains no data.", path.display());
+                Err(Failed)
+            }
+            Err(err) => {
+                error!(
+                    "Failed to read snapshot file {}: {}",
+                    path.display(), err
+                );
+                Err(Failed)
+            }
+        }
+    }
+
+    /// Serves `source` on `listeners` until enough clients have been served.
+    ///
+    /// Returns the number of clients that have received the complete data
+    /// set. This is less than `clients` if `timeout` has passed first.
+    pub async fn serve(
+        listeners: Vec<StdTcpListener>,
+        source: ImportSource,
+        clients: usize,
+        timeout_after: Duration,
+    ) -> Result<usize, Failed> {
+        let notify = NotifySender::new();
+        for listener in listeners {
+            let addr = listener.local_addr().ok();
+            let mut listener = match TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Failed to listen on {:?}: {}", addr, err);
+                    return Err(Failed)
+                }
+            };
+            let server_source = source.clone();
+            let notify = notify.clone();
+            tokio::spawn(async move {
+                let served = server_source.served.clone();
+                let server = Server::new(
+                    listener.incoming().map(move |sock| {
+                        sock.map(|sock| ServedStream::new(
+                            sock, served.clone()
+                        ))
+                    }),
+                    notify, server_source
+                );
+                if let Err(err) = server.run().await {
+                    warn!("Error listening on {:?}: {}", addr, err);
+                }
+            });
+        }
+
+        let mut served = source.served.subscribe();
+        let res = timeout(timeout_after, async {
+            while let Some(count) = served.recv().await {
+                if count >= clients {
+                    break
+                }
+            }
+        }).await;
+        if res.is_ok() {
+            Ok(clients)
+        }
+        else {
+            Ok(source.served.count())
+        }
+    }
+
+    /// Returns the value of a numeric argument.
+    fn number<T: std::str::FromStr>(
+        matches: &ArgMatches, name: &str
+    ) -> Result<T, Failed> {
+        match matches.value_of(name).unwrap().parse() {
+            Ok(value) => Ok(value),
+            Err(_) => {
+                error!("Invalid value for --{}.", name);
+                Err(Failed)
+            }
+        }
+    }
+
+    /// Binds a listener to the given address.
+    fn bind(addr: &str) -> Result<StdTcpListener, Failed> {
+        let addr: SocketAddr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                error!("Invalid listen address '{}'.", addr);
+                return Err(Failed)
+            }
+        };
+        match StdTcpListener::bind(addr) {
+            Ok(listener) => Ok(listener),
+            Err(err) => {
+                error!("Failed to bind to {}: {}", addr, err);
+                Err(Failed)
+            }
+        }
+    }
+}
+
+
+//------------ ImportSource --------------------------------------------------
+
+/// The RTR source serving the snapshot.
+#[derive(Clone)]
+pub struct ImportSource {
+    /// The data set to serve.
+    set: Arc<payload::Set>,
+
+    /// The RTR state of the data set.
+    state: State,
+
+    /// The clients that have received the data set.
+    served: Arc<Served>,
+}
+
+impl ImportSource {
+    /// Creates a new source for the given data set.
+    pub fn new(set: Arc<payload::Set>) -> Self {
+        let mut state = State::new();
+        state.inc();
+        ImportSource {
+            set,
+            state,
+            served: Default::default(),
+        }
+    }
+
+    /// Returns the number of clients that have received the data set.
+    pub fn served(&self) -> usize {
+        self.served.count()
+    }
+}
+
+impl VrpSource for ImportSource {
+    type FullIter = payload::SetIter;
+    type DiffIter = std::iter::Empty<(Action, Payload)>;
+
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn notify(&self) -> State {
+        self.state
+    }
+
+    fn full(&self) -> (State, Self::FullIter) {
+        (self.state, self.set.clone().into())
+    }
+
+    fn diff(&self, _state: State) -> Option<(State, Self::DiffIter)> {
+        // There is no history, so every client gets a cache reset.
+        None
+    }
+
+    fn timing(&self) -> Timing {
+        Timing::default()
+    }
+}
+
+
+//------------ ServedStream --------------------------------------------------
+
+/// A socket wrapper noting when a client has received the data set.
+///
+/// The wrapper follows the PDUs written to the socket. Once an End of Data
+/// PDU has been written completely, the response is done as soon as the
+/// server flushes the socket or starts reading again. The client is then
+/// counted as served. Each connection is only counted once.
+struct ServedStream<Sock> {
+    /// The actual socket.
+    sock: Sock,
+
+    /// The header of the PDU currently being written.
+    header: [u8; 8],
+
+    /// The number of header octets written so far.
+    header_len: usize,
+
+    /// The number of octets of the current PDU’s body still to be written.
+    remaining: usize,
+
+    /// Whether the current PDU is an End of Data PDU.
+    is_eod: bool,
+
+    /// Whether an End of Data PDU has been written but not flushed.
+    eod_written: bool,
+
+    /// Where to note that the data set has been served.
+    ///
+    /// This is taken once the client has been counted.
+    served: Option<Arc<Served>>,
+}
+
+impl<Sock> ServedStream<Sock> {
+    /// The PDU type of an End of Data PDU.
+    const END_OF_DATA: u8 = 7;
+
+    /// Creates a new wrapper.
+    fn new(sock: Sock, served: Arc<Served>) -> Self {
+        ServedStream {
+            sock,
+            header: [0; 8],
+            header_len: 0,
+            remaining: 0,
+            is_eod: false,
+            eod_written: false,
+            served: Some(served),
+        }
+    }
+
+    /// Follows the PDUs in data written to the socket.
+    fn track(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.header_len < self.header.len() {
+                let len = std::cmp::min(
+                    self.header.len() - self.header_len, data.len()
+                );
+                self.header[self.header_len..self.header_len + len]
+                    .copy_from_slice(&data[..len]);
+                self.header_len += len;
+                data = &data[len..];
+                if self.header_len < self.header.len() {
+                    return
+                }
+                self.is_eod = self.header[1] == Self::END_OF_DATA;
+                self.remaining = (u32::from_be_bytes([
+                    self.header[4], self.header[5],
+                    self.header[6], self.header[7],
+                ]) as usize).saturating_sub(self.header.len());
+            }
+            else {
+                let len = std::cmp::min(self.remaining, data.len());
+                self.remaining -= len;
+                data = &data[len..];
+            }
+            if self.header_len == self.header.len() && self.remaining == 0 {
+                self.eod_written |= self.is_eod;
+                self.header_len = 0;
+            }
+        }
+    }
+
+    /// Counts the client if an End of Data PDU has been written.
+    fn response_done(&mut self) {
+        if self.eod_written {
+            if let Some(served) = self.served.take() {
+                served.add()
+            }
+        }
+    }
+}
+
+impl<Sock: AsyncRead + Unpin> AsyncRead for ServedStream<Sock> {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        // Reading means the response is complete.
+        let this = self.get_mut();
+        this.response_done();
+        Pin::new(&mut this.sock).poll_read(cx, buf)
+    }
+}
+
+impl<Sock: AsyncWrite + Unpin> AsyncWrite for ServedStream<Sock> {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        let len = ready!(Pin::new(&mut this.sock).poll_write(cx, buf))?;
+        this.track(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.sock).poll_flush(cx))?;
+        this.response_done();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_shutdown(cx)
+    }
+}
+
+
+//------------ Served --------------------------------------------------------
+
+/// The number of clients that have received the complete data set.
+#[derive(Debug)]
+struct Served {
+    /// The current number.
+    count: AtomicUsize,
+
+    /// Announces changes of the number.
+    tx: watch::Sender<usize>,
+
+    /// A receiver to clone for new subscribers.
+    ///
+    /// This also keeps the channel open even if nobody is listening.
+    rx: watch::Receiver<usize>,
+}
+
+impl Served {
+    /// Notes that one more client has received the data set.
+    fn add(&self) {
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("Client {} has received the complete data set.", count);
+        let _ = self.tx.broadcast(count);
+    }
+
+    /// Returns the current number.
+    fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Returns a receiver for changes of the number.
+    fn subscribe(&self) -> watch::Receiver<usize> {
+        self.rx.clone()
+    }
+}
+
+impl Default for Served {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(0);
+        Served {
+            count: AtomicUsize::new(0),
+            tx, rx,
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::tests::assert_state_eq;
+    use crate::payload::testing::v4;
+    use super::*;
+
+    fn set() -> Arc<payload::Set> {
+        let mut set = payload::SetBuilder::empty();
+        for asn in 1..4 {
+            set.insert(v4([192, 0, 2, 0], 24, 24, asn)).unwrap();
+        }
+        Arc::new(set.finalize())
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let source = ImportSource::new(set());
+        let serve = tokio::spawn(Import::serve(
+            vec![listener], source.clone(), 1, Duration::from_secs(10)
+        ));
+
+        // Send a reset query and read until the end of data PDU.
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(&[1, 2, 0, 0, 0, 0, 0, 8]).await.unwrap();
+        loop {
+            let mut header = [0u8; 8];
+            sock.read_exact(&mut header).await.unwrap();
+            let len = u32::from_be_bytes(
+                [header[4], header[5], header[6], header[7]]
+            ) as usize;
+            let mut body = vec![0u8; len - 8];
+            sock.read_exact(&mut body).await.unwrap();
+            if header[1] == 7 {
+                break
+            }
+        }
+        assert_eq!(serve.await.unwrap().ok(), Some(1));
+        assert_eq!(source.served(), 1);
+    }
+
+    #[tokio::test]
+    async fn serve_timeout() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let source = ImportSource::new(set());
+        assert_eq!(
+            Import::serve(
+                vec![listener], source, 2, Duration::from_millis(100)
+            ).await.ok(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn diff_is_reset() {
+        let source = ImportSource::new(set());
+        assert!(source.diff(source.notify()).is_none());
+        let (state, iter) = source.full();
+        assert_state_eq(state, source.notify());
+        assert_eq!(iter.count(), 3);
+        assert_eq!(source.served(), 0);
+    }
+
+    #[tokio::test]
+    async fn served_stream() {
+        let served = Arc::new(Served::default());
+        let mut sock = ServedStream::new(Vec::new(), served.clone());
+
+        // A Cache Response and the first half of an End of Data PDU.
+        sock.write_all(&[1, 3, 0, 0, 0, 0, 0, 8, 1, 7, 0, 0]).await.unwrap();
+        sock.flush().await.unwrap();
+        assert_eq!(served.count(), 0);
+
+        // The rest of End of Data. Only flushing counts the client.
+        sock.write_all(&[0, 0, 0, 24]).await.unwrap();
+        sock.write_all(&[0; 16]).await.unwrap();
+        assert_eq!(served.count(), 0);
+        sock.flush().await.unwrap();
+        assert_eq!(served.count(), 1);
+
+        // Further responses on the same connection don’t count again.
+        sock.write_all(&[1, 7, 0, 0, 0, 0, 0, 24]).await.unwrap();
+        sock.write_all(&[0; 16]).await.unwrap();
+        sock.flush().await.unwrap();
+        assert_eq!(served.count(), 1);
+        assert_eq!(sock.sock.len(), 56);
+    }
+}
@@ -22,13 +22,26 @@
 //! give you a somewhat gentle introduction into the overall architecture.
 #![allow(clippy::unknown_clippy_lints)]
 
+pub mod analysis;
+pub mod check;
 pub mod comms;
 pub mod config;
+pub mod eval;
 pub mod formats;
+pub mod harden;
 pub mod http;
+pub mod import;
+pub mod limits;
 pub mod log;
 pub mod manager;
 pub mod metrics;
+pub mod net;
 pub mod payload;
+pub mod random;
+pub mod siem;
 pub mod targets;
 pub mod units;
+pub mod watch;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,376 @@
+//! Checking system resource limits at startup.
+//!
+//! Every connection RTRTR makes or accepts needs a file descriptor. If the
+//! limit for open files is too low, connections start failing once the
+//! limit is reached, which is hard to diagnose after the fact. At startup,
+//! RTRTR therefore estimates how many file descriptors the configuration
+//! will need, raises the soft limit to the hard limit if possible, and
+//! warns if the soft limit still looks too low. With `strict-limits`, this
+//! is fatal instead.
+//!
+//! The estimate is deliberately rough: one descriptor per unit, one per
+//! listening socket, `expected-clients` per target, plus a fixed margin for
+//! log files, the runtime, and connections made by the HTTP client.
+//!
+//! The checks are only performed on Unix systems. Elsewhere, the limits are
+//! reported as unknown and nothing else happens.
+
+use std::sync::Arc;
+use log::{error, info, warn};
+use serde::Deserialize;
+use crate::metrics;
+use crate::config::Config;
+use crate::log::ExitError;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ ResourceLimits ------------------------------------------------
+
+/// The configuration for checking resource limits.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ResourceLimits {
+    /// Whether a too low limit is fatal.
+    #[serde(rename = "strict-limits", default)]
+    strict: bool,
+
+    /// The number of clients assumed for each target.
+    #[serde(
+        rename = "expected-clients",
+        default = "ResourceLimits::default_expected_clients"
+    )]
+    expected_clients: usize,
+}
+
+impl ResourceLimits {
+    /// The default number of clients assumed for each target.
+    pub fn default_expected_clients() -> usize {
+        100
+    }
+
+    /// Checks the limit of open files against what `config` needs.
+    ///
+    /// Tries to raise the soft limit to the hard limit first. Returns the
+    /// metrics for the limits which need to be kept alive for as long as
+    /// they should be reported.
+    pub fn check(
+        &self, config: &Config, metrics: &metrics::Collection
+    ) -> Result<Arc<LimitMetrics>, ExitError> {
+        let estimate = FdEstimate::from_config(
+            config, self.expected_clients
+        ).total();
+        let (soft, hard) = match sys::raise_nofile() {
+            Ok(Some((soft, hard, raised))) => {
+                if raised {
+                    info!(
+                        "Raised the limit of open files to {}.",
+                        Limit(soft)
+                    );
+                }
+                (Some(soft), Some(hard))
+            }
+            Ok(None) => (None, None),
+            Err(err) => {
+                warn!("Failed to get the limit of open files: {}", err);
+                (None, None)
+            }
+        };
+        let res = Arc::new(LimitMetrics { soft, hard, estimate });
+        metrics.register("limits".into(), Arc::downgrade(&res) as _);
+
+        if let Some(soft) = soft {
+            if soft < estimate {
+                if self.strict {
+                    error!(
+                        "Fatal: the limit of open files is {} but the \
+                         configuration may need up to {}.",
+                        soft, estimate
+                    );
+                    return Err(ExitError)
+                }
+                warn!(
+                    "The limit of open files is {} but the configuration \
+                     may need up to {}. Connections may fail. Consider \
+                     raising the limit.",
+                    soft, estimate
+                );
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        ResourceLimits {
+            strict: false,
+            expected_clients: Self::default_expected_clients(),
+        }
+    }
+}
+
+
+//------------ FdEstimate ----------------------------------------------------
+
+/// The numbers that go into estimating the needed file descriptors.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FdEstimate {
+    /// The number of units.
+    pub units: usize,
+
+    /// The number of targets.
+    pub targets: usize,
+
+    /// The number of listening sockets of targets and the HTTP server.
+    pub listeners: usize,
+
+    /// The number of clients assumed for each target.
+    pub clients: usize,
+}
+
+impl FdEstimate {
+    /// The number of descriptors needed regardless of the configuration.
+    pub const MARGIN: u64 = 64;
+
+    /// Collects the numbers from a configuration.
+    pub fn from_config(config: &Config, clients: usize) -> Self {
+        FdEstimate {
+            units: config.units.len(),
+            targets: config.targets.len(),
+            listeners: {
+                config.targets.listeners() + config.http.listen().len()
+            },
+            clients,
+        }
+    }
+
+    /// Returns the estimated number of file descriptors needed.
+    pub fn total(self) -> u64 {
+        (self.targets as u64).saturating_mul(self.clients as u64)
+            .saturating_add(self.units as u64)
+            .saturating_add(self.listeners as u64)
+            .saturating_add(Self::MARGIN)
+    }
+}
+
+
+//------------ LimitMetrics --------------------------------------------------
+
+/// The limit of open files and the estimate for the configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct LimitMetrics {
+    /// The soft limit if known.
+    ///
+    /// An unlimited limit is given as `u64::MAX`.
+    soft: Option<u64>,
+
+    /// The hard limit if known.
+    hard: Option<u64>,
+
+    /// The estimated number of files needed.
+    estimate: u64,
+}
+
+impl LimitMetrics {
+    const LIMIT_METRIC: Metric = Metric::new(
+        "open_files_limit",
+        "the limit of open files, -1 if unlimited or unknown",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const ESTIMATE_METRIC: Metric = Metric::new(
+        "open_files_estimate",
+        "the estimated number of open files needed by the configuration",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for LimitMetrics {
+    fn append(&self, _unit_name: &str, target: &mut metrics::Target)  {
+        target.append(&Self::LIMIT_METRIC, None, |records| {
+            records.label_value(
+                &[("kind", "soft")], Limit::metric(self.soft)
+            );
+            records.label_value(
+                &[("kind", "hard")], Limit::metric(self.hard)
+            );
+        });
+        target.append_simple(&Self::ESTIMATE_METRIC, None, self.estimate);
+    }
+}
+
+
+//------------ Limit ---------------------------------------------------------
+
+/// Displays a limit.
+struct Limit(u64);
+
+impl Limit {
+    /// Returns the value of a limit for the metrics.
+    fn metric(limit: Option<u64>) -> i128 {
+        match limit {
+            Some(limit) if limit != u64::MAX => i128::from(limit),
+            _ => -1
+        }
+    }
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0 == u64::MAX {
+            f.write_str("unlimited")
+        }
+        else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+
+//------------ sys -----------------------------------------------------------
+
+/// The Unix implementation of raising the limit.
+#[cfg(unix)]
+mod sys {
+    use std::io;
+
+    /// Raises the soft limit of open files to the hard limit.
+    ///
+    /// Returns the soft and hard limit afterwards and whether the soft
+    /// limit was raised. Unlimited values are given as `u64::MAX`.
+    pub fn raise_nofile() -> Result<Option<(u64, u64, bool)>, io::Error> {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let (soft, hard) = (value(limit.rlim_cur), value(limit.rlim_max));
+        if soft >= hard {
+            return Ok(Some((soft, hard, false)))
+        }
+
+        // macOS refuses anything above OPEN_MAX even if the hard limit is
+        // unlimited.
+        #[cfg(target_os = "macos")]
+        let target = std::cmp::min(
+            limit.rlim_max, libc::OPEN_MAX as libc::rlim_t
+        );
+        #[cfg(not(target_os = "macos"))]
+        let target = limit.rlim_max;
+
+        let raised = libc::rlimit {
+            rlim_cur: target, rlim_max: limit.rlim_max
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+            // Not being able to raise the limit isn’t an error, we just
+            // have to make do with what we have.
+            return Ok(Some((soft, hard, false)))
+        }
+        Ok(Some((value(target), hard, true)))
+    }
+
+    /// Converts a limit value.
+    #[allow(clippy::useless_conversion)] // rlim_t is u64 only on some
+    fn value(value: libc::rlim_t) -> u64 {
+        if value == libc::RLIM_INFINITY {
+            u64::MAX
+        }
+        else {
+            u64::from(value)
+        }
+    }
+}
+
+/// The implementation for systems without limits.
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+
+    pub fn raise_nofile() -> Result<Option<(u64, u64, bool)>, io::Error> {
+        Ok(None)
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::config::ConfigFile;
+    use crate::manager::Manager;
+    use super::*;
+
+    fn estimate(config: &str) -> u64 {
+        let path = std::env::temp_dir().join(
+            format!("rtrtr-limits-{}.conf", std::process::id())
+        );
+        fs::write(&path, config).unwrap();
+        let file = ConfigFile::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let config = Manager::new().load(file).unwrap();
+        FdEstimate::from_config(
+            &config, config.limits.expected_clients
+        ).total()
+    }
+
+    #[test]
+    fn fd_estimate() {
+        // Nothing configured but the HTTP server.
+        assert_eq!(
+            estimate(
+                "http-listen = [\"127.0.0.1:8323\"]\n\
+                 [units]\n[targets]\n"
+            ),
+            1 + FdEstimate::MARGIN
+        );
+
+        // Two units and an RTR target with two listeners.
+        assert_eq!(
+            estimate(
+                "http-listen = []\n\
+                 [units.a]\n\
+                 type = \"json\"\n\
+                 uri = \"http://localhost/a.json\"\n\
+                 refresh = 60\n\
+                 [units.b]\n\
+                 type = \"any\"\n\
+                 sources = [\"a\"]\n\
+                 random = false\n\
+                 [targets.rtr]\n\
+                 type = \"rtr\"\n\
+                 listen = [\"127.0.0.1:3323\", \"[::1]:3323\"]\n\
+                 unit = \"b\"\n"
+            ),
+            2 + 2 + 100 + FdEstimate::MARGIN
+        );
+
+        // The number of clients is configurable and applies to all
+        // targets.
+        assert_eq!(
+            estimate(
+                "http-listen = [\"127.0.0.1:8323\"]\n\
+                 expected-clients = 1000\n\
+                 [units.a]\n\
+                 type = \"json\"\n\
+                 uri = \"http://localhost/a.json\"\n\
+                 refresh = 60\n\
+                 [targets.rtr]\n\
+                 type = \"rtr\"\n\
+                 listen = [\"127.0.0.1:3323\"]\n\
+                 unit = \"a\"\n\
+                 [targets.http]\n\
+                 type = \"http\"\n\
+                 path = \"/json\"\n\
+                 format = \"json\"\n\
+                 unit = \"a\"\n"
+            ),
+            1 + 2 + 2000 + FdEstimate::MARGIN
+        );
+
+        // Overflow saturates.
+        assert_eq!(
+            FdEstimate {
+                units: 1, targets: 2, listeners: 0, clients: usize::MAX
+            }.total(),
+            u64::MAX
+        );
+    }
+}
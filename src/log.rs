@@ -6,19 +6,22 @@
 //! The module also provides two error types [`Failed`] and [`ExitError`] that
 //! indicate that error information has been logged and a consumer can just
 //! return quietly.
-use std::{fmt, io, process};
+use std::{fmt, io, process, thread};
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use clap::{App, Arg, ArgMatches};
-use log::{error, LevelFilter, Log};
+use log::{error, Level, LevelFilter, Log, Metadata, Record};
 use serde::Deserialize;
 
 
 //------------ LogConfig -----------------------------------------------------
 
 /// Logging configuration.
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
 pub struct LogConfig {
     /// Where to log to?
     #[serde(default)]
@@ -40,6 +43,12 @@ pub struct LogConfig {
     /// The minimum log level to actually log.
     #[serde(default)]
     pub log_level: LogFilter,
+
+    /// The maximum number of log messages per second from units and targets.
+    ///
+    /// If this is `None`, the messages are not limited.
+    #[serde(default)]
+    pub log_rate_limit: Option<u32>,
 }
 
 impl LogConfig {
@@ -232,6 +241,10 @@ impl LogConfig {
                 self.file_logger()?
             }
         };
+        let logger = match self.log_rate_limit {
+            Some(rate) => RateLimit::spawn(logger, rate),
+            None => logger
+        };
         log_reroute::reroute_boxed(logger);
         log::set_max_level(self.log_level.0);
         Ok(())
@@ -319,6 +332,178 @@ impl LogConfig {
 
 
 
+//------------ RateLimit -----------------------------------------------------
+
+/// A logger limiting the rate of log messages from units and targets.
+///
+/// The limit is enforced through a token bucket that allows a burst of up
+/// to one second’s worth of messages. If the bucket is empty, a message is
+/// still logged if it is the first one from its place in the code during
+/// the current summary period or if it has level info. The former makes
+/// sure that new kinds of problems are reported, the latter lets through
+/// messages about recovery. All other messages are suppressed and their
+/// number is logged at the end of the summary period.
+///
+/// Messages from outside units and targets are not limited.
+struct RateLimit {
+    /// The logger to pass messages on to.
+    inner: Box<dyn Log>,
+
+    /// The number of messages per second.
+    rate: u32,
+
+    /// The mutable state.
+    state: Mutex<RateLimitState>,
+}
+
+/// The mutable state of the rate limit.
+struct RateLimitState {
+    /// The number of tokens currently in the bucket.
+    tokens: f64,
+
+    /// When the bucket was last refilled.
+    refilled: Instant,
+
+    /// When the current summary period started.
+    period_start: Instant,
+
+    /// The places in the code that logged during the current period.
+    seen: HashSet<(Option<&'static str>, Option<u32>)>,
+
+    /// The number of messages suppressed during the current period.
+    suppressed: u64,
+}
+
+impl RateLimit {
+    /// The length of a summary period.
+    const PERIOD: Duration = Duration::from_secs(10);
+
+    /// The module prefixes of messages subject to the limit.
+    const LIMITED: &'static [&'static str] = &[
+        "rtrtr::units", "rtrtr::targets"
+    ];
+
+    /// Creates a new rate limit atop the given logger.
+    fn new(inner: Box<dyn Log>, rate: u32, now: Instant) -> Self {
+        RateLimit {
+            inner, rate,
+            state: Mutex::new(RateLimitState {
+                tokens: f64::from(rate),
+                refilled: now,
+                period_start: now,
+                seen: HashSet::new(),
+                suppressed: 0,
+            })
+        }
+    }
+
+    /// Creates a new boxed rate limit.
+    ///
+    /// Also spawns a thread that regularly flushes the logger so that
+    /// summaries of suppressed messages are logged even if nothing else is.
+    fn spawn(inner: Box<dyn Log>, rate: u32) -> Box<dyn Log> {
+        thread::spawn(|| loop {
+            thread::sleep(Self::PERIOD);
+            log::logger().flush();
+        });
+        Box::new(Self::new(inner, rate, Instant::now()))
+    }
+
+    /// Decides whether to log a record at the given time.
+    ///
+    /// Returns whether to log the record and the number of suppressed
+    /// messages to report first if the summary period has ended.
+    fn check(&self, record: &Record, now: Instant) -> (bool, Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let summary = state.end_period(now);
+        if !Self::LIMITED.iter().any(|&prefix| {
+            record.target().starts_with(prefix)
+        }) {
+            return (true, summary)
+        }
+        let rate = f64::from(self.rate);
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(rate);
+        state.refilled = now;
+        let first = state.seen.insert(
+            (record.module_path_static(), record.line())
+        );
+        let pass = if state.tokens >= 1. {
+            state.tokens -= 1.;
+            true
+        }
+        else if first || record.level() == Level::Info {
+            true
+        }
+        else {
+            state.suppressed += 1;
+            false
+        };
+        (pass, summary)
+    }
+
+    /// Logs the summary of suppressed messages.
+    fn log_summary(&self, suppressed: u64) {
+        self.inner.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target(module_path!())
+                .args(format_args!(
+                    "{} additional log messages suppressed.", suppressed
+                ))
+                .build()
+        );
+    }
+}
+
+impl RateLimitState {
+    /// Ends the summary period if it is over.
+    ///
+    /// Returns the number of suppressed messages if there were any.
+    fn end_period(&mut self, now: Instant) -> Option<u64> {
+        if now.duration_since(self.period_start) < RateLimit::PERIOD {
+            return None
+        }
+        self.period_start = now;
+        self.seen.clear();
+        match self.suppressed {
+            0 => None,
+            suppressed => {
+                self.suppressed = 0;
+                Some(suppressed)
+            }
+        }
+    }
+}
+
+impl Log for RateLimit {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return
+        }
+        let (pass, summary) = self.check(record, Instant::now());
+        if let Some(suppressed) = summary {
+            self.log_summary(suppressed)
+        }
+        if pass {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        let summary = self.state.lock().unwrap().end_period(Instant::now());
+        if let Some(suppressed) = summary {
+            self.log_summary(suppressed)
+        }
+        self.inner.flush()
+    }
+}
+
+
 //------------ LogTarget -----------------------------------------------------
 
 /// The target to log to.
@@ -467,3 +652,54 @@ impl From<Failed> for ExitError {
     }
 }
 
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Discard;
+
+    impl Log for Discard {
+        fn enabled(&self, _: &Metadata) -> bool { true }
+        fn log(&self, _: &Record) { }
+        fn flush(&self) { }
+    }
+
+    #[test]
+    fn rate_limit() {
+        let start = Instant::now();
+        let limit = RateLimit::new(Box::new(Discard), 2, start);
+        let check = |level, target, line, secs| {
+            let record = Record::builder()
+                .level(level).target(target)
+                .module_path_static(Some(target)).line(Some(line))
+                .build();
+            limit.check(
+                &record, start + Duration::from_millis(secs)
+            )
+        };
+        let unit = "rtrtr::units::rtr";
+
+        // The burst is two messages.
+        assert_eq!(check(Level::Warn, unit, 1, 0), (true, None));
+        assert_eq!(check(Level::Warn, unit, 1, 0), (true, None));
+        assert_eq!(check(Level::Warn, unit, 1, 0), (false, None));
+
+        // First occurrences, info messages, and other modules pass.
+        assert_eq!(check(Level::Error, unit, 2, 0), (true, None));
+        assert_eq!(check(Level::Error, unit, 2, 0), (false, None));
+        assert_eq!(check(Level::Info, unit, 3, 0), (true, None));
+        assert_eq!(check(Level::Info, unit, 3, 0), (true, None));
+        assert_eq!(check(Level::Warn, "rtrtr::http", 4, 0), (true, None));
+
+        // The bucket refills over time.
+        assert_eq!(check(Level::Warn, unit, 1, 500), (true, None));
+        assert_eq!(check(Level::Warn, unit, 1, 500), (false, None));
+
+        // The summary comes with the first message of the next period.
+        assert_eq!(check(Level::Warn, unit, 1, 10_000), (true, Some(3)));
+    }
+}
@@ -4,7 +4,10 @@ use clap::{App, crate_authors, crate_version};
 use futures::future::pending;
 use log::error;
 use tokio::runtime;
+use rtrtr::check::{CheckHijacks, CheckRedundant, CheckSpecial};
 use rtrtr::config::Config;
+use rtrtr::eval::Eval;
+use rtrtr::http;
 use rtrtr::log::ExitError;
 use rtrtr::manager::Manager;
 
@@ -16,6 +19,10 @@ fn _main() -> Result<(), ExitError> {
         .version(crate_version!())
         .author(crate_authors!())
         .about("collecting, processing and distributing route filtering data")
+        .subcommand(Eval::subcommand())
+        .subcommand(CheckRedundant::subcommand())
+        .subcommand(CheckSpecial::subcommand())
+        .subcommand(CheckHijacks::subcommand())
     ).get_matches();
     let cur_dir = match current_dir() {
         Ok(dir) => dir,
@@ -27,16 +34,45 @@ fn _main() -> Result<(), ExitError> {
             return Err(ExitError);
         }
     };
+    if let Some(matches) = matches.subcommand_matches(Eval::NAME) {
+        return Eval::run(matches, &cur_dir)
+    }
+    if let Some(matches) = matches.subcommand_matches(CheckRedundant::NAME) {
+        return CheckRedundant::run(matches, &cur_dir)
+    }
+    if let Some(matches) = matches.subcommand_matches(CheckSpecial::NAME) {
+        return CheckSpecial::run(matches, &cur_dir)
+    }
+    if let Some(matches) = matches.subcommand_matches(CheckHijacks::NAME) {
+        return CheckHijacks::run(matches, &cur_dir)
+    }
     let mut manager = Manager::new();
     let mut config = Config::from_arg_matches(
         &matches, &cur_dir, &mut manager
     )?;
+
+    // Sockets passed in by a supervisor need to be taken over before we
+    // open anything else.
+    config.units.adopt_fds()?;
+
+    // Raising the limit of open files may need privileges, too.
+    let _limits = config.limits.check(&config, &manager.metrics())?;
+
+    // Everything that needs privileges has to happen before we drop them
+    // and before the runtime starts any threads.
+    let listeners = config.http.bind()?;
+    config.targets.bind()?;
+    config.siem.open()?;
+    config.harden.apply()?;
+
     let mut runtime = runtime::Builder::new()
         .threaded_scheduler()
         .enable_all()
         .build()
         .unwrap();
-    config.http.run(manager.metrics(), manager.http_resources(), &runtime)?;
+    http::Server::run(
+        listeners, manager.metrics(), manager.http_resources(), &runtime
+    );
     manager.spawn(&mut config, &runtime);
     runtime.block_on(pending())
 }
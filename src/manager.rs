@@ -2,17 +2,23 @@
 
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use log::error;
 use serde::Deserialize;
 use reqwest::blocking::Client as HttpClient;
 use tokio::runtime::Runtime;
-use crate::{http, metrics};
-use crate::comms::{Gate, GateAgent, Link};
+use tokio::time::delay_for;
+use crate::{http, metrics, net, payload};
+use crate::analysis::SetAnalysis;
+use crate::comms::{Gate, GateAgent, Link, Registry};
 use crate::config::{Config, ConfigFile, Marked};
-use crate::log::Failed;
-use crate::targets::Target;
-use crate::units::Unit;
+use crate::log::{ExitError, Failed};
+use crate::random::Random;
+use crate::siem::{SiemEvent, SiemSink};
+use crate::targets::{EvalOutput, Target};
+use crate::units::{Unit, UnitConfig};
+use crate::watch::WatchList;
 
 
 //------------ Component -----------------------------------------------------
@@ -34,18 +40,37 @@ pub struct Component {
 
     /// A reference to the HTTP resources collection.
     http_resources: http::Resources,
+
+    /// The global limits for outbound connections.
+    outbound: net::Outbound,
+
+    /// A reference to the registry of all units.
+    registry: Registry,
+
+    /// The source of random numbers for the component.
+    random: Random,
+
+    /// The sink for events exported to SIEM systems if enabled.
+    siem: Option<Arc<SiemSink>>,
 }
 
 impl Component {
     /// Creates a new component from its, well, components.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         name: String,
         http_client: HttpClient,
         metrics: metrics::Collection,
         http_resources: http::Resources,
+        outbound: net::Outbound,
+        registry: Registry,
+        random: &Random,
+        siem: Option<Arc<SiemSink>>,
     ) -> Self {
         Component {
+            random: random.for_component(&name),
             name: name.into(), http_client, metrics, http_resources,
+            outbound, registry, siem,
         }
     }
 
@@ -59,6 +84,40 @@ impl Component {
         &self.http_client
     }
 
+    /// Returns a reference to the source of random numbers.
+    ///
+    /// Components should use this rather than the thread-local generator so
+    /// that their random choices become reproducible if a seed is
+    /// configured.
+    pub fn random(&self) -> &Random {
+        &self.random
+    }
+
+    /// Returns a reference to the registry of all units.
+    ///
+    /// The registry allows looking up the current state of other units by
+    /// their name.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Returns the sink for events exported to SIEM systems if enabled.
+    pub fn siem(&self) -> Option<&SiemSink> {
+        self.siem.as_deref()
+    }
+
+    /// Returns the outbound connection limits for the component.
+    ///
+    /// All outbound connections of the component should be made while
+    /// holding a permit from the returned value. If `limit` is given, the
+    /// component may not have more than that many connections open at the
+    /// same time in addition to the global limit.
+    pub fn outbound(&mut self, limit: Option<usize>) -> net::Outbound {
+        let res = self.outbound.for_component(limit);
+        self.register_metrics(res.metrics());
+        res
+    }
+
     /// Register a metrics source.
     pub fn register_metrics(&mut self, source: Arc<dyn metrics::Source>) {
         self.metrics.register(self.name.clone(), Arc::downgrade(&source));
@@ -81,8 +140,8 @@ pub struct Manager {
     /// The currently active units represented by agents to their gates..
     units: HashMap<String, GateAgent>,
 
-    /// Gates for newly loaded, not yet spawned units.
-    pending: HashMap<String, Gate>,
+    /// Gates and agents for newly loaded, not yet spawned units.
+    pending: HashMap<String, (Gate, GateAgent)>,
 
     /// An HTTP client.
     http_client: HttpClient,
@@ -92,6 +151,30 @@ pub struct Manager {
 
     /// The HTTP resources collection maintained by this manager.
     http_resources: http::Resources,
+
+    /// The global limits for outbound connections.
+    outbound: net::Outbound,
+
+    /// The registry of all spawned units.
+    registry: Registry,
+
+    /// The global source of random numbers.
+    random: Random,
+
+    /// The health summaries of all spawned units.
+    ///
+    /// The HTTP resources collection only keeps weak references, so we
+    /// need to hold on to it.
+    health: Option<Arc<http::UnitHealth>>,
+
+    /// The list of watched prefixes if there are any.
+    ///
+    /// This needs to be kept for the same reason as `health`.
+    watch_list: Option<Arc<WatchList>>,
+
+    /// Whether the gates of spawned units send their last update to new
+    /// links.
+    replay: bool,
 }
 
 
@@ -125,7 +208,7 @@ impl Manager {
         });
 
         // Now load the config file.
-        let config = match Config::from_toml(file.bytes()) {
+        let config = match Config::from_slice(file.bytes(), file.format()) {
             Ok(config) => config,
             Err(err) => {
                 error!("{}: {}", file.path(), err);
@@ -148,7 +231,7 @@ impl Manager {
                     }
                 }
                 else {
-                    self.pending.insert(name, gate);
+                    self.pending.insert(name, (gate, load.agent));
                 }
             }
         }
@@ -162,6 +245,14 @@ impl Manager {
         Ok(config)
     }
 
+    /// Makes the gates of all units spawned later replay their last update.
+    ///
+    /// Links connected to these gates receive the last update right away
+    /// rather than only the next one. See [`Gate::enable_replay`].
+    pub fn enable_replay(&mut self) {
+        self.replay = true
+    }
+
     /// Spawns all units and targets in the config unto the given runtime.
     ///
     /// # Panics
@@ -169,35 +260,111 @@ impl Manager {
     /// The method panics if the config hasn’t been successfully loaded via
     /// the same manager earlier.
     pub fn spawn(&mut self, config: &mut Config, runtime: &Runtime) {
+        self.outbound = net::Outbound::new(config.max_outbound_connections);
+        self.random = Random::new(config.random_seed);
+        let siem = config.siem.sink();
+        let health = Arc::new(http::UnitHealth::new(
+            self.registry.clone(), config.http.health_format()
+        ));
+        self.http_resources.register(
+            Arc::downgrade(&health) as Weak<dyn http::ProcessRequest>
+        );
+        self.health = Some(health);
+        if !config.watch.is_empty() {
+            let watch_list = Arc::new(WatchList::new(config.watch.clone()));
+            self.http_resources.register(
+                Arc::downgrade(&watch_list) as Weak<dyn http::ProcessRequest>
+            );
+            self.watch_list = Some(watch_list);
+        }
         for (name, unit) in config.units.units.drain() {
-            let gate = match self.pending.remove(&name) {
-                Some(gate) => gate,
+            let (mut gate, agent) = match self.pending.remove(&name) {
+                Some(pending) => pending,
                 None => {
                     error!("Unit {} is unused and will not be started.", name);
                     continue
                 }
             };
+            if self.replay {
+                gate.enable_replay();
+            }
+            if let Some(max) = unit.max_consumers {
+                gate.set_max_consumers(&name, max);
+            }
+            if unit.shadow_mode {
+                let shadow = gate.enable_shadow_mode(&name);
+                self.http_resources.register(
+                    Arc::downgrade(&shadow) as Weak<dyn http::ProcessRequest>
+                );
+            }
+            self.registry.register(&name, gate.handle());
+            if let Some(ref watch_list) = self.watch_list {
+                watch_list.watch(&name, &agent);
+            }
+            if let Some(siem) = siem.clone() {
+                let mut watcher = agent.create_watcher();
+                let name = name.clone();
+                runtime.spawn(async move {
+                    while let Ok(notice) = watcher.changed().await {
+                        siem.emit(&SiemEvent::update(
+                            &name, notice.serial, notice.count,
+                            notice.changes
+                        ));
+                    }
+                });
+            }
+            if let Some(secs) = config.set_analysis {
+                SetAnalysis::spawn(
+                    &name, gate.handle(), Duration::from_secs(secs),
+                    &self.metrics, &self.http_resources, runtime
+                );
+            }
             let controller = Component::new(
                 name, self.http_client.clone(), self.metrics.clone(),
-                self.http_resources.clone()
+                self.http_resources.clone(), self.outbound.clone(),
+                self.registry.clone(), &self.random, siem.clone()
             );
-            runtime.spawn(unit.run(controller, gate));
+            runtime.spawn(unit.unit.run(controller, gate));
         }
 
         for (name, target) in config.targets.targets.drain() {
             let controller = Component::new(
                 name, self.http_client.clone(), self.metrics.clone(),
-                self.http_resources.clone()
+                self.http_resources.clone(), self.outbound.clone(),
+                self.registry.clone(), &self.random, siem.clone()
             );
             runtime.spawn(target.run(controller));
         }
     }
 
+    /// Waits until the spawned units have settled.
+    ///
+    /// This is the case once neither the status nor the data of any unit
+    /// has changed for the duration of `settle`. Note that units which are
+    /// waiting for something, such as a server to become available, are
+    /// considered settled, too.
+    pub async fn wait_quiescent(&self, settle: Duration) {
+        let mut states = self.registry.states();
+        loop {
+            delay_for(settle).await;
+            let new_states = self.registry.states();
+            if new_states == states {
+                return
+            }
+            states = new_states;
+        }
+    }
+
     /// Returns a new reference to the manager’s metrics collection.
     pub fn metrics(&self) -> metrics::Collection {
         self.metrics.clone()
     }
 
+    /// Returns a new reference to the registry of all units.
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+
     /// Returns a new reference the the HTTP resources collection.
     pub fn http_resources(&self) -> http::Resources {
         self.http_resources.clone()
@@ -211,7 +378,46 @@ impl Manager {
 #[derive(Deserialize)]
 #[serde(transparent)]
 pub struct UnitSet {
-    units: HashMap<String, Unit>,
+    units: HashMap<String, UnitConfig>,
+}
+
+impl UnitSet {
+    /// Replaces the data of all units fetching data from elsewhere.
+    ///
+    /// See [`Unit::inject`] for which units are affected.
+    pub fn inject(&mut self, set: Arc<payload::Set>) {
+        self.units = self.units.drain().map(|(name, config)| {
+            (name, UnitConfig {
+                unit: config.unit.inject(set.clone()),
+                ..config
+            })
+        }).collect();
+    }
+
+    /// Takes over the file descriptors passed in for all units.
+    pub fn adopt_fds(&mut self) -> Result<(), ExitError> {
+        for (name, config) in &mut self.units {
+            config.unit.adopt_fds(name)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of units in the set.
+    pub fn len(&self) -> usize {
+        self.units.len()
+    }
+
+    /// Returns whether the set contains no units.
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty()
+    }
+
+    /// Returns an iterator over the names and units in the set.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Unit)> {
+        self.units.iter().map(|(name, config)| {
+            (name.as_str(), &config.unit)
+        })
+    }
 }
 
 
@@ -228,6 +434,44 @@ impl TargetSet {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Binds the listening sockets of all targets.
+    pub fn bind(&mut self) -> Result<(), ExitError> {
+        for target in self.targets.values_mut() {
+            target.bind()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of targets in the set.
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Returns whether the set contains no targets.
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Returns an iterator over the names and targets in the set.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Target)> {
+        self.targets.iter().map(|(name, target)| (name.as_str(), target))
+    }
+
+    /// Returns the number of listening sockets of all targets.
+    pub fn listeners(&self) -> usize {
+        self.targets.values().map(Target::listeners).sum()
+    }
+
+    /// Converts all targets for evaluating a config.
+    ///
+    /// See [`Target::into_eval`] for details.
+    pub fn into_eval(self) -> Vec<(String, Link, EvalOutput)> {
+        self.targets.into_iter().map(|(name, target)| {
+            let (link, output) = target.into_eval();
+            (name, link, output)
+        }).collect()
+    }
 }
 
 //------------ LoadUnit ------------------------------------------------------
@@ -293,9 +537,9 @@ pub fn load_link(name: Marked<String>) -> Link {
 
         let mark = name.mark(());
         let name = name.into_inner();
-        let unit = gates.entry(name).or_default();
+        let unit = gates.entry(name.clone()).or_default();
         unit.links.push(mark);
-        unit.agent.create_link()
+        unit.agent.create_link().with_unit_name(name)
     })
 }
 
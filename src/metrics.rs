@@ -22,7 +22,9 @@
 
 use std::fmt;
 use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::fmt::Write;
+use std::time::Duration;
 use arc_swap::ArcSwap;
 use clap::{crate_name, crate_version};
 
@@ -345,6 +347,129 @@ impl<'a> Records<'a> {
             }
         }
     }
+
+    /// Appends the values of a histogram to the metrics target.
+    ///
+    /// The metric should be of type [`MetricType::Histogram`]. For
+    /// Prometheus output, this produces the cumulative `_bucket` values as
+    /// well as `_sum` and `_count`.
+    pub fn histogram(&mut self, histogram: &Histogram) {
+        let mut cumulative = 0;
+        for (bound, count) in histogram.bounds.iter().zip(
+            histogram.buckets.iter()
+        ) {
+            cumulative += count.load(Ordering::Relaxed);
+            self.histogram_value("_bucket", Some(*bound), cumulative);
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        self.histogram_value("_bucket", None, count);
+        self.histogram_value(
+            "_sum", None,
+            histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.
+        );
+        self.histogram_value("_count", None, count);
+    }
+
+    /// Appends a single value of a histogram.
+    ///
+    /// The `suffix` is added to the metric name. If `le` is given, it is
+    /// the upper bound of a bucket. For the `_bucket` suffix, `None` is the
+    /// bucket of all values.
+    fn histogram_value(
+        &mut self, suffix: &str, le: Option<f64>, value: impl fmt::Display
+    ) {
+        let le = match le {
+            Some(le) => le.to_string(),
+            None => "+Inf".into()
+        };
+        let is_bucket = suffix == "_bucket";
+        self.target.append_metric_name(self.metric, self.unit_name);
+        match self.target.format {
+            OutputFormat::Prometheus => {
+                self.target.target.push_str(suffix);
+                let mut labels = Vec::new();
+                if let Some(unit_name) = self.unit_name {
+                    labels.push(format!("component=\"{}\"", unit_name));
+                }
+                if is_bucket {
+                    labels.push(format!("le=\"{}\"", le));
+                }
+                if !labels.is_empty() {
+                    write!(
+                        &mut self.target.target, "{{{}}}", labels.join(", ")
+                    ).unwrap();
+                }
+                writeln!(&mut self.target.target, " {}", value).unwrap()
+            }
+            OutputFormat::Plain => {
+                if is_bucket {
+                    write!(&mut self.target.target, " le={}", le).unwrap();
+                }
+                else {
+                    write!(
+                        &mut self.target.target, " {}", &suffix[1..]
+                    ).unwrap();
+                }
+                writeln!(&mut self.target.target, ": {}", value).unwrap()
+            }
+        }
+    }
+}
+
+
+//------------ Histogram -----------------------------------------------------
+
+/// A histogram of durations.
+///
+/// The histogram counts observed durations in buckets with fixed upper
+/// bounds given in seconds. It can be updated concurrently and output via
+/// [`Records::histogram`].
+#[derive(Debug)]
+pub struct Histogram {
+    /// The upper bounds of the buckets in seconds in increasing order.
+    bounds: &'static [f64],
+
+    /// The number of values falling into each bucket.
+    ///
+    /// The bucket for values larger than the last bound is not kept, as
+    /// it follows from `count`.
+    buckets: Vec<AtomicU64>,
+
+    /// The sum of all values in microseconds.
+    sum_micros: AtomicU64,
+
+    /// The number of values.
+    count: AtomicU64,
+}
+
+impl Histogram {
+    /// Creates a new, empty histogram with the given bucket bounds.
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of values in the histogram.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Adds a value to the histogram.
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let idx = self.bounds.iter().position(|bound| secs <= *bound);
+        if let Some(idx) = idx {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(
+            value.as_micros() as u64, Ordering::Relaxed
+        );
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 
@@ -0,0 +1,521 @@
+//! Outbound network connections.
+//!
+//! All outbound connections of components are established while holding an
+//! [`OutboundPermit`] acquired from an [`Outbound`] value. This limits the
+//! number of concurrent outbound connections both globally and per
+//! component and provides a single place to keep metrics about them.
+//!
+//! The module also provides [`connect_vrf`] for connecting within a VRF,
+//! [`connect_interface`] for connecting via a certain network interface,
+//! and [`adopt_stream`] for taking over a connection opened by someone else.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use crate::metrics;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Outbound ------------------------------------------------------
+
+/// The limits for outbound connections of a component.
+///
+/// A value of this type is created once for the whole application via
+/// [`new`](Self::new) with the global limit. Each component derives its own
+/// value with an optional limit of its own via
+/// [`for_component`](Self::for_component). Cloned values share their limits
+/// and metrics.
+#[derive(Clone, Debug, Default)]
+pub struct Outbound {
+    /// The global limit if there is one.
+    global: Option<Arc<Semaphore>>,
+
+    /// The limit for the component if there is one.
+    local: Option<Arc<Semaphore>>,
+
+    /// The metrics for the component.
+    metrics: Arc<OutboundMetrics>,
+}
+
+impl Outbound {
+    /// Creates a new value with the given global limit.
+    ///
+    /// If `limit` is `None`, there is no global limit.
+    pub fn new(limit: Option<usize>) -> Self {
+        Outbound {
+            global: limit.map(|limit| Arc::new(Semaphore::new(limit))),
+            local: None,
+            metrics: Default::default(),
+        }
+    }
+
+    /// Creates a value for a component with its own limit.
+    ///
+    /// The new value shares the global limit but has its own metrics. If
+    /// `limit` is `None`, only the global limit applies.
+    pub fn for_component(&self, limit: Option<usize>) -> Self {
+        Outbound {
+            global: self.global.clone(),
+            local: limit.map(|limit| Arc::new(Semaphore::new(limit))),
+            metrics: Default::default(),
+        }
+    }
+
+    /// Returns the metrics of the component.
+    pub fn metrics(&self) -> Arc<OutboundMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Acquires a permit for a new outbound connection.
+    ///
+    /// Waits until both the component’s and the global limit allow for
+    /// another connection. The permit should be kept for as long as the
+    /// connection is open. The time spent waiting is recorded in the
+    /// metrics.
+    ///
+    /// Dropping the returned future stops waiting.
+    pub async fn permit(&self) -> OutboundPermit {
+        let start = Instant::now();
+        let local = match self.local {
+            Some(ref local) => Some(local.clone().acquire_owned().await),
+            None => None
+        };
+        let global = match self.global {
+            Some(ref global) => Some(global.clone().acquire_owned().await),
+            None => None
+        };
+        self.metrics.connections.fetch_add(1, Ordering::Relaxed);
+        self.metrics.wait_micros.fetch_add(
+            start.elapsed().as_micros() as u64, Ordering::Relaxed
+        );
+        OutboundPermit { _local: local, _global: global }
+    }
+}
+
+
+//------------ OutboundPermit ------------------------------------------------
+
+/// The permission to have an outbound connection open.
+///
+/// The permission is returned when the value is dropped.
+#[derive(Debug)]
+pub struct OutboundPermit {
+    _local: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+
+//------------ Outgoing ------------------------------------------------------
+
+/// An outbound connection together with its permit.
+#[derive(Debug)]
+pub struct Outgoing<Sock> {
+    /// The actual socket.
+    sock: Sock,
+
+    /// The permit for the socket.
+    _permit: OutboundPermit,
+}
+
+impl<Sock> Outgoing<Sock> {
+    /// Creates a new outgoing connection from a socket and its permit.
+    pub fn new(sock: Sock, permit: OutboundPermit) -> Self {
+        Outgoing { sock, _permit: permit }
+    }
+}
+
+impl<Sock: AsyncRead + Unpin> AsyncRead for Outgoing<Sock> {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_read(cx, buf)
+    }
+}
+
+impl<Sock: AsyncWrite + Unpin> AsyncWrite for Outgoing<Sock> {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_shutdown(cx)
+    }
+}
+
+
+//------------ connect_vrf ---------------------------------------------------
+
+/// The maximum length of the name of a network interface.
+///
+/// This is the size of a device name minus the terminating zero byte.
+pub const MAX_INTERFACE_LEN: usize = 15;
+
+/// The maximum length of a VRF name.
+///
+/// VRFs are network devices, so their names are interface names.
+pub const MAX_VRF_LEN: usize = MAX_INTERFACE_LEN;
+
+/// Connects to the given address from within a VRF.
+///
+/// The socket is bound to the VRF’s master device via `SO_BINDTODEVICE`
+/// before connecting, so the connection uses the VRF’s routing table. This
+/// requires the `CAP_NET_RAW` capability.
+///
+/// VRFs are only supported on Linux. Elsewhere, the function always fails.
+#[cfg(target_os = "linux")]
+pub async fn connect_vrf(
+    addr: SocketAddr, vrf: &str
+) -> Result<TcpStream, io::Error> {
+    bound::connect(addr, vrf).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn connect_vrf(
+    _addr: SocketAddr, _vrf: &str
+) -> Result<TcpStream, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other, "VRFs are only supported on Linux"
+    ))
+}
+
+
+//------------ connect_interface ---------------------------------------------
+
+/// Whether connecting via a certain interface is supported on this system.
+pub const INTERFACE_SUPPORTED: bool = cfg!(
+    any(target_os = "linux", target_os = "macos")
+);
+
+/// Connects to the given address via the given network interface.
+///
+/// On Linux, the socket is bound to the interface via `SO_BINDTODEVICE`
+/// which requires the `CAP_NET_RAW` capability. If the interface belongs
+/// to a VRF, the connection uses the VRF’s routing table. On macOS, the
+/// socket is bound via `IP_BOUND_IF` or `IPV6_BOUND_IF`.
+///
+/// Elsewhere, the function always fails. Check
+/// [`INTERFACE_SUPPORTED`] beforehand.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub async fn connect_interface(
+    addr: SocketAddr, interface: &str
+) -> Result<TcpStream, io::Error> {
+    bound::connect(addr, interface).await
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub async fn connect_interface(
+    _addr: SocketAddr, _interface: &str
+) -> Result<TcpStream, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "binding to an interface is only supported on Linux and macOS"
+    ))
+}
+
+/// Connecting via a bound interface.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod bound {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+    use socket2::{Domain, Socket, Type};
+    use tokio::net::TcpStream;
+
+    /// Connects to `addr` via the interface `device`.
+    pub async fn connect(
+        addr: SocketAddr, device: &str
+    ) -> Result<TcpStream, io::Error> {
+        let sock = Socket::new(
+            if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() },
+            Type::stream(), None
+        )?;
+        if let Err(err) = bind(&sock, addr, device) {
+            return Err(if err.raw_os_error() == Some(libc::EPERM) {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "not permitted to bind to interface '{}': this \
+                         requires the CAP_NET_RAW capability or running \
+                         as root and drop-capabilities set to false",
+                        device
+                    )
+                )
+            }
+            else {
+                io::Error::new(
+                    err.kind(),
+                    format!("cannot bind to interface '{}': {}", device, err)
+                )
+            })
+        }
+        // Tokio 0.2 has no socket builder, so we have to hand over the
+        // unconnected socket.
+        TcpStream::connect_std(sock.into_tcp_stream(), &addr).await
+    }
+
+    /// Binds the socket to a device via `SO_BINDTODEVICE`.
+    #[cfg(target_os = "linux")]
+    fn bind(
+        sock: &Socket, _addr: SocketAddr, device: &str
+    ) -> Result<(), io::Error> {
+        let res = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                device.as_ptr() as *const libc::c_void,
+                device.len() as libc::socklen_t
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Binds the socket to an interface via `IP_BOUND_IF`.
+    #[cfg(target_os = "macos")]
+    fn bind(
+        sock: &Socket, addr: SocketAddr, device: &str
+    ) -> Result<(), io::Error> {
+        let name = match std::ffi::CString::new(device) {
+            Ok(name) => name,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput, "invalid interface name"
+                ))
+            }
+        };
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let index = index as libc::c_int;
+        let (level, name) = if addr.is_ipv4() {
+            (libc::IPPROTO_IP, libc::IP_BOUND_IF)
+        }
+        else {
+            (libc::IPPROTO_IPV6, libc::IPV6_BOUND_IF)
+        };
+        let res = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(), level, name,
+                &index as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+}
+
+
+//------------ adopt_stream --------------------------------------------------
+
+/// Takes over an already connected TCP socket given as a file descriptor.
+///
+/// This is used for sockets passed in by a supervisor. The descriptor is
+/// checked to be a connected stream socket before it is taken over, so
+/// nothing happens to it if the function fails. On success, the returned
+/// socket owns the descriptor and is in non-blocking mode.
+///
+/// Passing file descriptors is only supported on Unix. Elsewhere, the
+/// function always fails.
+#[cfg(unix)]
+pub fn adopt_stream(fd: i32) -> Result<std::net::TcpStream, io::Error> {
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let mut sock_type: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd, libc::SOL_SOCKET, libc::SO_TYPE,
+            &mut sock_type as *mut _ as *mut libc::c_void, &mut len
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    if sock_type != libc::SOCK_STREAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "not a stream socket"
+        ))
+    }
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    let res = unsafe {
+        libc::getpeername(
+            fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    if !matches!(
+        i32::from(addr.ss_family), libc::AF_INET | libc::AF_INET6
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "not a TCP socket"
+        ))
+    }
+    let sock = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    sock.set_nonblocking(true)?;
+    Ok(sock)
+}
+
+#[cfg(not(unix))]
+pub fn adopt_stream(_fd: i32) -> Result<std::net::TcpStream, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "passing file descriptors is only supported on Unix"
+    ))
+}
+
+
+//------------ OutboundMetrics -----------------------------------------------
+
+/// Metrics about the outbound connections of a component.
+#[derive(Debug, Default)]
+pub struct OutboundMetrics {
+    /// The number of permits handed out.
+    connections: AtomicU64,
+
+    /// The time spent waiting for permits in microseconds.
+    wait_micros: AtomicU64,
+}
+
+impl OutboundMetrics {
+    const CONNECTIONS_METRIC: Metric = Metric::new(
+        "outbound_connections",
+        "the number of outbound connection attempts",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const WAIT_METRIC: Metric = Metric::new(
+        "outbound_wait",
+        "the time spent waiting for the outbound connection limits",
+        MetricType::Counter, MetricUnit::Second
+    );
+}
+
+impl metrics::Source for OutboundMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::CONNECTIONS_METRIC, Some(unit_name),
+            self.connections.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::WAIT_METRIC, Some(unit_name),
+            self.wait_micros.load(Ordering::Relaxed) as f64 / 1_000_000.
+        );
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::{select, Either};
+    use futures::pin_mut;
+
+    #[tokio::test]
+    async fn limits() {
+        let global = Outbound::new(Some(2));
+        let first = global.for_component(Some(1));
+        let second = global.for_component(None);
+
+        let permit = first.permit().await;
+        {
+            // The component limit is reached.
+            let wait = first.permit();
+            pin_mut!(wait);
+            let ready = second.permit();
+            pin_mut!(ready);
+            assert!(matches!(select(wait, ready).await, Either::Right(_)));
+        }
+
+        // The global limit is reached, too, now.
+        let other = second.permit().await;
+        {
+            let wait = second.permit();
+            pin_mut!(wait);
+            let timeout = tokio::time::delay_for(
+                std::time::Duration::from_millis(10)
+            );
+            assert!(matches!(select(wait, timeout).await, Either::Right(_)));
+        }
+        drop(permit);
+        drop(other);
+        let _ = first.permit().await;
+        assert_eq!(first.metrics.connections.load(Ordering::Relaxed), 2);
+        assert_eq!(second.metrics.connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn adopt() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, UdpSocket};
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+        // A listening socket isn’t connected and a UDP socket isn’t a
+        // stream. Neither is taken over.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(adopt_stream(listener.as_raw_fd()).is_err());
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(adopt_stream(udp.as_raw_fd()).is_err());
+        assert!(udp.local_addr().is_ok());
+
+        let client = std::net::TcpStream::connect(
+            listener.local_addr().unwrap()
+        ).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut client = adopt_stream(client.into_raw_fd()).unwrap();
+        client.set_nonblocking(false).unwrap();
+        server.write_all(b"rtr").unwrap();
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"rtr");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn interface() {
+        let mut listener = tokio::net::TcpListener::bind(
+            "127.0.0.1:0"
+        ).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Binding requires privileges. Without them, the error says so.
+        match connect_interface(addr, "lo").await {
+            Ok(_) => {
+                assert!(listener.accept().await.is_ok());
+            }
+            Err(err) => {
+                assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+                assert!(err.to_string().contains("CAP_NET_RAW"));
+            }
+        }
+        let err = connect_interface(addr, "rtrtr-none0").await.unwrap_err();
+        assert!(err.to_string().contains("'rtrtr-none0'"));
+    }
+}
@@ -17,18 +17,54 @@
 //! available anyway or can be created cheaply. It should not be generated at
 //! all cost.
 
+use std::{cmp, fmt, net, slice};
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::cmp::Ordering;
-use std::sync::Arc;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicU64};
+use std::time::Instant;
+use async_stream::stream;
+use futures::Stream;
 use rpki_rtr::client::VrpError;
 use rpki_rtr::payload::{Action, Payload};
 use rpki_rtr::state::Serial;
+use serde::Deserialize;
+
+pub use self::aggregate::{Aggregation, DisplayPayload};
+pub use self::checkpoint::DiffCheckpoint;
+pub use self::dict::{DecodeError, DictDecoder, DictEncoder};
+pub use self::digest::DigestTree;
+pub use self::event_store::EventStore;
+pub use self::histogram::PrefixLenHistogram;
+pub use self::overlap::OverlapStats;
+pub use self::rov::{RovIndex, RovState, RovStats};
+pub use self::rtree::RtreeIndex;
+
+mod aggregate;
+mod checkpoint;
+mod covering;
+pub mod dict;
+pub mod digest;
+pub mod event_store;
+mod histogram;
+mod overlap;
+mod prefix_list;
+mod rov;
+mod rtree;
+mod special;
 
 
 //------------ Set -----------------------------------------------------------
 
 /// A set of payload.
+///
+/// In addition to the payload itself, a set can keep the name of the trust
+/// anchor each item was derived from if this information was available to
+/// the unit that created the set.
 #[derive(Clone, Debug, Default)]
 pub struct Set {
     /// The payload items.
@@ -36,9 +72,22 @@ pub struct Set {
     /// This vec is guaranteed to be ordered and not contain duplicated at all
     /// times.
     items: Vec<Payload>,
+
+    /// The trust anchors of the payload items.
+    ///
+    /// If trust anchor information is available, this vec has the same
+    /// length as `items` and contains the trust anchor for the item at the
+    /// same index. Otherwise it is empty.
+    tas: Vec<Option<Arc<str>>>,
+
+    /// The partitions of the set by trust anchor if already calculated.
+    partitions: TaPartitions,
 }
 
 impl Set {
+    /// The trust anchor name used for items without a known trust anchor.
+    pub const UNKNOWN_TA: &'static str = "N/A";
+
     pub fn len(&self) -> usize {
         self.items.len()
     }
@@ -47,6 +96,63 @@ impl Set {
         self.items.is_empty()
     }
 
+    /// Returns an iterator over the payload items in order.
+    pub fn iter(&self) -> slice::Iter<'_, Payload> {
+        self.items.iter()
+    }
+
+    /// Returns whether the set contains the given item.
+    pub fn contains(&self, payload: &Payload) -> bool {
+        self.items.binary_search(payload).is_ok()
+    }
+
+    /// Returns the trust anchor of the item at the given index if known.
+    fn ta(&self, idx: usize) -> Option<&Arc<str>> {
+        self.tas.get(idx).and_then(Option::as_ref)
+    }
+
+    /// Returns the item at the given index and the name of its trust anchor.
+    ///
+    /// If the trust anchor isn’t known, its name is
+    /// [`UNKNOWN_TA`](Self::UNKNOWN_TA).
+    pub fn item_with_ta(&self, idx: usize) -> Option<(&Payload, &str)> {
+        self.items.get(idx).map(|item| {
+            let ta = self.ta(idx).map(AsRef::as_ref);
+            (item, ta.unwrap_or(Self::UNKNOWN_TA))
+        })
+    }
+
+    /// Returns the set partitioned by trust anchor.
+    ///
+    /// Each item of the set appears in exactly one of the partitions. Items
+    /// without a known trust anchor end up in the partition named
+    /// [`UNKNOWN_TA`](Self::UNKNOWN_TA).
+    ///
+    /// The partitions are calculated upon first use and then kept with the
+    /// set. Since sets are never changed, a new update will come with a new
+    /// set and the partitions will be recalculated when needed.
+    pub fn partition_by_ta(&self) -> Arc<TaPartitionMap> {
+        let mut partitions = self.partitions.0.lock().unwrap();
+        if let Some(ref res) = *partitions {
+            return res.clone()
+        }
+        let unknown: Arc<str> = Self::UNKNOWN_TA.into();
+        let mut res = TaPartitionMap::new();
+        for (idx, item) in self.items.iter().enumerate() {
+            let ta = self.ta(idx).unwrap_or(&unknown);
+            let part = res.entry(ta.clone()).or_default();
+            part.items.push(*item);
+            if self.ta(idx).is_some() {
+                part.tas.push(Some(ta.clone()))
+            }
+        }
+        // The partition for unknown trust anchors is the only one without
+        // trust anchor information, so we don’t need to pad any tas.
+        let res = Arc::new(res);
+        *partitions = Some(res.clone());
+        res
+    }
+
     /*
     /// Removes all items in `set` from `self`.
     pub fn remove_set(&mut self, set: &Set) {
@@ -66,6 +172,91 @@ impl Set {
     }
     */
 
+    /// Returns a stream of all items `filter` returns `true` for.
+    ///
+    /// The items are produced in order only when the stream is polled, so
+    /// a slow consumer will not cause the result to be collected in memory.
+    /// Each item comes with the name of its trust anchor as returned by
+    /// [`item_with_ta`](Self::item_with_ta).
+    pub fn stream_query<'a, F>(
+        &'a self, filter: F
+    ) -> impl Stream<Item = (&'a Payload, &'a str)> + 'a
+    where F: Fn(&Payload) -> bool + 'a {
+        stream! {
+            for idx in 0..self.items.len() {
+                if let Some((item, ta)) = self.item_with_ta(idx) {
+                    if filter(item) {
+                        yield (item, ta)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a new set with only the items `keep` returns `true` for.
+    ///
+    /// Trust anchor information is preserved for the items that are kept.
+    pub fn filter<F: FnMut(&Payload) -> bool>(&self, mut keep: F) -> Set {
+        let mut items = Vec::new();
+        let mut tas = Vec::new();
+        for (idx, item) in self.items.iter().enumerate() {
+            if keep(item) {
+                items.push(*item);
+                if !self.tas.is_empty() {
+                    tas.push(self.tas[idx].clone())
+                }
+            }
+        }
+        Set { items, tas, partitions: Default::default() }
+    }
+
+    /// Returns a set with exact-match items made explicit.
+    ///
+    /// A max-length smaller than the prefix length is used by some sources
+    /// to signal that no max-length was given, which means the item only
+    /// matches its prefix exactly. In the returned set, the max-length of
+    /// these items is replaced by the prefix length. Items that become
+    /// duplicates keep the trust anchor of the first of them. All other
+    /// items are unchanged.
+    pub fn normalize(&self) -> Set {
+        let mut res = SetBuilder::empty();
+        for (idx, item) in self.items.iter().enumerate() {
+            let mut item = *item;
+            match item {
+                Payload::V4(ref mut prefix) => {
+                    prefix.max_len = cmp::max(
+                        prefix.max_len, prefix.prefix_len
+                    );
+                }
+                Payload::V6(ref mut prefix) => {
+                    prefix.max_len = cmp::max(
+                        prefix.max_len, prefix.prefix_len
+                    );
+                }
+            }
+            let _ = res.insert_with_ta(item, self.ta(idx).cloned());
+        }
+        res.finalize()
+    }
+
+    /// Returns the items with a max-length beyond the address length.
+    ///
+    /// These are items with a max-length larger than 32 for IPv4 or 128 for
+    /// IPv6. They can never match a route and indicate a broken source.
+    pub fn detect_redundant_max_len(&self) -> Vec<Payload> {
+        self.items.iter().filter(|item| {
+            Self::has_redundant_max_len(item)
+        }).copied().collect()
+    }
+
+    /// Returns whether an item has a max-length beyond the address length.
+    pub fn has_redundant_max_len(item: &Payload) -> bool {
+        match *item {
+            Payload::V4(ref prefix) => prefix.max_len > 32,
+            Payload::V6(ref prefix) => prefix.max_len > 128,
+        }
+    }
+
     /// Returns the diff to get from `other` to `self`.
     pub fn diff_from(&self, other: &Set) -> Diff {
         let mut diff = Vec::new();
@@ -105,6 +296,13 @@ impl Set {
     }
 }
 
+impl Set {
+    /// Creates a set from an ordered vec of items.
+    fn from_items(items: Vec<Payload>) -> Self {
+        Set { items, tas: Vec::new(), partitions: Default::default() }
+    }
+}
+
 impl From<SetBuilder> for Set {
     fn from(builder: SetBuilder) -> Self {
         builder.finalize()
@@ -112,6 +310,27 @@ impl From<SetBuilder> for Set {
 }
 
 
+//------------ TaPartitionMap ------------------------------------------------
+
+/// A set partitioned by trust anchor.
+pub type TaPartitionMap = HashMap<Arc<str>, Set>;
+
+
+//------------ TaPartitions --------------------------------------------------
+
+/// The cached partitions of a set by trust anchor.
+///
+/// Cloning a value results in an empty cache.
+#[derive(Debug, Default)]
+struct TaPartitions(Mutex<Option<Arc<TaPartitionMap>>>);
+
+impl Clone for TaPartitions {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+
 //------------ SetIter ------------------------------------------------
 
 /// An iterator over the content of an arc of a set.
@@ -149,7 +368,11 @@ impl Iterator for SetIter {
 /// A builder for a payload set.
 #[derive(Clone, Debug, Default)]
 pub struct SetBuilder {
-    items: HashSet<Payload>,
+    /// The items of the set and their trust anchor if known.
+    items: HashMap<Payload, Option<Arc<str>>>,
+
+    /// Do we know the trust anchor of at least one item?
+    has_tas: bool,
 }
 
 impl SetBuilder {
@@ -163,19 +386,86 @@ impl SetBuilder {
     /// The method fails with an appropriate error if there already is an
     /// element with the given payload in the set.
     pub fn insert(&mut self, payload: Payload) -> Result<(), VrpError> {
-        if self.items.insert(payload) {
-            Ok(())
+        self.insert_with_ta(payload, None)
+    }
+
+    /// Inserts a new element with a known trust anchor into the set.
+    ///
+    /// The method fails with an appropriate error if there already is an
+    /// element with the given payload in the set.
+    pub fn insert_with_ta(
+        &mut self, payload: Payload, ta: Option<Arc<str>>
+    ) -> Result<(), VrpError> {
+        match self.items.entry(payload) {
+            Entry::Vacant(entry) => {
+                self.has_tas |= ta.is_some();
+                entry.insert(ta);
+                Ok(())
+            }
+            Entry::Occupied(_) => Err(VrpError::DuplicateAnnounce)
+        }
+    }
+
+    /// Adds all items of a set not yet present in the builder.
+    ///
+    /// Items already present keep their trust anchor.
+    pub fn merge_set(&mut self, set: &Set) {
+        for (idx, item) in set.items.iter().enumerate() {
+            if let Entry::Vacant(entry) = self.items.entry(*item) {
+                let ta = set.ta(idx).cloned();
+                self.has_tas |= ta.is_some();
+                entry.insert(ta);
+            }
+        }
+    }
+
+    /// Checks that a payload item is valid on its own.
+    ///
+    /// Returns [`VrpError::Corrupt`] if the prefix length or max-length is
+    /// too large for the address family or if the max-length is smaller
+    /// than the prefix length.
+    pub fn validate(payload: &Payload) -> Result<(), VrpError> {
+        let (prefix_len, max_len, family_len) = match *payload {
+            Payload::V4(ref prefix) => (prefix.prefix_len, prefix.max_len, 32),
+            Payload::V6(ref prefix) => {
+                (prefix.prefix_len, prefix.max_len, 128)
+            }
+        };
+        if prefix_len > family_len || max_len > family_len
+            || max_len < prefix_len
+        {
+            Err(VrpError::Corrupt)
         }
         else {
-            Err(VrpError::DuplicateAnnounce)
+            Ok(())
         }
     }
 
+    /// Checks all payload items for violations.
+    ///
+    /// Unlike inserting the items one by one, this doesn’t stop at the
+    /// first problem but returns the index and error for each item that
+    /// would fail. Items are checked with [`validate`][Self::validate] and
+    /// any later repetition of an item is reported as a duplicate.
+    pub fn validate_all(payloads: &[Payload]) -> Vec<(usize, VrpError)> {
+        let mut seen = HashSet::with_capacity(payloads.len());
+        let mut res = Vec::new();
+        for (idx, payload) in payloads.iter().enumerate() {
+            if let Err(err) = Self::validate(payload) {
+                res.push((idx, err));
+            }
+            else if !seen.insert(payload) {
+                res.push((idx, VrpError::DuplicateAnnounce));
+            }
+        }
+        res
+    }
+
     /// Removes an existing element from the set.
     ///
     /// The method fails with an appropriate error if there is no such item.
     pub fn remove(&mut self, payload: &Payload) -> Result<(), VrpError> {
-        if self.items.remove(payload) {
+        if self.items.remove(payload).is_some() {
             Ok(())
         }
         else {
@@ -185,7 +475,7 @@ impl SetBuilder {
 
     /// Returns whether the set contains the given element.
     pub fn contains(&self, payload: &Payload) -> bool {
-        self.items.contains(payload)
+        self.items.contains_key(payload)
     }
 
     /// Returns the number of elements currently in the set.
@@ -216,24 +506,35 @@ impl SetBuilder {
 
     /// Converts the builder into an imutable set.
     pub fn finalize(self) -> Set {
-        let mut res = Set { items: self.items.into_iter().collect() };
-        res.items.sort_unstable();
-        res
+        if self.has_tas {
+            let mut items: Vec<_> = self.items.into_iter().collect();
+            items.sort_unstable_by_key(|item| item.0);
+            let (items, tas) = items.into_iter().unzip();
+            Set { items, tas, partitions: Default::default() }
+        }
+        else {
+            let mut items: Vec<_> = self.items.into_iter().map(|item| {
+                item.0
+            }).collect();
+            items.sort_unstable();
+            Set::from_items(items)
+        }
     }
 }
 
 impl From<Set> for SetBuilder {
     fn from(set: Set) -> Self {
-        SetBuilder {
-            items: set.items.into_iter().collect()
-        }
+        SetBuilder::from(&set)
     }
 }
 
 impl<'a> From<&'a Set> for SetBuilder {
     fn from(set: &'a Set) -> Self {
         SetBuilder {
-            items: set.items.iter().cloned().collect()
+            items: set.items.iter().enumerate().map(|(idx, item)| {
+                (*item, set.ta(idx).cloned())
+            }).collect(),
+            has_tas: !set.tas.is_empty(),
         }
     }
 }
@@ -252,6 +553,15 @@ pub struct Diff {
 }
 
 impl Diff {
+    /// Returns the minimal diff to get from `old_set` to `new_set`.
+    ///
+    /// This is useful if a full set has been received as a replacement for
+    /// a set that is still known, such as after a cache reset. Only those
+    /// items that actually changed will be part of the diff.
+    pub fn reconcile(old_set: &Set, new_set: &Set) -> Diff {
+        new_set.diff_from(old_set)
+    }
+
     /// Returns the number of changes in this diff.
     pub fn len(&self) -> usize {
         self.items.len()
@@ -262,6 +572,48 @@ impl Diff {
         self.items.is_empty()
     }
 
+    /// Returns the number of announcements and withdrawals in this diff.
+    pub fn action_counts(&self) -> (usize, usize) {
+        let announced = self.items.iter().filter(|item| {
+            item.1 == Action::Announce
+        }).count();
+        (announced, self.items.len() - announced)
+    }
+
+    /// Returns an iterator over the changes in order of the payload.
+    pub fn iter(&self) -> slice::Iter<'_, (Payload, Action)> {
+        self.items.iter()
+    }
+
+    /// Returns whether two diffs make the same changes.
+    ///
+    /// Both diffs are compared by their announcements and withdrawals
+    /// regardless of the order in which they appear.
+    pub fn is_semantically_equivalent(&self, other: &Diff) -> bool {
+        fn sorted(diff: &Diff) -> Vec<(Payload, bool)> {
+            let mut res: Vec<_> = diff.items.iter().map(|(payload, action)| {
+                (*payload, *action == Action::Announce)
+            }).collect();
+            res.sort_unstable();
+            res
+        }
+
+        self.len() == other.len() && sorted(self) == sorted(other)
+    }
+
+    /// Returns a new diff with only the changes for items `keep` returns
+    /// `true` for.
+    ///
+    /// If the diff leads from one set to another, the new diff leads from
+    /// the first set filtered the same way to the second set filtered the
+    /// same way.
+    pub fn filter<F: FnMut(&Payload) -> bool>(&self, mut keep: F) -> Diff {
+        Diff {
+            items: self.items.iter().filter(|item| keep(&item.0)).cloned()
+                .collect()
+        }
+    }
+
     /// Returns an iterator over a shared diff.
     pub fn shared_iter(self: &Arc<Self>) -> DiffIter {
         DiffIter::from(self.clone())
@@ -286,8 +638,14 @@ impl Diff {
     /// # Todo
     ///
     /// This should probably return an error if the diff cannot be applied.
+    ///
+    /// The new set is allocated for at most the size of `set` plus the
+    /// announcements of the diff, so applying many small diffs in a row
+    /// doesn’t accumulate unused capacity.
     pub fn apply(&self, set: &Set) -> Set {
-        let mut res = Vec::new();
+        let mut res = Vec::with_capacity(
+            set.items.len() + self.action_counts().0
+        );
         let mut diff = self.items.as_slice();
         let mut set = set.items.as_slice();
 
@@ -327,7 +685,7 @@ impl Diff {
             }
         }
 
-        Set { items: res }
+        Set::from_items(res)
     }
 
     /*
@@ -548,14 +906,73 @@ pub struct Update {
 
     /// The optional diff from the previous update.
     diff: Option<Arc<Diff>>,
+
+    /// Whether the update must not be served to RTR clients.
+    audit_only: bool,
+
+    /// The IDs of the source updates this update is derived from.
+    ids: UpdateIds,
+
+    /// The time the data of this update was first published by a unit.
+    ///
+    /// This is `None` until the update has passed through its first gate.
+    origin: Option<Instant>,
 }
 
 impl Update {
     /// Creates a new update.
+    ///
+    /// The update receives a new ID. Updates derived from other updates
+    /// should carry over their IDs via [`with_ids`](Self::with_ids) and
+    /// their origin via [`with_origin`](Self::with_origin).
     pub fn new(
         serial: Serial, set: Arc<Set>, diff: Option<Arc<Diff>>
     ) -> Self {
-        Update { serial, set, diff }
+        Update {
+            serial, set, diff, audit_only: false, ids: UpdateIds::next(),
+            origin: None,
+        }
+    }
+
+    /// Sets the IDs of the source updates the update is derived from.
+    pub fn with_ids(mut self, ids: UpdateIds) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Returns the IDs of the source updates the update is derived from.
+    pub fn ids(&self) -> UpdateIds {
+        self.ids
+    }
+
+    /// Sets the time the data of the update was first published.
+    pub fn with_origin(mut self, origin: Option<Instant>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Returns the time the data of the update was first published.
+    ///
+    /// The time is set by the gate of the unit that publishes the update
+    /// first and carried over by all units deriving updates from it. The
+    /// difference to the current time is the latency of the pipeline up to
+    /// this point.
+    pub fn origin(&self) -> Option<Instant> {
+        self.origin
+    }
+
+    /// Marks the update as being for auditing only.
+    ///
+    /// Such updates are processed by units and targets as usual except that
+    /// RTR server targets will not serve them to their clients.
+    pub fn with_audit_only(mut self, audit_only: bool) -> Self {
+        self.audit_only = audit_only;
+        self
+    }
+
+    /// Returns whether the update is for auditing only.
+    pub fn is_audit_only(&self) -> bool {
+        self.audit_only
     }
 
     /// Returns the serial number of the update.
@@ -568,6 +985,11 @@ impl Update {
         self.set.clone()
     }
 
+    /// Returns the diff from the previous update if there is one.
+    pub fn diff(&self) -> Option<&Diff> {
+        self.diff.as_ref().map(AsRef::as_ref)
+    }
+
     /// Returns the diff if it can be used for the given serial.
     ///
     /// The method will return the diff if it is preset and if the given
@@ -582,6 +1004,193 @@ impl Update {
             }
         })
     }
+
+    /// Returns a value displaying a summary of the update.
+    ///
+    /// See [`UpdateSummary`] for the format.
+    pub fn summary(&self) -> UpdateSummary<'_> {
+        UpdateSummary(self)
+    }
+}
+
+
+//------------ UpdateSummary -------------------------------------------------
+
+/// A short summary of an update for logging.
+///
+/// The summary states the number of VRPs in the update’s set and, if the
+/// update has a diff, the number of announcements and withdrawals as in
+/// `10 VRPs, +0 -2`. Updates without a diff are marked as `full set`.
+#[derive(Clone, Copy, Debug)]
+pub struct UpdateSummary<'a>(&'a Update);
+
+impl<'a> fmt::Display for UpdateSummary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} VRPs, ", self.0.set.len())?;
+        match self.0.diff() {
+            Some(diff) => {
+                let (announced, withdrawn) = diff.action_counts();
+                write!(f, "+{} -{}", announced, withdrawn)
+            }
+            None => f.write_str("full set")
+        }
+    }
+}
+
+
+//------------ UpdateIds -----------------------------------------------------
+
+/// The IDs of the source updates an update was derived from.
+///
+/// Every update created from data received from outside gets an ID unique
+/// within the process. Units deriving their updates from those of another
+/// unit carry over its IDs. Units merging the updates of several units use
+/// the range of all their IDs. Because IDs are handed out in order, the
+/// range covers all contributing updates. Logging the IDs allows following
+/// an update through all units.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct UpdateIds {
+    /// The smallest ID.
+    first: u64,
+
+    /// The largest ID.
+    last: u64,
+}
+
+/// The next update ID to hand out.
+static NEXT_UPDATE_ID: AtomicU64 = AtomicU64::new(1);
+
+impl UpdateIds {
+    /// Returns a new, unique ID.
+    pub fn next() -> Self {
+        let id = NEXT_UPDATE_ID.fetch_add(1, atomic::Ordering::Relaxed);
+        UpdateIds { first: id, last: id }
+    }
+
+    /// Returns the smallest ID.
+    pub fn first(self) -> u64 {
+        self.first
+    }
+
+    /// Returns the largest ID.
+    pub fn last(self) -> u64 {
+        self.last
+    }
+
+    /// Returns the range covering both `self` and `other`.
+    pub fn merge(self, other: Self) -> Self {
+        UpdateIds {
+            first: cmp::min(self.first, other.first),
+            last: cmp::max(self.last, other.last),
+        }
+    }
+}
+
+impl fmt::Display for UpdateIds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        }
+        else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+
+//------------ Prefix --------------------------------------------------------
+
+/// An address prefix.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Prefix {
+    /// The address of the prefix.
+    addr: IpAddr,
+
+    /// The length of the prefix.
+    len: u8,
+}
+
+impl Prefix {
+    /// Creates a new prefix from an address and a length.
+    ///
+    /// Returns an error if the length is too large for the address family.
+    pub fn new(addr: IpAddr, len: u8) -> Result<Self, PrefixError> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if len > max_len {
+            return Err(PrefixError)
+        }
+        Ok(Prefix { addr, len })
+    }
+
+    /// Returns the address of the prefix.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the length of the prefix.
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// Returns whether the prefix of `payload` is covered by this prefix.
+    ///
+    /// This is the case if the payload’s prefix is equal to or more
+    /// specific than this prefix.
+    pub fn covers(&self, payload: &Payload) -> bool {
+        let (addr, len) = payload_prefix(payload);
+        len >= self.len && prefix_match(self.addr, addr, self.len)
+    }
+
+    /// Returns whether this prefix is covered by the prefix of `payload`.
+    ///
+    /// This is the case if the payload’s prefix is equal to or less specific
+    /// than this prefix.
+    pub fn is_covered_by(&self, payload: &Payload) -> bool {
+        let (addr, len) = payload_prefix(payload);
+        len <= self.len && prefix_match(self.addr, addr, len)
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = PrefixError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let slash = s.find('/').ok_or(PrefixError)?;
+        Prefix::new(
+            IpAddr::from_str(&s[..slash]).map_err(|_| PrefixError)?,
+            u8::from_str(&s[slash + 1..]).map_err(|_| PrefixError)?,
+        )
+    }
+}
+
+impl TryFrom<String> for Prefix {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Prefix::from_str(&value).map_err(|_| {
+            format!("invalid prefix '{}'", value)
+        })
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+
+//------------ PrefixError ---------------------------------------------------
+
+/// A prefix could not be parsed or was invalid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PrefixError;
+
+impl fmt::Display for PrefixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid prefix")
+    }
 }
 
 
@@ -591,3 +1200,298 @@ fn skip_first<T>(slice: &mut &[T]) {
     *slice = slice.split_first().map(|s| s.1).unwrap_or(&[])
 }
 
+/// Returns the prefix of a payload item.
+fn payload_prefix(payload: &Payload) -> (IpAddr, u8) {
+    match *payload {
+        Payload::V4(ref prefix) => {
+            (IpAddr::V4(prefix.prefix), prefix.prefix_len)
+        }
+        Payload::V6(ref prefix) => {
+            (IpAddr::V6(prefix.prefix), prefix.prefix_len)
+        }
+    }
+}
+
+/// Returns whether the first `len` bits of two addresses are equal.
+///
+/// Addresses of different families never match.
+fn prefix_match(left: IpAddr, right: IpAddr, len: u8) -> bool {
+    match (left, right) {
+        (IpAddr::V4(left), IpAddr::V4(right)) => {
+            mask_v4(left, len) == mask_v4(right, len)
+        }
+        (IpAddr::V6(left), IpAddr::V6(right)) => {
+            mask_v6(left, len) == mask_v6(right, len)
+        }
+        _ => false
+    }
+}
+
+/// Returns the first `len` bits of an IPv4 address.
+fn mask_v4(addr: net::Ipv4Addr, len: u8) -> u32 {
+    if len == 0 { 0 } else { u32::from(addr) >> (32 - u32::from(len)) }
+}
+
+/// Returns the first `len` bits of an IPv6 address.
+fn mask_v6(addr: net::Ipv6Addr, len: u8) -> u128 {
+    if len == 0 { 0 } else { u128::from(addr) >> (128 - u32::from(len)) }
+}
+
+
+
+//============ Testing =======================================================
+
+/// Constructors for payload items used by tests throughout the crate.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use rpki_rtr::payload::{Ipv4Prefix, Ipv6Prefix, Payload};
+
+    /// Creates an IPv4 prefix item.
+    pub fn v4(
+        addr: impl Into<Ipv4Addr>, prefix_len: u8, max_len: u8, asn: u32
+    ) -> Payload {
+        Payload::V4(Ipv4Prefix {
+            prefix: addr.into(), prefix_len, max_len, asn
+        })
+    }
+
+    /// Creates an IPv6 prefix item.
+    pub fn v6(
+        addr: impl Into<Ipv6Addr>, prefix_len: u8, max_len: u8, asn: u32
+    ) -> Payload {
+        Payload::V6(Ipv6Prefix {
+            prefix: addr.into(), prefix_len, max_len, asn
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::testing::{v4, v6};
+
+    #[test]
+    fn update_summary() {
+        let mut builder = SetBuilder::empty();
+        builder.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        builder.insert(v4([198, 51, 100, 0], 24, 24, 64497)).unwrap();
+        let old = builder.finalize();
+        let mut builder = SetBuilder::empty();
+        builder.insert(v4([198, 51, 100, 0], 24, 24, 64497)).unwrap();
+        let new = Arc::new(builder.finalize());
+
+        let update = Update::new(
+            Serial::from(2), new.clone(),
+            Some(Arc::new(new.diff_from(&old)))
+        );
+        assert_eq!(update.summary().to_string(), "1 VRPs, +0 -1");
+        let update = Update::new(Serial::from(2), new, None);
+        assert_eq!(update.summary().to_string(), "1 VRPs, full set");
+    }
+
+    #[test]
+    fn partition_by_ta() {
+        let arin: Arc<str> = "arin".into();
+        let ripe: Arc<str> = "ripe".into();
+        let mut builder = SetBuilder::empty();
+        builder.insert_with_ta(
+            v4([192, 0, 2, 0], 24, 24, 64496), Some(arin.clone())
+        ).unwrap();
+        builder.insert_with_ta(
+            v4([198, 51, 100, 0], 24, 24, 64497), Some(ripe.clone())
+        ).unwrap();
+        builder.insert_with_ta(
+            v4([203, 0, 113, 0], 24, 24, 64498), Some(arin.clone())
+        ).unwrap();
+        builder.insert(v4([10, 0, 0, 0], 8, 8, 64499)).unwrap();
+        let set = builder.finalize();
+
+        let parts = set.partition_by_ta();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[&arin].len(), 2);
+        assert_eq!(parts[&ripe].len(), 1);
+        assert_eq!(parts[Set::UNKNOWN_TA].len(), 1);
+        assert_eq!(
+            parts.values().map(Set::len).sum::<usize>(), set.len()
+        );
+        assert!(Arc::ptr_eq(&parts, &set.partition_by_ta()));
+
+        // Trust anchors survive a round trip through the builder.
+        let again = SetBuilder::from(&set).finalize().partition_by_ta();
+        assert_eq!(again[&arin].len(), 2);
+    }
+
+    #[test]
+    fn reconcile() {
+        fn set(items: &[Payload]) -> Set {
+            let mut builder = SetBuilder::empty();
+            for item in items {
+                builder.insert(*item).unwrap();
+            }
+            builder.finalize()
+        }
+
+        let kept = v4([192, 0, 2, 0], 24, 24, 64496);
+        let gone = v4([198, 51, 100, 0], 24, 24, 64497);
+        let new = v4([203, 0, 113, 0], 24, 24, 64498);
+        let old_set = set(&[kept, gone]);
+        let new_set = set(&[kept, new]);
+
+        let diff = Diff::reconcile(&old_set, &new_set);
+        let mut expected = DiffBuilder::default();
+        expected.push(new, Action::Announce).unwrap();
+        expected.push(gone, Action::Withdraw).unwrap();
+        assert!(diff.is_semantically_equivalent(&expected.finalize()));
+        assert!(!diff.is_semantically_equivalent(&Diff::default()));
+        assert_eq!(diff.apply(&old_set).items, new_set.items);
+        assert!(Diff::reconcile(&new_set, &new_set).is_empty());
+    }
+
+    #[test]
+    fn apply_many_small_diffs() {
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::StdRng;
+
+        let mut rng = StdRng::seed_from_u64(0x5254_5254);
+        let mut builder = SetBuilder::empty();
+        let item = |rng: &mut StdRng| {
+            let addr: [u8; 3] = rng.gen();
+            v4([addr[0], addr[1], addr[2], 0], 24, 24, rng.gen())
+        };
+        while builder.len() < 2_000 {
+            let _ = builder.insert(item(&mut rng));
+        }
+        let mut set = builder.finalize();
+        for _ in 0..10_000 {
+            let mut diff = DiffBuilder::default();
+            for _ in 0..rng.gen_range(1, 4) {
+                let _ = diff.push(item(&mut rng), Action::Announce);
+            }
+            let withdraw = set.items[rng.gen_range(0, set.len())];
+            let _ = diff.push(withdraw, Action::Withdraw);
+            let diff = diff.finalize();
+            set = diff.apply(&set);
+
+            // The unused capacity never exceeds what the last diff
+            // needed.
+            assert!(set.items.capacity() <= set.len() + diff.len());
+        }
+    }
+
+    #[test]
+    fn prefix_cover() {
+        let prefix = Prefix::from_str("192.0.2.0/24").unwrap();
+        assert!(prefix.covers(&v4([192, 0, 2, 128], 25, 25, 64496)));
+        assert!(prefix.covers(&v4([192, 0, 2, 0], 24, 24, 64496)));
+        assert!(!prefix.covers(&v4([192, 0, 0, 0], 16, 16, 64496)));
+        assert!(prefix.is_covered_by(&v4([192, 0, 0, 0], 16, 16, 64496)));
+        assert!(!prefix.is_covered_by(&v4([192, 0, 2, 128], 25, 25, 64496)));
+        assert!(!prefix.is_covered_by(&v4([198, 51, 100, 0], 16, 16, 64496)));
+
+        let any = Prefix::from_str("0.0.0.0/0").unwrap();
+        assert!(any.covers(&v4([10, 0, 0, 0], 8, 8, 64496)));
+        let v6 = Prefix::from_str("2001:db8::/32").unwrap();
+        assert!(!v6.covers(&v4([10, 0, 0, 0], 8, 8, 64496)));
+
+        assert!(Prefix::from_str("10.0.0.0").is_err());
+        assert!(Prefix::from_str("10.0.0.0/33").is_err());
+        assert!(Prefix::from_str("2001:db8::/129").is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_query() {
+        use futures::StreamExt;
+
+        let mut builder = SetBuilder::empty();
+        builder.insert(v4([198, 51, 100, 0], 24, 24, 64497)).unwrap();
+        builder.insert_with_ta(
+            v4([192, 0, 2, 0], 24, 24, 64496), Some("arin".into())
+        ).unwrap();
+        builder.insert(v4([192, 0, 0, 0], 16, 16, 64498)).unwrap();
+        let set = builder.finalize();
+
+        let all: Vec<_> = set.stream_query(|_| true).collect().await;
+        assert_eq!(all.len(), 3);
+        assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        let prefix = Prefix::from_str("192.0.2.0/24").unwrap();
+        let covering: Vec<_> = set.stream_query(|item| {
+            prefix.is_covered_by(item)
+        }).collect().await;
+        assert_eq!(
+            covering,
+            [
+                (&v4([192, 0, 0, 0], 16, 16, 64498), Set::UNKNOWN_TA),
+                (&v4([192, 0, 2, 0], 24, 24, 64496), "arin"),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_all() {
+        let good = v4([192, 0, 2, 0], 24, 24, 64496);
+        let long_max = v4([192, 0, 2, 0], 24, 33, 64496);
+        let short_max = v4([192, 0, 2, 0], 24, 16, 64496);
+        let res = SetBuilder::validate_all(
+            &[good, long_max, good, short_max, long_max]
+        );
+        assert_eq!(
+            res.iter().map(|&(idx, ref err)| {
+                (idx, matches!(*err, VrpError::Corrupt))
+            }).collect::<Vec<_>>(),
+            [(1, true), (2, false), (3, true), (4, true)]
+        );
+        assert!(matches!(res[1].1, VrpError::DuplicateAnnounce));
+        assert!(SetBuilder::validate_all(&[good]).is_empty());
+    }
+
+    #[test]
+    fn normalize_max_len() {
+        let v4_max = |prefix_len, max_len| {
+            v4([192, 0, 2, 0], prefix_len, max_len, 64496)
+        };
+        let v6_long = v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 129, 64496);
+        let mut builder = SetBuilder::empty();
+        for &item in &[
+            v4_max(24, 0), v4_max(24, 24), v4_max(32, 32), v4_max(24, 33),
+            v6_long,
+        ] {
+            builder.insert(item).unwrap();
+        }
+        let set = builder.finalize();
+
+        // The missing max-length becomes the prefix length and merges with
+        // the explicit one.
+        let normal = set.normalize();
+        assert_eq!(normal.len(), 4);
+        assert!(normal.contains(&v4_max(24, 24)));
+        assert!(!normal.contains(&v4_max(24, 0)));
+        assert!(normal.contains(&v4_max(32, 32)));
+
+        assert_eq!(
+            set.detect_redundant_max_len(), [v4_max(24, 33), v6_long]
+        );
+        assert!(
+            set.filter(|item| SetBuilder::validate(item).is_ok())
+                .detect_redundant_max_len().is_empty()
+        );
+    }
+
+    #[test]
+    fn update_ids() {
+        let first = Update::new(Serial::default(), Default::default(), None);
+        let second = Update::new(Serial::default(), Default::default(), None);
+        assert!(first.ids().last() < second.ids().first());
+        let merged = second.ids().merge(first.ids());
+        assert_eq!(
+            merged.to_string(),
+            format!("{}-{}", first.ids().first(), second.ids().last())
+        );
+        assert_eq!(first.ids().to_string(), first.ids().first().to_string());
+        let derived = Update::new(
+            Serial::from(7), first.set(), None
+        ).with_ids(merged);
+        assert_eq!(derived.ids(), merged);
+    }
+}
@@ -0,0 +1,184 @@
+//! Finding payload items that could be aggregated.
+//!
+//! Operators sometimes issue separate ROAs for adjacent prefixes where a
+//! single ROA for the covering prefix with a suitable maximum length would
+//! do. The [`Set::aggregations`] method finds such cases in a payload set.
+//!
+//! The results are purely advisory. An aggregate also authorizes the
+//! covering prefix itself and, depending on its maximum length, further
+//! prefixes not authorized by the original items.
+
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use rpki_rtr::payload::{Ipv4Prefix, Ipv6Prefix, Payload};
+use super::Set;
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Returns all pairs of items that could be aggregated.
+    ///
+    /// A pair consists of the items for the two halves of a prefix that
+    /// have the same origin AS and maximum length. The pairs are returned
+    /// ordered by their lower item. A longer run of adjacent prefixes shows
+    /// up as multiple pairs.
+    pub fn aggregations(&self) -> Vec<Aggregation> {
+        self.items.iter().filter_map(|item| {
+            let high = upper_sibling(item)?;
+            if self.items.binary_search(&high).is_ok() {
+                Some(Aggregation { low: *item, high })
+            }
+            else {
+                None
+            }
+        }).collect()
+    }
+}
+
+
+//------------ Aggregation ---------------------------------------------------
+
+/// Two payload items that could be replaced by a single one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Aggregation {
+    /// The item for the lower half of the covering prefix.
+    pub low: Payload,
+
+    /// The item for the upper half of the covering prefix.
+    pub high: Payload,
+}
+
+impl Aggregation {
+    /// Returns the item that could replace both.
+    ///
+    /// This is the item for the covering prefix with the maximum length of
+    /// the two original items.
+    pub fn aggregate(&self) -> Payload {
+        match self.low {
+            Payload::V4(prefix) => Payload::V4(Ipv4Prefix {
+                prefix_len: prefix.prefix_len - 1, .. prefix
+            }),
+            Payload::V6(prefix) => Payload::V6(Ipv6Prefix {
+                prefix_len: prefix.prefix_len - 1, .. prefix
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{} + {} => {}",
+            DisplayPayload(&self.low), DisplayPayload(&self.high),
+            DisplayPayload(&self.aggregate())
+        )
+    }
+}
+
+
+//------------ DisplayPayload ------------------------------------------------
+
+/// A helper type to display a payload item as `prefix/len-max AS`.
+pub struct DisplayPayload<'a>(pub &'a Payload);
+
+impl<'a> fmt::Display for DisplayPayload<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            Payload::V4(ref prefix) => write!(
+                f, "{}/{}-{} AS{}",
+                prefix.prefix, prefix.prefix_len, prefix.max_len, prefix.asn
+            ),
+            Payload::V6(ref prefix) => write!(
+                f, "{}/{}-{} AS{}",
+                prefix.prefix, prefix.prefix_len, prefix.max_len, prefix.asn
+            ),
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the item for the upper half if `item` is for the lower half.
+///
+/// Returns `None` if the item’s prefix is the upper half of its covering
+/// prefix or there is no covering prefix.
+fn upper_sibling(item: &Payload) -> Option<Payload> {
+    match *item {
+        Payload::V4(prefix) => {
+            if prefix.prefix_len == 0 {
+                return None
+            }
+            let bit = 1u32 << (32 - u32::from(prefix.prefix_len));
+            let addr = u32::from(prefix.prefix);
+            if addr & bit != 0 {
+                return None
+            }
+            Some(Payload::V4(Ipv4Prefix {
+                prefix: Ipv4Addr::from(addr | bit), .. prefix
+            }))
+        }
+        Payload::V6(prefix) => {
+            if prefix.prefix_len == 0 {
+                return None
+            }
+            let bit = 1u128 << (128 - u32::from(prefix.prefix_len));
+            let addr = u128::from(prefix.prefix);
+            if addr & bit != 0 {
+                return None
+            }
+            Some(Payload::V6(Ipv6Prefix {
+                prefix: Ipv6Addr::from(addr | bit), .. prefix
+            }))
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::{v4, v6};
+    
+
+    #[test]
+    fn aggregations() {
+        let mut builder = SetBuilder::empty();
+        // Siblings with the same origin and max-length.
+        builder.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        builder.insert(v4([192, 0, 3, 0], 24, 24, 64496)).unwrap();
+        // Siblings with a different origin.
+        builder.insert(v4([198, 51, 100, 0], 25, 25, 64496)).unwrap();
+        builder.insert(v4([198, 51, 100, 128], 25, 25, 64497)).unwrap();
+        // Siblings with a different max-length.
+        builder.insert(v4([203, 0, 113, 0], 25, 25, 64496)).unwrap();
+        builder.insert(v4([203, 0, 113, 128], 25, 26, 64496)).unwrap();
+        // Adjacent but not siblings.
+        builder.insert(v4([10, 1, 0, 0], 16, 16, 64496)).unwrap();
+        builder.insert(v4([10, 2, 0, 0], 16, 16, 64496)).unwrap();
+        builder.insert(
+            v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 33, 48, 64496)
+        ).unwrap();
+        builder.insert(
+            v6([0x2001, 0xdb8, 0x8000, 0, 0, 0, 0, 0], 33, 48, 64496)
+        ).unwrap();
+        let set = builder.finalize();
+
+        let res = set.aggregations();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].aggregate(), v4([192, 0, 2, 0], 23, 24, 64496));
+        assert_eq!(
+            res[0].to_string(),
+            "192.0.2.0/24-24 AS64496 + 192.0.3.0/24-24 AS64496 \
+             => 192.0.2.0/23-24 AS64496"
+        );
+        assert_eq!(
+            res[1].aggregate(),
+            v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 48, 64496)
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Storing diffs in files.
+//!
+//! A [`DiffCheckpoint`] file starts with the eight octets `RTRTRDC1`,
+//! followed by the serial number the diff leads to as a 32 bit integer in
+//! network byte order. The rest of the file are the changes of the diff in
+//! the same encoding as diff records of the
+//! [event store][super::event_store]: an octet with the value 1 for an
+//! announcement or 0 for a withdrawal followed by the encoded payload item.
+
+use std::{fs, io};
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::Path;
+use rpki_rtr::payload::Action;
+use rpki_rtr::state::Serial;
+use super::{Diff, DiffBuilder};
+use super::digest::{decode, encode};
+
+
+//------------ DiffCheckpoint ------------------------------------------------
+
+/// Storing a diff and its serial number in a file.
+pub struct DiffCheckpoint;
+
+impl DiffCheckpoint {
+    /// The octets the file starts with.
+    const MAGIC: &'static [u8] = b"RTRTRDC1";
+
+    /// Stores a diff in the file at `path`.
+    ///
+    /// The data is first written to a temporary file next to `path` which
+    /// is then renamed, so readers never see a partially written file.
+    pub fn store(
+        diff: &Diff, serial: Serial, path: &Path
+    ) -> Result<(), io::Error> {
+        let mut data = Self::MAGIC.to_vec();
+        data.extend_from_slice(&u32::from(serial).to_be_bytes());
+        for &(item, action) in diff.iter() {
+            data.push(action.into_flags());
+            data.extend_from_slice(&encode(&item));
+        }
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Loads a diff and its serial number from the file at `path`.
+    pub fn load(path: &Path) -> Result<(Diff, Serial), io::Error> {
+        let data = fs::read(path)?;
+        if !data.starts_with(Self::MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "not a diff checkpoint file"
+            ))
+        }
+        let data = &data[Self::MAGIC.len()..];
+        if data.len() < 4 {
+            return Err(corrupt())
+        }
+        let serial = Serial::from(u32::from_be_bytes(
+            <[u8; 4]>::try_from(&data[..4]).map_err(|_| corrupt())?
+        ));
+        let mut data = &data[4..];
+        let mut diff = DiffBuilder::default();
+        while !data.is_empty() {
+            let action = Action::from_flags(data[0]);
+            let (item, rest) = decode(&data[1..]).ok_or_else(corrupt)?;
+            diff.push(item, action).map_err(|_| corrupt())?;
+            data = rest;
+        }
+        Ok((diff.finalize(), serial))
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the error for a corrupt file.
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt diff checkpoint")
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::testing::{v4, v6};
+    use super::*;
+
+    #[test]
+    fn store_and_load() {
+        let mut builder = DiffBuilder::default();
+        builder.push(
+            v4([192, 0, 2, 0], 24, 24, 64496), Action::Announce
+        ).unwrap();
+        builder.push(
+            v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 48, 64497),
+            Action::Withdraw
+        ).unwrap();
+        let diff = builder.finalize();
+
+        let path = std::env::temp_dir().join(format!(
+            "rtrtr-diff-checkpoint-{}", std::process::id()
+        ));
+        DiffCheckpoint::store(&diff, Serial::from(12), &path).unwrap();
+        let (loaded, serial) = DiffCheckpoint::load(&path).unwrap();
+        assert_eq!(serial, Serial::from(12));
+        assert_eq!(loaded, diff);
+
+        // A truncated file is rejected.
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(DiffCheckpoint::load(&path).is_err());
+        fs::write(&path, b"RTRTREV1").unwrap();
+        assert!(DiffCheckpoint::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! Reducing a payload set to its covering items.
+//!
+//! An item is redundant if there is another item for the same origin AS
+//! whose prefix covers the item’s prefix and whose maximum length is at
+//! least the item’s maximum length. The covering set is the set with all
+//! redundant items removed.
+//!
+//! The covering set is equivalent to the original set for route origin
+//! validation as per RFC 6811. Any route matched by a redundant item is
+//! also matched by the item it is redundant to, since that item has the same
+//! origin AS, covers the route, and allows at least the same prefix length.
+//! Any route covered by a redundant item is also covered by that item, so
+//! routes that were invalid stay invalid. Finally, since being redundant is
+//! a strict partial order, every removed item is redundant to some item
+//! that is kept.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use rpki_rtr::payload::Payload;
+use super::{mask_v4, mask_v6, payload_prefix, Set};
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Returns the covering set of the set.
+    ///
+    /// The returned set contains all items of the set that are not covered
+    /// by a less specific item for the same origin AS with at least the
+    /// same maximum length. Trust anchor information is kept.
+    pub fn covering(&self) -> Set {
+        let index = CoveringIndex::new(self);
+        self.filter(|item| !index.is_redundant(item))
+    }
+
+    /// Returns the redundant items of the set.
+    ///
+    /// These are the items left out of the [covering set][Self::covering]:
+    /// items for which there is a less specific item for the same origin AS
+    /// with at least the same maximum length. They don’t add anything for
+    /// route origin validation. The items are returned in order.
+    pub fn find_redundant_vrps(&self) -> Vec<&Payload> {
+        let index = CoveringIndex::new(self);
+        self.iter().filter(|item| index.is_redundant(item)).collect()
+    }
+
+    /// Returns the number of redundant items of the set.
+    ///
+    /// This is the number of items
+    /// [`find_redundant_vrps`][Self::find_redundant_vrps] would return.
+    pub fn count_redundant_vrps(&self) -> usize {
+        let index = CoveringIndex::new(self);
+        self.iter().filter(|item| index.is_redundant(item)).count()
+    }
+}
+
+
+//------------ CoveringIndex -------------------------------------------------
+
+/// An index for finding the items that make an item redundant.
+struct CoveringIndex {
+    /// The largest maximum length by family, prefix, and origin AS.
+    max_lens: HashMap<(bool, u128, u8, u32), u8>,
+
+    /// The IPv4 prefix lengths present in the set.
+    v4_lens: Vec<u8>,
+
+    /// The IPv6 prefix lengths present in the set.
+    v6_lens: Vec<u8>,
+}
+
+impl CoveringIndex {
+    /// Creates the index for a set.
+    fn new(set: &Set) -> Self {
+        let mut res = CoveringIndex {
+            max_lens: HashMap::new(),
+            v4_lens: Vec::new(),
+            v6_lens: Vec::new(),
+        };
+        for item in set.iter() {
+            let (addr, len) = payload_prefix(item);
+            let (asn, max_len) = asn_max_len(item);
+            let entry = res.max_lens.entry(
+                key(addr, len, asn)
+            ).or_insert(max_len);
+            if max_len > *entry {
+                *entry = max_len
+            }
+            let lens = if addr.is_ipv4() {
+                &mut res.v4_lens
+            }
+            else {
+                &mut res.v6_lens
+            };
+            if !lens.contains(&len) {
+                lens.push(len)
+            }
+        }
+        res
+    }
+
+    /// Returns whether an item is redundant.
+    fn is_redundant(&self, item: &Payload) -> bool {
+        let (addr, item_len) = payload_prefix(item);
+        let (asn, item_max_len) = asn_max_len(item);
+        let lens = if addr.is_ipv4() {
+            &self.v4_lens
+        }
+        else {
+            &self.v6_lens
+        };
+        lens.iter().any(|&len| {
+            if len > item_len {
+                return false
+            }
+            match self.max_lens.get(&key(addr, len, asn)) {
+                // An item with the same prefix needs to have a larger
+                // maximum length or it is the item itself.
+                Some(&max_len) if len == item_len => max_len > item_max_len,
+                Some(&max_len) => max_len >= item_max_len,
+                None => false
+            }
+        })
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the index key for the first `len` bits of an address.
+fn key(addr: IpAddr, len: u8, asn: u32) -> (bool, u128, u8, u32) {
+    match addr {
+        IpAddr::V4(addr) => {
+            (false, u128::from(mask_v4(addr, len)), len, asn)
+        }
+        IpAddr::V6(addr) => (true, mask_v6(addr, len), len, asn),
+    }
+}
+
+/// Returns the origin AS and maximum length of an item.
+fn asn_max_len(item: &Payload) -> (u32, u8) {
+    match *item {
+        Payload::V4(ref item) => (item.asn, item.max_len),
+        Payload::V6(ref item) => (item.asn, item.max_len),
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    
+    use crate::payload::{Prefix, SetBuilder};
+    use crate::payload::testing::v4;
+    use super::*;
+
+    #[test]
+    fn covering() {
+        let mut builder = SetBuilder::empty();
+        for item in &[
+            v4([10, 0, 0, 0], 8, 10, 64496),
+            v4([10, 0, 0, 0], 9, 10, 64496), // redundant
+            v4([10, 0, 0, 0], 9, 11, 64496),
+            v4([10, 0, 0, 0], 10, 11, 64496), // redundant
+            v4([10, 64, 0, 0], 10, 12, 64496),
+            v4([10, 64, 0, 0], 10, 10, 64496), // redundant
+            v4([10, 0, 0, 0], 10, 12, 64497),
+            v4([10, 16, 0, 0], 12, 12, 64497), // redundant
+            v4([10, 128, 0, 0], 9, 12, 0),
+            v4([10, 128, 0, 0], 12, 12, 0), // redundant
+        ] {
+            builder.insert(*item).unwrap();
+        }
+        let set = builder.finalize();
+        let covering = set.covering();
+        assert_eq!(covering.len(), 5);
+        let redundant = set.find_redundant_vrps();
+        assert_eq!(redundant.len(), 5);
+        assert_eq!(set.count_redundant_vrps(), 5);
+        assert!(redundant.iter().all(|item| !covering.contains(item)));
+        assert!(covering.find_redundant_vrps().is_empty());
+        assert!(covering.iter().all(|item| {
+            !CoveringIndex::new(&covering).is_redundant(item)
+        }));
+
+        // Compare the outcome of all routes between /8 and /13 within
+        // 10.0.0.0/8 for a few origins.
+        for len in 8..14u8 {
+            for idx in 0..(1u32 << (len - 8)) {
+                let addr = Ipv4Addr::from(
+                    0x0a00_0000 | (idx << (32 - u32::from(len)))
+                );
+                let prefix = Prefix::new(addr.into(), len).unwrap();
+                for &asn in &[0, 64496, 64497, 64498] {
+                    assert_eq!(
+                        set.rov_stats(&[(prefix, asn)]),
+                        covering.rov_stats(&[(prefix, asn)]),
+                        "{} AS{}", prefix, asn
+                    );
+                }
+            }
+        }
+    }
+}
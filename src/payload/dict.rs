@@ -0,0 +1,363 @@
+//! Compact encoding of a sequence of updates via a shared dictionary.
+//!
+//! Consecutive updates tend to touch the same items over and over again:
+//! an item announced in one update is withdrawn in a later one or flaps
+//! between the two. The [`DictEncoder`] therefore keeps a dictionary of the
+//! items it has encoded as part of a diff and refers to them by a 16 bit ID
+//! the next time they appear. The [`DictDecoder`] builds the same
+//! dictionary from the encoded updates, so it has to see all of them in
+//! order.
+//!
+//! An encoded update starts with the update’s serial number as a 32 bit
+//! integer in network byte order and a flags octet. If bit 0 of the flags
+//! is set, the update contains the complete data set. If bit 1 is set, the
+//! dictionary is cleared before the update is processed. Bit 2 is set if
+//! the update is for auditing only.
+//!
+//! The flags octet is followed by the items. Each item starts with an
+//! octet. If its bit 1 is set, the item itself follows in the encoding
+//! used by the [digest tree][super::digest]. Otherwise, the 16 bit ID of
+//! the item follows in network byte order. For a diff, bit 0 is set for an
+//! announcement and cleared for a withdrawal. Items of a diff given
+//! literally are added to the dictionary in order. Items of a complete set
+//! are always given literally and never added since a set is too large
+//! for the dictionary anyway.
+//!
+//! Once the dictionary is full, the next update that would need to add an
+//! item to it clears the dictionary and gives all items of its diff
+//! literally, i.e., it has bit 1 of its flags set but not bit 0. Only if
+//! the diff has more items than fit into the empty dictionary, the update
+//! is encoded as a complete set instead.
+
+use std::fmt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use rpki_rtr::payload::{Action, Payload};
+use rpki_rtr::state::Serial;
+use super::{DiffBuilder, Set, SetBuilder, Update};
+use super::digest::{decode, encode};
+
+
+//------------ Constants -----------------------------------------------------
+
+/// The flag marking an update with the complete set.
+const FLAG_SET: u8 = 0x01;
+
+/// The flag marking an update that clears the dictionary.
+const FLAG_RESET: u8 = 0x02;
+
+/// The flag marking an update for auditing only.
+const FLAG_AUDIT_ONLY: u8 = 0x04;
+
+/// The item flag marking an item given literally.
+const ITEM_LITERAL: u8 = 0x02;
+
+/// The maximum number of entries in the dictionary.
+const MAX_ENTRIES: usize = 1 << 16;
+
+
+//------------ DictEncoder ---------------------------------------------------
+
+/// Encodes a sequence of updates using a shared dictionary.
+///
+/// Each update with a diff is expected to lead from the previously encoded
+/// update. Updates without a diff are encoded with their complete set.
+#[derive(Clone, Debug, Default)]
+pub struct DictEncoder {
+    /// The IDs of the items in the dictionary.
+    ids: HashMap<Payload, u16>,
+}
+
+impl DictEncoder {
+    /// Creates a new encoder with an empty dictionary.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of items in the dictionary.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Returns whether the dictionary is empty.
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Encodes an update.
+    pub fn encode(&mut self, update: &Update) -> Vec<u8> {
+        let mut flags = 0;
+        if update.is_audit_only() {
+            flags |= FLAG_AUDIT_ONLY
+        }
+        let diff = match update.diff() {
+            Some(diff) => diff,
+            None => return Self::encode_set(update, flags)
+        };
+        let new = diff.iter().filter(|(item, _)| {
+            !self.ids.contains_key(item)
+        }).count();
+        if self.ids.len() + new > MAX_ENTRIES {
+            self.ids.clear();
+            if diff.len() > MAX_ENTRIES {
+                return Self::encode_set(update, flags | FLAG_RESET)
+            }
+            flags |= FLAG_RESET;
+        }
+        let mut res = Self::header(update, flags);
+        for &(item, action) in diff.iter() {
+            match self.ids.get(&item) {
+                Some(id) => {
+                    res.push(action.into_flags());
+                    res.extend_from_slice(&id.to_be_bytes());
+                }
+                None => {
+                    res.push(action.into_flags() | ITEM_LITERAL);
+                    res.extend_from_slice(&encode(&item));
+                    let id = self.ids.len() as u16;
+                    self.ids.insert(item, id);
+                }
+            }
+        }
+        res
+    }
+
+    /// Encodes an update with its complete set.
+    fn encode_set(update: &Update, flags: u8) -> Vec<u8> {
+        let mut res = Self::header(update, flags | FLAG_SET);
+        for item in update.set().iter() {
+            res.push(ITEM_LITERAL);
+            res.extend_from_slice(&encode(item));
+        }
+        res
+    }
+
+    /// Returns the header of an encoded update.
+    fn header(update: &Update, flags: u8) -> Vec<u8> {
+        let mut res = Vec::new();
+        res.extend_from_slice(&u32::from(update.serial()).to_be_bytes());
+        res.push(flags);
+        res
+    }
+}
+
+
+//------------ DictDecoder ---------------------------------------------------
+
+/// Decodes a sequence of updates encoded by a [`DictEncoder`].
+#[derive(Clone, Debug, Default)]
+pub struct DictDecoder {
+    /// The items of the dictionary in the order of their IDs.
+    items: Vec<Payload>,
+
+    /// The data set of the last update.
+    current: Option<Arc<Set>>,
+}
+
+impl DictDecoder {
+    /// Creates a new decoder with an empty dictionary.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes an update.
+    ///
+    /// If the update contains a diff, it is applied to the set of the
+    /// previously decoded update and the decoded update contains both. This
+    /// is also true if the update clears the dictionary.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Update, DecodeError> {
+        if data.len() < 5 {
+            return Err(DecodeError)
+        }
+        let serial = Serial::from(u32::from_be_bytes(
+            <[u8; 4]>::try_from(&data[..4]).map_err(|_| DecodeError)?
+        ));
+        let flags = data[4];
+        let mut data = &data[5..];
+        if flags & FLAG_RESET != 0 {
+            self.items.clear();
+        }
+
+        let update = if flags & FLAG_SET != 0 {
+            let mut set = SetBuilder::empty();
+            while !data.is_empty() {
+                let (item, rest) = self.decode_item(data, false)?;
+                set.insert(item).map_err(|_| DecodeError)?;
+                data = rest;
+            }
+            Update::new(serial, Arc::new(set.finalize()), None)
+        }
+        else {
+            let current = self.current.as_ref().ok_or(DecodeError)?.clone();
+            let mut diff = DiffBuilder::default();
+            while !data.is_empty() {
+                let action = Action::from_flags(data[0]);
+                let (item, rest) = self.decode_item(data, true)?;
+                diff.push(item, action).map_err(|_| DecodeError)?;
+                data = rest;
+            }
+            let diff = diff.finalize();
+            Update::new(
+                serial, Arc::new(diff.apply(&current)), Some(Arc::new(diff))
+            )
+        };
+        let update = update.with_audit_only(flags & FLAG_AUDIT_ONLY != 0);
+        self.current = Some(update.set());
+        Ok(update)
+    }
+
+    /// Decodes a single item from the start of `data`.
+    ///
+    /// If `add` is `true`, a literal item is added to the dictionary.
+    /// Returns the item and the remaining data.
+    fn decode_item<'a>(
+        &mut self, data: &'a [u8], add: bool
+    ) -> Result<(Payload, &'a [u8]), DecodeError> {
+        if data[0] & ITEM_LITERAL != 0 {
+            let (item, rest) = decode(&data[1..]).ok_or(DecodeError)?;
+            if add {
+                if self.items.len() == MAX_ENTRIES {
+                    return Err(DecodeError)
+                }
+                self.items.push(item);
+            }
+            Ok((item, rest))
+        }
+        else {
+            if data.len() < 3 {
+                return Err(DecodeError)
+            }
+            let id = u16::from_be_bytes([data[1], data[2]]) as usize;
+            let item = self.items.get(id).ok_or(DecodeError)?;
+            Ok((*item, &data[3..]))
+        }
+    }
+}
+
+
+//------------ DecodeError ---------------------------------------------------
+
+/// An encoded update could not be decoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("corrupt dictionary-encoded update")
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::testing::v4;
+    use super::*;
+
+    fn set(items: impl Iterator<Item = u32>) -> Arc<Set> {
+        let mut set = SetBuilder::empty();
+        for n in items {
+            set.insert(v4(n << 8, 24, 24, 64496)).unwrap();
+        }
+        Arc::new(set.finalize())
+    }
+
+    fn update(serial: u32, old: &Arc<Set>, new: &Arc<Set>) -> Update {
+        Update::new(
+            Serial::from(serial), new.clone(),
+            Some(Arc::new(new.diff_from(old)))
+        )
+    }
+
+    fn check(decoder: &mut DictDecoder, data: &[u8], expected: &Update) {
+        let decoded = decoder.decode(data).unwrap();
+        assert_eq!(decoded.serial(), expected.serial());
+        assert!(decoded.set().iter().eq(expected.set().iter()));
+        assert_eq!(decoded.is_audit_only(), expected.is_audit_only());
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut encoder = DictEncoder::new();
+        let mut decoder = DictDecoder::new();
+
+        // The first update is a complete set.
+        let one = set(0..10);
+        let first = Update::new(Serial::from(1), one.clone(), None);
+        let data = encoder.encode(&first);
+        assert_eq!(data[4], FLAG_SET);
+        check(&mut decoder, &data, &first);
+        assert!(encoder.is_empty());
+
+        // Items appear literally the first time they are in a diff ...
+        let two = set(1..11);
+        let second = update(2, &one, &two).with_audit_only(true);
+        let data = encoder.encode(&second);
+        assert_eq!(data.len(), 5 + 2 * 12);
+        check(&mut decoder, &data, &second);
+        assert_eq!(encoder.len(), 2);
+
+        // ... and by ID afterwards.
+        let third = update(3, &two, &one);
+        let data = encoder.encode(&third);
+        assert_eq!(data.len(), 5 + 2 * 3);
+        check(&mut decoder, &data, &third);
+
+        // A diff needs the previous set.
+        assert_eq!(DictDecoder::new().decode(&data).err(), Some(DecodeError));
+        assert_eq!(decoder.decode(&data[..6]).err(), Some(DecodeError));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut encoder = DictEncoder::new();
+        let mut decoder = DictDecoder::new();
+        let empty = set(0..0);
+        let data = encoder.encode(&Update::new(
+            Serial::from(1), empty.clone(), None
+        ));
+        decoder.decode(&data).unwrap();
+
+        // Fill the dictionary completely.
+        let full = set(0..MAX_ENTRIES as u32);
+        let update_full = update(2, &empty, &full);
+        let data = encoder.encode(&update_full);
+        assert_eq!(data[4], 0);
+        check(&mut decoder, &data, &update_full);
+        assert_eq!(encoder.len(), MAX_ENTRIES);
+
+        // Known items still work.
+        let fewer = set(1..MAX_ENTRIES as u32);
+        let update_fewer = update(3, &full, &fewer);
+        let data = encoder.encode(&update_fewer);
+        assert_eq!(data.len(), 5 + 3);
+        check(&mut decoder, &data, &update_fewer);
+
+        // One more item resets the dictionary but keeps the diff.
+        let more = set(1..MAX_ENTRIES as u32 + 1);
+        let update_more = update(4, &fewer, &more);
+        let data = encoder.encode(&update_more);
+        assert_eq!(data[4], FLAG_RESET);
+        assert_eq!(data.len(), 5 + 12);
+        check(&mut decoder, &data, &update_more);
+        assert_eq!(encoder.len(), 1);
+
+        // And we start over.
+        let last = update(5, &more, &fewer);
+        let data = encoder.encode(&last);
+        assert_eq!(data.len(), 5 + 3);
+        check(&mut decoder, &data, &last);
+
+        // A diff too large for the dictionary needs the complete set.
+        let other = set(MAX_ENTRIES as u32..2 * MAX_ENTRIES as u32);
+        let update_other = update(6, &fewer, &other);
+        let data = encoder.encode(&update_other);
+        assert_eq!(data[4], FLAG_SET | FLAG_RESET);
+        check(&mut decoder, &data, &update_other);
+        assert!(encoder.is_empty());
+    }
+}
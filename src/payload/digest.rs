@@ -0,0 +1,397 @@
+//! A digest tree for comparing payload sets.
+//!
+//! Two instances holding what should be the same data can compare the
+//! root digest of their [`DigestTree`]s to confirm that it is. If the root
+//! digests differ, they can walk down the tree comparing the digests of
+//! the child nodes and only need to exchange the items of the few buckets
+//! that actually differ.
+//!
+//! The tree is a complete binary tree of depth [`DigestTree::DEPTH`]. Each
+//! item is assigned to one of its leaves, the buckets, by the first bits of
+//! the SHA-256 digest of its canonical encoding. This way, adding or
+//! removing an item only changes the digests on the path to its bucket.
+//!
+//! The construction is fully determined by the following rules and must
+//! never change, as otherwise instances running different versions would
+//! disagree:
+//!
+//! * An IPv4 item is encoded as the octet 4, followed by the four octets of
+//!   the address, the prefix length, the maximum length, and the AS number
+//!   as four octets in network byte order. An IPv6 item is encoded in the
+//!   same way but starts with the octet 6 and has sixteen octets of
+//!   address.
+//! * The items in a bucket are ordered by their encoding, compared octet
+//!   by octet.
+//! * The digest of a bucket is the SHA-256 digest of the octet 0 followed
+//!   by the encodings of its items in order.
+//! * The digest of an inner node is the SHA-256 digest of the octet 1
+//!   followed by the digests of its left and right child.
+//!
+//! Nodes are identified by their path from the root, given as a string of
+//! `0` for the left and `1` for the right child. The empty path is the
+//! root.
+
+use std::fmt;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use ring::digest::{Context, SHA256, SHA256_OUTPUT_LEN};
+use rpki_rtr::payload::{Ipv4Prefix, Ipv6Prefix, Payload};
+use super::Set;
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Returns the digest tree for the set.
+    pub fn digest_tree(&self) -> DigestTree {
+        DigestTree::new(self)
+    }
+}
+
+
+//------------ DigestTree ----------------------------------------------------
+
+/// The digest tree of a payload set.
+#[derive(Clone, Debug)]
+pub struct DigestTree {
+    /// The digests of all nodes.
+    ///
+    /// The nodes are stored in heap order: the root is at index 1 and the
+    /// children of the node at index `i` are at `2 * i` and `2 * i + 1`.
+    /// The buckets thus start at index `1 << DEPTH`. Index 0 is unused.
+    nodes: Vec<Digest>,
+
+    /// The items in bucket order and ordered by encoding within a bucket.
+    items: Vec<Payload>,
+
+    /// The index into `items` where each bucket starts.
+    ///
+    /// This has one more element than there are buckets, the last being
+    /// the length of `items`.
+    starts: Vec<usize>,
+}
+
+impl DigestTree {
+    /// The depth of the tree.
+    pub const DEPTH: usize = 12;
+
+    /// The number of buckets.
+    const BUCKETS: usize = 1 << Self::DEPTH;
+
+    /// Creates the tree for a set.
+    fn new(set: &Set) -> Self {
+        let mut items: Vec<_> = set.iter().map(|item| {
+            let encoded = encode(item);
+            (bucket(&encoded), encoded, *item)
+        }).collect();
+        items.sort_unstable_by(|left, right| {
+            (left.0, &left.1).cmp(&(right.0, &right.1))
+        });
+
+        let mut nodes = vec![Digest::default(); 2 * Self::BUCKETS];
+        let mut starts = Vec::with_capacity(Self::BUCKETS + 1);
+        let mut pos = 0;
+        for idx in 0..Self::BUCKETS {
+            starts.push(pos);
+            let mut context = Context::new(&SHA256);
+            context.update(&[0]);
+            while pos < items.len() && items[pos].0 == idx {
+                context.update(&items[pos].1);
+                pos += 1;
+            }
+            nodes[Self::BUCKETS + idx] = Digest::from_context(context);
+        }
+        starts.push(pos);
+        for idx in (1..Self::BUCKETS).rev() {
+            let mut context = Context::new(&SHA256);
+            context.update(&[1]);
+            context.update(&nodes[2 * idx].0);
+            context.update(&nodes[2 * idx + 1].0);
+            nodes[idx] = Digest::from_context(context);
+        }
+
+        DigestTree {
+            nodes,
+            items: items.into_iter().map(|item| item.2).collect(),
+            starts,
+        }
+    }
+
+    /// Returns the digest of the root node.
+    pub fn root(&self) -> Digest {
+        self.nodes[1]
+    }
+
+    /// Returns the node with the given path.
+    ///
+    /// Returns `None` if the path contains characters other than `0` and
+    /// `1` or is longer than the depth of the tree.
+    pub fn node(&self, path: &str) -> Option<Node<'_>> {
+        if path.len() > Self::DEPTH {
+            return None
+        }
+        let mut idx = 1;
+        for ch in path.chars() {
+            idx = match ch {
+                '0' => 2 * idx,
+                '1' => 2 * idx + 1,
+                _ => return None
+            };
+        }
+        Some(Node { tree: self, idx })
+    }
+}
+
+
+//------------ Node ----------------------------------------------------------
+
+/// A node of a digest tree.
+#[derive(Clone, Copy, Debug)]
+pub struct Node<'a> {
+    /// The tree the node is part of.
+    tree: &'a DigestTree,
+
+    /// The index of the node in the tree’s nodes.
+    idx: usize,
+}
+
+impl<'a> Node<'a> {
+    /// Returns the digest of the node.
+    pub fn digest(self) -> Digest {
+        self.tree.nodes[self.idx]
+    }
+
+    /// Returns the digests of the left and right child.
+    ///
+    /// Returns `None` if the node is a bucket.
+    pub fn children(self) -> Option<(Digest, Digest)> {
+        if self.idx >= DigestTree::BUCKETS {
+            None
+        }
+        else {
+            Some((
+                self.tree.nodes[2 * self.idx],
+                self.tree.nodes[2 * self.idx + 1]
+            ))
+        }
+    }
+
+    /// Returns the items of the node if it is a bucket.
+    pub fn items(self) -> Option<&'a [Payload]> {
+        if self.idx < DigestTree::BUCKETS {
+            return None
+        }
+        let bucket = self.idx - DigestTree::BUCKETS;
+        Some(
+            &self.tree.items[
+                self.tree.starts[bucket]..self.tree.starts[bucket + 1]
+            ]
+        )
+    }
+}
+
+
+//------------ Digest --------------------------------------------------------
+
+/// The SHA-256 digest of a node.
+#[derive(Clone, Copy, Default, Eq, PartialEq)]
+pub struct Digest([u8; SHA256_OUTPUT_LEN]);
+
+impl Digest {
+    /// Finishes a digest context.
+    fn from_context(context: Context) -> Self {
+        let mut res = Digest::default();
+        res.0.copy_from_slice(context.finish().as_ref());
+        res
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for octet in &self.0 {
+            write!(f, "{:02x}", octet)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the canonical encoding of an item.
+pub(super) fn encode(item: &Payload) -> Vec<u8> {
+    let mut res = Vec::with_capacity(23);
+    match *item {
+        Payload::V4(ref prefix) => {
+            res.push(4);
+            res.extend_from_slice(&prefix.prefix.octets());
+            res.push(prefix.prefix_len);
+            res.push(prefix.max_len);
+            res.extend_from_slice(&prefix.asn.to_be_bytes());
+        }
+        Payload::V6(ref prefix) => {
+            res.push(6);
+            res.extend_from_slice(&prefix.prefix.octets());
+            res.push(prefix.prefix_len);
+            res.push(prefix.max_len);
+            res.extend_from_slice(&prefix.asn.to_be_bytes());
+        }
+    }
+    res
+}
+
+/// Decodes an item from the start of `data`.
+///
+/// Returns the item and the remaining data or `None` if `data` doesn’t
+/// start with a valid encoding.
+pub(super) fn decode(data: &[u8]) -> Option<(Payload, &[u8])> {
+    match data.first()? {
+        4 if data.len() >= 11 => {
+            let addr = <[u8; 4]>::try_from(&data[1..5]).ok()?;
+            let asn = <[u8; 4]>::try_from(&data[7..11]).ok()?;
+            Some((
+                Payload::V4(Ipv4Prefix {
+                    prefix: Ipv4Addr::from(addr),
+                    prefix_len: data[5], max_len: data[6],
+                    asn: u32::from_be_bytes(asn),
+                }),
+                &data[11..]
+            ))
+        }
+        6 if data.len() >= 23 => {
+            let addr = <[u8; 16]>::try_from(&data[1..17]).ok()?;
+            let asn = <[u8; 4]>::try_from(&data[19..23]).ok()?;
+            Some((
+                Payload::V6(Ipv6Prefix {
+                    prefix: Ipv6Addr::from(addr),
+                    prefix_len: data[17], max_len: data[18],
+                    asn: u32::from_be_bytes(asn),
+                }),
+                &data[23..]
+            ))
+        }
+        _ => None
+    }
+}
+
+/// Returns the bucket for an encoded item.
+///
+/// This is the value of the first `DEPTH` bits of the encoding’s digest.
+fn bucket(encoded: &[u8]) -> usize {
+    let digest = ring::digest::digest(&SHA256, encoded);
+    let digest = digest.as_ref();
+    let value = (usize::from(digest[0]) << 8) | usize::from(digest[1]);
+    value >> (16 - DigestTree::DEPTH)
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::{v4, v6};
+    use super::*;
+
+    fn set(items: &[Payload]) -> Set {
+        let mut builder = SetBuilder::empty();
+        for item in items {
+            builder.insert(*item).unwrap();
+        }
+        builder.finalize()
+    }
+
+    /// Appends the paths of all differing buckets below `path` to `res`.
+    fn diverging(
+        left: &DigestTree, right: &DigestTree, path: String,
+        res: &mut Vec<String>
+    ) {
+        let (left_node, right_node) = (
+            left.node(&path).unwrap(), right.node(&path).unwrap()
+        );
+        if left_node.digest() == right_node.digest() {
+            return
+        }
+        if left_node.children().is_none() {
+            res.push(path);
+            return
+        }
+        diverging(left, right, format!("{}0", path), res);
+        diverging(left, right, format!("{}1", path), res);
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(
+            encode(&v4([192, 0, 2, 0], 24, 24, 64496)),
+            b"\x04\xc0\x00\x02\x00\x18\x18\x00\x00\xfb\xf0"
+        );
+        let v6 = v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 48, 64496);
+        let encoded = encode(&v6);
+        assert_eq!(encoded.len(), 23);
+        assert_eq!(&encoded[..5], b"\x06\x20\x01\x0d\xb8");
+        assert_eq!(&encoded[17..], b"\x20\x30\x00\x00\xfb\xf0");
+
+        let mut data = encode(&v4([192, 0, 2, 0], 24, 24, 64496));
+        data.extend_from_slice(&encoded);
+        let (item, data) = decode(&data).unwrap();
+        assert_eq!(item, v4([192, 0, 2, 0], 24, 24, 64496));
+        assert_eq!(decode(data), Some((v6, &b""[..])));
+        assert_eq!(decode(&encoded[..22]), None);
+    }
+
+    #[test]
+    fn empty_tree() {
+        let tree = set(&[]).digest_tree();
+        let empty_bucket = ring::digest::digest(&SHA256, &[0]);
+        assert_eq!(
+            tree.node("000000000000").unwrap().digest().as_ref(),
+            empty_bucket.as_ref()
+        );
+        assert!(tree.node("0000000000000").is_none());
+        assert!(tree.node("2").is_none());
+    }
+
+    #[test]
+    fn divergence() {
+        let items: Vec<_> = (0..200u8).map(|idx| {
+            v4([10, 0, idx, 0], 24, 24, 64496)
+        }).collect();
+        let mut reversed = items.clone();
+        reversed.reverse();
+        let tree = set(&items).digest_tree();
+        assert_eq!(tree.root(), set(&reversed).digest_tree().root());
+
+        // Changing one item changes its old and new bucket only.
+        let mut changed = items.clone();
+        changed[17] = v4([10, 0, 17, 0], 24, 24, 64497);
+        let other = set(&changed).digest_tree();
+        let mut paths = Vec::new();
+        diverging(&tree, &other, String::new(), &mut paths);
+        assert!(!paths.is_empty() && paths.len() <= 2);
+        assert!(paths.iter().any(|path| {
+            tree.node(path).unwrap().items().unwrap().contains(&items[17])
+        }));
+        assert!(paths.iter().any(|path| {
+            other.node(path).unwrap().items().unwrap().contains(&changed[17])
+        }));
+        let total: usize = (0..DigestTree::BUCKETS).map(|idx| {
+            let path = format!("{:012b}", idx);
+            tree.node(&path).unwrap().items().unwrap().len()
+        }).sum();
+        assert_eq!(total, items.len());
+    }
+}
@@ -0,0 +1,481 @@
+//! An append-only store of payload updates.
+//!
+//! The [`EventStore`] keeps every update it is given in a single file, so
+//! that the history of a data set can later be replayed from that file
+//! alone. Anything derived from the history can be rebuilt this way rather
+//! than keeping its own copy of the data.
+//!
+//! The file starts with the eight octets `RTRTREV1`. It is followed by the
+//! records, one per update. Each record starts with its length as a 32 bit
+//! integer in network byte order, not including the length itself. The
+//! record consists of the update’s serial number as a 32 bit integer in
+//! network byte order, a flags octet, and the items. If bit 0 of the flags
+//! is set, the record contains the complete data set and the items are the
+//! payload items in the encoding used by the [digest tree][super::digest].
+//! Otherwise the record contains the diff to the previous record and each
+//! item is an octet with the value 1 for an announcement or 0 for a
+//! withdrawal followed by the encoded payload item. Bit 1 of the flags is
+//! set if the update is for auditing only. If bit 2 is set, the items are
+//! compressed with zstd. This is done for all records with the complete
+//! set, using level 3 as chosen by the `compression_bench` benchmark.
+
+use std::{fs, io};
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use async_stream::stream;
+use futures::Stream;
+use log::warn;
+use rpki_rtr::payload::Action;
+use rpki_rtr::state::Serial;
+use tokio::task::spawn_blocking;
+use super::{DiffBuilder, Set, SetBuilder, Update};
+use super::digest::{decode, encode};
+
+
+//------------ EventStore ----------------------------------------------------
+
+/// An append-only file of payload updates.
+///
+/// The file is opened in append mode and locked exclusively for as long as
+/// the store exists, so only one store can write to a file at a time.
+#[derive(Debug)]
+pub struct EventStore {
+    /// The path of the file.
+    path: PathBuf,
+
+    /// The file opened for appending.
+    file: fs::File,
+
+    /// Whether the next update needs to be stored with the complete set.
+    ///
+    /// We don’t know whether the diff of the first update we get after
+    /// opening refers to the last update in the file, so it is always
+    /// stored as a complete set.
+    needs_set: bool,
+}
+
+impl EventStore {
+    /// The octets the file starts with.
+    const MAGIC: &'static [u8] = b"RTRTREV1";
+
+    /// The flag marking a record with the complete set.
+    const FLAG_SET: u8 = 0x01;
+
+    /// The flag marking an update for auditing only.
+    const FLAG_AUDIT_ONLY: u8 = 0x02;
+
+    /// The flag marking a record with zstd-compressed items.
+    const FLAG_ZSTD: u8 = 0x04;
+
+    /// The zstd level for compressing complete sets.
+    const ZSTD_LEVEL: i32 = 3;
+
+    /// Opens the store at the given path, creating it if necessary.
+    ///
+    /// If the file ends in a partially written record, for instance after
+    /// a crash, that record is removed.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, io::Error> {
+        let path = path.into();
+        let mut file = fs::OpenOptions::new()
+            .read(true).append(true).create(true)
+            .open(&path)?;
+        lock(&file)?;
+        let len = file.metadata()?.len();
+        if len == 0 {
+            file.write_all(Self::MAGIC)?;
+        }
+        else {
+            let mut magic = [0u8; 8];
+            file.seek(SeekFrom::Start(0))?;
+            file.read_exact(&mut magic)?;
+            if magic != Self::MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData, "not an event store file"
+                ))
+            }
+            let end = Self::complete_len(&mut file, len)?;
+            if end < len {
+                warn!(
+                    "Removing incomplete last record from event store {}.",
+                    path.display()
+                );
+                file.set_len(end)?;
+            }
+        }
+        Ok(EventStore { path, file, needs_set: true })
+    }
+
+    /// Returns the length of the file up to the last complete record.
+    ///
+    /// Expects the file to be positioned right after the magic.
+    fn complete_len(file: &mut fs::File, len: u64) -> Result<u64, io::Error> {
+        let mut pos = Self::MAGIC.len() as u64;
+        loop {
+            let mut octets = [0u8; 4];
+            if pos + 4 > len {
+                return Ok(pos)
+            }
+            file.read_exact(&mut octets)?;
+            let next = pos + 4 + u64::from(u32::from_be_bytes(octets));
+            if next > len {
+                return Ok(pos)
+            }
+            file.seek(SeekFrom::Start(next))?;
+            pos = next;
+        }
+    }
+
+    /// Returns the path of the store’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends an update to the store.
+    ///
+    /// The update is stored as a diff if it has one, otherwise with its
+    /// complete set which is compressed. The record is written with a
+    /// single write and synced to disk before returning.
+    pub fn append(&mut self, update: &Update) -> Result<(), io::Error> {
+        let mut record = vec![0u8; 4];
+        record.extend_from_slice(
+            &u32::from(update.serial()).to_be_bytes()
+        );
+        let mut flags = 0;
+        if update.is_audit_only() {
+            flags |= Self::FLAG_AUDIT_ONLY
+        }
+        match update.diff() {
+            Some(diff) if !self.needs_set => {
+                record.push(flags);
+                for &(item, action) in diff.iter() {
+                    record.push(action.into_flags());
+                    record.extend_from_slice(&encode(&item));
+                }
+            }
+            _ => {
+                record.push(flags | Self::FLAG_SET | Self::FLAG_ZSTD);
+                let mut items = Vec::new();
+                for item in update.set().iter() {
+                    items.extend_from_slice(&encode(item));
+                }
+                record.extend_from_slice(
+                    &zstd::encode_all(items.as_slice(), Self::ZSTD_LEVEL)?
+                );
+            }
+        }
+        let len = u32::try_from(record.len() - 4).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "update too large")
+        })?;
+        record[..4].copy_from_slice(&len.to_be_bytes());
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.needs_set = false;
+        Ok(())
+    }
+
+    /// Returns whether the data starts like an event store file.
+    pub fn is_event_store(data: &[u8]) -> bool {
+        data.starts_with(Self::MAGIC)
+    }
+
+    /// Reads the last update in the store file at `path`.
+    ///
+    /// The update contains the complete data set at that point. Returns
+    /// `Ok(None)` if there are no updates in the file. Unlike
+    /// [`open`](Self::open), this doesn’t lock or otherwise modify the
+    /// file.
+    pub fn read_last(path: &Path) -> Result<Option<Update>, io::Error> {
+        let mut reader = Reader::open(path)?;
+        let mut last = None;
+        while let Some(update) = reader.next_update()? {
+            last = Some(update)
+        }
+        Ok(last)
+    }
+
+    /// Replays the updates in the store.
+    ///
+    /// If `from_serial` is given, the updates before the first update with
+    /// this serial number or a later one are skipped. The stream ends at
+    /// the end of the file as it was when reaching it. If the file is
+    /// damaged, a warning is logged and the stream ends early.
+    pub fn replay(
+        &self, from_serial: Option<Serial>
+    ) -> impl Stream<Item = Update> {
+        let path = self.path.clone();
+        stream! {
+            let open_path = path.clone();
+            let opened = spawn_blocking(move || {
+                Reader::open(&open_path)
+            }).await;
+            let mut reader = match opened {
+                Ok(Ok(reader)) => reader,
+                Ok(Err(err)) => {
+                    warn!(
+                        "Failed to open event store {}: {}",
+                        path.display(), err
+                    );
+                    return
+                }
+                Err(_) => return
+            };
+            let mut started = from_serial.is_none();
+            loop {
+                let res = spawn_blocking(move || {
+                    let res = reader.next_update();
+                    (reader, res)
+                }).await;
+                let update = match res {
+                    Ok((next_reader, Ok(Some(update)))) => {
+                        reader = next_reader;
+                        update
+                    }
+                    Ok((_, Ok(None))) => return,
+                    Ok((_, Err(err))) => {
+                        warn!(
+                            "Failed to read event store {}: {}",
+                            path.display(), err
+                        );
+                        return
+                    }
+                    Err(_) => return
+                };
+                if let Some(from_serial) = from_serial {
+                    started = started || update.serial() >= from_serial;
+                }
+                if started {
+                    yield update
+                }
+            }
+        }
+    }
+}
+
+
+//------------ Reader --------------------------------------------------------
+
+/// Reading the updates from an event store file.
+struct Reader {
+    /// The file to read from.
+    file: io::BufReader<fs::File>,
+
+    /// The data set after the last update read.
+    current: Option<Arc<Set>>,
+}
+
+impl Reader {
+    /// Opens the file at `path` and checks its magic.
+    fn open(path: &Path) -> Result<Self, io::Error> {
+        let mut file = io::BufReader::new(fs::File::open(path)?);
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        if magic != EventStore::MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "not an event store file"
+            ))
+        }
+        Ok(Reader { file, current: None })
+    }
+
+    /// Reads the next update.
+    ///
+    /// Returns `Ok(None)` at the end of the file. An incomplete last
+    /// record is treated as the end of the file, too, since it may still
+    /// be in the process of being written.
+    fn next_update(&mut self) -> Result<Option<Update>, io::Error> {
+        let mut octets = [0u8; 4];
+        if !read_all_or_none(&mut self.file, &mut octets)? {
+            return Ok(None)
+        }
+        let mut record = vec![0u8; u32::from_be_bytes(octets) as usize];
+        if !read_all_or_none(&mut self.file, &mut record)? {
+            return Ok(None)
+        }
+        if record.len() < 5 {
+            return Err(corrupt())
+        }
+        let serial = Serial::from(u32::from_be_bytes(
+            <[u8; 4]>::try_from(&record[..4]).map_err(|_| corrupt())?
+        ));
+        let flags = record[4];
+        let decompressed;
+        let mut data = if flags & EventStore::FLAG_ZSTD != 0 {
+            decompressed = zstd::decode_all(&record[5..]).map_err(|_| {
+                corrupt()
+            })?;
+            decompressed.as_slice()
+        }
+        else {
+            &record[5..]
+        };
+
+        let update = if flags & EventStore::FLAG_SET != 0 {
+            let mut set = SetBuilder::empty();
+            while !data.is_empty() {
+                let (item, rest) = decode(data).ok_or_else(corrupt)?;
+                set.insert(item).map_err(|_| corrupt())?;
+                data = rest;
+            }
+            Update::new(serial, Arc::new(set.finalize()), None)
+        }
+        else {
+            let current = self.current.as_ref().ok_or_else(corrupt)?;
+            let mut diff = DiffBuilder::default();
+            while !data.is_empty() {
+                let action = Action::from_flags(data[0]);
+                let (item, rest) = decode(&data[1..]).ok_or_else(corrupt)?;
+                diff.push(item, action).map_err(|_| corrupt())?;
+                data = rest;
+            }
+            let diff = diff.finalize();
+            Update::new(
+                serial, Arc::new(diff.apply(current)), Some(Arc::new(diff))
+            )
+        };
+        let update = update.with_audit_only(
+            flags & EventStore::FLAG_AUDIT_ONLY != 0
+        );
+        self.current = Some(update.set());
+        Ok(Some(update))
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Locks the file exclusively without waiting.
+#[cfg(unix)]
+fn lock(file: &fs::File) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let res = unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+    };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    }
+    else {
+        Ok(())
+    }
+}
+
+/// Locks the file exclusively without waiting.
+///
+/// File locking is only supported on Unix systems.
+#[cfg(not(unix))]
+fn lock(_file: &fs::File) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Fills `buf` completely.
+///
+/// Returns `Ok(false)` if the end of the file is reached before.
+fn read_all_or_none(
+    reader: &mut impl Read, buf: &mut [u8]
+) -> Result<bool, io::Error> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+/// Returns the error for a corrupt record.
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt record")
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    use futures::StreamExt;
+    use rpki_rtr::payload::Payload;
+    use crate::payload::Diff;
+    use crate::payload::testing::v4;
+    use super::*;
+
+    fn set(items: &[Payload]) -> Arc<Set> {
+        let mut builder = SetBuilder::empty();
+        for item in items {
+            builder.insert(*item).unwrap();
+        }
+        Arc::new(builder.finalize())
+    }
+
+    fn update(serial: u32, old: &Arc<Set>, new: &Arc<Set>) -> Update {
+        Update::new(
+            Serial::from(serial), new.clone(),
+            Some(Arc::new(Diff::reconcile(old, new)))
+        )
+    }
+
+    #[tokio::test]
+    async fn append_replay() {
+        let path = std::env::temp_dir().join(format!(
+            "rtrtr-event-store-{}", std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let first = set(&[v4([192, 0, 2, 0], 24, 24, 64496)]);
+        let second = set(&[
+            v4([192, 0, 2, 0], 24, 24, 64496),
+            v4([198, 51, 100, 0], 24, 24, 64497)
+        ]);
+        let third = set(&[v4([198, 51, 100, 0], 24, 24, 64497)]);
+
+        {
+            let mut store = EventStore::open(&path).unwrap();
+            #[cfg(unix)]
+            assert!(EventStore::open(&path).is_err());
+            // This one is stored as a set despite the diff.
+            store.append(&update(1, &Default::default(), &first)).unwrap();
+            store.append(&update(2, &first, &second)).unwrap();
+        }
+        // Complete sets are compressed, the first record’s flags follow
+        // the magic, the record length, and the serial.
+        assert_ne!(
+            fs::read(&path).unwrap()[16] & EventStore::FLAG_ZSTD, 0
+        );
+        {
+            let mut store = EventStore::open(&path).unwrap();
+            store.append(
+                &update(3, &second, &third).with_audit_only(true)
+            ).unwrap();
+            store.append(&update(4, &third, &third)).unwrap();
+        }
+
+        // Simulate a partially written record.
+        fs::OpenOptions::new().append(true).open(&path).unwrap()
+            .write_all(b"\x00\x00\x01\x00\x00").unwrap();
+        let store = EventStore::open(&path).unwrap();
+
+        let updates: Vec<_> = store.replay(None).collect().await;
+        assert_eq!(updates.len(), 4);
+        assert!(updates[0].diff().is_none());
+        assert_eq!(updates[1].set().iter().collect::<Vec<_>>(),
+                   second.iter().collect::<Vec<_>>());
+        assert_eq!(updates[1].diff().unwrap().len(), 1);
+        assert_eq!(updates[2].set().iter().collect::<Vec<_>>(),
+                   third.iter().collect::<Vec<_>>());
+        assert!(updates[2].is_audit_only());
+        assert!(updates[3].diff().unwrap().is_empty());
+
+        let updates: Vec<_> = store.replay(
+            Some(Serial::from(3))
+        ).collect().await;
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].serial(), Serial::from(3));
+        assert_eq!(updates[0].set().len(), 1);
+
+        let last = EventStore::read_last(&path).unwrap().unwrap();
+        assert_eq!(last.serial(), Serial::from(4));
+        assert_eq!(last.set().len(), 1);
+
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+}
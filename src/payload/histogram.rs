@@ -0,0 +1,92 @@
+//! The distribution of prefix lengths in a payload set.
+
+use std::collections::HashMap;
+use rpki_rtr::payload::Payload;
+use super::Set;
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Returns the number of items by prefix length.
+    ///
+    /// The items are counted separately for each address family.
+    pub fn prefix_len_histogram(&self) -> PrefixLenHistogram {
+        let mut res = PrefixLenHistogram::default();
+        for item in self.iter() {
+            match *item {
+                Payload::V4(ref prefix) => {
+                    *res.v4.entry(prefix.prefix_len).or_default() += 1
+                }
+                Payload::V6(ref prefix) => {
+                    *res.v6.entry(prefix.prefix_len).or_default() += 1
+                }
+            }
+        }
+        res
+    }
+}
+
+
+//------------ PrefixLenHistogram --------------------------------------------
+
+/// The number of items of a set by prefix length.
+///
+/// Only prefix lengths that actually appear in the set are present.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PrefixLenHistogram {
+    /// The number of IPv4 items by prefix length.
+    pub v4: HashMap<u8, usize>,
+
+    /// The number of IPv6 items by prefix length.
+    pub v6: HashMap<u8, usize>,
+}
+
+impl PrefixLenHistogram {
+    /// Returns the IPv4 counts ordered by prefix length.
+    pub fn v4_sorted(&self) -> Vec<(u8, usize)> {
+        Self::sorted(&self.v4)
+    }
+
+    /// Returns the IPv6 counts ordered by prefix length.
+    pub fn v6_sorted(&self) -> Vec<(u8, usize)> {
+        Self::sorted(&self.v6)
+    }
+
+    fn sorted(counts: &HashMap<u8, usize>) -> Vec<(u8, usize)> {
+        let mut res: Vec<_> = counts.iter().map(|(&len, &count)| {
+            (len, count)
+        }).collect();
+        res.sort_unstable();
+        res
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::{v4, v6};
+    
+
+    #[test]
+    fn prefix_len_histogram() {
+        let mut builder = SetBuilder::empty();
+        for &(addr, len) in &[
+            ([192, 0, 2, 0], 24), ([198, 51, 100, 0], 24),
+            ([198, 51, 100, 0], 22), ([10, 0, 0, 0], 8)
+        ] {
+            builder.insert(v4(addr, len, 24, 64496)).unwrap();
+        }
+        builder.insert(
+            v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 24, 48, 64496)
+        ).unwrap();
+        let histogram = builder.finalize().prefix_len_histogram();
+        assert_eq!(histogram.v4_sorted(), [(8, 1), (22, 1), (24, 2)]);
+        assert_eq!(histogram.v6_sorted(), [(24, 1)]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Statistics about overlapping items in a payload set.
+//!
+//! Two items overlap if the prefix of one of them covers the prefix of the
+//! other, including the case of both having the same prefix. A prefix is
+//! announced by multiple origins – a MOAS prefix – if there are items for
+//! it with different origin AS numbers.
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use rpki_rtr::payload::Payload;
+use super::{mask_v4, mask_v6, payload_prefix, Set};
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Returns statistics about overlapping items in the set.
+    ///
+    /// This looks at every distinct prefix in the set for every prefix
+    /// length present in the set and is therefore somewhat expensive.
+    pub fn overlap_stats(&self) -> OverlapStats {
+        let mut prefixes = HashMap::<PrefixKey, PrefixInfo>::new();
+        let mut v4_lens = HashSet::new();
+        let mut v6_lens = HashSet::new();
+        let mut res = OverlapStats::default();
+        for item in self.iter() {
+            let (addr, len) = payload_prefix(item);
+            let (asn, max_len) = match *item {
+                Payload::V4(ref item) => (item.asn, item.max_len),
+                Payload::V6(ref item) => (item.asn, item.max_len),
+            };
+            let excess = max_len.saturating_sub(len);
+            if addr.is_ipv4() {
+                v4_lens.insert(len);
+                *res.v4_max_len_excess.entry(excess).or_default() += 1;
+            }
+            else {
+                v6_lens.insert(len);
+                *res.v6_max_len_excess.entry(excess).or_default() += 1;
+            }
+            let info = prefixes.entry(key(addr, len)).or_insert(
+                PrefixInfo { count: 0, asn, moas: false }
+            );
+            info.count += 1;
+            if info.asn != asn {
+                info.moas = true
+            }
+        }
+
+        // Collect all prefixes covering or covered by another prefix.
+        let mut overlapping = HashSet::new();
+        for (&prefix, info) in &prefixes {
+            if info.count > 1 {
+                overlapping.insert(prefix);
+            }
+            let lens = if prefix.0 { &v6_lens } else { &v4_lens };
+            for &shorter in lens {
+                if shorter >= prefix.2 {
+                    continue
+                }
+                let covering = shorten(prefix, shorter);
+                if prefixes.contains_key(&covering) {
+                    overlapping.insert(covering);
+                    overlapping.insert(prefix);
+                }
+            }
+        }
+        res.overlapping = overlapping.iter().map(|key| {
+            prefixes[key].count
+        }).sum();
+        res.moas_prefixes = prefixes.values().filter(|info| {
+            info.moas
+        }).count();
+        res
+    }
+}
+
+
+//------------ OverlapStats --------------------------------------------------
+
+/// Statistics about overlapping items in a set.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OverlapStats {
+    /// The number of items that overlap with at least one other item.
+    pub overlapping: usize,
+
+    /// The number of prefixes with items for more than one origin AS.
+    pub moas_prefixes: usize,
+
+    /// The number of IPv4 items by how much the max-length exceeds the
+    /// prefix length.
+    ///
+    /// Items with a max-length smaller than their prefix length are
+    /// counted with an excess of zero.
+    pub v4_max_len_excess: HashMap<u8, usize>,
+
+    /// The number of IPv6 items by how much the max-length exceeds the
+    /// prefix length.
+    pub v6_max_len_excess: HashMap<u8, usize>,
+}
+
+impl OverlapStats {
+    /// Returns the IPv4 max-length excess counts ordered by excess.
+    pub fn v4_max_len_excess_sorted(&self) -> Vec<(u8, usize)> {
+        Self::sorted(&self.v4_max_len_excess)
+    }
+
+    /// Returns the IPv6 max-length excess counts ordered by excess.
+    pub fn v6_max_len_excess_sorted(&self) -> Vec<(u8, usize)> {
+        Self::sorted(&self.v6_max_len_excess)
+    }
+
+    fn sorted(counts: &HashMap<u8, usize>) -> Vec<(u8, usize)> {
+        let mut res: Vec<_> = counts.iter().map(|(&excess, &count)| {
+            (excess, count)
+        }).collect();
+        res.sort_unstable();
+        res
+    }
+}
+
+
+//------------ PrefixInfo ----------------------------------------------------
+
+/// What we know about the items for a prefix.
+struct PrefixInfo {
+    /// The number of items.
+    count: usize,
+
+    /// The origin AS of the first item.
+    asn: u32,
+
+    /// Whether there are items for more than one origin AS.
+    moas: bool,
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// The key for a prefix: whether it is IPv6, its first bits, and length.
+type PrefixKey = (bool, u128, u8);
+
+/// Returns the key for the first `len` bits of an address.
+fn key(addr: IpAddr, len: u8) -> PrefixKey {
+    match addr {
+        IpAddr::V4(addr) => (false, u128::from(mask_v4(addr, len)), len),
+        IpAddr::V6(addr) => (true, mask_v6(addr, len), len),
+    }
+}
+
+/// Returns the key of the covering prefix of length `len`.
+///
+/// The length must not be larger than that of `prefix`.
+fn shorten(prefix: PrefixKey, len: u8) -> PrefixKey {
+    let bits = prefix.1.checked_shr(
+        u32::from(prefix.2 - len)
+    ).unwrap_or(0);
+    (prefix.0, bits, len)
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::{v4, v6};
+    
+
+    #[test]
+    fn overlap_stats() {
+        let mut builder = SetBuilder::empty();
+        for &(addr, prefix_len, max_len, asn) in &[
+            ([10, 0, 0, 0], 8, 8, 64496),
+            ([10, 1, 0, 0], 16, 24, 64497), // covered by 10/8
+            ([10, 1, 0, 0], 16, 16, 64498), // MOAS with the above
+            ([192, 0, 2, 0], 24, 24, 64496),
+            ([192, 0, 2, 0], 24, 25, 64496), // same prefix
+            ([198, 51, 100, 0], 24, 16, 64496), // alone, no max-len
+        ] {
+            builder.insert(v4(addr, prefix_len, max_len, asn)).unwrap();
+        }
+        // An IPv6 prefix whose address bits match 10/8 doesn’t overlap.
+        builder.insert(
+            v6([0x0a00, 0, 0, 0, 0, 0, 0, 0], 16, 48, 64496)
+        ).unwrap();
+        let stats = builder.finalize().overlap_stats();
+        assert_eq!(stats.overlapping, 5);
+        assert_eq!(stats.moas_prefixes, 1);
+        assert_eq!(
+            stats.v4_max_len_excess_sorted(), [(0, 4), (1, 1), (8, 1)]
+        );
+        assert_eq!(stats.v6_max_len_excess_sorted(), [(32, 1)]);
+    }
+}
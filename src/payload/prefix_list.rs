@@ -0,0 +1,216 @@
+//! Reducing a payload set to an aggregated list of prefixes.
+//!
+//! The prefix list of a set covers exactly the address space covered by
+//! the prefixes of the set’s items – no more, no less – using as few
+//! prefixes as possible. It is produced by classic prefix aggregation:
+//! prefixes covered by other prefixes are dropped and two sibling prefixes,
+//! i.e., the two halves of the same less specific prefix, are replaced by
+//! that prefix until no more siblings are left.
+//!
+//! Origin AS numbers and maximum lengths are ignored. This is useful for
+//! sets that are essentially deny lists, such as the AS0 items published
+//! by some trust anchors.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use rpki_rtr::payload::Payload;
+use super::{Prefix, Set};
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Returns the aggregated list of the prefixes of the set.
+    ///
+    /// The list is ordered with all IPv4 prefixes first, each family
+    /// ordered by address.
+    pub fn prefix_list(&self) -> Vec<Prefix> {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for item in self.iter() {
+            match *item {
+                Payload::V4(ref item) => {
+                    v4.push((
+                        u128::from(u32::from(item.prefix)), item.prefix_len
+                    ))
+                }
+                Payload::V6(ref item) => {
+                    v6.push((u128::from(item.prefix), item.prefix_len))
+                }
+            }
+        }
+        let v4 = aggregate(v4, 32).into_iter().map(|(addr, len)| {
+            (IpAddr::V4(Ipv4Addr::from(addr as u32)), len)
+        });
+        let v6 = aggregate(v6, 128).into_iter().map(|(addr, len)| {
+            (IpAddr::V6(Ipv6Addr::from(addr)), len)
+        });
+        v4.chain(v6).map(|(addr, len)| {
+            // The lengths come from valid items or are one less, so this
+            // can’t fail.
+            Prefix::new(addr, len).unwrap()
+        }).collect()
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Aggregates the prefixes of one address family.
+///
+/// Addresses are given as integers with `bits` being the address length
+/// of the family. Host bits of the addresses are ignored. The result is
+/// ordered by address.
+fn aggregate(mut prefixes: Vec<(u128, u8)>, bits: u8) -> Vec<(u128, u8)> {
+    for item in &mut prefixes {
+        item.0 = network(item.0, item.1, bits);
+    }
+
+    // Ordered by address and then length, a prefix comes right after the
+    // prefix that covers it, if any. Because the result only ever contains
+    // disjoint prefixes in order, only the last one needs checking and the
+    // two siblings for a merge are always the last two.
+    prefixes.sort_unstable();
+    let mut res: Vec<(u128, u8)> = Vec::with_capacity(prefixes.len());
+    for (addr, len) in prefixes {
+        if let Some(&(last, last_len)) = res.last() {
+            if covers(last, last_len, addr, len, bits) {
+                continue
+            }
+        }
+        res.push((addr, len));
+        while res.len() >= 2 {
+            let (left, left_len) = res[res.len() - 2];
+            let (right, right_len) = res[res.len() - 1];
+            if left_len != right_len || left_len == 0 {
+                break
+            }
+            let bit = 1u128 << (bits - left_len);
+            if left & bit != 0 || right != left | bit {
+                break
+            }
+            res.pop();
+            res.pop();
+            res.push((left, left_len - 1));
+        }
+    }
+    res
+}
+
+/// Returns the address with all bits after the first `len` bits cleared.
+fn network(addr: u128, len: u8, bits: u8) -> u128 {
+    if len == 0 {
+        0
+    }
+    else {
+        let host = bits - len;
+        (addr >> host) << host
+    }
+}
+
+/// Returns whether the first prefix covers the second.
+fn covers(
+    addr: u128, len: u8, other_addr: u128, other_len: u8, bits: u8
+) -> bool {
+    len <= other_len && network(other_addr, len, bits) == addr
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::{v4, v6};
+    use super::*;
+
+    fn set(prefixes: &[&str]) -> Set {
+        let mut builder = SetBuilder::empty();
+        for prefix in prefixes {
+            let prefix = Prefix::from_str(prefix).unwrap();
+            let len = prefix.prefix_len();
+            builder.insert(match prefix.addr() {
+                IpAddr::V4(addr) => v4(addr, len, len, 0),
+                IpAddr::V6(addr) => v6(addr, len, len, 0),
+            }).unwrap();
+        }
+        builder.finalize()
+    }
+
+    fn list(prefixes: &[&str]) -> Vec<String> {
+        set(prefixes).prefix_list().iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn sibling_merge() {
+        assert_eq!(
+            list(&["192.0.2.0/25", "192.0.2.128/25"]),
+            ["192.0.2.0/24"]
+        );
+        // Merges cascade up.
+        assert_eq!(
+            list(&[
+                "10.0.0.0/10", "10.64.0.0/10", "10.128.0.0/10",
+                "10.192.0.0/11", "10.224.0.0/11",
+            ]),
+            ["10.0.0.0/8"]
+        );
+        assert_eq!(
+            list(&["0.0.0.0/1", "128.0.0.0/1"]),
+            ["0.0.0.0/0"]
+        );
+        assert_eq!(
+            list(&["2001:db8::/33", "2001:db8:8000::/33"]),
+            ["2001:db8::/32"]
+        );
+        assert_eq!(list(&["::/1", "8000::/1"]), ["::/0"]);
+    }
+
+    #[test]
+    fn containment() {
+        assert_eq!(
+            list(&["10.0.0.0/8", "10.1.0.0/16", "10.255.255.0/24"]),
+            ["10.0.0.0/8"]
+        );
+        // More specifics with the same address.
+        assert_eq!(
+            list(&["192.0.2.0/26", "192.0.2.0/24", "192.0.2.0/25"]),
+            ["192.0.2.0/24"]
+        );
+        assert_eq!(
+            list(&["2001:db8::/32", "2001:db8:1::/48", "2001:db8::1/128"]),
+            ["2001:db8::/32"]
+        );
+        // A merge result covers later prefixes.
+        assert_eq!(
+            list(&["10.0.0.0/9", "10.128.0.0/9", "10.200.0.0/16"]),
+            ["10.0.0.0/8"]
+        );
+    }
+
+    #[test]
+    fn non_mergeable() {
+        // Adjacent but not siblings.
+        assert_eq!(
+            list(&["192.0.2.128/25", "192.0.3.0/25"]),
+            ["192.0.2.128/25", "192.0.3.0/25"]
+        );
+        // Siblings of different lengths.
+        assert_eq!(
+            list(&["192.0.2.0/25", "192.0.2.128/26"]),
+            ["192.0.2.0/25", "192.0.2.128/26"]
+        );
+        // Three quarters only get partially merged.
+        assert_eq!(
+            list(&["10.0.0.0/10", "10.64.0.0/10", "10.128.0.0/10"]),
+            ["10.0.0.0/9", "10.128.0.0/10"]
+        );
+        // The families are kept apart.
+        assert_eq!(
+            list(&["2001:db8::/33", "0.0.0.0/1", "2001:db8:8000::/34"]),
+            ["0.0.0.0/1", "2001:db8::/33", "2001:db8:8000::/34"]
+        );
+        assert!(list(&[]).is_empty());
+    }
+}
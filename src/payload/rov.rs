@@ -0,0 +1,287 @@
+//! Route origin validation against a payload set.
+//!
+//! The validation follows RFC 6811: a route is covered by all items whose
+//! prefix is equal to or less specific than the route’s prefix. It is
+//! valid if any of these items has the route’s origin AS and a maximum
+//! length of at least the route’s prefix length, invalid if there are
+//! covering items but none of them match, and not found if there are no
+//! covering items at all.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use rpki_rtr::payload::Payload;
+use super::{mask_v4, mask_v6, payload_prefix, Prefix, Set};
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Validates a list of routes and returns the number of each outcome.
+    ///
+    /// Each route is given as its prefix and origin AS number.
+    pub fn rov_stats(&self, routes: &[(Prefix, u32)]) -> RovStats {
+        let index = RovIndex::new(self);
+        let mut res = RovStats::default();
+        for &(prefix, asn) in routes {
+            match index.validate(prefix, asn) {
+                RovState::Valid => res.valid += 1,
+                RovState::Invalid => res.invalid += 1,
+                RovState::NotFound => res.not_found += 1,
+            }
+        }
+        res
+    }
+
+    /// Returns the share of routes that are valid.
+    ///
+    /// Each route is given as its prefix and origin AS number. The result
+    /// is between 0 and 1. It is 0 if there are no routes at all.
+    pub fn health_score(&self, bgp_routes: &[(Prefix, u32)]) -> f64 {
+        if bgp_routes.is_empty() {
+            return 0.
+        }
+        let stats = self.rov_stats(bgp_routes);
+        stats.valid as f64 / bgp_routes.len() as f64
+    }
+}
+
+
+//------------ RovState ------------------------------------------------------
+
+/// The route origin validation state of a route.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RovState {
+    /// A covering item matches the route.
+    Valid,
+
+    /// There are covering items but none matches the route.
+    Invalid,
+
+    /// There are no covering items.
+    NotFound,
+}
+
+
+//------------ RovStats ------------------------------------------------------
+
+/// The number of routes in each route origin validation state.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RovStats {
+    /// The number of valid routes.
+    pub valid: usize,
+
+    /// The number of invalid routes.
+    pub invalid: usize,
+
+    /// The number of routes not found.
+    pub not_found: usize,
+}
+
+
+//------------ RovIndex ------------------------------------------------------
+
+/// An index for looking up the items covering a route.
+///
+/// The items are kept by their exact prefix. Finding the covering items
+/// of a route thus takes one lookup for each prefix length that is both
+/// present in the set and not longer than the route’s prefix length.
+///
+/// Use the index directly when validating many routes against the same
+/// set and more than the number of outcomes is needed.
+pub struct RovIndex<'a> {
+    /// The items by their family, their masked address, and their length.
+    items: HashMap<(bool, u128, u8), Vec<&'a Payload>>,
+
+    /// The IPv4 prefix lengths present in the set.
+    v4_lens: Vec<u8>,
+
+    /// The IPv6 prefix lengths present in the set.
+    v6_lens: Vec<u8>,
+}
+
+impl<'a> RovIndex<'a> {
+    /// Creates the index for a set.
+    pub fn new(set: &'a Set) -> Self {
+        let mut res = RovIndex {
+            items: HashMap::new(),
+            v4_lens: Vec::new(),
+            v6_lens: Vec::new(),
+        };
+        for item in set.iter() {
+            let (addr, len) = payload_prefix(item);
+            res.items.entry(key(addr, len)).or_default().push(item);
+            let lens = if addr.is_ipv4() {
+                &mut res.v4_lens
+            }
+            else {
+                &mut res.v6_lens
+            };
+            if !lens.contains(&len) {
+                lens.push(len)
+            }
+        }
+        res
+    }
+
+    /// Returns the validation state of a route.
+    pub fn validate(&self, prefix: Prefix, asn: u32) -> RovState {
+        let lens = if prefix.addr().is_ipv4() {
+            &self.v4_lens
+        }
+        else {
+            &self.v6_lens
+        };
+        let mut covered = false;
+        for &len in lens {
+            if len > prefix.prefix_len() {
+                continue
+            }
+            let items = match self.items.get(&key(prefix.addr(), len)) {
+                Some(items) => items,
+                None => continue
+            };
+            covered = true;
+            if items.iter().any(|item| matches(item, prefix, asn)) {
+                return RovState::Valid
+            }
+        }
+        if covered {
+            RovState::Invalid
+        }
+        else {
+            RovState::NotFound
+        }
+    }
+
+    /// Returns all items covering a prefix.
+    ///
+    /// The items are ordered from less to more specific.
+    pub fn covering(&self, prefix: Prefix) -> Vec<&'a Payload> {
+        let lens = if prefix.addr().is_ipv4() {
+            &self.v4_lens
+        }
+        else {
+            &self.v6_lens
+        };
+        let mut lens: Vec<_> = lens.iter().copied().filter(|&len| {
+            len <= prefix.prefix_len()
+        }).collect();
+        lens.sort_unstable();
+        lens.into_iter().filter_map(|len| {
+            self.items.get(&key(prefix.addr(), len))
+        }).flatten().copied().collect()
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the index key for the first `len` bits of an address.
+fn key(addr: IpAddr, len: u8) -> (bool, u128, u8) {
+    match addr {
+        IpAddr::V4(addr) => (false, u128::from(mask_v4(addr, len)), len),
+        IpAddr::V6(addr) => (true, mask_v6(addr, len), len),
+    }
+}
+
+/// Returns whether a covering item matches a route.
+///
+/// AS 0 never matches.
+fn matches(item: &Payload, prefix: Prefix, asn: u32) -> bool {
+    let (item_asn, max_len) = match *item {
+        Payload::V4(ref item) => (item.asn, item.max_len),
+        Payload::V6(ref item) => (item.asn, item.max_len),
+    };
+    item_asn != 0 && item_asn == asn && prefix.prefix_len() <= max_len
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    use std::str::FromStr;
+    
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::v4;
+    use super::*;
+
+    fn prefix(prefix: &str) -> Prefix {
+        Prefix::from_str(prefix).unwrap()
+    }
+
+    fn route(prefix: &str, asn: u32) -> (Prefix, u32) {
+        (Prefix::from_str(prefix).unwrap(), asn)
+    }
+
+    #[test]
+    fn rov_stats() {
+        let mut builder = SetBuilder::empty();
+        builder.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        builder.insert(v4([198, 51, 100, 0], 22, 24, 64497)).unwrap();
+        builder.insert(v4([198, 51, 100, 0], 24, 24, 0)).unwrap();
+        builder.insert(v4([203, 0, 113, 0], 24, 24, 0)).unwrap();
+        let set = builder.finalize();
+        let index = RovIndex::new(&set);
+
+        assert_eq!(
+            index.validate(prefix("192.0.2.0/24"), 64496),
+            RovState::Valid
+        );
+        assert_eq!(
+            index.validate(prefix("192.0.2.0/25"), 64496),
+            RovState::Invalid
+        );
+        assert_eq!(
+            index.validate(prefix("192.0.2.0/24"), 64497),
+            RovState::Invalid
+        );
+        assert_eq!(
+            index.validate(prefix("192.0.0.0/16"), 64496),
+            RovState::NotFound
+        );
+        assert_eq!(
+            index.validate(prefix("2001:db8::/32"), 64496),
+            RovState::NotFound
+        );
+        // A less specific item still validates despite an AS0 item.
+        assert_eq!(
+            index.validate(prefix("198.51.100.0/24"), 64497),
+            RovState::Valid
+        );
+        assert_eq!(
+            index.validate(prefix("203.0.113.0/24"), 0),
+            RovState::Invalid
+        );
+        assert_eq!(
+            index.covering(prefix("198.51.100.0/24")),
+            [
+                &v4([198, 51, 100, 0], 22, 24, 64497),
+                &v4([198, 51, 100, 0], 24, 24, 0),
+            ]
+        );
+        assert!(index.covering(prefix("10.0.0.0/8")).is_empty());
+
+        assert_eq!(
+            set.rov_stats(&[
+                route("192.0.2.0/24", 64496),
+                route("192.0.2.128/25", 64496),
+                route("198.51.101.0/24", 64497),
+                route("203.0.113.0/24", 64496),
+                route("10.0.0.0/8", 64496),
+            ]),
+            RovStats { valid: 2, invalid: 2, not_found: 1 }
+        );
+        assert_eq!(
+            set.health_score(&[
+                route("192.0.2.0/24", 64496),
+                route("192.0.2.0/24", 64497),
+                route("198.51.101.0/24", 64497),
+                route("10.0.0.0/8", 64496),
+            ]),
+            0.5
+        );
+        assert_eq!(set.health_score(&[]), 0.);
+    }
+}
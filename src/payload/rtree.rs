@@ -0,0 +1,254 @@
+//! An index for prefix range queries on payload sets.
+//!
+//! The index is an R-tree over the address ranges of the payload’s
+//! prefixes. Since an address range is a one-dimensional interval, the
+//! tree degenerates into a hierarchy of intervals. As payload sets never
+//! change, the tree is bulk-loaded once from the sorted items and never
+//! modified, which allows it to be packed into a few flat vecs.
+
+use std::cmp::Reverse;
+use std::net::IpAddr;
+use rpki_rtr::payload::Payload;
+use super::{Prefix, Set};
+
+
+//------------ RtreeIndex ----------------------------------------------------
+
+/// An R-tree index over the prefixes of a payload set.
+///
+/// The index borrows the set it was created from. It answers queries for
+/// all payload items whose prefix lies within a given prefix via
+/// [`covering`](Self::covering) in O(log n + k).
+#[derive(Clone, Debug)]
+pub struct RtreeIndex<'a> {
+    /// The tree for IPv4 prefixes.
+    v4: Tree<'a>,
+
+    /// The tree for IPv6 prefixes.
+    v6: Tree<'a>,
+}
+
+impl<'a> RtreeIndex<'a> {
+    /// Creates the index for the given set.
+    pub fn new(set: &'a Set) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for item in set.iter() {
+            match *item {
+                Payload::V4(ref prefix) => v4.push((
+                    Interval::from_prefix(
+                        IpAddr::V4(prefix.prefix), prefix.prefix_len
+                    ),
+                    item
+                )),
+                Payload::V6(ref prefix) => v6.push((
+                    Interval::from_prefix(
+                        IpAddr::V6(prefix.prefix), prefix.prefix_len
+                    ),
+                    item
+                )),
+            }
+        }
+        RtreeIndex {
+            v4: Tree::new(v4),
+            v6: Tree::new(v6),
+        }
+    }
+
+    /// Returns all payload items covered by the given prefix.
+    ///
+    /// These are all items whose prefix is equal to or more specific than
+    /// `range`. The items are returned in the same order as in the set.
+    pub fn covering(&self, range: Prefix) -> Vec<&'a Payload> {
+        let query = Interval::from_prefix(range.addr(), range.prefix_len());
+        let mut res = Vec::new();
+        match range.addr() {
+            IpAddr::V4(_) => self.v4.covered(query, &mut res),
+            IpAddr::V6(_) => self.v6.covered(query, &mut res),
+        }
+        res
+    }
+}
+
+
+//------------ Tree ----------------------------------------------------------
+
+/// A packed, bulk-loaded R-tree over intervals.
+#[derive(Clone, Debug)]
+struct Tree<'a> {
+    /// The items ordered by the start of their interval.
+    leaves: Vec<(Interval, &'a Payload)>,
+
+    /// The inner nodes level by level.
+    ///
+    /// The first level contains the bounding intervals of each group of
+    /// `FANOUT` leaves, each following level those of `FANOUT` nodes of the
+    /// level before. The last level has at most `FANOUT` nodes.
+    levels: Vec<Vec<Interval>>,
+}
+
+/// The number of children of each node.
+const FANOUT: usize = 16;
+
+impl<'a> Tree<'a> {
+    /// Creates a tree from a list of items.
+    fn new(mut leaves: Vec<(Interval, &'a Payload)>) -> Self {
+        // Ordering less specific prefixes first keeps the order of the set.
+        leaves.sort_by_key(|item| (item.0.start, Reverse(item.0.end)));
+        let mut levels = Vec::new();
+        let mut current: Vec<_> = leaves.chunks(FANOUT).map(|chunk| {
+            Interval::bounding(chunk.iter().map(|item| item.0))
+        }).collect();
+        while current.len() > FANOUT {
+            let next = current.chunks(FANOUT).map(|chunk| {
+                Interval::bounding(chunk.iter().copied())
+            }).collect();
+            levels.push(current);
+            current = next;
+        }
+        levels.push(current);
+        Tree { leaves, levels }
+    }
+
+    /// Appends all items whose interval lies within `query` to `res`.
+    fn covered(&self, query: Interval, res: &mut Vec<&'a Payload>) {
+        if let Some(top) = self.levels.last() {
+            self.descend(self.levels.len() - 1, 0..top.len(), query, res)
+        }
+    }
+
+    /// Walks down the tree from the nodes `range` on level `level`.
+    fn descend(
+        &self,
+        level: usize,
+        range: std::ops::Range<usize>,
+        query: Interval,
+        res: &mut Vec<&'a Payload>,
+    ) {
+        let nodes = &self.levels[level];
+        for idx in range {
+            if !nodes[idx].intersects(query) {
+                continue
+            }
+            let start = idx * FANOUT;
+            if level == 0 {
+                let end = (start + FANOUT).min(self.leaves.len());
+                res.extend(
+                    self.leaves[start..end].iter().filter(|item| {
+                        query.contains(item.0)
+                    }).map(|item| item.1)
+                );
+            }
+            else {
+                let end = (start + FANOUT).min(
+                    self.levels[level - 1].len()
+                );
+                self.descend(level - 1, start..end, query, res)
+            }
+        }
+    }
+}
+
+
+//------------ Interval ------------------------------------------------------
+
+/// The closed interval of addresses covered by a prefix.
+///
+/// IPv4 addresses are simply stored in the lower bits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Interval {
+    /// The first address of the interval.
+    start: u128,
+
+    /// The last address of the interval.
+    end: u128,
+}
+
+impl Interval {
+    /// Creates the interval for the given prefix.
+    ///
+    /// Any bits of `addr` beyond `len` are ignored.
+    fn from_prefix(addr: IpAddr, len: u8) -> Self {
+        let (addr, bits) = match addr {
+            IpAddr::V4(addr) => (u128::from(u32::from(addr)), 32),
+            IpAddr::V6(addr) => (u128::from(addr), 128),
+        };
+        let host_bits = bits - u32::from(len.min(bits as u8));
+        let host_mask = if host_bits == 128 {
+            !0
+        }
+        else {
+            (1u128 << host_bits) - 1
+        };
+        Interval {
+            start: addr & !host_mask,
+            end: addr | host_mask,
+        }
+    }
+
+    /// Returns the smallest interval containing all given intervals.
+    ///
+    /// The iterator must not be empty.
+    fn bounding(mut iter: impl Iterator<Item = Interval>) -> Self {
+        let first = iter.next().expect("empty node");
+        iter.fold(first, |res, item| Interval {
+            start: res.start.min(item.start),
+            end: res.end.max(item.end),
+        })
+    }
+
+    /// Returns whether the two intervals overlap.
+    fn intersects(self, other: Interval) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// Returns whether `other` lies entirely within this interval.
+    fn contains(self, other: Interval) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use crate::payload::SetBuilder;
+    use crate::payload::testing::{v4, v6};
+
+    #[test]
+    fn covering() {
+        let mut builder = SetBuilder::empty();
+        for i in 0..200u32 {
+            builder.insert(v4(
+                [10, (i / 4) as u8, ((i % 4) * 64) as u8, 0], 26, 26, i
+            )).unwrap();
+        }
+        builder.insert(v4([10, 0, 0, 0], 8, 8, 1000)).unwrap();
+        builder.insert(v4([10, 1, 0, 0], 16, 16, 1001)).unwrap();
+        builder.insert(v4([192, 0, 2, 0], 24, 24, 1002)).unwrap();
+        builder.insert(
+            v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 48, 1003)
+        ).unwrap();
+        let set = builder.finalize();
+        let index = RtreeIndex::new(&set);
+
+        for range in &[
+            "10.0.0.0/8", "10.1.0.0/16", "10.1.0.0/17", "10.1.2.3/16",
+            "0.0.0.0/0", "192.0.2.128/25", "::/0", "2001:db8::/31",
+            "2001:db8:1::/48",
+        ] {
+            let range = Prefix::from_str(range).unwrap();
+            let linear: Vec<_> = set.iter().filter(|item| {
+                range.covers(item)
+            }).collect();
+            assert_eq!(index.covering(range), linear, "{}", range);
+        }
+        assert_eq!(
+            index.covering(Prefix::from_str("10.0.0.0/8").unwrap()).len(),
+            202
+        );
+    }
+}
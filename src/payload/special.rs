@@ -0,0 +1,159 @@
+//! Finding items for special-purpose addresses.
+//!
+//! IANA keeps registries of special-purpose IPv4 and IPv6 addresses as
+//! described in RFC 6890 and RFC 8190. Most of these addresses are not
+//! meant to be routed in the global Internet, so items for them almost
+//! certainly stem from misconfigured ROAs.
+//!
+//! Only the registry entries that are not globally reachable are
+//! considered. Entries for less specific prefixes that contain globally
+//! reachable more specifics, such as the IETF protocol assignments in
+//! 2001::/23, are left out as a whole and only their more specific entries
+//! that are not globally reachable are used.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use rpki_rtr::payload::Payload;
+use super::{Prefix, Set};
+
+
+//------------ Set -----------------------------------------------------------
+
+impl Set {
+    /// Returns the items for prefixes in special-purpose address ranges.
+    ///
+    /// An item is returned if its prefix is equal to or more specific than
+    /// one of the ranges. It is returned together with a description of
+    /// the range’s purpose. If several ranges match, the description of
+    /// the most specific one is used.
+    pub fn find_special_purpose_vrps(&self) -> Vec<(&Payload, &'static str)> {
+        let ranges = special_purpose_ranges();
+        self.iter().filter_map(|item| {
+            ranges.iter().find(|(prefix, _)| {
+                prefix.covers(item)
+            }).map(|(_, purpose)| (item, *purpose))
+        }).collect()
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// The IPv4 special-purpose ranges that are not globally reachable.
+///
+/// More specific ranges need to come before less specific ones.
+const V4_RANGES: &[([u8; 4], u8, &str)] = &[
+    ([0, 0, 0, 0], 8, "\"This network\" (RFC 791)"),
+    ([10, 0, 0, 0], 8, "Private-Use (RFC 1918)"),
+    ([100, 64, 0, 0], 10, "Shared Address Space (RFC 6598)"),
+    ([127, 0, 0, 0], 8, "Loopback (RFC 1122)"),
+    ([169, 254, 0, 0], 16, "Link Local (RFC 3927)"),
+    ([172, 16, 0, 0], 12, "Private-Use (RFC 1918)"),
+    ([192, 0, 0, 0], 24, "IETF Protocol Assignments (RFC 6890)"),
+    ([192, 0, 2, 0], 24, "Documentation prefix (RFC 5737)"),
+    ([192, 88, 99, 0], 24, "Deprecated 6to4 Relay Anycast (RFC 7526)"),
+    ([192, 168, 0, 0], 16, "Private-Use (RFC 1918)"),
+    ([198, 18, 0, 0], 15, "Benchmarking (RFC 2544)"),
+    ([198, 51, 100, 0], 24, "Documentation prefix (RFC 5737)"),
+    ([203, 0, 113, 0], 24, "Documentation prefix (RFC 5737)"),
+    ([255, 255, 255, 255], 32, "Limited Broadcast (RFC 919)"),
+    ([240, 0, 0, 0], 4, "Reserved (RFC 1112)"),
+];
+
+/// The IPv6 special-purpose ranges that are not globally reachable.
+///
+/// More specific ranges need to come before less specific ones.
+const V6_RANGES: &[([u16; 8], u8, &str)] = &[
+    ([0, 0, 0, 0, 0, 0, 0, 1], 128, "Loopback Address (RFC 4291)"),
+    ([0, 0, 0, 0, 0, 0, 0, 0], 128, "Unspecified Address (RFC 4291)"),
+    (
+        [0, 0, 0, 0, 0, 0xffff, 0, 0], 96,
+        "IPv4-mapped Address (RFC 4291)"
+    ),
+    (
+        [0x64, 0xff9b, 1, 0, 0, 0, 0, 0], 48,
+        "IPv4-IPv6 Translation (RFC 8215)"
+    ),
+    (
+        [0x100, 0, 0, 0, 0, 0, 0, 0], 64,
+        "Discard-Only Address Block (RFC 6666)"
+    ),
+    ([0x2001, 2, 0, 0, 0, 0, 0, 0], 48, "Benchmarking (RFC 5180)"),
+    ([0x2001, 0x10, 0, 0, 0, 0, 0, 0], 28, "Deprecated ORCHID (RFC 4843)"),
+    (
+        [0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32,
+        "Documentation prefix (RFC 3849)"
+    ),
+    ([0x3fff, 0, 0, 0, 0, 0, 0, 0], 20, "Documentation prefix (RFC 9637)"),
+    ([0x5f00, 0, 0, 0, 0, 0, 0, 0], 16, "Segment Routing SIDs (RFC 9602)"),
+    ([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7, "Unique-Local (RFC 4193)"),
+    ([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10, "Link-Local Unicast (RFC 4291)"),
+];
+
+/// Returns all special-purpose ranges with their purpose.
+fn special_purpose_ranges() -> Vec<(Prefix, &'static str)> {
+    V4_RANGES.iter().map(|&(addr, len, purpose)| {
+        (IpAddr::V4(Ipv4Addr::from(addr)), len, purpose)
+    }).chain(V6_RANGES.iter().map(|&(addr, len, purpose)| {
+        (IpAddr::V6(Ipv6Addr::from(addr)), len, purpose)
+    })).map(|(addr, len, purpose)| {
+        // The lengths in the tables are all valid.
+        (Prefix::new(addr, len).unwrap(), purpose)
+    }).collect()
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    
+    use crate::payload::{payload_prefix, SetBuilder};
+    use crate::payload::testing::{v4, v6};
+    use super::*;
+
+    fn item(prefix: &str, max_len: u8) -> Payload {
+        let prefix = Prefix::from_str(prefix).unwrap();
+        let prefix_len = prefix.prefix_len();
+        match prefix.addr() {
+            IpAddr::V4(prefix) => v4(prefix, prefix_len, max_len, 64496),
+            IpAddr::V6(prefix) => v6(prefix, prefix_len, max_len, 64496),
+        }
+    }
+
+    #[test]
+    fn find_special_purpose_vrps() {
+        let mut builder = SetBuilder::empty();
+        for &(prefix, max_len) in &[
+            ("192.0.2.0/24", 24),
+            ("10.1.0.0/16", 24),
+            ("192.0.0.0/16", 24), // covers special ranges but isn’t one
+            ("193.0.0.0/16", 16),
+            ("255.255.255.255/32", 32),
+            ("2001:db8:1::/48", 48),
+            ("2001:4:112::/48", 48), // AS112 is globally reachable
+            ("fd00::/8", 48),
+        ] {
+            builder.insert(item(prefix, max_len)).unwrap();
+        }
+        let set = builder.finalize();
+        let mut found: Vec<_> = set.find_special_purpose_vrps().into_iter()
+            .map(|(item, purpose)| {
+                let (addr, len) = payload_prefix(item);
+                (format!("{}/{}", addr, len), purpose)
+            }).collect();
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                ("10.1.0.0/16", "Private-Use (RFC 1918)"),
+                ("192.0.2.0/24", "Documentation prefix (RFC 5737)"),
+                ("2001:db8:1::/48", "Documentation prefix (RFC 3849)"),
+                ("255.255.255.255/32", "Limited Broadcast (RFC 919)"),
+                ("fd00::/8", "Unique-Local (RFC 4193)"),
+            ].iter().map(|&(prefix, purpose)| {
+                (String::from(prefix), purpose)
+            }).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Randomness.
+//!
+//! Components that need random numbers get them from the [`Random`] value
+//! provided via their [`Component`](crate::manager::Component). Normally,
+//! this is just the thread-local generator. If a seed is configured via the
+//! `random-seed` option or the `RTRTR_RANDOM_SEED` environment variable,
+//! each component instead gets its own deterministic generator derived from
+//! the seed and its name. This makes the random choices of a component
+//! reproducible independently of how the tasks of all components are
+//! scheduled.
+
+use std::env;
+use std::sync::{Arc, Mutex};
+use log::error;
+use rand::{thread_rng, Rng, SeedableRng};
+use rand::distributions::uniform::SampleUniform;
+use rand::rngs::StdRng;
+use crate::log::Failed;
+
+
+//------------ Random --------------------------------------------------------
+
+/// A source of random numbers.
+///
+/// Cloned values share the same generator.
+#[derive(Clone, Debug, Default)]
+pub struct Random {
+    /// The seed if there is one.
+    seed: Option<u64>,
+
+    /// The deterministic generator if there is a seed.
+    rng: Option<Arc<Mutex<StdRng>>>,
+}
+
+impl Random {
+    /// The environment variable overriding the configured seed.
+    pub const SEED_ENV: &'static str = "RTRTR_RANDOM_SEED";
+
+    /// Creates a new value from an optional seed.
+    ///
+    /// If `seed` is `None`, the value uses the thread-local generator.
+    pub fn new(seed: Option<u64>) -> Self {
+        Random {
+            seed,
+            rng: seed.map(|seed| {
+                Arc::new(Mutex::new(StdRng::seed_from_u64(seed)))
+            }),
+        }
+    }
+
+    /// Returns the seed given in the environment if there is one.
+    pub fn seed_from_env() -> Result<Option<u64>, Failed> {
+        let value = match env::var(Self::SEED_ENV) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => return Ok(None),
+            Err(env::VarError::NotUnicode(_)) => {
+                error!("Invalid value in {}.", Self::SEED_ENV);
+                return Err(Failed)
+            }
+        };
+        match value.trim().parse() {
+            Ok(seed) => Ok(Some(seed)),
+            Err(_) => {
+                error!(
+                    "Invalid value '{}' in {}: expected an integer.",
+                    value, Self::SEED_ENV
+                );
+                Err(Failed)
+            }
+        }
+    }
+
+    /// Creates the value for a component.
+    ///
+    /// If there is a seed, the component gets its own generator seeded
+    /// from the seed and the component’s name.
+    pub fn for_component(&self, name: &str) -> Self {
+        Random::new(self.seed.map(|seed| {
+            // FNV-1a so the derived seed stays the same across releases.
+            name.bytes().fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, ch| {
+                (hash ^ u64::from(ch)).wrapping_mul(0x0100_0000_01b3)
+            })
+        }))
+    }
+
+    /// Returns whether the value is deterministic.
+    pub fn is_seeded(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    /// Returns a random value in the range from `low` to but excluding
+    /// `high`.
+    ///
+    /// # Panics
+    ///
+    /// The method panics if `low` is not less than `high`.
+    pub fn gen_range<T: SampleUniform>(&self, low: T, high: T) -> T {
+        match self.rng {
+            Some(ref rng) => rng.lock().unwrap().gen_range(low, high),
+            None => thread_rng().gen_range(low, high),
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sequence(random: &Random) -> Vec<u64> {
+        (0..16).map(|_| random.gen_range(0, 1_000_000)).collect()
+    }
+
+    #[test]
+    fn seeded() {
+        let global = Random::new(Some(12));
+        let first = sequence(&global.for_component("any"));
+        assert_eq!(
+            first, sequence(&Random::new(Some(12)).for_component("any"))
+        );
+        assert_ne!(first, sequence(&global.for_component("other")));
+        assert_ne!(
+            first, sequence(&Random::new(Some(13)).for_component("any"))
+        );
+        assert!(!Random::new(None).for_component("any").is_seeded());
+    }
+}
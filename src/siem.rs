@@ -0,0 +1,394 @@
+//! Exporting events for security information and event management.
+//!
+//! If the `siem-format` option is given, significant events are written
+//! to a separate sink in one of the formats SIEM systems understand out of
+//! the box: ArcSight’s Common Event Format (CEF) or IBM’s Log Event
+//! Extended Format (LEEF). The sink is either a file given via `siem-file`
+//! or the local syslog daemon.
+//!
+//! Components emit events via the [`SiemSink`] available through
+//! [`Component::siem`][crate::manager::Component::siem]. Events for
+//! published updates are emitted by the manager for all units.
+
+use std::{fmt, fs};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use chrono::Utc;
+use log::{error, warn};
+use serde::Deserialize;
+use crate::log::ExitError;
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The vendor reported in the events.
+const VENDOR: &str = "NLnet Labs";
+
+/// The product reported in the events.
+const PRODUCT: &str = "RTRTR";
+
+/// The product version reported in the events.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+
+//------------ SiemConfig ----------------------------------------------------
+
+/// The configuration of the SIEM event export.
+#[derive(Debug, Default, Deserialize)]
+pub struct SiemConfig {
+    /// The format of the events.
+    ///
+    /// If this is `None`, no events are exported.
+    #[serde(rename = "siem-format", default)]
+    format: Option<SiemFormat>,
+
+    /// Where to write the events to.
+    #[serde(rename = "siem-target", default)]
+    target: SiemTarget,
+
+    /// The file to write the events to if the target is a file.
+    #[serde(rename = "siem-file", default)]
+    file: Option<PathBuf>,
+
+    /// The sink once opened.
+    #[serde(skip)]
+    sink: Option<Arc<SiemSink>>,
+}
+
+impl SiemConfig {
+    /// Opens the sink if events are to be exported.
+    ///
+    /// This needs to happen before privileges are dropped.
+    pub fn open(&mut self) -> Result<(), ExitError> {
+        let format = match self.format {
+            Some(format) => format,
+            None => return Ok(())
+        };
+        let target = match self.target {
+            SiemTarget::File => {
+                let path = match self.file {
+                    Some(ref path) => path,
+                    None => {
+                        error!(
+                            "Fatal: 'siem-file' is required for the SIEM \
+                             target 'file'."
+                        );
+                        return Err(ExitError)
+                    }
+                };
+                let file = fs::OpenOptions::new()
+                    .create(true).append(true).open(path);
+                match file {
+                    Ok(file) => SinkTarget::File(file),
+                    Err(err) => {
+                        error!(
+                            "Fatal: failed to open SIEM file {}: {}",
+                            path.display(), err
+                        );
+                        return Err(ExitError)
+                    }
+                }
+            }
+            #[cfg(unix)]
+            SiemTarget::Syslog => {
+                let formatter = syslog::Formatter3164 {
+                    facility: syslog::Facility::LOG_DAEMON,
+                    hostname: None,
+                    process: String::from("rtrtr"),
+                    pid: unsafe { libc::getpid() },
+                };
+                match syslog::unix(formatter) {
+                    Ok(logger) => SinkTarget::Syslog(logger),
+                    Err(err) => {
+                        error!(
+                            "Fatal: cannot connect to syslog for SIEM \
+                             events: {}",
+                            err
+                        );
+                        return Err(ExitError)
+                    }
+                }
+            }
+        };
+        self.sink = Some(Arc::new(SiemSink {
+            format, target: Mutex::new(target)
+        }));
+        Ok(())
+    }
+
+    /// Returns the sink if it has been opened.
+    pub fn sink(&self) -> Option<Arc<SiemSink>> {
+        self.sink.clone()
+    }
+}
+
+
+//------------ SiemFormat ----------------------------------------------------
+
+/// The format of exported events.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum SiemFormat {
+    /// ArcSight’s Common Event Format.
+    #[serde(rename = "cef")]
+    Cef,
+
+    /// IBM’s Log Event Extended Format.
+    #[serde(rename = "leef")]
+    Leef,
+}
+
+
+//------------ SiemTarget ----------------------------------------------------
+
+/// Where to write exported events to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum SiemTarget {
+    /// A file given separately.
+    #[serde(rename = "file")]
+    File,
+
+    /// The local syslog daemon.
+    #[cfg(unix)]
+    #[serde(rename = "syslog")]
+    Syslog,
+}
+
+impl Default for SiemTarget {
+    fn default() -> Self {
+        SiemTarget::File
+    }
+}
+
+
+//------------ SiemSink ------------------------------------------------------
+
+/// The sink for exported events.
+pub struct SiemSink {
+    /// The format of the events.
+    format: SiemFormat,
+
+    /// Where the events go.
+    target: Mutex<SinkTarget>,
+}
+
+/// Where the events of a sink go.
+enum SinkTarget {
+    /// A file opened for appending.
+    File(fs::File),
+
+    /// The local syslog daemon.
+    #[cfg(unix)]
+    Syslog(syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>),
+}
+
+impl SiemSink {
+    /// Writes an event to the sink.
+    ///
+    /// Failures are logged but otherwise ignored.
+    pub fn emit(&self, event: &SiemEvent) {
+        let line = event.format(self.format);
+        let res = match *self.target.lock().unwrap() {
+            SinkTarget::File(ref mut file) => writeln!(file, "{}", line),
+            #[cfg(unix)]
+            SinkTarget::Syslog(ref mut logger) => {
+                logger.info(line).map_err(|err| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other, err.to_string()
+                    )
+                })
+            }
+        };
+        if let Err(err) = res {
+            warn!("Failed to write SIEM event: {}", err);
+        }
+    }
+}
+
+impl fmt::Debug for SiemSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SiemSink")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+
+//------------ SiemEvent -----------------------------------------------------
+
+/// A single event to export.
+#[derive(Clone, Debug)]
+pub struct SiemEvent {
+    /// The identifier of the kind of event.
+    id: &'static str,
+
+    /// A human readable description of the kind of event.
+    name: &'static str,
+
+    /// The severity from 0 for least to 10 for most important.
+    severity: u8,
+
+    /// Additional information as pairs of keys and values.
+    fields: Vec<(&'static str, String)>,
+}
+
+impl SiemEvent {
+    /// A connection to a server has been established.
+    pub fn connection_up(unit: &str, remote: &str) -> Self {
+        Self::new("connection-up", "Connection established", 3)
+            .field("unit", unit).field("remote", remote)
+    }
+
+    /// A connection to a server has been closed.
+    pub fn connection_down(unit: &str, remote: &str) -> Self {
+        Self::new("connection-down", "Connection closed", 5)
+            .field("unit", unit).field("remote", remote)
+    }
+
+    /// A unit has published an update.
+    pub fn update(
+        unit: &str, serial: impl fmt::Display, count: usize,
+        changes: Option<(usize, usize)>
+    ) -> Self {
+        let res = Self::new("update", "Update published", 3)
+            .field("unit", unit).field("serial", serial)
+            .field("count", count);
+        match changes {
+            Some((announced, withdrawn)) => {
+                res.field("announced", announced)
+                    .field("withdrawn", withdrawn)
+            }
+            None => res
+        }
+    }
+
+    /// A filter unit has removed items from its source’s data.
+    pub fn filter_drop(unit: &str, dropped: usize) -> Self {
+        Self::new("filter-drop", "Items removed by filter", 4)
+            .field("unit", unit).field("dropped", dropped)
+    }
+
+    /// Creates a new event without any fields.
+    fn new(id: &'static str, name: &'static str, severity: u8) -> Self {
+        SiemEvent { id, name, severity, fields: Vec::new() }
+    }
+
+    /// Adds a field to the event.
+    fn field(mut self, key: &'static str, value: impl fmt::Display) -> Self {
+        self.fields.push((key, value.to_string()));
+        self
+    }
+
+    /// Returns the event in the given format.
+    pub fn format(&self, format: SiemFormat) -> String {
+        match format {
+            SiemFormat::Cef => self.to_cef(),
+            SiemFormat::Leef => self.to_leef(),
+        }
+    }
+
+    /// Returns the event in CEF.
+    ///
+    /// The time of the event is given in the `rt` field as milliseconds
+    /// since the epoch.
+    pub fn to_cef(&self) -> String {
+        let mut res = format!(
+            "CEF:0|{}|{}|{}|{}|{}|{}|rt={}",
+            cef_header(VENDOR), cef_header(PRODUCT), cef_header(VERSION),
+            cef_header(self.id), cef_header(self.name), self.severity,
+            Utc::now().timestamp_millis()
+        );
+        for (key, value) in &self.fields {
+            res.push(' ');
+            res.push_str(key);
+            res.push('=');
+            for ch in value.chars() {
+                match ch {
+                    '\\' | '=' => { res.push('\\'); res.push(ch) }
+                    '\n' => res.push_str("\\n"),
+                    '\r' => res.push_str("\\r"),
+                    _ => res.push(ch)
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns the event in LEEF version 1.0.
+    ///
+    /// The time of the event is given in the `devTime` field as
+    /// milliseconds since the epoch.
+    pub fn to_leef(&self) -> String {
+        let mut res = format!(
+            "LEEF:1.0|{}|{}|{}|{}|devTime={}\tsev={}\tcat={}",
+            leef_header(VENDOR), leef_header(PRODUCT), leef_header(VERSION),
+            leef_header(self.id), Utc::now().timestamp_millis(),
+            self.severity, leef_value(self.name)
+        );
+        for (key, value) in &self.fields {
+            res.push('\t');
+            res.push_str(key);
+            res.push('=');
+            res.push_str(&leef_value(value));
+        }
+        res
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Escapes a field of a CEF header.
+fn cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+/// Escapes a field of a LEEF header.
+fn leef_header(value: &str) -> String {
+    value.replace('|', " ")
+}
+
+/// Makes a value safe for use in a LEEF attribute.
+///
+/// Attributes are separated by tabs and events by line breaks, so these
+/// are replaced by spaces.
+fn leef_value(value: &str) -> String {
+    value.replace(&['\t', '\n', '\r'][..], " ")
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cef() {
+        let event = SiemEvent::update("rtr", 12, 100, Some((3, 1)));
+        let cef = event.to_cef();
+        assert!(cef.starts_with(
+            "CEF:0|NLnet Labs|RTRTR|"
+        ));
+        assert!(cef.contains("|update|Update published|3|rt="));
+        assert!(cef.ends_with(
+            " unit=rtr serial=12 count=100 announced=3 withdrawn=1"
+        ));
+        assert!(
+            SiemEvent::connection_up("a=b", "x\\y").to_cef().ends_with(
+                " unit=a\\=b remote=x\\\\y"
+            )
+        );
+    }
+
+    #[test]
+    fn leef() {
+        let leef = SiemEvent::filter_drop("filter\tunit", 5).to_leef();
+        assert!(leef.starts_with("LEEF:1.0|NLnet Labs|RTRTR|"));
+        assert!(leef.contains("|filter-drop|devTime="));
+        assert!(leef.ends_with(
+            "\tsev=4\tcat=Items removed by filter\tunit=filter unit\t\
+             dropped=5"
+        ));
+    }
+}
@@ -1,41 +1,98 @@
 //! A target using the HTTP server.
 
+use std::{fmt, fs, io};
 use std::convert::Infallible;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use arc_swap::ArcSwap;
-use futures::stream;
-use hyper::{Body, Method, Request, Response};
-use log::debug;
+use async_stream::stream;
+use futures::{pin_mut, Stream, StreamExt};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use reqwest::Url;
+use reqwest::blocking::Client as HttpClient;
 use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::delay_for;
+use url::form_urlencoded;
 use crate::payload;
-use crate::comms::Link;
-use crate::formats::output;
+use crate::payload::Prefix;
+use crate::comms::{Link, PipelineLatency};
+use crate::formats::{ghostbusters, json, output};
+use crate::http::RequestBody;
 use crate::log::ExitError;
 use crate::manager::Component;
+use crate::net;
+use super::sources::{SourceList, SourcePolicy, Sources};
 
 
 //------------ Target --------------------------------------------------------
 
 /// A target using the HTTP server.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Target {
     path: String,
     format: output::Format,
-    unit: Link,
+
+    /// The units to receive data from in order of precedence.
+    unit: SourceList,
+
+    /// How to pick the unit to serve from.
+    #[serde(rename = "source-policy", default)]
+    source_policy: SourcePolicy,
+
+    /// How many seconds the first unit stays active after startup.
+    ///
+    /// This only applies to [`SourcePolicy::FirstWithData`].
+    #[serde(
+        rename = "source-grace-period",
+        default = "SourcePolicy::default_grace_period"
+    )]
+    source_grace_period: u64,
+
+    /// The maximum prefix length of IPv4 items to serve.
+    #[serde(rename = "max-prefix-len-v4", default)]
+    max_prefix_len_v4: Option<u8>,
+
+    /// The maximum prefix length of IPv6 items to serve.
+    #[serde(rename = "max-prefix-len-v6", default)]
+    max_prefix_len_v6: Option<u8>,
+
+    /// What to serve before the unit has produced data.
+    #[serde(default)]
+    bootstrap: super::Bootstrap,
 }
 
 impl Target {
+    /// The cache lifetime in seconds of the document served while
+    /// bootstrapping.
+    const BOOTSTRAP_MAX_AGE: u64 = 10;
+
     /// Runs the target.
     pub async fn run(
         self, mut component: Component
     ) -> Result<(), ExitError> {
         let source = Source::default();
-        let (path, format, mut unit) = (self.path, self.format, self.unit);
+        let limit = self.limit();
+        let (path, format) = (self.path, self.format);
+        let mut unit = Sources::new(
+            self.unit, self.source_policy,
+            Duration::from_secs(self.source_grace_period), &mut component
+        );
+        let bootstrap = self.bootstrap;
+        let dropped = Arc::new(super::PrefixLenDropped::default());
+        if limit.is_limited() {
+            component.register_metrics(dropped.clone());
+        }
+        let latency = Arc::new(PipelineLatency::default());
+        component.register_metrics(latency.clone());
 
         let http_source = source.clone();
         
         let processor = Arc::new(
-            move |request: &Request<_>| {
+            move |request: &mut Request<_>| {
                 if 
                     request.method() != Method::GET
                     || request.uri().path() != path
@@ -47,13 +104,24 @@ impl Target {
                     Some(
                         Response::builder()
                         .header("Content-Type", format.content_type())
-                        .body(Body::wrap_stream(stream::iter(
+                        .body(Body::wrap_stream(output::SetStream::new(
                             format.stream(update.clone())
-                            .map(Result::<_, Infallible>::Ok)
                         )))
                         .unwrap()
                     )
                 }
+                else if bootstrap == super::Bootstrap::Empty {
+                    Some(
+                        Response::builder()
+                        .header("Content-Type", format.content_type())
+                        .header(
+                            "Cache-Control",
+                            format!("max-age={}", Self::BOOTSTRAP_MAX_AGE)
+                        )
+                        .body(format.bootstrap().into())
+                        .unwrap()
+                    )
+                }
                 else {
                     Some(
                         Response::builder()
@@ -73,16 +141,617 @@ impl Target {
             );
             if let Ok(update) = unit.query().await {
                 debug!(
-                    "Target {}: Got update ({} entries)",
-                    component.name(), update.set().len()
+                    "Target {}: Got update {} with serial {} ({})",
+                    component.name(), update.ids(), update.serial(),
+                    update.summary()
                 );
+                latency.record(&update);
+                source.update(limit.apply(update, &dropped));
+            }
+        }
+    }
+
+    /// Returns the limit of prefix lengths to serve.
+    fn limit(&self) -> super::PrefixLenLimit {
+        super::PrefixLenLimit::new(
+            self.max_prefix_len_v4, self.max_prefix_len_v6
+        )
+    }
+
+    /// Converts the target for evaluating a config.
+    pub fn into_eval(self) -> (Link, super::EvalOutput) {
+        let limit = self.limit();
+        (
+            self.unit.into_first(),
+            super::EvalOutput {
+                format: self.format, covering: false, limit
+            }
+        )
+    }
+}
+
+
+
+//------------ VrpApi --------------------------------------------------------
+
+/// A target answering queries for VRPs using the HTTP server.
+///
+/// A GET request to the target’s path returns all VRPs as JSON. If the
+/// query parameter `prefix` is given, only those VRPs that cover the prefix
+/// are returned, i.e., those relevant for route origin validation of a
+/// route for that prefix.
+///
+/// If a feed of Ghostbusters Records is configured, a GET request to the
+/// contacts path returns the operator contacts for the VRPs as CSV.
+///
+/// A POST request to the ROV statistics path with a JSON array of routes
+/// returns how many of them are valid, invalid, or not found according to
+/// route origin validation against the VRPs.
+///
+/// If a digest path is configured, a GET request to it returns a node of
+/// the set’s [digest tree][payload::digest]. The node is selected via the
+/// query parameter `node` and defaults to the root.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VrpApi {
+    #[serde(default = "VrpApi::default_path")]
+    path: String,
+    unit: Link,
+
+    /// The URL or local path of the GBR feed.
+    #[serde(rename = "gbr-feed", default)]
+    gbr_feed: Option<String>,
+
+    /// How many seconds to wait before refreshing the GBR feed.
+    #[serde(
+        rename = "gbr-refresh",
+        default = "VrpApi::default_gbr_refresh"
+    )]
+    gbr_refresh: u64,
+
+    /// The path for the contacts.
+    #[serde(
+        rename = "contacts-path",
+        default = "VrpApi::default_contacts_path"
+    )]
+    contacts_path: String,
+
+    /// The path for the digest tree.
+    #[serde(rename = "digest-path", default)]
+    digest_path: Option<String>,
+
+    /// The path for the ROV statistics.
+    #[serde(
+        rename = "rov-stats-path",
+        default = "VrpApi::default_rov_stats_path"
+    )]
+    rov_stats_path: String,
+}
+
+impl VrpApi {
+    pub fn default_path() -> String {
+        String::from("/api/v1/vrps")
+    }
+
+    pub fn default_gbr_refresh() -> u64 {
+        3600
+    }
+
+    pub fn default_contacts_path() -> String {
+        String::from("/api/v1/contacts")
+    }
+
+    pub fn default_rov_stats_path() -> String {
+        String::from("/api/v1/rov-stats")
+    }
+
+    /// Converts the target for evaluating a config.
+    pub fn into_eval(self) -> (Link, super::EvalOutput) {
+        (
+            self.unit,
+            super::EvalOutput {
+                format: output::Format::Json, covering: false,
+                limit: Default::default(),
+            }
+        )
+    }
+
+    /// Runs the target.
+    pub async fn run(
+        self, mut component: Component
+    ) -> Result<(), ExitError> {
+        let source = Source::default();
+        let VrpApi {
+            path, mut unit, gbr_feed, gbr_refresh, contacts_path,
+            digest_path, rov_stats_path
+        } = self;
+        let latency = Arc::new(PipelineLatency::default());
+        component.register_metrics(latency.clone());
+        let contacts = gbr_feed.map(|feed| {
+            GbrFeed::spawn(
+                feed, Duration::from_secs(gbr_refresh),
+                component.http_client().clone(), component.outbound(None),
+                component.name().clone()
+            )
+        });
+        let contacts_processor = contacts.map(|contacts| {
+            let processor = Arc::new(Self::contacts_processor(
+                contacts_path, source.clone(), contacts
+            ));
+            component.register_http_resource(processor.clone());
+            processor
+        });
+        let digest_processor = digest_path.map(|digest_path| {
+            let processor = Arc::new(Self::digest_processor(
+                digest_path, source.clone()
+            ));
+            component.register_http_resource(processor.clone());
+            processor
+        });
+        let rov_stats_processor = Arc::new(Self::rov_stats_processor(
+            rov_stats_path, source.clone()
+        ));
+        component.register_http_resource(rov_stats_processor.clone());
+
+        let http_source = source.clone();
+
+        let processor = Arc::new(
+            move |request: &mut Request<_>| {
+                if 
+                    request.method() != Method::GET
+                    || request.uri().path() != path
+                {
+                    return None
+                }
+
+                let prefix = match Self::query_prefix(request) {
+                    Ok(prefix) => prefix,
+                    Err(value) => {
+                        return Some(
+                            Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .header("Content-Type", "text/plain")
+                            .body(
+                                format!("Invalid prefix '{}'.", value).into()
+                            )
+                            .unwrap()
+                        )
+                    }
+                };
+
+                if let Some(set) = http_source.set() {
+                    Some(
+                        Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(Body::wrap_stream(Self::stream(set, prefix)))
+                        .unwrap()
+                    )
+                }
+                else {
+                    Some(
+                        Response::builder()
+                        .status(503)
+                        .header("Content-Type", "text/plain")
+                        .body(
+                            "Initial validation ongoing. Please wait.".into()
+                        )
+                        .unwrap()
+                    )
+                }
+            }
+        );
+        component.register_http_resource(processor.clone());
+
+        // The HTTP resources only hold on to the processors weakly.
+        let _contacts_processor = contacts_processor;
+        let _digest_processor = digest_processor;
+        let _rov_stats_processor = rov_stats_processor;
+
+        loop {
+            if let Ok(update) = unit.query().await {
+                debug!(
+                    "Target {}: Got update {} with serial {} ({})",
+                    component.name(), update.ids(), update.serial(),
+                    update.summary()
+                );
+                latency.record(&update);
                 source.update(update);
             }
         }
     }
+
+    /// Returns the processor for the contacts path.
+    fn contacts_processor(
+        path: String, source: Source, contacts: GbrFeed
+    ) -> impl Fn(&mut Request<Body>) -> Option<Response<Body>> {
+        move |request| {
+            if
+                request.method() != Method::GET
+                || request.uri().path() != path
+            {
+                return None
+            }
+            let set = match source.set() {
+                Some(set) => set,
+                None => {
+                    return Some(
+                        Response::builder()
+                        .status(503)
+                        .header("Content-Type", "text/plain")
+                        .body(
+                            "Initial validation ongoing. Please wait.".into()
+                        )
+                        .unwrap()
+                    )
+                }
+            };
+            let contacts = match contacts.get() {
+                Some(contacts) => contacts,
+                None => {
+                    return Some(match contacts.error() {
+                        Some(err) => {
+                            Response::builder()
+                            .status(StatusCode::BAD_GATEWAY)
+                            .header("Content-Type", "text/plain")
+                            .body(
+                                format!("GBR feed unavailable: {}", err)
+                                .into()
+                            )
+                            .unwrap()
+                        }
+                        None => {
+                            Response::builder()
+                            .status(503)
+                            .header("Content-Type", "text/plain")
+                            .body(
+                                "GBR feed not loaded yet. Please wait."
+                                .into()
+                            )
+                            .unwrap()
+                        }
+                    })
+                }
+            };
+            let mut body = Vec::new();
+            ghostbusters::write_csv(&set, &contacts, &mut body).expect(
+                "writing to vec failed"
+            );
+            Some(
+                Response::builder()
+                .header("Content-Type", "text/csv")
+                .body(body.into())
+                .unwrap()
+            )
+        }
+    }
+
+    /// Returns the processor for the ROV statistics path.
+    fn rov_stats_processor(
+        path: String, source: Source
+    ) -> impl Fn(&mut Request<Body>) -> Option<Response<Body>> {
+        move |request| {
+            if
+                request.method() != Method::POST
+                || request.uri().path() != path
+            {
+                return None
+            }
+            let routes = serde_json::from_slice::<json::Routes>(
+                RequestBody::get(request).unwrap_or_default()
+            );
+            let routes = match routes {
+                Ok(routes) => routes.into_vec(),
+                Err(err) => {
+                    return Some(
+                        Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Type", "text/plain")
+                        .body(format!("Invalid routes: {}", err).into())
+                        .unwrap()
+                    )
+                }
+            };
+            let set = match source.set() {
+                Some(set) => set,
+                None => {
+                    return Some(
+                        Response::builder()
+                        .status(503)
+                        .header("Content-Type", "text/plain")
+                        .body(
+                            "Initial validation ongoing. Please wait.".into()
+                        )
+                        .unwrap()
+                    )
+                }
+            };
+            let stats = set.rov_stats(&routes);
+            Some(
+                Response::builder()
+                .header("Content-Type", "application/json")
+                .body(format!(
+                    "{{\n  \"valid\": {},\n  \"invalid\": {},\n  \
+                     \"notFound\": {}\n}}\n",
+                    stats.valid, stats.invalid, stats.not_found
+                ).into())
+                .unwrap()
+            )
+        }
+    }
+
+    /// Returns the processor for the digest path.
+    ///
+    /// The digest tree is only calculated when requested and then kept
+    /// until the data set changes.
+    fn digest_processor(
+        path: String, source: Source
+    ) -> impl Fn(&mut Request<Body>) -> Option<Response<Body>> {
+        let cache = Mutex::new(
+            None::<(Arc<payload::Set>, Arc<payload::DigestTree>)>
+        );
+        move |request| {
+            if
+                request.method() != Method::GET
+                || request.uri().path() != path
+            {
+                return None
+            }
+            let set = match source.set() {
+                Some(set) => set,
+                None => {
+                    return Some(
+                        Response::builder()
+                        .status(503)
+                        .header("Content-Type", "text/plain")
+                        .body(
+                            "Initial validation ongoing. Please wait.".into()
+                        )
+                        .unwrap()
+                    )
+                }
+            };
+            let tree = {
+                let mut cache = cache.lock().unwrap();
+                match *cache {
+                    Some((ref cached, ref tree))
+                        if Arc::ptr_eq(cached, &set) => tree.clone(),
+                    _ => {
+                        let tree = Arc::new(set.digest_tree());
+                        *cache = Some((set, tree.clone()));
+                        tree
+                    }
+                }
+            };
+            let path = Self::query_param(request, "node").unwrap_or_default();
+            let node = match tree.node(&path) {
+                Some(node) => node,
+                None => {
+                    return Some(
+                        Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Type", "text/plain")
+                        .body(format!("Invalid node '{}'.", path).into())
+                        .unwrap()
+                    )
+                }
+            };
+            Some(
+                Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Self::format_node(&path, node).into())
+                .unwrap()
+            )
+        }
+    }
+
+    /// Formats a node of a digest tree as a JSON object.
+    ///
+    /// Inner nodes contain the digests of their children, buckets contain
+    /// their VRPs.
+    fn format_node(path: &str, node: payload::digest::Node) -> String {
+        let mut res = format!(
+            "{{\n  \"depth\": {},\n  \"node\": \"{}\",\n  \
+             \"digest\": \"{}\",\n",
+            payload::DigestTree::DEPTH, path, node.digest()
+        );
+        if let Some((left, right)) = node.children() {
+            res.push_str(&format!(
+                "  \"children\": [ \"{}\", \"{}\" ]\n}}\n", left, right
+            ));
+        }
+        else {
+            res.push_str("  \"roas\": [\n");
+            // The digest tree doesn’t keep the trust anchors.
+            let items = node.items().unwrap_or(&[]);
+            for (idx, item) in items.iter().enumerate() {
+                res.push_str(&json::format_vrp(
+                    item, payload::Set::UNKNOWN_TA, idx == 0
+                ));
+            }
+            res.push_str("\n  ]\n}\n");
+        }
+        res
+    }
+
+    /// Returns the value of a query parameter of a request, if present.
+    fn query_param(request: &Request<Body>, name: &str) -> Option<String> {
+        let query = request.uri().query()?;
+        form_urlencoded::parse(query.as_bytes()).find(|(key, _)| {
+            key == name
+        }).map(|(_, value)| value.into_owned())
+    }
+
+    /// Returns the prefix given in the query of a request, if any.
+    ///
+    /// If the prefix is invalid, returns its value as the error.
+    fn query_prefix(
+        request: &Request<Body>
+    ) -> Result<Option<Prefix>, String> {
+        let query = match request.uri().query() {
+            Some(query) => query,
+            None => return Ok(None)
+        };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            if key != "prefix" {
+                continue
+            }
+            return match Prefix::from_str(&value) {
+                Ok(prefix) => Ok(Some(prefix)),
+                Err(_) => Err(value.into_owned())
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns a stream with the JSON output for a query.
+    fn stream(
+        set: Arc<payload::Set>, prefix: Option<Prefix>
+    ) -> impl Stream<Item = Result<Vec<u8>, Infallible>> {
+        stream! {
+            yield Ok(b"{\n  \"roas\": [\n".to_vec());
+            let query = set.stream_query(|item| {
+                match prefix {
+                    Some(prefix) => prefix.is_covered_by(item),
+                    None => true
+                }
+            });
+            pin_mut!(query);
+            let mut first = true;
+            while let Some((item, ta)) = query.next().await {
+                yield Ok(json::format_vrp(item, ta, first).into_bytes());
+                first = false;
+            }
+            yield Ok(b"\n  ]\n}\n".to_vec());
+        }
+    }
 }
 
 
+//------------ GbrFeed -------------------------------------------------------
+
+/// The regularly refreshed contacts from a GBR feed.
+#[derive(Clone, Default)]
+struct GbrFeed {
+    /// The contacts of the last successful fetch.
+    data: Arc<ArcSwap<Option<Arc<ghostbusters::Contacts>>>>,
+
+    /// The error of the last fetch if it failed.
+    error: Arc<ArcSwap<Option<String>>>,
+}
+
+impl GbrFeed {
+    /// Starts fetching the feed from `feed` every `refresh`.
+    ///
+    /// The feed can be given as an HTTP or HTTPS URL, a file URL, or a
+    /// local path. If fetching fails, the previous contacts are kept.
+    /// Remote feeds are fetched while holding a permit from `outbound`.
+    fn spawn(
+        feed: String, refresh: Duration, client: HttpClient,
+        outbound: net::Outbound, name: Arc<str>
+    ) -> Self {
+        let res = GbrFeed::default();
+        let (data, error) = (res.data.clone(), res.error.clone());
+        let remote = match Url::parse(&feed) {
+            Ok(url) => url.scheme() == "http" || url.scheme() == "https",
+            Err(_) => false
+        };
+        tokio::spawn(async move {
+            loop {
+                let permit = if remote {
+                    Some(outbound.permit().await)
+                }
+                else {
+                    None
+                };
+                let (feed, client) = (feed.clone(), client.clone());
+                let fetched = spawn_blocking(move || {
+                    Self::fetch(&feed, &client)
+                }).await;
+                drop(permit);
+                match fetched {
+                    Ok(Ok(contacts)) => {
+                        debug!(
+                            "Target {}: loaded {} GBR contacts.",
+                            name, contacts.len()
+                        );
+                        data.store(Some(Arc::new(contacts)).into());
+                        error.store(None.into());
+                    }
+                    Ok(Err(err)) => {
+                        warn!(
+                            "Target {}: failed to load GBR feed: {}",
+                            name, err
+                        );
+                        error.store(Some(err.to_string()).into());
+                    }
+                    Err(_) => return
+                }
+                delay_for(refresh).await;
+            }
+        });
+        res
+    }
+
+    /// Fetches and parses the feed.
+    fn fetch(
+        feed: &str, client: &HttpClient
+    ) -> Result<ghostbusters::Contacts, FetchError> {
+        match Url::parse(feed) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                let response = client.get(url).send().and_then(|response| {
+                    response.error_for_status()
+                }).map_err(FetchError::Http)?;
+                ghostbusters::Contacts::from_json(response).map_err(
+                    FetchError::Parse
+                )
+            }
+            Ok(url) if url.scheme() == "file" => {
+                let file = fs::File::open(url.path()).map_err(
+                    FetchError::Io
+                )?;
+                ghostbusters::Contacts::from_json(io::BufReader::new(file))
+                    .map_err(FetchError::Parse)
+            }
+            _ => {
+                let file = fs::File::open(feed).map_err(FetchError::Io)?;
+                ghostbusters::Contacts::from_json(io::BufReader::new(file))
+                    .map_err(FetchError::Parse)
+            }
+        }
+    }
+
+    /// Returns the current contacts if there are any.
+    fn get(&self) -> Option<Arc<ghostbusters::Contacts>> {
+        (**self.data.load()).clone()
+    }
+
+    /// Returns the error of the last fetch if it failed.
+    fn error(&self) -> Option<String> {
+        (**self.error.load()).clone()
+    }
+}
+
+
+//------------ FetchError ----------------------------------------------------
+
+/// Fetching the GBR feed failed.
+#[derive(Debug)]
+enum FetchError {
+    Io(io::Error),
+    Http(reqwest::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FetchError::Io(ref err) => err.fmt(f),
+            FetchError::Http(ref err) => err.fmt(f),
+            FetchError::Parse(ref err) => err.fmt(f),
+        }
+    }
+}
+
 
 //------------ Source --------------------------------------------------------
 
@@ -1,8 +1,9 @@
 //! The targets for RPKI data.
 //!
 //! A target is anything that produces the final output from payload data.
-//! Each target is connected to exactly one unit and constantly converts its
-//! payload set into some form of output.
+//! Each target is connected to one unit and constantly converts its payload
+//! set into some form of output. The rtr and http targets can also be
+//! given a list of units to fall back on, see the [`sources`] module.
 //!
 //! This module contains all the different kinds of targets currently
 //! available. It provides access to them via the enum [`Target`] that
@@ -17,13 +18,25 @@
 // These contain all the actual unit types grouped by shared functionality.
 mod http;
 mod rtr;
+mod sources;
+mod throttle;
 
 
 //------------ Target --------------------------------------------------------
 
+use std::{fs, io};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rpki_rtr::payload::Payload;
 use serde::Deserialize;
+use crate::{metrics, payload};
+use crate::comms::Link;
+use crate::formats::output;
 use crate::log::ExitError;
 use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
 
 
 /// The component for outputting data.
@@ -35,15 +48,302 @@ pub enum Target {
 
     #[serde(rename = "http")]
     Http(http::Target),
+
+    #[serde(rename = "vrp-api")]
+    VrpApi(http::VrpApi),
 }
 
 impl Target {
+    /// Binds any listening sockets the target needs.
+    ///
+    /// This happens before the runtime is started and privileges are
+    /// dropped.
+    pub fn bind(&mut self) -> Result<(), ExitError> {
+        match *self {
+            Target::RtrTcp(ref mut target) => target.bind(),
+            Target::Http(_) | Target::VrpApi(_) => Ok(()),
+        }
+    }
+
+    /// Returns the number of listening sockets the target will have.
+    pub fn listeners(&self) -> usize {
+        match *self {
+            Target::RtrTcp(ref target) => target.listeners(),
+            Target::Http(_) | Target::VrpApi(_) => 0,
+        }
+    }
+
     /// Runs the target.
     pub async fn run(self, component: Component) -> Result<(), ExitError> {
         match self {
             Target::RtrTcp(target) => target.run(component).await,
             Target::Http(target) => target.run(component).await,
+            Target::VrpApi(target) => target.run(component).await,
+        }
+    }
+
+    /// Converts the target into what it needs for evaluating a config.
+    ///
+    /// Returns the link to the target’s unit and what the target’s output
+    /// would be.
+    pub fn into_eval(self) -> (Link, EvalOutput) {
+        match self {
+            Target::RtrTcp(target) => target.into_eval(),
+            Target::Http(target) => target.into_eval(),
+            Target::VrpApi(target) => target.into_eval(),
+        }
+    }
+}
+
+
+//------------ EvalOutput ----------------------------------------------------
+
+/// The output of a target when evaluating a config.
+///
+/// Targets that don’t have a configurable format produce JSON. For the RTR
+/// target, this is the data set it would serve.
+#[derive(Clone, Copy, Debug)]
+pub struct EvalOutput {
+    /// The format of the output.
+    format: output::Format,
+
+    /// Whether the target serves the covering set of the data.
+    covering: bool,
+
+    /// The limit of prefix lengths the target serves.
+    limit: PrefixLenLimit,
+}
+
+impl EvalOutput {
+    /// Returns the file name extension for the output.
+    pub fn extension(self) -> &'static str {
+        self.format.extension()
+    }
+
+    /// Writes the output for a data set to the file at `path`.
+    pub fn write(
+        self, set: Arc<payload::Set>, path: &Path
+    ) -> Result<(), io::Error> {
+        let set = if self.limit.is_limited() {
+            Arc::new(self.limit.apply_set(&set))
+        }
+        else {
+            set
+        };
+        let set = if self.covering {
+            Arc::new(set.covering())
+        }
+        else {
+            set
+        };
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for chunk in self.format.stream(set) {
+            file.write_all(&chunk)?;
+        }
+        file.flush()
+    }
+}
+
+
+//------------ PrefixLenLimit ------------------------------------------------
+
+/// The maximum prefix length a target serves for each address family.
+///
+/// Items with a longer prefix are dropped when serving. Because each item
+/// is looked at on its own, the diffs of an update are filtered the same
+/// way as the set, so clients never see a withdrawal for an item they
+/// haven’t been sent before.
+///
+/// The limit is part of the target’s configuration and thus fixed for the
+/// lifetime of the target. A changed limit only takes effect with a new
+/// target which starts out without any history of diffs and, for the RTR
+/// target, with a new session, so clients will have to start over with a
+/// reset query.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrefixLenLimit {
+    /// The maximum prefix length of IPv4 items.
+    v4: Option<u8>,
+
+    /// The maximum prefix length of IPv6 items.
+    v6: Option<u8>,
+}
+
+impl PrefixLenLimit {
+    /// Creates a new limit from the maximum lengths for each family.
+    ///
+    /// The lengths are given by targets via their `max-prefix-len-v4` and
+    /// `max-prefix-len-v6` options.
+    pub fn new(v4: Option<u8>, v6: Option<u8>) -> Self {
+        PrefixLenLimit { v4, v6 }
+    }
+
+    /// Returns whether there is a limit for any address family.
+    pub fn is_limited(self) -> bool {
+        self.v4.is_some() || self.v6.is_some()
+    }
+
+    /// Returns whether an item is within the limit.
+    pub fn keep(self, item: &Payload) -> bool {
+        let (len, max) = match *item {
+            Payload::V4(ref item) => (item.prefix_len, self.v4),
+            Payload::V6(ref item) => (item.prefix_len, self.v6),
+        };
+        match max {
+            Some(max) => len <= max,
+            None => true
+        }
+    }
+
+    /// Returns a set with only the items within the limit.
+    pub fn apply_set(self, set: &payload::Set) -> payload::Set {
+        set.filter(|item| self.keep(item))
+    }
+
+    /// Returns an update with only the items within the limit.
+    ///
+    /// The number of items dropped from the update’s set is recorded in
+    /// `dropped`.
+    pub fn apply(
+        self, update: payload::Update, dropped: &PrefixLenDropped
+    ) -> payload::Update {
+        if !self.is_limited() {
+            return update
         }
+        let (mut v4, mut v6) = (0, 0);
+        let set = update.set().filter(|item| {
+            if self.keep(item) {
+                return true
+            }
+            match *item {
+                Payload::V4(_) => v4 += 1,
+                Payload::V6(_) => v6 += 1,
+            }
+            false
+        });
+        dropped.v4.store(v4, Ordering::Relaxed);
+        dropped.v6.store(v6, Ordering::Relaxed);
+        payload::Update::new(
+            update.serial(),
+            Arc::new(set),
+            update.diff().map(|diff| {
+                Arc::new(diff.filter(|item| self.keep(item)))
+            })
+        ).with_audit_only(
+            update.is_audit_only()
+        ).with_ids(update.ids()).with_origin(update.origin())
     }
 }
 
+
+//------------ PrefixLenDropped ----------------------------------------------
+
+/// The number of items currently dropped by a prefix length limit.
+#[derive(Debug, Default)]
+pub struct PrefixLenDropped {
+    /// The number of dropped IPv4 items.
+    v4: AtomicUsize,
+
+    /// The number of dropped IPv6 items.
+    v6: AtomicUsize,
+}
+
+impl PrefixLenDropped {
+    const DROPPED_METRIC: Metric = Metric::new(
+        "prefix_len_dropped",
+        "the number of items not served because of their prefix length",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for PrefixLenDropped {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append(&Self::DROPPED_METRIC, Some(unit_name), |records| {
+            records.label_value(
+                &[("af", "ipv4")], self.v4.load(Ordering::Relaxed)
+            );
+            records.label_value(
+                &[("af", "ipv6")], self.v6.load(Ordering::Relaxed)
+            );
+        });
+    }
+}
+
+
+//------------ Bootstrap -----------------------------------------------------
+
+/// What a target serves before its unit has produced any data.
+///
+/// With [`Bootstrap::Empty`], the HTTP target serves a document without
+/// any items that is marked as bootstrapping while the RTR target serves an
+/// empty data set. Once the unit produces data, it replaces the empty set
+/// just like any other update.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum Bootstrap {
+    /// Tell clients that there is no data yet.
+    #[serde(rename = "unavailable")]
+    Unavailable,
+
+    /// Serve an empty data set.
+    #[serde(rename = "empty")]
+    Empty,
+}
+
+impl Default for Bootstrap {
+    fn default() -> Self {
+        Bootstrap::Unavailable
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use rpki_rtr::state::Serial;
+    use crate::payload::testing::{v4, v6};
+    use super::*;
+
+    fn set(items: &[Payload]) -> payload::Set {
+        let mut set = payload::SetBuilder::empty();
+        for item in items {
+            set.insert(*item).unwrap();
+        }
+        set.finalize()
+    }
+
+    fn items(set: &payload::Set) -> Vec<Payload> {
+        set.iter().cloned().collect()
+    }
+
+    #[test]
+    fn prefix_len_limit() {
+        let v4 = |len| v4([192, 0, 2, 0], len, 32, 64496);
+        let v6 = |len| v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], len, 128, 64496);
+        let limit = PrefixLenLimit::new(Some(24), Some(48));
+        let dropped = PrefixLenDropped::default();
+        let old = set(&[v4(24), v4(32), v6(32)]);
+        let new = set(&[v4(24), v4(25), v4(32), v6(48), v6(128)]);
+
+        let update = limit.apply(
+            payload::Update::new(
+                Serial::from(2), Arc::new(new.clone()),
+                Some(Arc::new(new.diff_from(&old)))
+            ),
+            &dropped
+        );
+        assert_eq!(items(&update.set()), items(&set(&[v4(24), v6(48)])));
+        assert_eq!(dropped.v4.load(Ordering::Relaxed), 2);
+        assert_eq!(dropped.v6.load(Ordering::Relaxed), 1);
+
+        // The diff only touches items that were served before.
+        let old = limit.apply_set(&old);
+        let diff = update.diff().unwrap();
+        assert!(diff.iter().all(|&(item, _)| limit.keep(&item)));
+        assert_eq!(items(&diff.apply(&old)), items(&update.set()));
+
+        // Without a limit, nothing changes.
+        let limit = PrefixLenLimit::default();
+        assert!(!limit.is_limited());
+        assert_eq!(items(&limit.apply_set(&new)), items(&new));
+    }
+}
@@ -1,63 +1,447 @@
 /// RTR servers as a target.
 
-use std::cmp;
-use std::sync::Arc;
+use std::{cmp, fs, io, mem};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::net::SocketAddr;
 use std::net::TcpListener as StdTcpListener;
+use std::path::PathBuf;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 use arc_swap::ArcSwap;
-use log::{debug, error};
+use crossbeam_utils::atomic::AtomicCell;
+use futures::{ready, Sink, Stream, StreamExt};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::upgrade::Upgraded;
+use log::{debug, error, info, warn};
 use serde::Deserialize;
 use rpki_rtr::payload::Timing;
 use rpki_rtr::server::{NotifySender, Server, VrpSource};
 use rpki_rtr::state::{Serial, State};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use crate::payload;
-use crate::comms::Link;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{block_in_place, spawn_blocking};
+use tokio::time::timeout_at;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    create_response, Request as HandshakeRequest
+};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use crate::{metrics, payload};
+use crate::metrics::{Histogram, Metric, MetricType, MetricUnit};
+use crate::comms::{Link, PipelineLatency, UnitStatus};
+use crate::formats::{json, output};
+use crate::http::ProcessRequest;
 use crate::log::ExitError;
 use crate::manager::Component;
+use super::sources::{SourceList, SourcePolicy, Sources};
+use super::throttle::{Throttle, ThrottledStream};
 
 
 //------------ Tcp -----------------------------------------------------------
 
 /// An RTR server atop unencrypted, plain TCP.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Tcp {
     listen: Vec<SocketAddr>,
-    unit: Link,
+
+    /// The path on the HTTP server for serving RTR over WebSocket.
+    ///
+    /// If this is `None`, RTR is only served via the TCP listeners.
+    #[serde(rename = "websocket-path", default)]
+    websocket_path: Option<String>,
+
+    /// The maximum number of diffs to keep for serial queries.
+    #[serde(rename = "history-size", default = "Tcp::default_history_size")]
+    history_size: usize,
+
+    /// The maximum age in seconds of diffs to keep for serial queries.
+    ///
+    /// If this is `None`, diffs are only limited by `history_size`.
+    #[serde(rename = "history-age", default)]
+    history_age: Option<u64>,
+
+    /// How diffs are kept for serial queries.
+    #[serde(rename = "history-mode", default)]
+    history_mode: HistoryMode,
+
+    /// Whether an update without changes still advances the serial.
+    ///
+    /// This only happens if the unit’s serial has advanced, which needs
+    /// the unit to forward empty updates in the first place.
+    #[serde(rename = "forward-empty-updates", default)]
+    forward_empty_updates: bool,
+
+    /// The directory to keep the diffs for serial queries in.
+    ///
+    /// If this is `None`, the diffs are kept in memory.
+    #[serde(rename = "checkpoint-dir", default)]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// The minimum number of entries before data is served.
+    #[serde(rename = "min-ready-entries", default)]
+    min_ready_entries: Option<usize>,
+
+    /// The number of seconds the unit must be healthy before data is served.
+    #[serde(rename = "min-ready-healthy", default)]
+    min_ready_healthy: Option<u64>,
+
+    /// What to do while the target is not ready yet.
+    #[serde(rename = "not-ready", default)]
+    not_ready: NotReady,
+
+    /// What to serve before the unit has produced data.
+    ///
+    /// This only has an effect if `not_ready` is [`NotReady::NoData`].
+    #[serde(default)]
+    bootstrap: super::Bootstrap,
+
+    /// The size of the buffer for batching PDUs in bytes.
+    ///
+    /// If this is zero, each PDU is written to the socket immediately.
+    #[serde(rename = "write-buffer", default = "Tcp::default_write_buffer")]
+    write_buffer: usize,
+
+    /// The maximum rate of each connection in bytes per second.
+    ///
+    /// Only payload PDUs count towards the limit. If this is `None`,
+    /// connections are not limited.
+    #[serde(rename = "connection-rate-limit", default)]
+    connection_rate_limit: Option<u64>,
+
+    /// The maximum rate of all connections together in bytes per second.
+    ///
+    /// Only payload PDUs count towards the limit. If this is `None`,
+    /// there is no overall limit.
+    #[serde(rename = "aggregate-rate-limit", default)]
+    aggregate_rate_limit: Option<u64>,
+
+    /// Whether to serve the covering set of the unit’s data.
+    ///
+    /// The covering set leaves out items already covered by a less specific
+    /// item for the same origin AS. See [`payload::Set::covering`] for
+    /// details. The unit’s data itself is not affected, so other targets
+    /// still see the complete set.
+    #[serde(rename = "covering-set", default)]
+    covering_set: bool,
+
+    /// The maximum prefix length of IPv4 items to serve.
+    ///
+    /// The limit is applied before the covering set is determined.
+    #[serde(rename = "max-prefix-len-v4", default)]
+    max_prefix_len_v4: Option<u8>,
+
+    /// The maximum prefix length of IPv6 items to serve.
+    #[serde(rename = "max-prefix-len-v6", default)]
+    max_prefix_len_v6: Option<u8>,
+
+    /// The file with the BGP routes for determining the ROV coverage.
+    ///
+    /// If this is `None`, the coverage is not determined.
+    #[serde(rename = "bgp-routes", default)]
+    bgp_routes: Option<PathBuf>,
+
+    /// The ROV coverage below which a warning is logged.
+    #[serde(rename = "rov-coverage-threshold", default)]
+    rov_coverage_threshold: Option<f64>,
+
+    /// The units to receive data from in order of precedence.
+    unit: SourceList,
+
+    /// How to pick the unit to serve from.
+    #[serde(rename = "source-policy", default)]
+    source_policy: SourcePolicy,
+
+    /// How many seconds the first unit stays active after startup.
+    ///
+    /// This only applies to [`SourcePolicy::FirstWithData`].
+    #[serde(
+        rename = "source-grace-period",
+        default = "SourcePolicy::default_grace_period"
+    )]
+    source_grace_period: u64,
+
+    /// The listeners bound via `bind`.
+    #[serde(skip)]
+    bound: Vec<(SocketAddr, StdTcpListener)>,
 }
 
 impl Tcp {
+    /// The default for the maximum number of diffs to keep.
+    pub fn default_history_size() -> usize {
+        10
+    }
+
+    /// The default size of the write buffer.
+    pub fn default_write_buffer() -> usize {
+        16_384
+    }
+
+    /// Returns the number of listening sockets.
+    pub fn listeners(&self) -> usize {
+        self.listen.len()
+    }
+
+    /// Binds the listening sockets.
+    ///
+    /// This needs to happen before privileges are dropped.
+    pub fn bind(&mut self) -> Result<(), ExitError> {
+        for &addr in &self.listen {
+            match StdTcpListener::bind(addr) {
+                Ok(listener) => self.bound.push((addr, listener)),
+                Err(err) => {
+                    error!("Can’t bind to {}: {}", addr, err);
+                    return Err(ExitError)
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Runs the target.
-    pub async fn run(mut self, component: Component) -> Result<(), ExitError> {
+    ///
+    /// The listening sockets must have been bound via
+    /// [`bind`](Self::bind) before.
+    pub async fn run(
+        mut self, mut component: Component
+    ) -> Result<(), ExitError> {
         let mut notify = NotifySender::new();
-        let target = Source::default();
-        for &addr in &self.listen {
-            self.spawn_listener(addr, target.clone(), notify.clone())?;
+        let mut target = Source::new(
+            self.history_size, self.history_age.map(Duration::from_secs)
+        ).with_history_mode(
+            self.history_mode
+        ).with_forward_empty(self.forward_empty_updates);
+        if let Some(dir) = self.checkpoint_dir.take() {
+            target = match target.with_checkpoints(dir.clone()) {
+                Ok(target) => target,
+                Err(err) => {
+                    error!(
+                        "Target {}: cannot prepare checkpoint directory \
+                         {}: {}",
+                        component.name(), dir.display(), err
+                    );
+                    return Err(ExitError)
+                }
+            };
+        }
+        // As with HTTP resources, only a weak reference to the metrics is
+        // kept.
+        let _metrics = Arc::new(target.clone());
+        component.register_metrics(_metrics.clone());
+        let dropped = Arc::new(super::PrefixLenDropped::default());
+        let limit = self.limit();
+        if limit.is_limited() {
+            component.register_metrics(dropped.clone());
         }
+        let latency = Arc::new(PipelineLatency::default());
+        component.register_metrics(latency.clone());
+        let throttle = Arc::new(Throttle::new(
+            self.connection_rate_limit, self.aggregate_rate_limit
+        ));
+        component.register_metrics(throttle.clone());
+        let coverage = self.bgp_routes.take().map(|path| {
+            RovCoverage::new(
+                component.name().clone(), path, self.rov_coverage_threshold,
+                target.coverage.clone()
+            ).spawn()
+        });
 
+        // The HTTP server only keeps a weak reference to the bridge, so we
+        // need to hold on to it.
+        let mut _bridge = None;
+        if self.not_ready == NotReady::NoData {
+            if self.bootstrap == super::Bootstrap::Empty {
+                target.bootstrap();
+            }
+            _bridge = self.serve(
+                &mut component, &target, &notify, &throttle
+            )?;
+        }
+
+        let mut unit = Sources::new(
+            mem::take(&mut self.unit), self.source_policy,
+            Duration::from_secs(self.source_grace_period), &mut component
+        );
+        let mut readiness = Readiness::new(
+            self.min_ready_entries,
+            self.min_ready_healthy.map(Duration::from_secs),
+            Instant::now(),
+        );
+        let mut pending = None;
+        let mut reset = false;
+        let mut audit_only = false;
         loop {
-            if let Ok(update) = self.unit.query().await {
-                debug!(
-                    "Target {}: Got update ({} entries)",
+            // Only wait for the deadline if there is data to serve.
+            let deadline = match pending {
+                Some(_) => readiness.deadline(),
+                None => None
+            };
+            let res = match deadline {
+                Some(deadline) => {
+                    timeout_at(deadline.into(), unit.query()).await.ok()
+                }
+                None => Some(unit.query().await)
+            };
+            match res {
+                Some(Ok(update)) => {
+                    // Serials of different units aren’t comparable, so
+                    // clients need to start over with a new session.
+                    if unit.take_switch() {
+                        reset = true;
+                    }
+                    debug!(
+                        "Target {}: Got update {} with serial {} ({})",
+                        component.name(), update.ids(), update.serial(),
+                        update.summary()
+                    );
+                    if update.is_audit_only() {
+                        if !audit_only {
+                            warn!(
+                                "Target {}: source unit provides data for \
+                                 auditing only. Not serving it.",
+                                component.name()
+                            );
+                            audit_only = true;
+                        }
+                        continue
+                    }
+                    audit_only = false;
+                    latency.record(&update);
+                    let update = limit.apply(update, &dropped);
+                    if let Some(ref coverage) = coverage {
+                        // This only fails if the task has panicked.
+                        let _ = coverage.broadcast(Some(update.set()));
+                    }
+                    // Looking for redundant items may take a while. The
+                    // task only fails if the closure panics in which case
+                    // we should panic, too.
+                    let covering = self.covering_set;
+                    let (update, redundant) = spawn_blocking(move || {
+                        Self::prepare(update, covering)
+                    }).await.unwrap();
+                    target.redundant.store(redundant, Ordering::Relaxed);
+                    pending = Some(update);
+                }
+                Some(Err(status)) => {
+                    readiness.set_status(status, Instant::now());
+                }
+                None => { }
+            }
+
+            let update = match pending.take() {
+                Some(update) => update,
+                None => continue
+            };
+            if !readiness.is_ready() {
+                if !readiness.check(update.set().len(), Instant::now()) {
+                    pending = Some(update);
+                    continue
+                }
+                info!(
+                    "Target {}: ready with {} entries.",
                     component.name(), update.set().len()
                 );
-                target.update(update);
-                notify.notify()
+                if self.not_ready == NotReady::Delay {
+                    _bridge = self.serve(
+                        &mut component, &target, &notify, &throttle
+                    )?;
+                }
+            }
+            let reset = mem::replace(&mut reset, false);
+            if target.checkpoints.is_some() {
+                // Writing the checkpoints may take a while.
+                let target = target.clone();
+                let _ = spawn_blocking(move || {
+                    target.apply(update, reset)
+                }).await;
+            }
+            else {
+                target.apply(update, reset);
+            }
+            notify.notify()
+        }
+    }
+
+    /// Returns the limit of prefix lengths to serve.
+    fn limit(&self) -> super::PrefixLenLimit {
+        super::PrefixLenLimit::new(
+            self.max_prefix_len_v4, self.max_prefix_len_v6
+        )
+    }
+
+    /// Converts the target for evaluating a config.
+    pub fn into_eval(self) -> (Link, super::EvalOutput) {
+        let limit = self.limit();
+        (
+            self.unit.into_first(),
+            super::EvalOutput {
+                format: output::Format::Json,
+                covering: self.covering_set,
+                limit,
             }
+        )
+    }
+
+    /// Prepares an update for serving.
+    ///
+    /// If `covering` is true, the update is converted into one for its
+    /// covering set. Returns the update and the number of redundant items
+    /// in its original set.
+    fn prepare(
+        update: payload::Update, covering: bool
+    ) -> (payload::Update, usize) {
+        if !covering {
+            let redundant = update.set().count_redundant_vrps();
+            return (update, redundant)
         }
+        // The covering set is exactly the set without its redundant items.
+        let len = update.set().len();
+        let update = Self::covering(update);
+        let redundant = len - update.set().len();
+        (update, redundant)
+    }
+
+    /// Converts an update into one for its covering set.
+    ///
+    /// The diff of the update is not valid for the covering set, so it is
+    /// dropped.
+    fn covering(update: payload::Update) -> payload::Update {
+        payload::Update::new(
+            update.serial(), Arc::new(update.set().covering()), None
+        ).with_ids(update.ids()).with_origin(update.origin())
+    }
+
+    /// Starts serving RTR on all listeners and the WebSocket path.
+    ///
+    /// Returns the WebSocket bridge if there is one.
+    fn serve(
+        &mut self, component: &mut Component,
+        target: &Source, notify: &NotifySender, throttle: &Arc<Throttle>,
+    ) -> Result<Option<Arc<WebSocketBridge>>, ExitError> {
+        for (addr, listener) in self.bound.drain(..) {
+            Self::spawn_listener(
+                addr, listener, self.write_buffer,
+                target.clone(), notify.clone(), throttle.clone()
+            )?;
+        }
+        let write_buffer = self.write_buffer;
+        Ok(self.websocket_path.take().map(|path| {
+            Self::spawn_websocket(
+                path, write_buffer, component, target.clone(), notify.clone(),
+                throttle.clone()
+            )
+        }))
     }
 
     /// Spawns a single listener onto the current runtime.
     fn spawn_listener(
-        &self, addr: SocketAddr, target: Source, notify: NotifySender,
+        addr: SocketAddr, listener: StdTcpListener, write_buffer: usize,
+        target: Source, notify: NotifySender, throttle: Arc<Throttle>,
     ) -> Result<(), ExitError> {
-        let listener = match StdTcpListener::bind(addr) {
-            Ok(listener) => listener,
-            Err(err) => {
-                error!("Can’t bind to {}: {}", addr, err);
-                return Err(ExitError)
-            }
-        };
         let mut listener = match TcpListener::from_std(listener) {
             Ok(listener) => listener,
             Err(err) => {
@@ -65,8 +449,21 @@ impl Tcp {
                 return Err(ExitError)
             }
         };
+        let responses = target.responses.clone();
         tokio::spawn(async move {
-            let listener = listener.incoming();
+            let listener = listener.incoming().map(move |sock| {
+                sock.map(|sock| {
+                    // We do our own batching, so we don’t need Nagle’s
+                    // algorithm delaying the last bit of a response.
+                    let _ = sock.set_nodelay(true);
+                    ThrottledStream::new(
+                        BatchedStream::new(
+                            sock, write_buffer, responses.clone()
+                        ),
+                        throttle.clone()
+                    )
+                })
+            });
             let server = Server::new(listener, notify, target);
             if server.run().await.is_err() {
                 error!("Fatal error listening on {}.", addr);
@@ -75,21 +472,617 @@ impl Tcp {
         Ok(())
     }
 
+    /// Spawns an RTR server for connections arriving via WebSocket.
+    ///
+    /// Returns the bridge that has been registered with the HTTP server at
+    /// `path`.
+    fn spawn_websocket(
+        path: String, write_buffer: usize, component: &mut Component,
+        target: Source, notify: NotifySender, throttle: Arc<Throttle>,
+    ) -> Arc<WebSocketBridge> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let bridge = Arc::new(WebSocketBridge { path, sockets: tx });
+        component.register_http_resource(bridge.clone());
+        let name = component.name().clone();
+        let responses = target.responses.clone();
+        tokio::spawn(async move {
+            let server = Server::new(
+                rx.map(move |sock| {
+                    Ok::<_, io::Error>(ThrottledStream::new(
+                        BatchedStream::new(
+                            sock, write_buffer, responses.clone()
+                        ),
+                        throttle.clone()
+                    ))
+                }),
+                notify, target
+            );
+            if server.run().await.is_err() {
+                error!("Target {}: Fatal error in WebSocket server.", name);
+            }
+        });
+        bridge
+    }
+}
+
+
+//------------ NotReady ------------------------------------------------------
+
+/// What the target does while it is not ready yet.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum NotReady {
+    /// Accept connections but answer with No Data Available.
+    #[serde(rename = "no-data")]
+    NoData,
+
+    /// Don’t accept connections until ready.
+    ///
+    /// The sockets are bound at startup, so the system will queue incoming
+    /// connections until they are accepted.
+    #[serde(rename = "delay")]
+    Delay,
+}
+
+impl Default for NotReady {
+    fn default() -> Self {
+        NotReady::NoData
+    }
+}
+
+
+//------------ HistoryMode ---------------------------------------------------
+
+/// How the target keeps diffs for serial queries.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum HistoryMode {
+    /// Keep a diff from each past serial to the current serial.
+    ///
+    /// All diffs are updated with every new serial, so serial queries are
+    /// answered right away.
+    #[serde(rename = "cumulative")]
+    Cumulative,
+
+    /// Keep the diff from each past serial to the next one.
+    ///
+    /// The diffs are composed into a single diff when answering a serial
+    /// query. Each change is only kept once, which needs less memory for
+    /// a long history at the cost of work for each query.
+    #[serde(rename = "compose")]
+    Compose,
+}
+
+impl Default for HistoryMode {
+    fn default() -> Self {
+        HistoryMode::Cumulative
+    }
+}
+
+
+//------------ Readiness -----------------------------------------------------
+
+/// Tracks whether the target is ready to serve data.
+///
+/// The target is ready once the source unit has produced data and either
+/// of the configured conditions is met: the data has at least a minimum
+/// number of entries or the unit has been healthy for a minimum time. If no
+/// condition is configured, the first data makes the target ready. Once
+/// ready, the target stays ready.
+#[derive(Clone, Debug)]
+struct Readiness {
+    /// The minimum number of entries.
+    min_entries: Option<usize>,
+
+    /// The minimum time the unit needs to be healthy.
+    min_healthy: Option<Duration>,
+
+    /// Since when the unit has been healthy.
+    healthy_since: Option<Instant>,
+
+    /// Whether the target has become ready.
+    ready: bool,
+}
+
+impl Readiness {
+    /// Creates a new value for a unit that is healthy at `now`.
+    ///
+    /// This is the initial status of all units.
+    fn new(
+        min_entries: Option<usize>, min_healthy: Option<Duration>,
+        now: Instant,
+    ) -> Self {
+        Readiness {
+            min_entries, min_healthy,
+            healthy_since: Some(now),
+            ready: false,
+        }
+    }
+
+    /// Returns whether the target has become ready.
+    fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Updates the status of the unit.
+    fn set_status(&mut self, status: UnitStatus, now: Instant) {
+        if status != UnitStatus::Healthy {
+            self.healthy_since = None
+        }
+        else if self.healthy_since.is_none() {
+            self.healthy_since = Some(now)
+        }
+    }
+
+    /// Checks whether data with `entries` entries makes the target ready.
+    fn check(&mut self, entries: usize, now: Instant) -> bool {
+        if self.ready {
+            return true
+        }
+        let entries_ok = self.min_entries.map(|min| entries >= min);
+        let healthy_ok = self.min_healthy.map(|min| {
+            match self.healthy_since {
+                Some(since) => now.saturating_duration_since(since) >= min,
+                None => false
+            }
+        });
+        self.ready = match (entries_ok, healthy_ok) {
+            (None, None) => true,
+            (Some(entries), None) => entries,
+            (None, Some(healthy)) => healthy,
+            (Some(entries), Some(healthy)) => entries || healthy,
+        };
+        self.ready
+    }
+
+    /// Returns when the unit will have been healthy long enough.
+    ///
+    /// Returns `None` if the target is ready already or the time doesn’t
+    /// matter.
+    fn deadline(&self) -> Option<Instant> {
+        if self.ready {
+            return None
+        }
+        match (self.healthy_since, self.min_healthy) {
+            (Some(since), Some(min)) => Some(since + min),
+            _ => None
+        }
+    }
+}
+
+
+//------------ WebSocketBridge -----------------------------------------------
+
+/// Accepts RTR connections via WebSocket on the HTTP server.
+///
+/// Requests to the configured path are upgraded to WebSocket and the
+/// resulting sockets are handed to the RTR server.
+struct WebSocketBridge {
+    /// The path of the WebSocket endpoint.
+    path: String,
+
+    /// The sender for handing new sockets to the RTR server.
+    sockets: mpsc::UnboundedSender<WsStream>,
+}
+
+impl WebSocketBridge {
+    /// Produces the response for a failed WebSocket handshake.
+    fn bad_request(err: WsError) -> Response<Body> {
+        Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header("Content-Type", "text/plain")
+        .body(format!("{}", err).into())
+        .unwrap()
+    }
+}
+
+impl ProcessRequest for WebSocketBridge {
+    fn process_request(
+        &self, request: &mut Request<Body>
+    ) -> Option<Response<Body>> {
+        if request.uri().path() != self.path {
+            return None
+        }
+
+        let mut handshake = HandshakeRequest::new(());
+        *handshake.method_mut() = request.method().clone();
+        *handshake.version_mut() = request.version();
+        *handshake.uri_mut() = request.uri().clone();
+        *handshake.headers_mut() = request.headers().clone();
+        let response = match create_response(&handshake) {
+            Ok(response) => response,
+            Err(err) => return Some(Self::bad_request(err))
+        };
+
+        let upgrade = mem::replace(request.body_mut(), Body::empty());
+        let sockets = self.sockets.clone();
+        tokio::spawn(async move {
+            match upgrade.on_upgrade().await {
+                Ok(sock) => {
+                    let sock = WebSocketStream::from_raw_socket(
+                        sock, Role::Server, None
+                    ).await;
+                    let _ = sockets.send(WsStream::new(sock));
+                }
+                Err(err) => {
+                    debug!("WebSocket upgrade failed: {}", err);
+                }
+            }
+        });
+        Some(response.map(|_| Body::empty()))
+    }
+}
+
+
+//------------ WsStream ------------------------------------------------------
+
+/// A WebSocket connection used as a byte stream.
+///
+/// Data is read from binary messages and each write is sent as a binary
+/// message of its own. Text messages are considered an error.
+struct WsStream {
+    /// The WebSocket.
+    ///
+    /// The RTR server requires its sockets to be `Sync` which the upgraded
+    /// HTTP connection isn’t. Since the mutex is only ever accessed through
+    /// a mutable reference, it is never actually locked.
+    sock: Mutex<WebSocketStream<Upgraded>>,
+
+    /// The data of the last binary message received.
+    read_buf: Vec<u8>,
+
+    /// The position of the remaining data in `read_buf`.
+    read_pos: usize,
+}
+
+impl WsStream {
+    /// Creates a new stream from a WebSocket.
+    fn new(sock: WebSocketStream<Upgraded>) -> Self {
+        WsStream {
+            sock: Mutex::new(sock),
+            read_buf: Vec::new(),
+            read_pos: 0,
+        }
+    }
+
+    /// Returns a pinned reference to the WebSocket.
+    fn sock(&mut self) -> Pin<&mut WebSocketStream<Upgraded>> {
+        // The mutex is never locked, so it can’t be poisoned either.
+        Pin::new(self.sock.get_mut().unwrap())
+    }
+
+    /// Converts a WebSocket error into an IO error.
+    fn io_error(err: WsError) -> io::Error {
+        match err {
+            WsError::Io(err) => err,
+            WsError::ConnectionClosed | WsError::AlreadyClosed => {
+                io::Error::new(io::ErrorKind::ConnectionAborted, err)
+            }
+            err => io::Error::new(io::ErrorKind::InvalidData, err)
+        }
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        while this.read_pos >= this.read_buf.len() {
+            match ready!(this.sock().poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected text message"
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    return Poll::Ready(Ok(0))
+                }
+                Some(Ok(_)) => { }
+                Some(Err(err)) => {
+                    return Poll::Ready(Err(WsStream::io_error(err)))
+                }
+            }
+        }
+        let data = &this.read_buf[this.read_pos..];
+        let len = cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut sock = self.get_mut().sock();
+        ready!(sock.as_mut().poll_ready(cx)).map_err(WsStream::io_error)?;
+        sock.start_send(
+            Message::Binary(buf.into())
+        ).map_err(WsStream::io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        self.get_mut().sock().poll_flush(cx).map_err(WsStream::io_error)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        self.get_mut().sock().poll_close(cx).map_err(WsStream::io_error)
+    }
+}
+
+
+//------------ BatchedStream -------------------------------------------------
+
+/// A socket wrapper batching the PDUs of a response.
+///
+/// The RTR server writes each PDU separately. The wrapper collects these
+/// writes in a buffer and only writes to the socket once the buffer is full
+/// or the response is complete. The latter is the case when the server
+/// flushes or starts reading again, which it does once it is done sending.
+/// A lone Serial Notify is thus still sent right away.
+///
+/// The wrapper also measures the time from the first write of a response
+/// until all of it has been handed to the socket.
+struct BatchedStream<Sock> {
+    /// The actual socket.
+    sock: Sock,
+
+    /// The data not yet written to the socket.
+    buf: Vec<u8>,
+
+    /// The size of the buffer.
+    ///
+    /// If this is zero, writes are passed through directly.
+    capacity: usize,
+
+    /// When the current response started if there is one.
+    response_start: Option<Instant>,
+
+    /// The histogram to add the response durations to.
+    responses: Arc<Histogram>,
+}
+
+impl<Sock> BatchedStream<Sock> {
+    /// Creates a new wrapper with the given buffer size.
+    fn new(sock: Sock, capacity: usize, responses: Arc<Histogram>) -> Self {
+        BatchedStream {
+            sock,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            response_start: None,
+            responses,
+        }
+    }
+
+    /// Ends the current response if there is one.
+    fn response_done(&mut self) {
+        if let Some(start) = self.response_start.take() {
+            self.responses.observe(start.elapsed())
+        }
+    }
+}
+
+impl<Sock: AsyncWrite + Unpin> BatchedStream<Sock> {
+    /// Writes all buffered data to the socket.
+    fn poll_write_buf(
+        &mut self, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        while !self.buf.is_empty() {
+            let len = ready!(
+                Pin::new(&mut self.sock).poll_write(cx, &self.buf)
+            )?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+            }
+            self.buf.drain(..len);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Sock> AsyncRead for BatchedStream<Sock>
+where Sock: AsyncRead + AsyncWrite + Unpin {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+
+        // Reading means the response is complete. If the buffered data
+        // can’t be written right away, we will be woken up to try again.
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => this.response_done(),
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => { }
+        }
+        Pin::new(&mut this.sock).poll_read(cx, buf)
+    }
+}
+
+impl<Sock: AsyncWrite + Unpin> AsyncWrite for BatchedStream<Sock> {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        if this.response_start.is_none() {
+            this.response_start = Some(Instant::now());
+        }
+        if this.buf.len() + buf.len() > this.capacity {
+            ready!(this.poll_write_buf(cx))?;
+            if buf.len() >= this.capacity {
+                return Pin::new(&mut this.sock).poll_write(cx, buf)
+            }
+        }
+        this.buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        ready!(Pin::new(&mut this.sock).poll_flush(cx))?;
+        this.response_done();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.sock).poll_shutdown(cx)
+    }
 }
 
 
 //------------ Source --------------------------------------------------------
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 struct Source {
     data: Arc<ArcSwap<SourceData>>,
+
+    /// The maximum number of diffs to keep.
     diff_num: usize,
+
+    /// The maximum age of diffs to keep.
+    diff_age: Option<Duration>,
+
+    /// How diffs are kept.
+    history_mode: HistoryMode,
+
+    /// Whether an empty update with a new unit serial advances the serial.
+    forward_empty: bool,
+
+    /// The directory to keep diffs in as checkpoint files.
+    ///
+    /// If this is `None`, diffs are kept in memory.
+    checkpoints: Option<Arc<PathBuf>>,
+
+    /// The durations of responses sent to clients.
+    responses: Arc<Histogram>,
+
+    /// The number of redundant items in the last update from the unit.
+    redundant: Arc<AtomicUsize>,
+
+    /// The share of BGP routes valid with the last update from the unit.
+    ///
+    /// This is `None` if the coverage isn’t determined.
+    coverage: Arc<AtomicCell<Option<f64>>>,
 }
 
 impl Source {
+    /// The bucket bounds in seconds for the response duration histogram.
+    const RESPONSE_BUCKETS: &'static [f64] = &[
+        0.001, 0.01, 0.1, 0.5, 1., 5., 10., 30.
+    ];
+
+    fn new(diff_num: usize, diff_age: Option<Duration>) -> Self {
+        Source {
+            data: Default::default(),
+            diff_num,
+            diff_age,
+            history_mode: HistoryMode::default(),
+            forward_empty: false,
+            checkpoints: None,
+            responses: Arc::new(Histogram::new(Self::RESPONSE_BUCKETS)),
+            redundant: Default::default(),
+            coverage: Default::default(),
+        }
+    }
+
+    /// Keeps diffs according to the given mode.
+    fn with_history_mode(mut self, mode: HistoryMode) -> Self {
+        self.history_mode = mode;
+        self
+    }
+
+    /// Advances the serial with empty updates if `forward` is true.
+    fn with_forward_empty(mut self, forward: bool) -> Self {
+        self.forward_empty = forward;
+        self
+    }
+
+    /// Keeps diffs as checkpoint files in the given directory.
+    ///
+    /// The directory is created if necessary. Checkpoint files left over
+    /// from earlier runs are removed since they are for a different RTR
+    /// session.
+    fn with_checkpoints(mut self, dir: PathBuf) -> Result<Self, io::Error> {
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == "diff").unwrap_or(false) {
+                fs::remove_file(&path)?;
+            }
+        }
+        self.checkpoints = Some(Arc::new(dir));
+        Ok(self)
+    }
+
+    /// Starts serving an empty set until the first update.
+    ///
+    /// The first update then is applied like any other, so clients that
+    /// have received the empty set get it as an incremental update.
+    fn bootstrap(&self) {
+        self.data.store(SourceData {
+            current: Some(Default::default()),
+            bootstrap: true,
+            .. Default::default()
+        }.into())
+    }
+
+    /// Returns whether the source only serves the bootstrap set.
+    fn is_bootstrapping(&self) -> bool {
+        self.data.load().bootstrap
+    }
+
     fn update(&self, update: payload::Update) {
+        self.update_at(update, Instant::now())
+    }
+
+    /// Applies an update or starts a new session with it.
+    fn apply(&self, update: payload::Update, reset: bool) {
+        if reset {
+            self.reset(update)
+        }
+        else {
+            self.update(update)
+        }
+    }
+
+    /// Starts a new RTR session with the data of an update.
+    ///
+    /// All diffs are dropped and the serial number starts over, so clients
+    /// of the old session will have to do a cache reset. This is necessary
+    /// when the data comes from a different unit than before.
+    fn reset(&self, update: payload::Update) {
         let data = self.data.load();
+        self.data.store(SourceData {
+            state: State::from_parts(
+                data.state.session().wrapping_add(1), Serial::from(0)
+            ),
+            unit_serial: update.serial(),
+            current: Some(update.set()),
+            diffs: Vec::new(),
+            timing: Timing::default(),
+            bootstrap: false,
+        }.into());
+    }
 
+    /// Applies an update received at the given time.
+    fn update_at(&self, update: payload::Update, now: Instant) {
+        let data = self.data.load();
         let new_data = match data.current.as_ref() {
             None => {
                 SourceData {
@@ -98,44 +1091,190 @@ impl Source {
                     current: Some(update.set()),
                     diffs: Vec::new(),
                     timing: Timing::default(),
+                    bootstrap: false,
                 }
             }
             Some(current) => {
+                // The unit’s diff doesn’t lead from the bootstrap set.
                 let diff = match update.get_usable_diff(data.unit_serial) {
-                    Some(diff) => diff,
-                    None => Arc::new(update.set().diff_from(current)),
+                    Some(diff) if !data.bootstrap => diff,
+                    _ => Arc::new(update.set().diff_from(current)),
                 };
-                if diff.is_empty() {
-                    // If there is no change in data, don’t update.
+                let forward = self.forward_empty
+                    && data.unit_serial != update.serial();
+                if diff.is_empty() && !forward {
+                    // If there is no change in data, don’t update. The
+                    // bootstrap set becomes the unit’s data, though, and
+                    // we follow the unit’s serial so its next diff can
+                    // still be used.
+                    if data.bootstrap || data.unit_serial != update.serial() {
+                        self.data.store(SourceData {
+                            unit_serial: update.serial(),
+                            bootstrap: false,
+                            .. (**data).clone()
+                        }.into());
+                    }
                     return
                 }
+                let mut state = data.state;
+                state.inc();
                 let mut diffs = Vec::with_capacity(
                     cmp::min(data.diffs.len() + 1, self.diff_num)
                 );
-                diffs.push((data.state.serial(), diff.clone()));
-                for (serial, old_diff) in &data.diffs {
-                    if diffs.len() == self.diff_num {
-                        break
+                if self.diff_num > 0 {
+                    if let Some(diff) = self.keep_diff(
+                        data.state.serial(), diff.clone(), state.serial()
+                    ) {
+                        diffs.push((data.state.serial(), diff, now));
+                    }
+                }
+                if self.composes() {
+                    // The old diffs stay as they are. Without the new diff,
+                    // they don’t lead to the current serial anymore.
+                    if !diffs.is_empty() {
+                        for (serial, old_diff, created) in &data.diffs {
+                            if diffs.len() == self.diff_num
+                                || self.is_expired(*created, now)
+                            {
+                                break
+                            }
+                            diffs.push((*serial, old_diff.clone(), *created));
+                        }
+                    }
+                }
+                else {
+                    for (serial, old_diff, created) in &data.diffs {
+                        if diffs.len() == self.diff_num
+                            || self.is_expired(*created, now)
+                        {
+                            break
+                        }
+                        let old_diff = match old_diff.load(
+                            data.state.serial()
+                        ) {
+                            Some(old_diff) => old_diff,
+                            None => continue
+                        };
+                        if let Some(diff) = self.keep_diff(
+                            *serial,
+                            Arc::new(old_diff.extend(&diff).unwrap()),
+                            state.serial()
+                        ) {
+                            diffs.push((*serial, diff, *created))
+                        }
                     }
-                    diffs.push((
-                        *serial,
-                        Arc::new(old_diff.extend(&diff).unwrap())
-                    ))
                 }
-                let mut state = data.state;
-                state.inc();
                 SourceData {
                     state,
                     unit_serial: update.serial(),
                     current: Some(update.set()),
                     diffs,
                     timing: Timing::default(),
+                    bootstrap: false,
                 }
             }
         };
 
         self.data.store(new_data.into());
     }
+
+    /// Returns whether diffs are kept from one version to the next.
+    ///
+    /// Checkpoint files are always kept this way, so each of them is
+    /// written only once rather than rewritten with every update.
+    fn composes(&self) -> bool {
+        self.history_mode == HistoryMode::Compose
+            || self.checkpoints.is_some()
+    }
+
+    /// Prepares a diff from serial `from` to `to` for keeping.
+    ///
+    /// If checkpoints are used, the diff is written to its file. If that
+    /// fails, the diff is not kept at all and `None` is returned.
+    fn keep_diff(
+        &self, from: Serial, diff: Arc<payload::Diff>, to: Serial
+    ) -> Option<HistoryDiff> {
+        let dir = match self.checkpoints {
+            Some(ref dir) => dir,
+            None => return Some(HistoryDiff::Memory(diff))
+        };
+        let path = dir.join(
+            format!("{}-{}.diff", u32::from(from), u32::from(to))
+        );
+        match payload::DiffCheckpoint::store(&diff, to, &path) {
+            Ok(()) => Some(HistoryDiff::Checkpoint(Arc::new(
+                CheckpointFile { path }
+            ))),
+            Err(err) => {
+                warn!(
+                    "Failed to write diff checkpoint {}: {}",
+                    path.display(), err
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns whether a diff created at `created` is too old at `now`.
+    fn is_expired(&self, created: Instant, now: Instant) -> bool {
+        match self.diff_age {
+            Some(age) => now.saturating_duration_since(created) > age,
+            None => false
+        }
+    }
+
+    /// Returns the diff from `state` to the current state if available.
+    fn diff_at(
+        &self, state: State, now: Instant
+    ) -> Option<(State, Arc<payload::Diff>)> {
+        let this = self.data.load();
+        if this.current.is_none() || state.session() != this.state.session() {
+            return None
+        }
+        if state.serial() == this.state.serial() {
+            return Some((this.state, Arc::new(payload::Diff::default())))
+        }
+        let pos = this.diffs.iter().position(|(serial, _, created)| {
+            *serial == state.serial() && !self.is_expired(*created, now)
+        })?;
+        if !self.composes() {
+            this.diffs[pos].1.load(this.state.serial()).map(|diff| {
+                (this.state, diff)
+            })
+        }
+        else {
+            // The diffs are ordered from newest to oldest, so we need
+            // to go backwards from the client’s serial. Changes undone
+            // by a later diff cancel each other out.
+            let mut res = payload::DiffBuilder::default();
+            for (serial, diff, _) in this.diffs[..=pos].iter().rev() {
+                let diff = diff.load(serial.add(1))?;
+                if res.push_diff(&diff).is_err() {
+                    warn!("Failed to compose diffs for serial query.");
+                    return None
+                }
+            }
+            Some((this.state, Arc::new(res.finalize())))
+        }
+    }
+
+    /// Returns the oldest serial still answered with a diff and its age.
+    ///
+    /// If there are no usable diffs, this is the current serial.
+    fn oldest_diff(&self, now: Instant) -> (Serial, Duration) {
+        let this = self.data.load();
+        this.diffs.iter().rev().find(|(_, _, created)| {
+            !self.is_expired(*created, now)
+        }).map(|(serial, _, created)| {
+            (*serial, now.saturating_duration_since(*created))
+        }).unwrap_or((this.state.serial(), Duration::default()))
+    }
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source::new(0, None)
+    }
 }
 
 impl VrpSource for Source {
@@ -159,13 +1298,8 @@ impl VrpSource for Source {
     }
 
     fn diff(&self, state: State) -> Option<(State, Self::DiffIter)> {
-        let this = self.data.load();
-        if this.current.is_none() || state.session() != this.state.session() {
-            return None
-        }
-
-        this.get_diff(state.serial()).map(|diff| {
-            (this.state, diff.shared_iter())
+        self.diff_at(state, Instant::now()).map(|(state, diff)| {
+            (state, diff.shared_iter())
         })
     }
 
@@ -174,6 +1308,214 @@ impl VrpSource for Source {
     }
 }
 
+impl Source {
+    const READY_METRIC: Metric = Metric::new(
+        "target_ready",
+        "whether the target is serving data",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const RESPONSE_METRIC: Metric = Metric::new(
+        "rtr_response_duration",
+        "the time it took to send a response to a client",
+        MetricType::Histogram, MetricUnit::Second
+    );
+    const OLDEST_SERIAL_METRIC: Metric = Metric::new(
+        "history_oldest_serial",
+        "the oldest serial still answered with an incremental update",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const OLDEST_AGE_METRIC: Metric = Metric::new(
+        "history_oldest_age",
+        "the age of the oldest diff still used for incremental updates",
+        MetricType::Gauge, MetricUnit::Second
+    );
+    const REDUNDANT_METRIC: Metric = Metric::new(
+        "rtr_redundant_vrp_count",
+        "the number of items covered by a less specific item",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const COVERAGE_METRIC: Metric = Metric::new(
+        "rtr_rov_coverage",
+        "the share of the given BGP routes that are valid",
+        MetricType::Gauge, MetricUnit::Ratio
+    );
+}
+
+impl metrics::Source for Source {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::READY_METRIC, Some(unit_name),
+            (self.ready() && !self.is_bootstrapping()) as u8
+        );
+        target.append(&Self::RESPONSE_METRIC, Some(unit_name), |records| {
+            records.histogram(&self.responses)
+        });
+        let (serial, age) = self.oldest_diff(Instant::now());
+        target.append_simple(
+            &Self::OLDEST_SERIAL_METRIC, Some(unit_name), serial
+        );
+        target.append_simple(
+            &Self::OLDEST_AGE_METRIC, Some(unit_name), age.as_secs()
+        );
+        target.append_simple(
+            &Self::REDUNDANT_METRIC, Some(unit_name),
+            self.redundant.load(Ordering::Relaxed)
+        );
+        if let Some(coverage) = self.coverage.load() {
+            target.append_simple(
+                &Self::COVERAGE_METRIC, Some(unit_name), coverage
+            );
+        }
+    }
+}
+
+
+//------------ RovCoverage ---------------------------------------------------
+
+/// Determines how many BGP routes are valid with the data served.
+///
+/// The routes are read from a JSON file in the format also used for the
+/// ROV statistics of the HTTP target. The file is read again whenever it
+/// has been modified, so it can be updated regularly from the routers.
+///
+/// Reading the file and determining the coverage happens in a task of its
+/// own, so that new data is served without waiting for it.
+struct RovCoverage {
+    /// The name of the target for logging.
+    name: Arc<str>,
+
+    /// The path of the file with the routes.
+    path: PathBuf,
+
+    /// The coverage below which a warning is logged.
+    threshold: Option<f64>,
+
+    /// The routes as prefix and origin AS number.
+    routes: Vec<(payload::Prefix, u32)>,
+
+    /// The modification time of the file when it was last read.
+    modified: Option<SystemTime>,
+
+    /// Where to store the coverage for the metrics.
+    coverage: Arc<AtomicCell<Option<f64>>>,
+}
+
+impl RovCoverage {
+    /// Creates a new value for the given file and threshold.
+    fn new(
+        name: Arc<str>,
+        path: PathBuf,
+        threshold: Option<f64>,
+        coverage: Arc<AtomicCell<Option<f64>>>,
+    ) -> Self {
+        RovCoverage {
+            name, path, threshold,
+            routes: Default::default(),
+            modified: None,
+            coverage
+        }
+    }
+
+    /// Spawns a task determining the coverage for each new data set.
+    ///
+    /// The data sets are given to the returned sender. If new data sets
+    /// arrive while the coverage is still being determined, only the
+    /// latest of them is looked at next.
+    fn spawn(mut self) -> watch::Sender<Option<Arc<payload::Set>>> {
+        let (tx, mut rx) = watch::channel::<Option<Arc<payload::Set>>>(
+            None
+        );
+        tokio::spawn(async move {
+            while let Some(set) = rx.recv().await {
+                let set = match set {
+                    Some(set) => set,
+                    None => continue
+                };
+                // The task only fails if the closure panics in which case
+                // we should panic, too.
+                self = spawn_blocking(move || {
+                    self.update(&set);
+                    self
+                }).await.unwrap();
+            }
+        });
+        tx
+    }
+
+    /// Determines the coverage for a new data set.
+    fn update(&mut self, set: &payload::Set) {
+        self.reload();
+        if self.routes.is_empty() {
+            return
+        }
+        let ratio = set.health_score(&self.routes);
+        let old = self.coverage.swap(Some(ratio));
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return
+        };
+        let was_below = old.map(|old| old < threshold).unwrap_or(false);
+        if ratio < threshold && !was_below {
+            warn!(
+                "Target {}: only {:.1}% of BGP routes are valid, below the \
+                 threshold of {:.1}%.",
+                self.name, ratio * 100., threshold * 100.
+            );
+        }
+        else if ratio >= threshold && was_below {
+            info!(
+                "Target {}: {:.1}% of BGP routes are valid again.",
+                self.name, ratio * 100.
+            );
+        }
+    }
+
+    /// Reads the routes file again if it has been modified.
+    ///
+    /// If reading fails, the routes read before are kept.
+    fn reload(&mut self) {
+        let modified = fs::metadata(&self.path).and_then(|meta| {
+            meta.modified()
+        });
+        let modified = match modified {
+            Ok(modified) => modified,
+            Err(err) => {
+                warn!(
+                    "Target {}: cannot access BGP routes file {}: {}",
+                    self.name, self.path.display(), err
+                );
+                return
+            }
+        };
+        if self.modified == Some(modified) {
+            return
+        }
+        let routes = fs::read(&self.path).map_err(|err| {
+            err.to_string()
+        }).and_then(|data| {
+            serde_json::from_slice::<json::Routes>(&data).map_err(|err| {
+                err.to_string()
+            })
+        });
+        match routes {
+            Ok(routes) => {
+                self.routes = routes.into_vec();
+                self.modified = Some(modified);
+                debug!(
+                    "Target {}: read {} BGP routes from {}.",
+                    self.name, self.routes.len(), self.path.display()
+                );
+            }
+            Err(err) => {
+                warn!(
+                    "Target {}: cannot read BGP routes file {}: {}",
+                    self.name, self.path.display(), err
+                );
+            }
+        }
+    }
+}
+
 
 //------------ SourceData ----------------------------------------------------
 
@@ -190,28 +1532,635 @@ struct SourceData {
 
     /// The diffs we currently keep.
     ///
-    /// The diff with the largest serial is first.
-    diffs: Vec<(Serial, Arc<payload::Diff>)>,
+    /// Each diff leads from the given serial to the current state. The last
+    /// element is the time the diff was first created, i.e., when the update
+    /// leaving the serial was received. The diff with the largest serial is
+    /// first.
+    diffs: Vec<(Serial, HistoryDiff, Instant)>,
 
     /// The timing paramters for this source.
     timing: Timing,
+
+    /// Whether `current` is the empty set served before the first update.
+    bootstrap: bool,
+}
+
+
+//------------ HistoryDiff ---------------------------------------------------
+
+/// A diff kept for serial queries.
+#[derive(Clone)]
+enum HistoryDiff {
+    /// The diff is kept in memory.
+    Memory(Arc<payload::Diff>),
+
+    /// The diff is kept in a checkpoint file.
+    Checkpoint(Arc<CheckpointFile>),
 }
 
-impl SourceData {
-    fn get_diff(&self, serial: Serial) -> Option<Arc<payload::Diff>> {
-        if serial == self.state.serial() {
+impl HistoryDiff {
+    /// Returns the diff leading to serial `to`.
+    ///
+    /// A checkpoint file is read from disk every time. Since this happens
+    /// while answering a query, the runtime is told that the thread will
+    /// block so it can move its other tasks elsewhere. If reading fails, a
+    /// warning is logged and `None` is returned, so the client will have
+    /// to make do with a cache reset.
+    fn load(&self, to: Serial) -> Option<Arc<payload::Diff>> {
+        let path = match *self {
+            HistoryDiff::Memory(ref diff) => return Some(diff.clone()),
+            HistoryDiff::Checkpoint(ref file) => &file.path,
+        };
+        match block_in_place(|| payload::DiffCheckpoint::load(path)) {
+            Ok((diff, serial)) if serial == to => Some(Arc::new(diff)),
+            Ok(_) => {
+                warn!(
+                    "Diff checkpoint {} is for the wrong serial.",
+                    path.display()
+                );
+                None
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to read diff checkpoint {}: {}",
+                    path.display(), err
+                );
+                None
+            }
+        }
+    }
+}
+
+
+//------------ CheckpointFile ------------------------------------------------
+
+/// A checkpoint file holding a diff.
+///
+/// The file is removed when the value is dropped. Since the value is shared
+/// by all versions of the source data that keep the diff, this happens only
+/// once the diff has been dropped from the history and no query is reading
+/// it anymore.
+struct CheckpointFile {
+    /// The path of the file.
+    path: PathBuf,
+}
+
+impl Drop for CheckpointFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            debug!(
+                "Failed to remove diff checkpoint {}: {}",
+                self.path.display(), err
+            );
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::Infallible;
+    
+    use std::time::Duration;
+    use futures::SinkExt;
+    use hyper::service::{make_service_fn, service_fn};
+    
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+    use crate::tests::assert_state_eq;
+    use crate::payload::testing::v4;
+
+    /// Returns the types of the PDUs received in response to a reset query.
+    async fn reset_query(
+        ws: &mut WebSocketStream<TcpStream>
+    ) -> Vec<u8> {
+        ws.send(Message::Binary(vec![1, 2, 0, 0, 0, 0, 0, 8])).await.unwrap();
+        let mut data = Vec::new();
+        let mut pdus = Vec::new();
+        while pdus.last() != Some(&7) {
+            if let Message::Binary(msg) = ws.next().await.unwrap().unwrap() {
+                data.extend_from_slice(&msg);
+            }
+            while data.len() >= 8 {
+                let len = u32::from_be_bytes(
+                    [data[4], data[5], data[6], data[7]]
+                ) as usize;
+                if data.len() < len {
+                    break
+                }
+                pdus.push(data[1]);
+                data.drain(..len);
+            }
+        }
+        pdus
+    }
+
+    #[tokio::test]
+    async fn batched_stream() {
+        use tokio::io::AsyncWriteExt;
+
+        let responses = Arc::new(Histogram::new(Source::RESPONSE_BUCKETS));
+        let mut sock = BatchedStream::new(Vec::new(), 16, responses.clone());
+        sock.write_all(&[1; 8]).await.unwrap();
+        sock.write_all(&[2; 8]).await.unwrap();
+        assert!(sock.sock.is_empty());
+        sock.write_all(&[3; 8]).await.unwrap();
+        assert_eq!(sock.sock.len(), 16);
+        sock.write_all(&[4; 40]).await.unwrap();
+        assert_eq!(sock.sock.len(), 64);
+        assert_eq!(responses.count(), 0);
+        sock.flush().await.unwrap();
+        assert_eq!(sock.sock.len(), 64);
+        assert_eq!(&sock.sock[16..24], &[3; 8]);
+        assert_eq!(responses.count(), 1);
+    }
+
+    #[test]
+    fn readiness() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut ready = Readiness::new(None, None, start);
+        assert!(ready.check(0, start));
+
+        let mut ready = Readiness::new(
+            Some(100), Some(Duration::from_secs(60)), start
+        );
+        assert!(!ready.check(10, at(10)));
+        assert_eq!(ready.deadline(), Some(at(60)));
+        ready.set_status(UnitStatus::Stalled, at(20));
+        assert_eq!(ready.deadline(), None);
+        ready.set_status(UnitStatus::Healthy, at(30));
+        assert!(!ready.check(10, at(60)));
+        assert!(ready.check(10, at(90)));
+        assert!(ready.is_ready());
+        assert_eq!(ready.deadline(), None);
+
+        // Once ready, always ready.
+        ready.set_status(UnitStatus::Stalled, at(100));
+        assert!(ready.check(0, at(100)));
+
+        let mut ready = Readiness::new(Some(100), None, start);
+        assert!(!ready.check(99, at(3600)));
+        assert!(ready.check(100, at(3600)));
+    }
+
+    #[test]
+    fn history_limits() {
+        fn update(serial: u32, asns: &[u32]) -> payload::Update {
+            let mut set = payload::SetBuilder::empty();
+            for &asn in asns {
+                set.insert(v4([192, 0, 2, 0], 24, 24, asn)).unwrap();
+            }
+            payload::Update::new(
+                Serial::from(serial), Arc::new(set.finalize()), None
+            )
+        }
+
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let source = Source::new(3, Some(Duration::from_secs(3600)));
+        source.update_at(update(0, &[1]), at(0));
+        let initial = source.notify();
+        for i in 1..5 {
+            source.update_at(
+                update(i, &[1, i + 1]), at(u64::from(i) * 1200)
+            );
+        }
+        let current = source.notify();
+        let state_at = |serial: u32| {
+            State::from_parts(current.session(), Serial::from(serial))
+        };
+
+        // The count limit keeps the last three diffs, the first diff is
+        // gone.
+        assert!(source.diff_at(initial, at(4800)).is_none());
+        assert!(source.diff_at(state_at(1), at(4800)).is_some());
+        assert_eq!(
+            source.oldest_diff(at(4800)),
+            (Serial::from(1), Duration::from_secs(2400))
+        );
+
+        // The age limit removes diffs older than an hour.
+        assert!(source.diff_at(state_at(1), at(6001)).is_none());
+        assert!(source.diff_at(state_at(2), at(6001)).is_some());
+        assert_eq!(source.oldest_diff(at(6001)).0, Serial::from(2));
+        assert!(source.diff_at(current, at(100_000)).is_some());
+        assert_eq!(
+            source.oldest_diff(at(100_000)),
+            (current.serial(), Duration::default())
+        );
+    }
+
+    #[test]
+    fn history_compose() {
+        fn set(octets: &[u8]) -> Arc<payload::Set> {
+            let mut set = payload::SetBuilder::empty();
+            for &octet in octets {
+                set.insert(v4([192, 0, octet, 0], 24, 24, 64496)).unwrap();
+            }
+            Arc::new(set.finalize())
+        }
+
+        // Item 4 is announced and withdrawn again, item 1 is withdrawn
+        // and announced again.
+        let sets = [
+            set(&[1, 2]), set(&[2, 3]), set(&[2, 3, 4]), set(&[1, 2, 3, 4]),
+            set(&[1, 3, 5]), set(&[1, 5, 6]),
+        ];
+        let source = Source::new(4, None).with_history_mode(
+            HistoryMode::Compose
+        );
+        for (serial, set) in sets.iter().enumerate() {
+            source.update(payload::Update::new(
+                Serial::from(serial as u32), set.clone(), None
+            ));
+        }
+        let current = source.notify();
+        assert_eq!(current.serial(), Serial::from(5));
+
+        // Only single steps are kept, the oldest is gone.
+        assert_eq!(source.data.load().diffs.len(), 4);
+        assert!(source.diff_at(
+            State::from_parts(current.session(), Serial::from(0)),
+            Instant::now()
+        ).is_none());
+        for serial in 1..5 {
+            let (state, diff) = source.diff_at(
+                State::from_parts(current.session(), Serial::from(serial)),
+                Instant::now()
+            ).unwrap();
+            assert_state_eq(state, current);
+            assert_eq!(*diff, sets[5].diff_from(&sets[serial as usize]));
+        }
+    }
+
+    #[test]
+    fn bootstrap() {
+        let mut set = payload::SetBuilder::empty();
+        for &octet in &[1, 2] {
+            set.insert(v4([192, 0, octet, 0], 24, 24, 64496)).unwrap();
+        }
+        let set = Arc::new(set.finalize());
+
+        let source = Source::new(10, None);
+        assert!(!source.ready());
+        source.bootstrap();
+        assert!(source.ready());
+        assert!(source.is_bootstrapping());
+        let initial = source.notify();
+        assert_eq!(source.full().1.count(), 0);
+
+        // The unit’s diff is for its own previous serial, not for the
+        // bootstrap set, and must be ignored.
+        source.update(payload::Update::new(
+            Serial::from(1), set.clone(),
             Some(Arc::new(payload::Diff::default()))
+        ));
+        assert!(!source.is_bootstrapping());
+        let (state, diff) = source.diff_at(initial, Instant::now()).unwrap();
+        assert_eq!(state.serial(), initial.serial().add(1));
+        assert_eq!(*diff, set.diff_from(&payload::Set::default()));
+
+        // Empty data from the unit ends bootstrapping without a new serial.
+        let source = Source::new(10, None);
+        source.bootstrap();
+        let initial = source.notify();
+        source.update(payload::Update::new(
+            Serial::default(), Default::default(), None
+        ));
+        assert!(!source.is_bootstrapping());
+        assert_state_eq(source.notify(), initial);
+    }
+
+    #[test]
+    fn forward_empty_updates() {
+        let mut set = payload::SetBuilder::empty();
+        set.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        let set = Arc::new(set.finalize());
+
+        // Normally, an update without changes keeps the serial.
+        let source = Source::new(10, None);
+        source.update(payload::Update::new(
+            Serial::from(1), set.clone(), None
+        ));
+        let initial = source.notify();
+        source.update(payload::Update::new(
+            Serial::from(2), set.clone(), None
+        ));
+        assert_state_eq(source.notify(), initial);
+
+        // With forwarding, it advances the serial via an empty diff.
+        let source = Source::new(10, None).with_forward_empty(true);
+        source.update(payload::Update::new(
+            Serial::from(1), set.clone(), None
+        ));
+        let initial = source.notify();
+        source.update(payload::Update::new(
+            Serial::from(2), set.clone(), None
+        ));
+        let current = source.notify();
+        assert_eq!(current.serial(), initial.serial().add(1));
+        let (state, diff) = source.diff_at(initial, Instant::now()).unwrap();
+        assert_state_eq(state, current);
+        assert!(diff.is_empty());
+
+        // Unless the unit’s serial stays the same.
+        source.update(payload::Update::new(Serial::from(2), set, None));
+        assert_state_eq(source.notify(), current);
+    }
+
+    #[test]
+    fn reset() {
+        fn set(octets: &[u8]) -> Arc<payload::Set> {
+            let mut set = payload::SetBuilder::empty();
+            for &octet in octets {
+                set.insert(v4([192, 0, octet, 0], 24, 24, 64496)).unwrap();
+            }
+            Arc::new(set.finalize())
         }
-        else {
-            self.diffs.iter().find_map(|item| {
-                if item.0 == serial {
-                    Some(item.1.clone())
+
+        let source = Source::new(10, None);
+        source.update(payload::Update::new(Serial::from(7), set(&[1]), None));
+        source.update(payload::Update::new(
+            Serial::from(8), set(&[1, 2]), None
+        ));
+        let old = source.notify();
+
+        // Switching to another unit starts a new session with its data.
+        source.apply(
+            payload::Update::new(Serial::from(100), set(&[2, 3]), None),
+            true
+        );
+        let new = source.notify();
+        assert_ne!(new.session(), old.session());
+        assert_eq!(new.serial(), Serial::from(0));
+        assert!(source.diff_at(old, Instant::now()).is_none());
+        assert_eq!(source.full().1.count(), 2);
+
+        // Updates from the new unit continue the session.
+        source.apply(
+            payload::Update::new(Serial::from(101), set(&[3]), None), false
+        );
+        let (state, diff) = source.diff_at(new, Instant::now()).unwrap();
+        assert_eq!(state.session(), new.session());
+        assert_eq!(*diff, set(&[3]).diff_from(&set(&[2, 3])));
+    }
+
+    #[tokio::test]
+    async fn source_switch() {
+        use tokio::sync::oneshot;
+        use crate::comms::Gate;
+
+        type Command = Result<payload::Update, UnitStatus>;
+
+        fn set(octets: &[u8]) -> Arc<payload::Set> {
+            let mut set = payload::SetBuilder::empty();
+            for &octet in octets {
+                set.insert(v4([192, 0, octet, 0], 24, 24, 64496)).unwrap();
+            }
+            Arc::new(set.finalize())
+        }
+
+        // Runs a unit passing on what it is sent. The receiver fires once
+        // the link has subscribed.
+        fn unit() -> (Link, mpsc::Sender<Command>, oneshot::Receiver<()>) {
+            let (mut gate, mut agent) = Gate::new();
+            let link = agent.create_link();
+            let (tx, mut rx) = mpsc::channel::<Command>(4);
+            let (ready_tx, ready_rx) = oneshot::channel();
+            tokio::spawn(async move {
+                let _agent = agent;
+                if gate.process().await.is_err() {
+                    return
                 }
-                else {
-                    None
+                let _ = ready_tx.send(());
+                while let Ok(Some(command)) = gate.process_until(
+                    rx.recv()
+                ).await {
+                    match command {
+                        Ok(update) => gate.update_data(update).await,
+                        Err(status) => gate.update_status(status).await,
+                    }
                 }
-            })
+            });
+            (link, tx, ready_rx)
         }
+
+        let (first, mut first_tx, first_ready) = unit();
+        let (second, mut second_tx, second_ready) = unit();
+        let mut sources = Sources::from_links(
+            vec![first, second], SourcePolicy::FirstHealthy,
+            Duration::from_secs(0), "rtr".into()
+        );
+        let source = Source::new(10, None);
+
+        // Both links need to be there before the units publish anything.
+        let (update, _) = futures::join!(sources.query(), async {
+            first_ready.await.unwrap();
+            second_ready.await.unwrap();
+            second_tx.send(Ok(payload::Update::new(
+                Serial::from(5), set(&[1, 2]),
+                Some(Arc::new(set(&[1, 2]).diff_from(&set(&[2]))))
+            ))).await.unwrap();
+            first_tx.send(Ok(payload::Update::new(
+                Serial::from(1), set(&[1]), None
+            ))).await.unwrap();
+        });
+        let update = update.unwrap();
+        assert_eq!(update.serial(), Serial::from(1));
+        assert!(!sources.take_switch());
+        source.apply(update, false);
+        let old = source.notify();
+
+        // Once the first unit stalls, the second one’s last update is
+        // served without its diff and the target starts a new session.
+        first_tx.send(Err(UnitStatus::Stalled)).await.unwrap();
+        let update = loop {
+            if let Ok(update) = sources.query().await {
+                break update
+            }
+        };
+        assert_eq!(update.serial(), Serial::from(5));
+        assert!(update.diff().is_none());
+        assert!(sources.take_switch());
+        source.apply(update, true);
+        let new = source.notify();
+        assert_ne!(new.session(), old.session());
+        assert_eq!(new.serial(), Serial::from(0));
+        assert!(source.diff_at(old, Instant::now()).is_none());
+        assert_eq!(source.full().1.count(), 2);
     }
-}
 
+    #[test]
+    fn history_checkpoints() {
+        fn update(serial: u32, asns: &[u32]) -> payload::Update {
+            let mut set = payload::SetBuilder::empty();
+            for &asn in asns {
+                set.insert(v4([192, 0, 2, 0], 24, 24, asn)).unwrap();
+            }
+            payload::Update::new(
+                Serial::from(serial), Arc::new(set.finalize()), None
+            )
+        }
+
+        let dir = std::env::temp_dir().join(format!(
+            "rtrtr-checkpoints-{}", std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("7-8.diff"), b"stale").unwrap();
+        let source = Source::new(2, None).with_checkpoints(
+            dir.clone()
+        ).unwrap();
+        assert!(!dir.join("7-8.diff").exists());
+
+        source.update(update(0, &[1]));
+        let initial = source.notify();
+        for i in 1..4 {
+            source.update(update(i, &[1, i + 1]));
+        }
+        let current = source.notify();
+        let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| {
+            entry.unwrap().file_name().into_string().unwrap()
+        }).collect();
+        files.sort();
+        assert_eq!(files, ["1-2.diff", "2-3.diff"]);
+
+        // The first diff is gone, the others are served from disk and
+        // combined.
+        let state_at = |serial: u32| {
+            State::from_parts(current.session(), Serial::from(serial))
+        };
+        assert!(source.diff_at(initial, Instant::now()).is_none());
+        let (state, diff) = source.diff_at(
+            state_at(1), Instant::now()
+        ).unwrap();
+        assert_state_eq(state, current);
+        assert_eq!(diff.len(), 2);
+
+        // A file that has gone missing means a cache reset.
+        fs::remove_file(dir.join("2-3.diff")).unwrap();
+        assert!(source.diff_at(state_at(2), Instant::now()).is_none());
+
+        // A file dropped from the history is only removed once nobody
+        // reads it anymore.
+        let reader = source.data.load_full();
+        source.update(update(4, &[1, 5]));
+        assert!(dir.join("1-2.diff").exists());
+        assert!(dir.join("3-4.diff").exists());
+        drop(reader);
+        assert!(!dir.join("1-2.diff").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn websocket_reset_query() {
+        let mut set = payload::SetBuilder::empty();
+        let vrps = [([192, 0, 2, 0], 64496), ([198, 51, 100, 0], 64497)];
+        for &(addr, asn) in &vrps {
+            set.insert(v4(addr, 24, 24, asn)).unwrap();
+        }
+        let source = Source::default();
+        source.update(payload::Update::new(
+            Serial::default(), Arc::new(set.finalize()), None
+        ));
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let bridge = Arc::new(
+            WebSocketBridge { path: "/rtr".into(), sockets: tx }
+        );
+        tokio::spawn(
+            Server::new(
+                rx.map(Ok::<_, io::Error>), NotifySender::new(), source
+            ).run()
+        );
+        let http = hyper::Server::bind(
+            &([127, 0, 0, 1], 0).into()
+        ).serve(make_service_fn(move |_| {
+            let bridge = bridge.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut req| {
+                    let res = bridge.process_request(&mut req).unwrap();
+                    async move { Ok::<_, Infallible>(res) }
+                }))
+            }
+        }));
+        let addr = http.local_addr();
+        tokio::spawn(http);
+
+        let pdus = timeout(Duration::from_secs(10), async {
+            let sock = TcpStream::connect(addr).await.unwrap();
+            let (mut ws, _) = tokio_tungstenite::client_async(
+                format!("ws://{}/rtr", addr), sock
+            ).await.unwrap();
+            reset_query(&mut ws).await
+        }).await.unwrap();
+
+        // Cache Response, two IPv4 Prefixes, End of Data.
+        assert_eq!(pdus, [3, 4, 4, 7]);
+    }
+
+    #[tokio::test]
+    async fn throttled_reset_queries() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // 200 prefixes of 20 bytes each for each of two clients with
+        // 4000 bytes per second for both: the first 4000 bytes go out
+        // right away, the other 4000 take a second.
+        let mut set = payload::SetBuilder::empty();
+        for i in 0..200u32 {
+            set.insert(v4(0xC000_0000 | (i << 8), 24, 24, 64496)).unwrap();
+        }
+        let source = Source::default();
+        source.update(payload::Update::new(
+            Serial::default(), Arc::new(set.finalize()), None
+        ));
+        let throttle = Arc::new(Throttle::new(None, Some(4000)));
+
+        let mut listener = TcpListener::bind(
+            ("127.0.0.1", 0)
+        ).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let listener = listener.incoming().map(move |sock| {
+                sock.map(|sock| {
+                    ThrottledStream::new(sock, throttle.clone())
+                })
+            });
+            Server::new(listener, NotifySender::new(), source).run().await
+        });
+
+        async fn client(addr: SocketAddr) -> Vec<u8> {
+            let mut sock = TcpStream::connect(addr).await.unwrap();
+            sock.write_all(&[1, 2, 0, 0, 0, 0, 0, 8]).await.unwrap();
+            let mut pdus = Vec::new();
+            while pdus.last() != Some(&7) {
+                let mut header = [0u8; 8];
+                sock.read_exact(&mut header).await.unwrap();
+                let len = u32::from_be_bytes(
+                    [header[4], header[5], header[6], header[7]]
+                ) as usize;
+                let mut body = vec![0u8; len - 8];
+                sock.read_exact(&mut body).await.unwrap();
+                pdus.push(header[1]);
+            }
+            pdus
+        }
+
+        let start = Instant::now();
+        let (one, two) = timeout(Duration::from_secs(10), async {
+            futures::join!(client(addr), client(addr))
+        }).await.unwrap();
+        let elapsed = start.elapsed();
+
+        for pdus in &[one, two] {
+            assert_eq!(pdus.len(), 202);
+            assert_eq!(pdus.first(), Some(&3));
+            assert_eq!(pdus.iter().filter(|&&pdu| pdu == 4).count(), 200);
+        }
+        assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+    }
+}
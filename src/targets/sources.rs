@@ -0,0 +1,624 @@
+//! Targets receiving data from more than one unit.
+//!
+//! Instead of a single unit, a target can be given an ordered list of
+//! units. It then serves the data of the first unit in the list that
+//! qualifies under the target’s [`SourcePolicy`] and switches to another
+//! unit when that one stops qualifying. This is similar to what the `any`
+//! unit does but allows different targets to fail over differently using
+//! the same units.
+//!
+//! The serial numbers and diffs of different units are not related, so an
+//! update received after a switch never carries a diff and targets need to
+//! start over with the new unit’s data set. The RTR target does this with a
+//! new session, so that its clients have to do a cache reset.
+//!
+//! With [`SourcePolicy::FirstWithData`], the unit that happens to deliver
+//! its data first after startup would become active even if a unit earlier
+//! in the list delivers its data only a moment later, leading to an
+//! unnecessary switch. The first unit therefore stays active for a grace
+//! period after startup unless it has gone away.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use futures::future::{select_all, FutureExt};
+use log::info;
+use serde::Deserialize;
+use tokio::time::timeout_at;
+use crate::{metrics, payload};
+use crate::comms::{Link, UnitStatus};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ SourceList ----------------------------------------------------
+
+/// The units a target receives its data from in order of precedence.
+///
+/// In the config, this is either a single link or a non-empty array of
+/// links. The default value is empty and only serves as a placeholder when
+/// taking the list out of a target.
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "SourceListSpec")]
+pub struct SourceList(Vec<Link>);
+
+impl SourceList {
+    /// Returns the link to the unit with the highest precedence.
+    pub fn into_first(self) -> Link {
+        self.0.into_iter().next().unwrap()
+    }
+}
+
+impl std::convert::TryFrom<SourceListSpec> for SourceList {
+    type Error = EmptySourceList;
+
+    fn try_from(spec: SourceListSpec) -> Result<Self, Self::Error> {
+        match spec {
+            SourceListSpec::One(link) => Ok(SourceList(vec![link])),
+            SourceListSpec::Many(links) => {
+                if links.is_empty() {
+                    Err(EmptySourceList)
+                }
+                else {
+                    Ok(SourceList(links))
+                }
+            }
+        }
+    }
+}
+
+
+//------------ SourceListSpec ------------------------------------------------
+
+/// How a source list is given in the config.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SourceListSpec {
+    One(Link),
+    Many(Vec<Link>),
+}
+
+
+//------------ EmptySourceList -----------------------------------------------
+
+/// A source list in the config was empty.
+#[derive(Clone, Copy, Debug)]
+pub struct EmptySourceList;
+
+impl fmt::Display for EmptySourceList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the list of units must not be empty")
+    }
+}
+
+
+//------------ SourcePolicy --------------------------------------------------
+
+/// How a target picks the unit to serve from.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum SourcePolicy {
+    /// Serve from the first unit that is healthy.
+    #[serde(rename = "first-healthy")]
+    FirstHealthy,
+
+    /// Serve from the first unit that has a non-empty data set.
+    ///
+    /// The unit may be stalled as long as it hasn’t gone away.
+    #[serde(rename = "first-with-data")]
+    FirstWithData,
+}
+
+impl SourcePolicy {
+    /// Returns the default grace period after startup in seconds.
+    pub fn default_grace_period() -> u64 {
+        10
+    }
+}
+
+impl Default for SourcePolicy {
+    fn default() -> Self {
+        SourcePolicy::FirstHealthy
+    }
+}
+
+
+//------------ Sources -------------------------------------------------------
+
+/// The units a running target receives its data from.
+///
+/// All units are queried all the time so that the last update of each of
+/// them is available when switching over. Only the updates and status
+/// changes of the currently active unit are passed on.
+pub struct Sources {
+    /// The links to the units in order of precedence.
+    links: Vec<Link>,
+
+    /// Which unit is active.
+    selection: Selection,
+
+    /// Whether the active unit has changed since the last update.
+    switched: bool,
+
+    /// Whether the next update of the active unit is its first one.
+    fresh: bool,
+
+    /// The name of the target for logging.
+    target: Arc<str>,
+
+    /// The metrics of the sources.
+    metrics: Arc<SourceMetrics>,
+}
+
+impl Sources {
+    /// Creates the sources for a target.
+    ///
+    /// If there is more than one unit, the metrics showing the active unit
+    /// are registered with the target’s component. With
+    /// [`SourcePolicy::FirstWithData`], the first unit stays active for
+    /// `grace` after startup.
+    pub fn new(
+        list: SourceList, policy: SourcePolicy, grace: Duration,
+        component: &mut Component
+    ) -> Self {
+        let res = Self::from_links(
+            list.0, policy, grace, component.name().clone()
+        );
+        if res.links.len() > 1 {
+            component.register_metrics(res.metrics.clone());
+        }
+        res
+    }
+
+    /// Creates the sources from a list of links.
+    ///
+    /// The name of the target is given via `target`. Unlike
+    /// [`new`](Self::new), this doesn’t register any metrics.
+    pub fn from_links(
+        links: Vec<Link>, policy: SourcePolicy, grace: Duration,
+        target: Arc<str>
+    ) -> Self {
+        let mut selection = Selection::new(policy, links.len());
+        if policy == SourcePolicy::FirstWithData {
+            selection = selection.with_grace(Instant::now() + grace);
+        }
+        Sources {
+            metrics: Arc::new(SourceMetrics::new(&links)),
+            selection,
+            links,
+            switched: false,
+            fresh: false,
+            target,
+        }
+    }
+
+    /// Returns the current status of the active unit.
+    pub fn get_status(&self) -> UnitStatus {
+        self.links[self.selection.active()].get_status()
+    }
+
+    /// Returns whether the active unit has changed since the last call.
+    ///
+    /// If this is `true` after an update was returned by
+    /// [`query`](Self::query), the update is the first one from the new
+    /// unit.
+    pub fn take_switch(&mut self) -> bool {
+        let res = self.switched;
+        self.switched = false;
+        res
+    }
+
+    /// Queries for the next update of the active unit.
+    ///
+    /// Like [`Link::query`], the method resolves into either the next
+    /// update or the active unit’s new status. When switching to a unit
+    /// that already has data, its last update is returned right away.
+    pub async fn query(&mut self) -> Result<payload::Update, UnitStatus> {
+        loop {
+            let (res, idx) = match self.selection.grace() {
+                Some(grace) => {
+                    match timeout_at(grace.into(), self.query_any()).await {
+                        Ok(res) => res,
+                        Err(_) => {
+                            // The grace period is over, so select anew.
+                            match self.switch() {
+                                Some(res) => return res,
+                                None => continue
+                            }
+                        }
+                    }
+                }
+                None => self.query_any().await
+            };
+            if let Ok(ref update) = res {
+                self.selection.set_data(idx, update.clone());
+            }
+            for (idx, link) in self.links.iter().enumerate() {
+                self.selection.set_status(idx, link.get_status());
+            }
+            if let Some(res) = self.switch() {
+                return res
+            }
+            if idx == self.selection.active() {
+                return match res {
+                    Ok(update) if self.fresh => {
+                        self.fresh = false;
+                        Ok(Self::strip_diff(update))
+                    }
+                    res => res
+                }
+            }
+        }
+    }
+
+    /// Switches to a new active unit if necessary.
+    ///
+    /// Returns the result of the query if the active unit has changed.
+    fn switch(&mut self) -> Option<Result<payload::Update, UnitStatus>> {
+        if !self.selection.select() {
+            return None
+        }
+        let active = self.selection.active();
+        info!(
+            "Target {}: switching to unit {}.",
+            self.target, self.metrics.names[active]
+        );
+        self.metrics.active.store(active, Ordering::Relaxed);
+        self.metrics.switches.fetch_add(1, Ordering::Relaxed);
+        self.switched = true;
+        self.fresh = false;
+        Some(match self.selection.data(active) {
+            Some(update) => Ok(Self::strip_diff(update.clone())),
+            None => {
+                self.fresh = true;
+                Err(self.links[active].get_status())
+            }
+        })
+    }
+
+    /// Queries all units that haven’t gone away for their next update.
+    ///
+    /// Returns the result and the index of the unit it came from. If all
+    /// units have gone, the active one is queried.
+    async fn query_any(
+        &mut self
+    ) -> (Result<payload::Update, UnitStatus>, usize) {
+        if self.links.len() == 1 {
+            return (self.links[0].query().await, 0)
+        }
+        let active = self.selection.active();
+        let queries: Vec<_> = self.links.iter_mut().enumerate().filter(
+            |(idx, link)| {
+                *idx == active || link.get_status() != UnitStatus::Gone
+            }
+        ).map(|(idx, link)| {
+            async move { (link.query().await, idx) }.boxed()
+        }).collect();
+        select_all(queries).await.0
+    }
+
+    /// Removes the diff from an update.
+    ///
+    /// The diff of an update received from a newly active unit doesn’t
+    /// lead from what the target has been serving before.
+    fn strip_diff(update: payload::Update) -> payload::Update {
+        if update.diff().is_none() {
+            return update
+        }
+        payload::Update::new(
+            update.serial(), update.set(), None
+        ).with_audit_only(
+            update.is_audit_only()
+        ).with_ids(update.ids()).with_origin(update.origin())
+    }
+}
+
+
+//------------ Selection -----------------------------------------------------
+
+/// Selecting the active unit according to a policy.
+#[derive(Clone, Debug)]
+struct Selection {
+    /// The policy to select a unit.
+    policy: SourcePolicy,
+
+    /// The last known status of each unit.
+    status: Vec<UnitStatus>,
+
+    /// The last update received from each unit.
+    data: Vec<Option<payload::Update>>,
+
+    /// The index of the active unit.
+    active: usize,
+
+    /// The end of the grace period after startup if it hasn’t passed yet.
+    ///
+    /// During the grace period, the first unit stays active unless it has
+    /// gone away.
+    grace: Option<Instant>,
+}
+
+impl Selection {
+    /// Creates a new selection for `len` units.
+    ///
+    /// Initially, the first unit is active.
+    fn new(policy: SourcePolicy, len: usize) -> Self {
+        Selection {
+            policy,
+            status: vec![UnitStatus::Healthy; len],
+            data: vec![None; len],
+            active: 0,
+            grace: None,
+        }
+    }
+
+    /// Keeps the first unit active until `until`.
+    fn with_grace(mut self, until: Instant) -> Self {
+        self.grace = Some(until);
+        self
+    }
+
+    /// Returns the end of the grace period if it hasn’t passed yet.
+    fn grace(&self) -> Option<Instant> {
+        self.grace
+    }
+
+    /// Returns the index of the active unit.
+    fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Returns the last update of a unit.
+    fn data(&self, idx: usize) -> Option<&payload::Update> {
+        self.data[idx].as_ref()
+    }
+
+    /// Records the status of a unit.
+    fn set_status(&mut self, idx: usize, status: UnitStatus) {
+        self.status[idx] = status
+    }
+
+    /// Records an update of a unit.
+    fn set_data(&mut self, idx: usize, update: payload::Update) {
+        self.data[idx] = Some(update)
+    }
+
+    /// Returns whether a unit qualifies for being active.
+    fn qualifies(&self, idx: usize) -> bool {
+        match self.policy {
+            SourcePolicy::FirstHealthy => {
+                self.status[idx] == UnitStatus::Healthy
+            }
+            SourcePolicy::FirstWithData => {
+                self.status[idx] != UnitStatus::Gone
+                && self.data[idx].as_ref().map(|update| {
+                    !update.set().is_empty()
+                }).unwrap_or(false)
+            }
+        }
+    }
+
+    /// Selects the active unit.
+    ///
+    /// This is the first unit that qualifies. If no unit qualifies, the
+    /// active unit stays the same. Returns whether the active unit has
+    /// changed.
+    fn select(&mut self) -> bool {
+        self.select_at(Instant::now())
+    }
+
+    /// Selects the active unit at the given time.
+    ///
+    /// Until the grace period has passed, the first unit stays active
+    /// unless it has gone away.
+    fn select_at(&mut self, now: Instant) -> bool {
+        if let Some(grace) = self.grace {
+            if now < grace && self.status[0] != UnitStatus::Gone {
+                return false
+            }
+            self.grace = None;
+        }
+        let best = (0..self.status.len()).find(|&idx| self.qualifies(idx));
+        match best {
+            Some(best) if best != self.active => {
+                self.active = best;
+                true
+            }
+            _ => false
+        }
+    }
+}
+
+
+//------------ SourceMetrics -------------------------------------------------
+
+/// The metrics of a target’s sources.
+#[derive(Debug)]
+struct SourceMetrics {
+    /// The names of the units.
+    names: Vec<String>,
+
+    /// The index of the active unit.
+    active: AtomicUsize,
+
+    /// The number of times the active unit has changed.
+    switches: AtomicU64,
+}
+
+impl SourceMetrics {
+    /// Creates the metrics for the given links.
+    ///
+    /// Units are named after the links or by their index if a link doesn’t
+    /// know its unit’s name.
+    fn new(links: &[Link]) -> Self {
+        SourceMetrics {
+            names: links.iter().enumerate().map(|(idx, link)| {
+                match link.unit_name() {
+                    Some(name) => name.into(),
+                    None => idx.to_string(),
+                }
+            }).collect(),
+            active: AtomicUsize::new(0),
+            switches: AtomicU64::new(0),
+        }
+    }
+}
+
+impl SourceMetrics {
+    const ACTIVE_SOURCE_METRIC: Metric = Metric::new(
+        "active_source",
+        "whether the target currently serves the data of the unit",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const ACTIVE_SOURCE_NAME_METRIC: Metric = Metric::new(
+        "active_source_name",
+        "the name of the unit the target currently serves",
+        MetricType::Text, MetricUnit::Info
+    );
+    const SOURCE_SWITCHES_METRIC: Metric = Metric::new(
+        "source_switches",
+        "the number of times the target switched to a different unit",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for SourceMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        let active = self.active.load(Ordering::Relaxed);
+        target.append(
+            &Self::ACTIVE_SOURCE_METRIC, Some(unit_name),
+            |records| {
+                for (idx, name) in self.names.iter().enumerate() {
+                    records.label_value(
+                        &[("source", name.as_str())],
+                        if idx == active { 1 } else { 0 }
+                    );
+                }
+            }
+        );
+        target.append_simple(
+            &Self::ACTIVE_SOURCE_NAME_METRIC, Some(unit_name),
+            &self.names[active]
+        );
+        target.append_simple(
+            &Self::SOURCE_SWITCHES_METRIC, Some(unit_name),
+            self.switches.load(Ordering::Relaxed)
+        );
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use rpki_rtr::state::Serial;
+    use crate::payload::testing::v4;
+    use super::*;
+
+    fn update(len: u32) -> payload::Update {
+        let mut set = payload::SetBuilder::empty();
+        for n in 0..len {
+            set.insert(v4(n << 8, 24, 24, 64496)).unwrap();
+        }
+        payload::Update::new(Serial::from(1), Arc::new(set.finalize()), None)
+    }
+
+    #[test]
+    fn first_healthy() {
+        let mut selection = Selection::new(SourcePolicy::FirstHealthy, 3);
+        assert!(!selection.select());
+        assert_eq!(selection.active(), 0);
+
+        // The first healthy unit is picked, data doesn’t matter.
+        selection.set_status(0, UnitStatus::Stalled);
+        selection.set_data(2, update(1));
+        assert!(selection.select());
+        assert_eq!(selection.active(), 1);
+
+        // Without any healthy unit, the active one stays.
+        selection.set_status(1, UnitStatus::Gone);
+        selection.set_status(2, UnitStatus::Stalled);
+        assert!(!selection.select());
+        assert_eq!(selection.active(), 1);
+
+        // A unit with higher precedence takes over once it recovers.
+        selection.set_status(2, UnitStatus::Healthy);
+        assert!(selection.select());
+        assert_eq!(selection.active(), 2);
+        selection.set_status(0, UnitStatus::Healthy);
+        assert!(selection.select());
+        assert_eq!(selection.active(), 0);
+    }
+
+    #[test]
+    fn first_with_data() {
+        let mut selection = Selection::new(SourcePolicy::FirstWithData, 2);
+        selection.set_data(1, update(1));
+        assert!(selection.select());
+        assert_eq!(selection.active(), 1);
+
+        // An empty set doesn’t count, a stalled unit with data does.
+        selection.set_data(0, update(0));
+        assert!(!selection.select());
+        selection.set_data(0, update(2));
+        selection.set_status(0, UnitStatus::Stalled);
+        assert!(selection.select());
+        assert_eq!(selection.active(), 0);
+
+        // A unit that has gone doesn’t qualify with its old data.
+        selection.set_status(0, UnitStatus::Gone);
+        assert!(selection.select());
+        assert_eq!(selection.active(), 1);
+    }
+
+    #[test]
+    fn grace_period() {
+        let start = Instant::now();
+        let grace = start + Duration::from_secs(10);
+
+        // The first unit stays active during the grace period.
+        let mut selection = Selection::new(
+            SourcePolicy::FirstWithData, 2
+        ).with_grace(grace);
+        selection.set_data(1, update(1));
+        assert!(!selection.select_at(start));
+        assert_eq!(selection.active(), 0);
+        assert!(selection.select_at(grace));
+        assert_eq!(selection.active(), 1);
+        assert_eq!(selection.grace(), None);
+
+        // Unless it has gone away.
+        let mut selection = Selection::new(
+            SourcePolicy::FirstWithData, 2
+        ).with_grace(grace);
+        selection.set_data(1, update(1));
+        selection.set_status(0, UnitStatus::Gone);
+        assert!(selection.select_at(start));
+        assert_eq!(selection.active(), 1);
+    }
+
+    #[test]
+    fn metrics() {
+        let metrics = SourceMetrics {
+            names: vec!["first".into(), "second".into()],
+            active: AtomicUsize::new(1),
+            switches: AtomicU64::new(1),
+        };
+        let mut target = metrics::Target::new(metrics::OutputFormat::Plain);
+        metrics::Source::append(&metrics, "rtr", &mut target);
+        let target = target.into_string();
+        assert!(target.contains("rtr active_source_name: second\n"));
+        assert!(
+            target.contains("rtr active_source source=second: 1\n")
+        );
+
+        // The name is plain text and not a Prometheus metric.
+        let mut target = metrics::Target::new(
+            metrics::OutputFormat::Prometheus
+        );
+        metrics::Source::append(&metrics, "rtr", &mut target);
+        assert!(!target.into_string().contains("active_source_name"));
+    }
+
+}
@@ -0,0 +1,386 @@
+//! Limiting the bandwidth used by RTR connections.
+//!
+//! When many routers do a full cache reset at the same time, the RTR
+//! target can easily saturate a constrained uplink. A [`Throttle`] limits
+//! the rate at which data is sent via token buckets: one for each
+//! connection and one shared by all connections of the target. Either of
+//! them is optional.
+//!
+//! Only the payload PDUs, i.e., prefixes, router keys, and ASPAs, are
+//! limited. All other PDUs, most importantly Serial Notify and End of
+//! Data, bypass the limit so that sessions don’t time out waiting behind
+//! a large transfer.
+//!
+//! The limits are applied by wrapping each connection into a
+//! [`ThrottledStream`]. This needs to happen where the RTR server still
+//! writes each PDU separately.
+
+use std::{cmp, io};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{delay_until, Delay, Instant};
+use crate::metrics;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ Throttle ------------------------------------------------------
+
+/// The bandwidth limits of all connections of a target.
+#[derive(Debug)]
+pub struct Throttle {
+    /// The maximum rate of each connection in bytes per second.
+    connection_rate: Option<u64>,
+
+    /// The bucket shared by all connections.
+    aggregate: Option<TokenBucket>,
+
+    /// The time connections have spent waiting in microseconds.
+    throttled: AtomicU64,
+
+    /// The throughput measurement.
+    throughput: Mutex<Throughput>,
+}
+
+impl Throttle {
+    /// Creates a new throttle.
+    ///
+    /// The rates are given in bytes per second. If a rate is `None`, there
+    /// is no limit.
+    pub fn new(connection_rate: Option<u64>, aggregate: Option<u64>) -> Self {
+        Throttle {
+            connection_rate,
+            aggregate: aggregate.map(TokenBucket::new),
+            throttled: AtomicU64::new(0),
+            throughput: Mutex::new(Throughput::new(Instant::now())),
+        }
+    }
+
+    /// Returns when sending may continue if it can’t right now.
+    fn admit(
+        &self, own: Option<&TokenBucket>, now: Instant
+    ) -> Option<Instant> {
+        let own = own.and_then(|bucket| bucket.ready_at(now));
+        let aggregate = self.aggregate.as_ref().and_then(|bucket| {
+            bucket.ready_at(now)
+        });
+        match (own, aggregate) {
+            (Some(own), Some(aggregate)) => Some(cmp::max(own, aggregate)),
+            (own, aggregate) => own.or(aggregate)
+        }
+    }
+
+    /// Records that `len` bytes have been sent.
+    fn sent(
+        &self, own: Option<&TokenBucket>, len: usize, limited: bool,
+        now: Instant
+    ) {
+        if limited {
+            if let Some(bucket) = own {
+                bucket.take(len, now)
+            }
+            if let Some(ref bucket) = self.aggregate {
+                bucket.take(len, now)
+            }
+        }
+        self.throughput.lock().unwrap().add(len, now);
+    }
+
+    /// Records that a connection has been waiting for `duration`.
+    fn waited(&self, duration: Duration) {
+        self.throttled.fetch_add(
+            duration.as_micros() as u64, Ordering::Relaxed
+        );
+    }
+}
+
+impl Throttle {
+    const THROUGHPUT_METRIC: Metric = Metric::new(
+        "rtr_throughput",
+        "bytes per second sent to all RTR clients during the last second",
+        MetricType::Gauge, MetricUnit::Byte
+    );
+    const THROTTLED_METRIC: Metric = Metric::new(
+        "rtr_throttled",
+        "the time RTR connections spent waiting for a bandwidth limit",
+        MetricType::Counter, MetricUnit::Second
+    );
+}
+
+impl metrics::Source for Throttle {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::THROUGHPUT_METRIC, Some(unit_name),
+            self.throughput.lock().unwrap().rate(Instant::now())
+        );
+        target.append_simple(
+            &Self::THROTTLED_METRIC, Some(unit_name),
+            self.throttled.load(Ordering::Relaxed) as f64 / 1_000_000.
+        );
+    }
+}
+
+
+//------------ ThrottledStream -----------------------------------------------
+
+/// A socket wrapper applying the bandwidth limits of a throttle.
+///
+/// The wrapper expects the RTR server to write each PDU with a separate
+/// call to `poll_write`. It keeps track of where in the current PDU it is
+/// so that a PDU written only partially is still recognized.
+pub struct ThrottledStream<Sock> {
+    /// The actual socket.
+    sock: Sock,
+
+    /// The bucket of this connection if there is a per-connection limit.
+    own: Option<TokenBucket>,
+
+    /// The throttle of the target.
+    throttle: Arc<Throttle>,
+
+    /// The number of bytes of the current PDU yet to be written.
+    pdu_left: usize,
+
+    /// Whether the current PDU is subject to the limits.
+    pdu_limited: bool,
+
+    /// The timer while waiting for the limits.
+    delay: Option<Delay>,
+
+    /// When we started waiting for the limits.
+    waiting_since: Option<Instant>,
+}
+
+impl<Sock> ThrottledStream<Sock> {
+    /// Creates a new wrapper for a socket.
+    pub fn new(sock: Sock, throttle: Arc<Throttle>) -> Self {
+        ThrottledStream {
+            sock,
+            own: throttle.connection_rate.map(TokenBucket::new),
+            throttle,
+            pdu_left: 0,
+            pdu_limited: false,
+            delay: None,
+            waiting_since: None,
+        }
+    }
+
+    /// Processes the start of a new PDU.
+    fn start_pdu(&mut self, buf: &[u8]) {
+        if buf.len() < 8 {
+            // Not a complete header. Let it pass.
+            self.pdu_left = buf.len();
+            self.pdu_limited = false;
+            return
+        }
+        self.pdu_left = cmp::max(
+            u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize,
+            1
+        );
+        // IPv4 Prefix, IPv6 Prefix, Router Key, and ASPA.
+        self.pdu_limited = matches!(buf[1], 4 | 6 | 9 | 11);
+    }
+
+    /// Waits until the limits allow sending.
+    fn poll_admit(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            if let Some(delay) = self.delay.as_mut() {
+                ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+            let now = Instant::now();
+            match self.throttle.admit(self.own.as_ref(), now) {
+                Some(until) => {
+                    if self.waiting_since.is_none() {
+                        self.waiting_since = Some(now);
+                    }
+                    self.delay = Some(delay_until(until));
+                }
+                None => {
+                    if let Some(since) = self.waiting_since.take() {
+                        self.throttle.waited(now - since);
+                    }
+                    return Poll::Ready(())
+                }
+            }
+        }
+    }
+}
+
+impl<Sock: AsyncRead + Unpin> AsyncRead for ThrottledStream<Sock> {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_read(cx, buf)
+    }
+}
+
+impl<Sock: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<Sock> {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        if this.pdu_left == 0 {
+            this.start_pdu(buf);
+        }
+        if this.pdu_limited {
+            ready!(this.poll_admit(cx));
+        }
+        let len = ready!(Pin::new(&mut this.sock).poll_write(cx, buf))?;
+        this.throttle.sent(
+            this.own.as_ref(), len, this.pdu_limited, Instant::now()
+        );
+        this.pdu_left = this.pdu_left.saturating_sub(len);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_shutdown(cx)
+    }
+}
+
+
+//------------ TokenBucket ---------------------------------------------------
+
+/// A token bucket for limiting the rate of sending data.
+///
+/// The bucket holds up to one second’s worth of tokens. Since PDUs are
+/// never split, a write may take more tokens than there are. The bucket
+/// then goes into debt and sending is held back until it is paid off.
+#[derive(Debug)]
+struct TokenBucket {
+    /// The rate in bytes per second.
+    rate: u64,
+
+    /// The current number of tokens and when it was last updated.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a new, full bucket for the given rate.
+    fn new(rate: u64) -> Self {
+        let rate = cmp::max(rate, 1);
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Adds the tokens accumulated since the last update.
+    fn refill(&self, state: &mut (f64, Instant), now: Instant) {
+        if now > state.1 {
+            let secs = (now - state.1).as_secs_f64();
+            state.0 = (
+                state.0 + secs * self.rate as f64
+            ).min(self.rate as f64);
+            state.1 = now;
+        }
+    }
+
+    /// Returns when the bucket will have tokens again if it has none now.
+    fn ready_at(&self, now: Instant) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        if state.0 >= 0. {
+            None
+        }
+        else {
+            Some(now + Duration::from_secs_f64(-state.0 / self.rate as f64))
+        }
+    }
+
+    /// Takes `len` tokens out of the bucket.
+    fn take(&self, len: usize, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+        state.0 -= len as f64;
+    }
+}
+
+
+//------------ Throughput ----------------------------------------------------
+
+/// Measures the rate of data sent.
+#[derive(Debug)]
+struct Throughput {
+    /// The start of the current measurement window.
+    start: Instant,
+
+    /// The bytes sent in the current window.
+    bytes: u64,
+
+    /// The rate in bytes per second of the last complete window.
+    last: u64,
+}
+
+impl Throughput {
+    /// The length of a measurement window.
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    fn new(now: Instant) -> Self {
+        Throughput { start: now, bytes: 0, last: 0 }
+    }
+
+    /// Starts a new window if the current one has ended.
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= Self::WINDOW {
+            self.last = (self.bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.start = now;
+            self.bytes = 0;
+        }
+    }
+
+    /// Adds data sent at `now`.
+    fn add(&mut self, len: usize, now: Instant) {
+        self.advance(now);
+        self.bytes += len as u64;
+    }
+
+    /// Returns the rate in bytes per second of the last window.
+    fn rate(&mut self, now: Instant) -> u64 {
+        self.advance(now);
+        self.last
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().1;
+        assert_eq!(bucket.ready_at(start), None);
+        bucket.take(1500, start);
+        assert_eq!(
+            bucket.ready_at(start), Some(start + Duration::from_millis(500))
+        );
+        assert_eq!(bucket.ready_at(start + Duration::from_millis(500)), None);
+
+        // Never more than a second’s worth of tokens.
+        let later = start + Duration::from_secs(10);
+        bucket.take(2000, later);
+        assert_eq!(
+            bucket.ready_at(later), Some(later + Duration::from_secs(1))
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Loading equivalent configs in all supported formats.
+
+use std::fs;
+use crate::config::{Config, ConfigFile, ConfigFormat};
+use crate::manager::Manager;
+
+const TOML: &str = r#"
+log-level = "debug"
+http-listen = ["127.0.0.1:8323"]
+
+[defaults.units.rtr]
+retry = 30
+
+[units.a]
+type = "rtr"
+remote = "localhost:3323"
+
+[units.b]
+type = "json"
+uri = "http://localhost/vrps.json"
+refresh = 60
+
+[units.c]
+type = "any"
+sources = ["a", { unit = "b", family = "ipv4", asns = [64496] }]
+random = false
+
+[targets.d]
+type = "rtr"
+listen = ["127.0.0.1:3323"]
+unit = "c"
+
+[targets.e]
+type = "http"
+path = "/json"
+format = "json"
+unit = ["a", { unit = "b", family = "ipv6" }]
+source-policy = "first-with-data"
+"#;
+
+const JSON: &str = r#"{
+    "log-level": "debug",
+    "http-listen": ["127.0.0.1:8323"],
+    "defaults": { "units": { "rtr": { "retry": 30 } } },
+    "units": {
+        "a": { "type": "rtr", "remote": "localhost:3323" },
+        "b": {
+            "type": "json", "uri": "http://localhost/vrps.json",
+            "refresh": 60
+        },
+        "c": {
+            "type": "any",
+            "sources": [
+                "a", { "unit": "b", "family": "ipv4", "asns": [64496] }
+            ],
+            "random": false
+        }
+    },
+    "targets": {
+        "d": { "type": "rtr", "listen": ["127.0.0.1:3323"], "unit": "c" },
+        "e": {
+            "type": "http", "path": "/json", "format": "json",
+            "unit": ["a", { "unit": "b", "family": "ipv6" }],
+            "source-policy": "first-with-data"
+        }
+    }
+}"#;
+
+const YAML: &str = r#"
+log-level: debug
+http-listen: ["127.0.0.1:8323"]
+defaults:
+  units:
+    rtr:
+      retry: 30
+units:
+  a:
+    type: rtr
+    remote: "localhost:3323"
+  b:
+    type: json
+    uri: "http://localhost/vrps.json"
+    refresh: 60
+  c:
+    type: any
+    sources:
+      - a
+      - unit: b
+        family: ipv4
+        asns: [64496]
+    random: false
+targets:
+  d:
+    type: rtr
+    listen: ["127.0.0.1:3323"]
+    unit: c
+  e:
+    type: http
+    path: /json
+    format: json
+    unit:
+      - a
+      - unit: b
+        family: ipv6
+    source-policy: first-with-data
+"#;
+
+/// Loads a config from a file with the given extension.
+fn load(data: &str, ext: &str) -> Result<Config, ()> {
+    let path = std::env::temp_dir().join(
+        format!("rtrtr-config-formats-{}.{}", std::process::id(), ext)
+    );
+    fs::write(&path, data).unwrap();
+    let file = ConfigFile::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    Manager::new().load(file).map_err(|_| ())
+}
+
+/// Returns a description of the units and targets of a config.
+///
+/// This is the debug output of all components in the order of their names.
+/// The channels inside links contain addresses and are left out.
+fn graph(config: &Config) -> Vec<String> {
+    let mut res: Vec<_> = config.units.iter().map(|(name, unit)| {
+        format!("unit {}: {:?}", name, unit)
+    }).chain(config.targets.iter().map(|(name, target)| {
+        format!("target {}: {:?}", name, target)
+    })).map(|item| strip_senders(&item)).collect();
+    res.sort();
+    res
+}
+
+/// Removes the debug output of all channel senders from a string.
+fn strip_senders(mut s: &str) -> String {
+    const SENDER: &str = "Sender {";
+    let mut res = String::new();
+    while let Some(start) = s.find(SENDER) {
+        res.push_str(&s[..start]);
+        res.push_str("Sender");
+        s = &s[start + SENDER.len()..];
+        let mut depth = 1;
+        while depth > 0 {
+            let end = s.find(|ch| ch == '{' || ch == '}').unwrap();
+            depth = if s.as_bytes()[end] == b'{' { depth + 1 }
+                    else { depth - 1 };
+            s = &s[end + 1..];
+        }
+    }
+    res.push_str(s);
+    res
+}
+
+/// Moves the defaults into the unit in one of the configs above.
+fn without_defaults(
+    data: &str, defaults: &str, remote: &str, retry: &str
+) -> String {
+    assert!(data.contains(defaults) && data.contains(remote));
+    data.replace(defaults, "").replace(
+        remote, &format!("{}{}", remote, retry)
+    )
+}
+
+#[test]
+fn round_trip() {
+    let toml = graph(&load(TOML, "conf").unwrap());
+    assert_eq!(toml.len(), 5);
+    assert_eq!(toml, graph(&load(JSON, "json").unwrap()));
+    assert_eq!(toml, graph(&load(YAML, "yaml").unwrap()));
+    assert_eq!(toml, graph(&load(YAML, "yml").unwrap()));
+
+    // Without a defaults section, the data is deserialized directly.
+    assert_eq!(toml, graph(&load(
+        &without_defaults(
+            TOML, "[defaults.units.rtr]\nretry = 30\n",
+            "\"localhost:3323\"\n", "retry = 30\n"
+        ),
+        "conf"
+    ).unwrap()));
+    assert_eq!(toml, graph(&load(
+        &without_defaults(
+            JSON,
+            "\"defaults\": { \"units\": { \"rtr\": { \"retry\": 30 } } \
+             },",
+            "\"localhost:3323\"", ", \"retry\": 30"
+        ),
+        "json"
+    ).unwrap()));
+    assert_eq!(toml, graph(&load(
+        &without_defaults(
+            YAML, "defaults:\n  units:\n    rtr:\n      retry: 30\n",
+            "\"localhost:3323\"\n", "    retry: 30\n"
+        ),
+        "yaml"
+    ).unwrap()));
+
+    // The format can be overridden.
+    let path = std::env::temp_dir().join(
+        format!("rtrtr-config-formats-{}.conf", std::process::id())
+    );
+    fs::write(&path, JSON).unwrap();
+    let mut file = ConfigFile::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(file.format(), ConfigFormat::Toml);
+    file.set_format(ConfigFormat::Json);
+    assert_eq!(toml, graph(&Manager::new().load(file).unwrap()));
+}
+
+#[test]
+fn error_positions() {
+    fn err(data: &str, format: ConfigFormat) -> String {
+        match Config::from_slice(data.as_bytes(), format) {
+            Ok(_) => panic!("config accepted"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    // Syntax errors.
+    assert!(
+        err("{\n  \"units\": {,\n}", ConfigFormat::Json)
+            .contains("line 2 column")
+    );
+    assert!(
+        err("units:\n  a: [\n", ConfigFormat::Yaml).contains("line 3")
+    );
+
+    // Invalid values. Since units are tagged enums, they are buffered
+    // and the position is that of the end of the unit’s table.
+    let json = err(
+        "{\n  \"units\": {\n    \"a\": { \"type\": \"json\", \"uri\": 5 }\n  \
+         },\n  \"targets\": {}\n}",
+        ConfigFormat::Json
+    );
+    assert!(json.contains("line 3 column"), "{}", json);
+    let yaml = err(
+        "units:\n  a: { type: json, uri: 5 }\ntargets: {}\n",
+        ConfigFormat::Yaml
+    );
+    assert!(yaml.contains("line 2 column"), "{}", yaml);
+}
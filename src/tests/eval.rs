@@ -0,0 +1,74 @@
+//! Evaluating a config with injected data.
+
+use std::fs;
+use std::sync::Arc;
+use crate::config::ConfigFile;
+use crate::eval::Eval;
+use crate::manager::Manager;
+use crate::payload::SetBuilder;
+use crate::payload::testing::v4;
+
+const CONFIG: &str = r#"
+http-listen = []
+
+[units.source]
+type = "rtr"
+remote = "localhost:1"
+
+[units.filtered]
+type = "filter"
+source = "source"
+rules = "RULES"
+
+[units.as0]
+type = "aggregate"
+source = "source"
+
+[targets.served]
+type = "rtr"
+listen = []
+unit = "filtered"
+
+[targets.deny]
+type = "http"
+path = "/deny"
+format = "prefix-list"
+unit = "as0"
+"#;
+
+#[test]
+fn eval() {
+    let dir = std::env::temp_dir().join(
+        format!("rtrtr-eval-{}", std::process::id())
+    );
+    fs::create_dir_all(&dir).unwrap();
+    let rules = dir.join("filter.toml");
+    fs::write(&rules, "exclude-asns = [64497]\n").unwrap();
+    let config = dir.join("rtrtr.conf");
+    fs::write(
+        &config, CONFIG.replace("RULES", rules.to_str().unwrap())
+    ).unwrap();
+
+    let mut set = SetBuilder::empty();
+    for &(octet, asn) in &[(0, 64496), (0, 0), (128, 0), (255, 64497)] {
+        let prefix_len = if octet == 255 { 32 } else { 25 };
+        set.insert(v4([192, 0, 2, octet], prefix_len, 32, asn)).unwrap();
+    }
+
+    let mut manager = Manager::new();
+    let config = manager.load(ConfigFile::load(&config).unwrap()).unwrap();
+    let out = dir.join("out");
+    assert!(
+        Eval::eval(manager, config, Arc::new(set.finalize()), &out).is_ok()
+    );
+
+    assert_eq!(
+        fs::read_to_string(out.join("deny.txt")).unwrap(),
+        "192.0.2.0/24\n"
+    );
+    let served = fs::read_to_string(out.join("served.json")).unwrap();
+    assert_eq!(served.matches("\"asn\"").count(), 3);
+    assert!(!served.contains("AS64497"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,121 @@
+//! Property tests for combining payload sets and diffs.
+//!
+//! Updates are passed along and combined in many places. These tests make
+//! sure that doing so is consistent: operations that should be idempotent
+//! are, and those that aren’t fail in the expected way rather than silently
+//! producing garbage.
+
+use std::collections::HashMap;
+use proptest::prelude::*;
+use rpki_rtr::client::VrpError;
+use rpki_rtr::payload::{Action, Payload};
+use crate::payload::{Diff, DiffBuilder, Set, SetBuilder};
+use crate::payload::testing::v4;
+
+
+//------------ Strategies ----------------------------------------------------
+
+/// Produces payload from a small space so that sets and diffs overlap.
+fn payload() -> impl Strategy<Value = Payload> {
+    (0u8..4, 24u8..27, 0u32..4).prop_map(|(octet, prefix_len, asn)| {
+        v4([192, 0, 2, octet << 6], prefix_len, prefix_len, asn)
+    })
+}
+
+/// Produces a set.
+fn set() -> impl Strategy<Value = Set> {
+    prop::collection::vec(payload(), 0..24).prop_map(|items| {
+        let mut res = SetBuilder::empty();
+        for item in items {
+            let _ = res.insert(item);
+        }
+        res.finalize()
+    })
+}
+
+/// Produces a diff with at most one change per payload.
+fn diff() -> impl Strategy<Value = Diff> {
+    prop::collection::vec((payload(), any::<bool>()), 0..24).prop_map(
+        |items| {
+            let items: HashMap<_, _> = items.into_iter().collect();
+            let mut res = DiffBuilder::default();
+            for (item, announce) in items {
+                let action = if announce {
+                    Action::Announce
+                }
+                else {
+                    Action::Withdraw
+                };
+                res.push(item, action).unwrap();
+            }
+            res.finalize()
+        }
+    )
+}
+
+/// Returns the content of a set for comparison.
+fn items(set: &Set) -> Vec<Payload> {
+    set.iter().copied().collect()
+}
+
+
+//------------ Properties ----------------------------------------------------
+
+proptest! {
+    #[test]
+    fn apply_is_idempotent(set in set(), diff in diff()) {
+        let once = diff.apply(&set);
+        let twice = diff.apply(&once);
+        prop_assert_eq!(items(&once), items(&twice));
+    }
+
+    #[test]
+    fn extend_with_itself_fails(diff in diff()) {
+        match diff.extend(&diff) {
+            Ok(extended) => {
+                prop_assert!(diff.is_empty() && extended.is_empty())
+            }
+            Err(err) => {
+                prop_assert!(!diff.is_empty());
+                prop_assert!(matches!(err, VrpError::Corrupt));
+            }
+        }
+    }
+
+    #[test]
+    fn reconcile_then_apply(old in set(), new in set()) {
+        let diff = Diff::reconcile(&old, &new);
+        prop_assert_eq!(items(&diff.apply(&old)), items(&new));
+
+        // Applying the reconciled diff again changes nothing.
+        let again = diff.apply(&diff.apply(&old));
+        prop_assert_eq!(items(&again), items(&new));
+        prop_assert!(Diff::reconcile(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn split_reconcile_is_equivalent(
+        old in set(), mid in set(), new in set()
+    ) {
+        // Going via an intermediate set makes the same changes.
+        let split = Diff::reconcile(&old, &mid).extend(
+            &Diff::reconcile(&mid, &new)
+        ).unwrap();
+        prop_assert!(
+            split.is_semantically_equivalent(&Diff::reconcile(&old, &new))
+        );
+    }
+
+    #[test]
+    fn merge_set_is_idempotent(left in set(), right in set()) {
+        let mut once = SetBuilder::from(&left);
+        once.merge_set(&right);
+        let once = once.finalize();
+        let mut twice = SetBuilder::from(&left);
+        twice.merge_set(&right);
+        twice.merge_set(&right);
+        let twice = twice.finalize();
+        prop_assert_eq!(items(&once), items(&twice));
+        prop_assert!(right.iter().all(|item| items(&once).contains(item)));
+    }
+}
@@ -0,0 +1,23 @@
+//! Tests spanning more than a single module.
+
+use rpki_rtr::State;
+
+mod config_formats;
+mod eval;
+mod merge_idempotency;
+mod reset_loop;
+mod rtree_bench;
+mod withdraw_only;
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Asserts that two RTR states are equal.
+///
+/// `State` doesn’t implement `PartialEq`, so this compares the session ID
+/// and serial number.
+pub fn assert_state_eq(left: State, right: State) {
+    assert_eq!(
+        (left.session(), left.serial()), (right.session(), right.serial())
+    );
+}
@@ -0,0 +1,129 @@
+//! Backing off from a server that keeps resetting.
+//!
+//! The test runs an RTR unit against a scripted server on the loopback
+//! interface. The server answers every serial query with a Cache Reset,
+//! then serves the complete data set for the following reset query and
+//! closes the connection. Since the unit reconnects with a serial query,
+//! every connection after the first one forces a full transfer. Once more
+//! than `max-resets-per-hour` of these happened, the unit must raise its
+//! alarm and stop reconnecting for `reset-backoff` seconds.
+
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::time::{delay_for, Instant};
+use crate::config::ConfigFile;
+use crate::manager::Manager;
+use crate::metrics::OutputFormat;
+
+const CONFIG: &str = r#"
+http-listen = []
+
+[units.cache]
+type = "rtr"
+remote = "SERVER"
+retry = 0
+reconnect-serial = "trust-serial"
+max-resets-per-hour = 2
+reset-backoff = 3600
+
+[targets.json]
+type = "http"
+path = "/json"
+format = "json"
+unit = "cache"
+"#;
+
+/// How long to wait for the unit to back off.
+const MAX_WAIT: Duration = Duration::from_secs(10);
+
+#[test]
+fn reset_loop() {
+    let mut runtime = runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    let mut listener = runtime.block_on(
+        TcpListener::bind(("127.0.0.1", 0))
+    ).unwrap();
+    let server = listener.local_addr().unwrap();
+    let count = connections.clone();
+    runtime.spawn(async move {
+        loop {
+            let (sock, _) = listener.accept().await.unwrap();
+            count.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(serve(sock));
+        }
+    });
+
+    let path = std::env::temp_dir().join(
+        format!("rtrtr-reset-loop-{}.conf", std::process::id())
+    );
+    fs::write(&path, CONFIG.replace("SERVER", &server.to_string())).unwrap();
+    let mut manager = Manager::new();
+    let mut config = manager.load(
+        ConfigFile::load(&path).unwrap()
+    ).unwrap();
+    fs::remove_file(&path).unwrap();
+    manager.spawn(&mut config, &runtime);
+
+    runtime.block_on(async {
+        // The initial connection plus three serial queries answered with a
+        // reset, the third of which exceeds the limit.
+        let deadline = Instant::now() + MAX_WAIT;
+        while !manager.metrics().assemble(OutputFormat::Plain).contains(
+            "cache reset_alarm: 1\n"
+        ) {
+            assert!(Instant::now() < deadline, "no reset alarm");
+            delay_for(Duration::from_millis(50)).await;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 4);
+
+        // The unit now backs off instead of reconnecting.
+        delay_for(Duration::from_millis(500)).await;
+        assert_eq!(connections.load(Ordering::SeqCst), 4);
+    });
+
+    let metrics = manager.metrics().assemble(OutputFormat::Plain);
+    assert!(metrics.contains("cache cache_resets: 3\n"), "{}", metrics);
+    assert!(metrics.contains("cache updates_full: 4\n"), "{}", metrics);
+}
+
+/// Answers the queries of a single connection and closes it.
+async fn serve(mut sock: TcpStream) {
+    let mut query = [0u8; 8];
+    sock.read_exact(&mut query).await.unwrap();
+    let version = query[0];
+    if query[1] == 1 {
+        // Serial Query: read the serial and answer with Cache Reset.
+        let mut serial = [0u8; 4];
+        sock.read_exact(&mut serial).await.unwrap();
+        sock.write_all(&[version, 8, 0, 0, 0, 0, 0, 8]).await.unwrap();
+        sock.read_exact(&mut query).await.unwrap();
+    }
+    assert_eq!(query[1], 2, "expected a Reset Query");
+
+    // Cache Response, one IPv4 Prefix, End of Data.
+    let mut response = vec![version, 3, 0, 7, 0, 0, 0, 8];
+    response.extend_from_slice(&[
+        version, 4, 0, 0, 0, 0, 0, 20,
+        1, 24, 24, 0, 192, 0, 2, 0, 0, 0, 0xfb, 0xf0
+    ]);
+    if version == 0 {
+        response.extend_from_slice(&[0, 7, 0, 7, 0, 0, 0, 12, 0, 0, 0, 1]);
+    }
+    else {
+        response.extend_from_slice(&[
+            version, 7, 0, 7, 0, 0, 0, 24, 0, 0, 0, 1,
+            0, 0, 14, 16, 0, 0, 2, 88, 0, 0, 28, 32
+        ]);
+    }
+    sock.write_all(&response).await.unwrap();
+}
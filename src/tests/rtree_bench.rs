@@ -0,0 +1,65 @@
+//! Comparing prefix range queries via the R-tree index with a linear scan.
+//!
+//! The benchmark is ignored by default as it only makes sense in release
+//! builds. Run it via:
+//!
+//! ```text
+//! cargo test --release rtree_bench -- --ignored --nocapture
+//! ```
+
+use std::net::Ipv4Addr;
+use std::time::Instant;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::payload::{Prefix, RtreeIndex, SetBuilder};
+use crate::payload::testing::v4;
+
+/// The number of items in the set.
+const SET_SIZE: usize = 200_000;
+
+/// The number of range queries.
+const QUERIES: usize = 10_000;
+
+#[test]
+#[ignore]
+fn rtree_bench() {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    let mut builder = SetBuilder::empty();
+    while builder.len() < SET_SIZE {
+        let prefix_len = rng.gen_range(8, 25);
+        let addr = rng.gen::<u32>() & !((1u32 << (32 - prefix_len)) - 1);
+        let _ = builder.insert(
+            v4(addr, prefix_len, prefix_len, rng.gen_range(1, 65536))
+        );
+    }
+    let set = builder.finalize();
+    let queries: Vec<_> = (0..QUERIES).map(|_| {
+        Prefix::new(
+            Ipv4Addr::from(rng.gen::<u32>()).into(), rng.gen_range(8, 25)
+        ).unwrap()
+    }).collect();
+
+    let start = Instant::now();
+    let index = RtreeIndex::new(&set);
+    let build = start.elapsed();
+
+    let start = Instant::now();
+    let indexed: Vec<_> = queries.iter().map(|range| {
+        index.covering(*range)
+    }).collect();
+    let rtree = start.elapsed();
+
+    let start = Instant::now();
+    let linear: Vec<Vec<_>> = queries.iter().map(|range| {
+        set.iter().filter(|item| range.covers(item)).collect()
+    }).collect();
+    let scan = start.elapsed();
+
+    assert_eq!(indexed, linear);
+    println!(
+        "{} items, {} queries, {} results: \
+         build {:?}, R-tree {:?}, linear scan {:?}",
+        set.len(), QUERIES, indexed.iter().map(Vec::len).sum::<usize>(),
+        build, rtree, scan
+    );
+}
@@ -0,0 +1,194 @@
+//! Propagating an update that only withdraws VRPs.
+//!
+//! The test runs a complete pipeline on the loopback interface: a local
+//! RTAN feed serves a unit which in turn feeds an RTR server target and an
+//! HTTP target producing JSON. Once both targets serve the feed’s snapshot,
+//! the feed sends a delta that only withdraws a VRP. This delta must reach
+//! an RTR client as a withdrawal and shrink the JSON output right away
+//! since the feed pushes its deltas rather than being polled.
+
+use std::fs;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Request};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::sync::oneshot;
+use tokio::time::{delay_for, Instant};
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+use crate::config::ConfigFile;
+use crate::http;
+use crate::manager::Manager;
+
+const CONFIG: &str = r#"
+http-listen = []
+
+[units.feed]
+type = "rtan"
+uri = "ws://FEED/"
+
+[targets.rtr]
+type = "rtr"
+listen = ["RTR"]
+unit = "feed"
+
+[targets.json]
+type = "http"
+path = "/json"
+format = "json"
+unit = "feed"
+"#;
+
+const SNAPSHOT: &str = r#"{
+    "type": "snapshot", "serial": 1, "roas": [
+        { "asn": "AS64496", "prefix": "192.0.2.0/24",
+          "maxLength": 24, "ta": "test" },
+        { "asn": "AS64497", "prefix": "198.51.100.0/24",
+          "maxLength": 24, "ta": "test" }
+    ]
+}"#;
+
+const DELTA: &str = r#"{
+    "type": "delta", "serial": 2, "withdrawn": [
+        { "asn": "AS64497", "prefix": "198.51.100.0/24",
+          "maxLength": 24, "ta": "test" }
+    ]
+}"#;
+
+/// How long to wait for the pipeline to deliver an update.
+const MAX_WAIT: Duration = Duration::from_secs(10);
+
+#[test]
+fn withdraw_only() {
+    let mut runtime = runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // The feed sends its snapshot to the first subscriber and the delta
+    // once told to.
+    let (delta_tx, delta_rx) = oneshot::channel::<()>();
+    let mut listener = runtime.block_on(
+        TcpListener::bind(("127.0.0.1", 0))
+    ).unwrap();
+    let feed = listener.local_addr().unwrap();
+    runtime.spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut sock = accept_async(sock).await.unwrap();
+        let _subscribe = sock.next().await;
+        sock.send(Message::Text(SNAPSHOT.into())).await.unwrap();
+        delta_rx.await.unwrap();
+        sock.send(Message::Text(DELTA.into())).await.unwrap();
+        while let Some(Ok(_)) = sock.next().await { }
+    });
+
+    let rtr = StdTcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap();
+    let path = std::env::temp_dir().join(
+        format!("rtrtr-withdraw-only-{}.conf", std::process::id())
+    );
+    fs::write(
+        &path,
+        CONFIG.replace("FEED", &feed.to_string())
+            .replace("RTR", &rtr.to_string())
+    ).unwrap();
+    let mut manager = Manager::new();
+    let mut config = manager.load(
+        ConfigFile::load(&path).unwrap()
+    ).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(config.targets.bind().is_ok());
+    manager.spawn(&mut config, &runtime);
+    let resources = manager.http_resources();
+
+    runtime.block_on(async {
+        // Wait for the snapshot to arrive at both targets.
+        let deadline = Instant::now() + MAX_WAIT;
+        while json_asns(&resources).await != Some(2) {
+            assert!(Instant::now() < deadline, "no data in JSON output");
+            delay_for(Duration::from_millis(50)).await;
+        }
+        let (session, serial) = loop {
+            let pdus = query(rtr, &[1, 2, 0, 0, 0, 0, 0, 8]).await;
+            if pdu_types(&pdus) == [3, 4, 4, 7] {
+                let end = &pdus[3];
+                break (
+                    [end[2], end[3]], [end[8], end[9], end[10], end[11]]
+                )
+            }
+            assert!(Instant::now() < deadline, "no data via RTR");
+            delay_for(Duration::from_millis(50)).await;
+        };
+
+        // Now withdraw one VRP.
+        delta_tx.send(()).unwrap();
+        let deadline = Instant::now() + MAX_WAIT;
+        while json_asns(&resources).await != Some(1) {
+            assert!(Instant::now() < deadline, "JSON output didn’t shrink");
+            delay_for(Duration::from_millis(50)).await;
+        }
+        let serial_query = [
+            1, 1, session[0], session[1], 0, 0, 0, 12,
+            serial[0], serial[1], serial[2], serial[3]
+        ];
+        let pdus = loop {
+            let pdus = query(rtr, &serial_query).await;
+            if pdus.len() > 2 {
+                break pdus
+            }
+            assert!(Instant::now() < deadline, "no withdrawal via RTR");
+            delay_for(Duration::from_millis(50)).await;
+        };
+
+        // Cache Response, IPv4 Prefix with the withdrawal, End of Data.
+        assert_eq!(pdu_types(&pdus), [3, 4, 7]);
+        let prefix = &pdus[1];
+        assert_eq!(prefix[8], 0);
+        assert_eq!(&prefix[12..16], &[198, 51, 100, 0]);
+    });
+
+    let metrics = manager.registry().get("feed").unwrap().metrics();
+    assert_eq!(metrics.updates(), 2);
+    assert_eq!(metrics.withdraw_only_updates(), 1);
+}
+
+/// Returns the number of VRPs in the JSON output if it is available.
+async fn json_asns(resources: &http::Resources) -> Option<usize> {
+    let mut request = Request::get("/json").body(Body::empty()).unwrap();
+    let response = resources.process_request(&mut request)?;
+    if !response.status().is_success() {
+        return None
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    Some(String::from_utf8_lossy(&body).matches("\"asn\"").count())
+}
+
+/// Sends a query to an RTR server and returns the PDUs of the response.
+///
+/// The response ends with an End of Data or Error Report PDU.
+async fn query(addr: SocketAddr, query: &[u8]) -> Vec<Vec<u8>> {
+    let mut sock = TcpStream::connect(addr).await.unwrap();
+    sock.write_all(query).await.unwrap();
+    let mut pdus = Vec::new();
+    loop {
+        let mut pdu = vec![0u8; 8];
+        sock.read_exact(&mut pdu).await.unwrap();
+        let len = u32::from_be_bytes([pdu[4], pdu[5], pdu[6], pdu[7]]);
+        pdu.resize(len as usize, 0);
+        sock.read_exact(&mut pdu[8..]).await.unwrap();
+        let pdu_type = pdu[1];
+        pdus.push(pdu);
+        if pdu_type == 7 || pdu_type == 10 {
+            return pdus
+        }
+    }
+}
+
+/// Returns the types of a sequence of PDUs.
+fn pdu_types(pdus: &[Vec<u8>]) -> Vec<u8> {
+    pdus.iter().map(|pdu| pdu[1]).collect()
+}
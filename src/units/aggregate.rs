@@ -0,0 +1,117 @@
+//! A unit aggregating the prefixes of another unit.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use log::debug;
+use rpki_rtr::Serial;
+use rpki_rtr::payload::{Ipv4Prefix, Ipv6Prefix, Payload};
+use serde::Deserialize;
+use crate::payload;
+use crate::comms::{Gate, Link, Terminated};
+use crate::manager::Component;
+
+
+//------------ Aggregate -----------------------------------------------------
+
+/// A unit publishing the aggregated prefixes of another unit’s data set.
+///
+/// This is meant for data sets that are used as deny lists, such as the
+/// AS0 items of some trust anchors. The unit takes the items of the source
+/// – by default only those for AS0 – and publishes the minimal list of
+/// prefixes covering exactly the same address space, as determined by
+/// [`Set::prefix_list`](payload::Set::prefix_list). Each prefix becomes an
+/// item for AS0 with a maximum length equal to the prefix length.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Aggregate {
+    /// The unit to aggregate the data of.
+    source: Link,
+
+    /// Whether to only consider the items for AS0.
+    #[serde(rename = "as0-only", default = "Aggregate::default_as0_only")]
+    as0_only: bool,
+}
+
+impl Aggregate {
+    /// The default for the `as0-only` option.
+    pub fn default_as0_only() -> bool {
+        true
+    }
+
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        component.register_metrics(gate.metrics());
+        let name = component.name().clone();
+        let mut published: Option<Arc<payload::Set>> = None;
+        let mut serial = Serial::default();
+
+        loop {
+            let update = match gate.process_until(
+                self.source.query()
+            ).await? {
+                Ok(update) => update,
+                Err(status) => {
+                    gate.update_status(status).await;
+                    continue
+                }
+            };
+            let set = Arc::new(self.aggregate(&update.set()));
+            let diff = match published {
+                Some(ref published) => {
+                    let diff = payload::Diff::reconcile(published, &set);
+                    if diff.is_empty() {
+                        debug!("Unit {}: aggregate unchanged.", name);
+                        continue
+                    }
+                    Some(Arc::new(diff))
+                }
+                None => None
+            };
+            debug!(
+                "Unit {}: {} items of update {} aggregated into {} \
+                 prefixes.",
+                name, update.set().len(), update.ids(), set.len()
+            );
+            serial = serial.add(1);
+            gate.update_data(
+                payload::Update::new(
+                    serial, set.clone(), diff
+                ).with_audit_only(
+                    update.is_audit_only()
+                ).with_ids(
+                    update.ids()
+                ).with_origin(update.origin())
+            ).await;
+            published = Some(set);
+        }
+    }
+
+    /// Returns the aggregated set for the set of the source.
+    fn aggregate(&self, set: &payload::Set) -> payload::Set {
+        let prefixes = if self.as0_only {
+            set.filter(|item| match *item {
+                Payload::V4(ref item) => item.asn == 0,
+                Payload::V6(ref item) => item.asn == 0,
+            }).prefix_list()
+        }
+        else {
+            set.prefix_list()
+        };
+        let mut res = payload::SetBuilder::empty();
+        for prefix in prefixes {
+            let len = prefix.prefix_len();
+            let item = match prefix.addr() {
+                IpAddr::V4(addr) => Payload::V4(Ipv4Prefix {
+                    prefix: addr, prefix_len: len, max_len: len, asn: 0
+                }),
+                IpAddr::V6(addr) => Payload::V6(Ipv6Prefix {
+                    prefix: addr, prefix_len: len, max_len: len, asn: 0
+                }),
+            };
+            // The prefixes are distinct, so this can’t fail.
+            let _ = res.insert(item);
+        }
+        res.finalize()
+    }
+}
@@ -0,0 +1,217 @@
+//! A policy for payload with reserved AS numbers.
+//!
+//! Some AS numbers are reserved and should never appear as the origin of a
+//! route in the global Internet. Payload for them occasionally leaks from
+//! misconfigured upstreams. Units receiving data from outside of RTRTR can
+//! be given an [`AsnPolicy`] via their `asn-policy` option to drop such
+//! payload right when it is received.
+//!
+//! The following AS numbers are considered reserved:
+//!
+//! * AS 64496 to 64511 and AS 65536 to 65551 for documentation
+//!   (RFC 5398),
+//! * AS 4200000000 to 4294967294 for private use (RFC 6996), and
+//! * AS 23456 which stands in for four-octet AS numbers (RFC 6793).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use rpki_rtr::payload::Payload;
+use serde::Deserialize;
+use crate::{metrics, payload};
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ AsnPolicy -----------------------------------------------------
+
+/// What to do with payload for reserved AS numbers.
+///
+/// The policy is created from the configuration of a unit via serde. It
+/// also keeps track of how many items it has dropped.
+#[derive(Debug, Default, Deserialize)]
+pub struct AsnPolicy {
+    /// What to do with payload for reserved AS numbers.
+    #[serde(default)]
+    reserved: ReservedPolicy,
+
+    /// The number of items dropped by category.
+    ///
+    /// The categories are indexed as in `ReservedAsn::ALL`.
+    #[serde(skip)]
+    dropped: [AtomicU64; 3],
+}
+
+impl AsnPolicy {
+    /// Returns whether an item should be kept.
+    ///
+    /// If the item is to be dropped, it is counted.
+    pub fn keep(&self, item: &Payload) -> bool {
+        if self.reserved == ReservedPolicy::Keep {
+            return true
+        }
+        match ReservedAsn::classify(payload_asn(item)) {
+            Some(category) => {
+                self.dropped[category as usize].fetch_add(
+                    1, Ordering::Relaxed
+                );
+                false
+            }
+            None => true
+        }
+    }
+
+    /// Returns the set with only the items the policy keeps.
+    pub fn apply(&self, set: payload::Set) -> payload::Set {
+        if self.reserved == ReservedPolicy::Keep {
+            return set
+        }
+        set.filter(|item| self.keep(item))
+    }
+
+    /// Returns the number of items dropped for the given category.
+    pub fn dropped(&self, category: ReservedAsn) -> u64 {
+        self.dropped[category as usize].load(Ordering::Relaxed)
+    }
+}
+
+impl AsnPolicy {
+    const DROPPED_METRIC: Metric = Metric::new(
+        "asn_policy_dropped",
+        "the number of items dropped for a reserved AS number by category",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for AsnPolicy {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append(&Self::DROPPED_METRIC, Some(unit_name), |records| {
+            for &category in &ReservedAsn::ALL {
+                records.label_value(
+                    &[("category", category.name())],
+                    self.dropped(category)
+                );
+            }
+        });
+    }
+}
+
+
+//------------ ReservedPolicy ------------------------------------------------
+
+/// What to do with payload for reserved AS numbers.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum ReservedPolicy {
+    /// Keep the payload.
+    #[serde(rename = "keep")]
+    Keep,
+
+    /// Drop the payload.
+    #[serde(rename = "drop")]
+    Drop,
+}
+
+impl Default for ReservedPolicy {
+    fn default() -> Self {
+        ReservedPolicy::Keep
+    }
+}
+
+
+//------------ ReservedAsn ---------------------------------------------------
+
+/// The category of a reserved AS number.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReservedAsn {
+    /// An AS number for use in documentation.
+    Documentation = 0,
+
+    /// An AS number for private use.
+    Private = 1,
+
+    /// The AS number standing in for four-octet AS numbers.
+    AsTrans = 2,
+}
+
+impl ReservedAsn {
+    /// All categories in the order of their index.
+    pub const ALL: [ReservedAsn; 3] = [
+        ReservedAsn::Documentation, ReservedAsn::Private, ReservedAsn::AsTrans
+    ];
+
+    /// Returns the category of an AS number if it is reserved.
+    pub fn classify(asn: u32) -> Option<Self> {
+        match asn {
+            64496..=64511 | 65536..=65551 => Some(ReservedAsn::Documentation),
+            4_200_000_000..=4_294_967_294 => Some(ReservedAsn::Private),
+            23456 => Some(ReservedAsn::AsTrans),
+            _ => None
+        }
+    }
+
+    /// Returns the name of the category as used in metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            ReservedAsn::Documentation => "documentation",
+            ReservedAsn::Private => "private",
+            ReservedAsn::AsTrans => "as-trans",
+        }
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns the AS number of a payload item.
+fn payload_asn(item: &Payload) -> u32 {
+    match *item {
+        Payload::V4(ref item) => item.asn,
+        Payload::V6(ref item) => item.asn,
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::testing::v4;
+    use super::*;
+
+    #[test]
+    fn classify() {
+        use self::ReservedAsn::*;
+
+        assert_eq!(ReservedAsn::classify(64495), None);
+        assert_eq!(ReservedAsn::classify(64496), Some(Documentation));
+        assert_eq!(ReservedAsn::classify(64511), Some(Documentation));
+        assert_eq!(ReservedAsn::classify(64512), None);
+        assert_eq!(ReservedAsn::classify(65551), Some(Documentation));
+        assert_eq!(ReservedAsn::classify(23456), Some(AsTrans));
+        assert_eq!(ReservedAsn::classify(4_199_999_999), None);
+        assert_eq!(ReservedAsn::classify(4_200_000_000), Some(Private));
+        assert_eq!(ReservedAsn::classify(4_294_967_294), Some(Private));
+        assert_eq!(ReservedAsn::classify(4_294_967_295), None);
+    }
+
+    #[test]
+    fn apply() {
+        let mut set = payload::SetBuilder::empty();
+        set.insert(v4([192, 0, 1, 0], 24, 24, 64496)).unwrap();
+        set.insert(v4([192, 0, 2, 0], 24, 24, 23456)).unwrap();
+        set.insert(v4([192, 0, 3, 0], 24, 24, 65000)).unwrap();
+        set.insert(v4([192, 0, 4, 0], 24, 24, 65537)).unwrap();
+        let set = set.finalize();
+
+        let keep = AsnPolicy::default();
+        assert_eq!(keep.apply(set.clone()).len(), 4);
+        assert_eq!(keep.dropped(ReservedAsn::Documentation), 0);
+
+        let drop: AsnPolicy = toml::from_str("reserved = \"drop\"").unwrap();
+        let set = drop.apply(set);
+        assert_eq!(set.len(), 1);
+        assert!(set.contains(&v4([192, 0, 3, 0], 24, 24, 65000)));
+        assert_eq!(drop.dropped(ReservedAsn::Documentation), 2);
+        assert_eq!(drop.dropped(ReservedAsn::AsTrans), 1);
+        assert_eq!(drop.dropped(ReservedAsn::Private), 0);
+    }
+}
@@ -0,0 +1,538 @@
+//! A unit batching the announcements and withdrawals of another unit.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use hyper::{Body, Method, Request, Response};
+use log::{debug, info};
+use rpki_rtr::Serial;
+use rpki_rtr::payload::{Action, Payload};
+use serde::Deserialize;
+use tokio::time::{timeout_at, Instant};
+use crate::{http, metrics, payload};
+use crate::comms::{Gate, Link, Terminated};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use crate::payload::{DisplayPayload, Prefix};
+
+
+//------------ Batch ---------------------------------------------------------
+
+/// A unit publishing the changes of another unit in separate batches.
+///
+/// Announcements and withdrawals of the source are collected separately.
+/// Once the first announcement is pending, all announcements are published
+/// after `announce_delay` seconds. Once the first withdrawal is pending,
+/// all changes are published after `withdraw_delay` seconds. With a longer
+/// withdrawal delay, routers learn about new routes before old ones are
+/// removed.
+///
+/// The pending changes are always determined by comparing the source’s
+/// current data set with the last published one. An item withdrawn and
+/// re-announced within a window thus isn’t published at all.
+///
+/// Optionally, items that have been withdrawn are kept from being
+/// announced again for `suppress_reannounce` seconds. This stops a
+/// flapping source from whipsawing routers. See [`Suppression`] for
+/// details.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Batch {
+    /// The unit to batch the changes of.
+    source: Link,
+
+    /// How many seconds to wait before publishing announcements.
+    #[serde(rename = "announce-delay", default)]
+    announce_delay: u64,
+
+    /// How many seconds to wait before publishing withdrawals.
+    #[serde(rename = "withdraw-delay", default)]
+    withdraw_delay: u64,
+
+    /// How many seconds to hold back re-announcements of withdrawn items.
+    ///
+    /// If this is `None`, re-announcements are published as usual.
+    #[serde(rename = "suppress-reannounce", default)]
+    suppress_reannounce: Option<u64>,
+
+    /// The prefixes whose items are subject to `suppress_reannounce`.
+    ///
+    /// If this is empty, all items are.
+    #[serde(rename = "suppress-prefixes", default)]
+    suppress_prefixes: Vec<Prefix>,
+}
+
+impl Batch {
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        component.register_metrics(gate.metrics());
+        let name = component.name().clone();
+        let mut windows = Windows::new(
+            Duration::from_secs(self.announce_delay),
+            Duration::from_secs(self.withdraw_delay),
+        );
+        let mut suppression = self.suppress_reannounce.map(|secs| {
+            let suppression = Suppression::new(
+                Duration::from_secs(secs),
+                self.suppress_prefixes.clone(),
+                &name
+            );
+            component.register_metrics(suppression.held.clone());
+            component.register_http_resource(suppression.held.clone());
+            suppression
+        });
+        let mut published: Option<Arc<payload::Set>> = None;
+        let mut current: Option<payload::Update> = None;
+        let mut serial = Serial::default();
+
+        loop {
+            let query = gate.process_until(self.source.query());
+            let deadline = windows.deadline().into_iter().chain(
+                suppression.as_ref().and_then(Suppression::deadline)
+            ).min();
+            let res = match deadline {
+                Some(until) => {
+                    match timeout_at(until, query).await {
+                        Ok(res) => Some(res?),
+                        Err(_) => None,
+                    }
+                }
+                None => Some(query.await?)
+            };
+            match res {
+                Some(Ok(update)) => current = Some(update),
+                Some(Err(status)) => {
+                    gate.update_status(status).await;
+                    continue
+                }
+                None => { }
+            }
+            let update = match current {
+                Some(ref update) => update,
+                None => continue
+            };
+            let now = Instant::now();
+            let set = match published {
+                Some(ref published) => {
+                    let available = match suppression {
+                        Some(ref mut suppression) => {
+                            suppression.hold(published, &update.set(), now)
+                        }
+                        None => update.set()
+                    };
+                    match windows.next(published, &available, now) {
+                        Some(set) => set,
+                        None => continue
+                    }
+                }
+                None => update.set()
+            };
+            let diff = published.as_ref().map(|published| {
+                Arc::new(payload::Diff::reconcile(published, &set))
+            });
+            if let Some((announced, withdrawn)) = diff.as_ref().map(|diff| {
+                diff.action_counts()
+            }) {
+                debug!(
+                    "Unit {}: publishing {} announcements and {} \
+                     withdrawals of update {}.",
+                    name, announced, withdrawn, update.ids()
+                );
+            }
+            if let (Some(suppression), Some(diff)) = (
+                suppression.as_mut(), diff.as_ref()
+            ) {
+                suppression.withdrawn(diff, now);
+            }
+            serial = serial.add(1);
+            gate.update_data(
+                payload::Update::new(
+                    serial, set.clone(), diff
+                ).with_audit_only(
+                    update.is_audit_only()
+                ).with_ids(
+                    update.ids()
+                ).with_origin(update.origin())
+            ).await;
+            published = Some(set);
+        }
+    }
+}
+
+
+//------------ Windows -------------------------------------------------------
+
+/// The batching windows for announcements and withdrawals.
+#[derive(Clone, Copy, Debug)]
+struct Windows {
+    /// How long announcements are held back.
+    announce_delay: Duration,
+
+    /// How long withdrawals are held back.
+    withdraw_delay: Duration,
+
+    /// When pending announcements are to be published.
+    announce: Option<Instant>,
+
+    /// When pending withdrawals are to be published.
+    withdraw: Option<Instant>,
+}
+
+impl Windows {
+    /// Creates new windows with the given delays and nothing pending.
+    fn new(announce_delay: Duration, withdraw_delay: Duration) -> Self {
+        Windows {
+            announce_delay, withdraw_delay,
+            announce: None,
+            withdraw: None,
+        }
+    }
+
+    /// Returns when the next window ends if any.
+    fn deadline(&self) -> Option<Instant> {
+        self.announce.into_iter().chain(self.withdraw).min()
+    }
+
+    /// Returns the set to publish at `now` if there is one.
+    ///
+    /// The pending changes are those between the `published` set and the
+    /// source’s `current` set. If the withdrawal window has ended, this is
+    /// the current set. If only the announcement window has ended, it is
+    /// the published set plus the pending announcements.
+    fn next(
+        &mut self,
+        published: &payload::Set,
+        current: &Arc<payload::Set>,
+        now: Instant,
+    ) -> Option<Arc<payload::Set>> {
+        let (announced, withdrawn) = current.diff_from(
+            published
+        ).action_counts();
+        Self::open(&mut self.announce, announced, now + self.announce_delay);
+        Self::open(&mut self.withdraw, withdrawn, now + self.withdraw_delay);
+
+        if self.withdraw.map(|until| until <= now).unwrap_or(false) {
+            self.announce = None;
+            self.withdraw = None;
+            Some(current.clone())
+        }
+        else if self.announce.map(|until| until <= now).unwrap_or(false) {
+            self.announce = None;
+            let mut set = payload::SetBuilder::from(published);
+            set.merge_set(current);
+            Some(Arc::new(set.finalize()))
+        }
+        else {
+            None
+        }
+    }
+
+    /// Opens or closes a window depending on the number of pending changes.
+    fn open(window: &mut Option<Instant>, pending: usize, until: Instant) {
+        if pending == 0 {
+            *window = None
+        }
+        else if window.is_none() {
+            *window = Some(until)
+        }
+    }
+}
+
+
+//------------ Suppression ---------------------------------------------------
+
+/// Holds back re-announcements of recently withdrawn items.
+///
+/// Once an item in scope has been published as withdrawn, it is suppressed
+/// for the length of the window. If the source announces it again during
+/// that time, the announcement is held. When the window ends and the source
+/// still has the item, it becomes a pending announcement like any other
+/// and is published with the next announcement window. Nothing is ever
+/// dropped, only delayed.
+#[derive(Debug)]
+struct Suppression {
+    /// How long items are suppressed after their withdrawal.
+    window: Duration,
+
+    /// The prefixes whose items are suppressed.
+    ///
+    /// If this is empty, all items are.
+    scope: Vec<Prefix>,
+
+    /// The suppressed items and when their suppression ends.
+    withdrawn: HashMap<Payload, Instant>,
+
+    /// The announcements currently held back.
+    held: Arc<HeldAnnouncements>,
+}
+
+impl Suppression {
+    /// Creates a new suppression for the unit with the given name.
+    fn new(window: Duration, scope: Vec<Prefix>, name: &str) -> Self {
+        Suppression {
+            window, scope,
+            withdrawn: HashMap::new(),
+            held: Arc::new(HeldAnnouncements::new(name)),
+        }
+    }
+
+    /// Returns whether an item is subject to suppression.
+    fn in_scope(&self, item: &Payload) -> bool {
+        self.scope.is_empty()
+            || self.scope.iter().any(|prefix| prefix.covers(item))
+    }
+
+    /// Records the withdrawals of a published diff at `now`.
+    fn withdrawn(&mut self, diff: &payload::Diff, now: Instant) {
+        for &(item, action) in diff.iter() {
+            if action == Action::Withdraw && self.in_scope(&item) {
+                self.withdrawn.insert(item, now + self.window);
+            }
+        }
+    }
+
+    /// Returns the source’s set without the held announcements.
+    ///
+    /// An announcement is held if the item isn’t `published` and still
+    /// suppressed at `now`. Suppressions that have ended are forgotten.
+    fn hold(
+        &mut self,
+        published: &payload::Set,
+        current: &Arc<payload::Set>,
+        now: Instant,
+    ) -> Arc<payload::Set> {
+        self.withdrawn.retain(|_, until| *until > now);
+        let mut held = Vec::new();
+        let res = if self.withdrawn.is_empty() {
+            current.clone()
+        }
+        else {
+            let withdrawn = &self.withdrawn;
+            let res = current.filter(|item| {
+                match withdrawn.get(item) {
+                    Some(&until) if !published.contains(item) => {
+                        held.push((*item, until));
+                        false
+                    }
+                    _ => true
+                }
+            });
+            if held.is_empty() {
+                current.clone()
+            }
+            else {
+                Arc::new(res)
+            }
+        };
+        let released = self.held.set(held);
+        if released > 0 {
+            info!(
+                "Unit {}: released {} held re-announcements.",
+                self.held.name, released
+            );
+        }
+        res
+    }
+
+    /// Returns when the next held announcement is released.
+    fn deadline(&self) -> Option<Instant> {
+        self.held.items.lock().unwrap().iter().map(|item| item.1).min()
+    }
+}
+
+
+//------------ HeldAnnouncements ---------------------------------------------
+
+/// The announcements currently held back by a suppression.
+///
+/// The number of announcements is available as a metric, the
+/// announcements themselves under `/suppressed/<unit-name>` on the HTTP
+/// server.
+#[derive(Debug)]
+struct HeldAnnouncements {
+    /// The name of the unit.
+    name: String,
+
+    /// The path of the HTTP resource.
+    path: String,
+
+    /// The held items and when they will be released.
+    items: Mutex<Vec<(Payload, Instant)>>,
+}
+
+impl HeldAnnouncements {
+    fn new(name: &str) -> Self {
+        HeldAnnouncements {
+            name: name.into(),
+            path: format!("/suppressed/{}", name),
+            items: Default::default(),
+        }
+    }
+
+    /// Replaces the held items.
+    ///
+    /// Returns the number of previously held items no longer held.
+    fn set(&self, items: Vec<(Payload, Instant)>) -> usize {
+        let mut current = self.items.lock().unwrap();
+        let released = current.iter().filter(|(item, _)| {
+            !items.iter().any(|(other, _)| other == item)
+        }).count();
+        *current = items;
+        released
+    }
+}
+
+impl HeldAnnouncements {
+    const HELD_METRIC: Metric = Metric::new(
+        "held_reannouncements",
+        "the number of re-announcements currently held back",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for HeldAnnouncements {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::HELD_METRIC, Some(unit_name),
+            self.items.lock().unwrap().len()
+        );
+    }
+}
+
+impl http::ProcessRequest for HeldAnnouncements {
+    fn process_request(
+        &self, request: &mut Request<Body>
+    ) -> Option<Response<Body>> {
+        if
+            request.method() != Method::GET
+            || request.uri().path() != self.path
+        {
+            return None
+        }
+        let now = Instant::now();
+        let items = self.items.lock().unwrap();
+        let mut body = format!("held: {}\n", items.len());
+        for (item, until) in items.iter() {
+            body.push_str(&format!(
+                "{} released in {}s\n",
+                DisplayPayload(item),
+                until.saturating_duration_since(now).as_secs()
+            ));
+        }
+        Some(
+            Response::builder()
+            .header("Content-Type", "text/plain")
+            .body(body.into())
+            .unwrap()
+        )
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    
+    use crate::payload::testing::v4;
+    use super::*;
+
+    fn set(octets: &[u8]) -> Arc<payload::Set> {
+        let mut set = payload::SetBuilder::empty();
+        for &octet in octets {
+            set.insert(v4([192, 0, octet, 0], 24, 24, 64496)).unwrap();
+        }
+        Arc::new(set.finalize())
+    }
+
+    #[test]
+    fn windows() {
+        let secs = Duration::from_secs;
+        let start = Instant::now();
+        let at = |n| start + secs(n);
+        let mut windows = Windows::new(secs(0), secs(30));
+        let published = set(&[1, 2]);
+
+        // Announcements go out right away, withdrawals are held back.
+        let next = windows.next(&published, &set(&[2, 3]), at(0)).unwrap();
+        assert_eq!(next.iter().count(), 3);
+        assert_eq!(windows.deadline(), Some(at(30)));
+        let published = next;
+
+        // Withdrawn and re-announced within the window: nothing happens.
+        assert!(windows.next(&published, &set(&[1, 2, 3]), at(10)).is_none());
+        assert_eq!(windows.deadline(), None);
+
+        // The window starts with the first withdrawal and ends with all
+        // changes published.
+        assert!(windows.next(&published, &set(&[2, 3]), at(20)).is_none());
+        assert!(windows.next(&published, &set(&[3, 4]), at(40)).is_some());
+        assert!(windows.next(&published, &set(&[3]), at(45)).is_none());
+        assert_eq!(windows.deadline(), Some(at(50)));
+        let next = windows.next(&published, &set(&[3]), at(50)).unwrap();
+        assert_eq!(next.iter().count(), 1);
+        assert_eq!(windows.deadline(), None);
+    }
+
+    #[test]
+    fn suppression() {
+        let secs = Duration::from_secs;
+        let start = Instant::now();
+        let at = |n| start + secs(n);
+        let mut suppression = Suppression::new(
+            secs(60), vec!["192.0.0.0/16".parse().unwrap()], "test"
+        );
+        let item = |octet| set(&[octet]).iter().next().cloned().unwrap();
+
+        // Withdrawing 1 starts its suppression.
+        let published = set(&[1, 2]);
+        let next = set(&[2]);
+        suppression.withdrawn(
+            &payload::Diff::reconcile(&published, &next), at(0)
+        );
+        let published = next;
+
+        // The re-announcement is held until the suppression ends.
+        let available = suppression.hold(&published, &set(&[1, 2]), at(10));
+        assert_eq!(available.iter().count(), 1);
+        assert_eq!(suppression.deadline(), Some(at(60)));
+        assert_eq!(suppression.held.items.lock().unwrap().len(), 1);
+
+        // Upon release, the diff is exactly the announcement of 1.
+        let available = suppression.hold(&published, &set(&[1, 2]), at(60));
+        assert_eq!(suppression.deadline(), None);
+        let diff = payload::Diff::reconcile(&published, &available);
+        assert_eq!(
+            diff.iter().cloned().collect::<Vec<_>>(),
+            vec![(item(1), Action::Announce)]
+        );
+
+        // An item withdrawn upstream while held is never published.
+        let published = set(&[1, 2]);
+        suppression.withdrawn(
+            &payload::Diff::reconcile(&published, &set(&[1])), at(100)
+        );
+        let published = set(&[1]);
+        let available = suppression.hold(&published, &set(&[1, 2]), at(110));
+        assert_eq!(available.iter().count(), 1);
+        let available = suppression.hold(&published, &set(&[1]), at(120));
+        assert_eq!(suppression.deadline(), None);
+        assert!(
+            payload::Diff::reconcile(&published, &available).iter()
+                .next().is_none()
+        );
+
+        // Items outside the scope are not suppressed.
+        let mut other = Suppression::new(
+            secs(60), vec!["10.0.0.0/8".parse().unwrap()], "test"
+        );
+        other.withdrawn(
+            &payload::Diff::reconcile(&set(&[1, 2]), &set(&[2])), at(0)
+        );
+        let available = other.hold(&set(&[2]), &set(&[1, 2]), at(10));
+        assert_eq!(available.iter().count(), 2);
+        assert_eq!(other.deadline(), None);
+    }
+}
@@ -0,0 +1,310 @@
+//! A circuit breaker for units connecting to a server.
+//!
+//! A unit that keeps failing to talk to its server doesn’t gain anything
+//! from hammering it with connection attempts. The [`CircuitBreaker`] keeps
+//! track of consecutive failures. Once there were too many of them,
+//! optionally within a limited time window, it _opens_ and the unit stops
+//! trying for a while. After this reset timeout
+//! has passed, the breaker becomes _half-open_ and allows a single attempt.
+//! If it succeeds, the breaker _closes_ again and everything is back to
+//! normal. If it fails, the breaker opens again, this time for twice as
+//! long as before.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use serde::Deserialize;
+use tokio::time::Instant;
+use crate::metrics;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+
+
+//------------ CircuitBreaker ------------------------------------------------
+
+/// A circuit breaker.
+///
+/// The breaker is created from the configuration of a unit via serde.
+#[derive(Debug, Deserialize)]
+pub struct CircuitBreaker {
+    /// How many consecutive failures open the breaker.
+    #[serde(
+        rename = "failure-threshold",
+        default = "CircuitBreaker::default_failure_threshold"
+    )]
+    failure_threshold: usize,
+
+    /// The number of seconds within which the failures have to happen.
+    ///
+    /// The window slides: only failures less than this many seconds before
+    /// the latest one count. If this is `None`, all consecutive failures
+    /// count regardless of how long ago they happened.
+    #[serde(rename = "failure-window", default)]
+    failure_window: Option<u64>,
+
+    /// How many seconds the breaker stays open the first time.
+    #[serde(
+        rename = "reset-timeout",
+        default = "CircuitBreaker::default_reset_timeout"
+    )]
+    reset_timeout: u64,
+
+    /// The current state of the breaker.
+    #[serde(skip)]
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn default_failure_threshold() -> usize {
+        5
+    }
+
+    pub fn default_reset_timeout() -> u64 {
+        60
+    }
+
+    /// How many times the reset timeout is doubled at most.
+    const MAX_DOUBLINGS: u32 = 6;
+
+    /// Creates a new, closed breaker.
+    #[cfg(test)]
+    fn new(failure_threshold: usize, reset_timeout: u64) -> Self {
+        CircuitBreaker {
+            failure_threshold, reset_timeout,
+            failure_window: None,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns whether an attempt is allowed at `now`.
+    ///
+    /// If the breaker is open, returns the time when the next attempt will
+    /// be allowed as the error. If the reset timeout has passed, the
+    /// breaker becomes half-open and the attempt is allowed.
+    pub fn allow(&self, now: Instant) -> Result<(), Instant> {
+        let mut state = self.state.lock().unwrap();
+        if let Position::Open(until) = state.position {
+            if now < until {
+                return Err(until)
+            }
+            state.position = Position::HalfOpen;
+        }
+        Ok(())
+    }
+
+    /// Records a successful attempt.
+    ///
+    /// This closes the breaker.
+    pub fn success(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    /// Records a failed attempt at `now`.
+    ///
+    /// If this opens the breaker, returns for how long.
+    pub fn failure(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        match state.position {
+            Position::Closed => {
+                // Forget the failures that have left the window. Without
+                // a window, we only need to remember enough of them.
+                while let Some(&first) = state.failures.front() {
+                    let expired = match self.failure_window {
+                        Some(window) => {
+                            now.saturating_duration_since(first)
+                                > Duration::from_secs(window)
+                        }
+                        None => {
+                            state.failures.len() >= self.failure_threshold
+                        }
+                    };
+                    if !expired {
+                        break
+                    }
+                    state.failures.pop_front();
+                }
+                state.failures.push_back(now);
+                if state.failures.len() < self.failure_threshold {
+                    return None
+                }
+                state.failures.clear();
+                state.trips = 0;
+            }
+            Position::HalfOpen => {
+                state.trips += 1;
+            }
+            Position::Open(_) => return None,
+        }
+        let timeout = Duration::from_secs(self.reset_timeout) * (
+            1 << cmp::min(state.trips, Self::MAX_DOUBLINGS)
+        );
+        state.position = Position::Open(now + timeout);
+        Some(timeout)
+    }
+}
+
+impl CircuitBreaker {
+    const STATE_METRIC: Metric = Metric::new(
+        "rtr_circuit_breaker_state", "the state of the circuit breaker",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const OPEN_METRIC: Metric = Metric::new(
+        "circuit_open", "whether the circuit breaker is open",
+        MetricType::Gauge, MetricUnit::Info
+    );
+}
+
+impl metrics::Source for CircuitBreaker {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        let current = self.state.lock().unwrap().position.name();
+        target.append(&Self::STATE_METRIC, Some(unit_name), |records| {
+            for &state in &["closed", "open", "half-open"] {
+                records.label_value(
+                    &[("state", state)], (state == current) as u8
+                );
+            }
+        });
+        target.append_simple(
+            &Self::OPEN_METRIC, Some(unit_name), (current == "open") as u8
+        );
+    }
+}
+
+
+//------------ BreakerState --------------------------------------------------
+
+/// The state of a circuit breaker.
+#[derive(Clone, Debug)]
+struct BreakerState {
+    /// Where the breaker currently is.
+    position: Position,
+
+    /// When the consecutive failures counted while closed happened.
+    ///
+    /// The failures are kept in the order they happened.
+    failures: VecDeque<Instant>,
+
+    /// How often the breaker has reopened after being half-open.
+    trips: u32,
+}
+
+impl Default for BreakerState {
+    fn default() -> Self {
+        BreakerState {
+            position: Position::Closed,
+            failures: VecDeque::new(),
+            trips: 0,
+        }
+    }
+}
+
+
+//------------ Position ------------------------------------------------------
+
+/// Whether the breaker is closed, open, or half-open.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Position {
+    /// Attempts are allowed.
+    Closed,
+
+    /// No attempts are allowed until the given time.
+    Open(Instant),
+
+    /// A single attempt is allowed.
+    HalfOpen,
+}
+
+impl Position {
+    /// Returns the name of the position as used in the metrics.
+    fn name(self) -> &'static str {
+        match self {
+            Position::Closed => "closed",
+            Position::Open(_) => "open",
+            Position::HalfOpen => "half-open",
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn position(breaker: &CircuitBreaker) -> &'static str {
+        breaker.state.lock().unwrap().position.name()
+    }
+
+    #[test]
+    fn breaker() {
+        let secs = Duration::from_secs;
+        let breaker = CircuitBreaker::new(3, 10);
+        let start = Instant::now();
+
+        // Failures open the breaker only once they reach the threshold and
+        // a success in between starts over.
+        assert_eq!(breaker.failure(start), None);
+        assert_eq!(breaker.failure(start), None);
+        breaker.success();
+        assert_eq!(breaker.failure(start), None);
+        assert_eq!(breaker.failure(start), None);
+        assert!(breaker.allow(start).is_ok());
+        assert_eq!(breaker.failure(start), Some(secs(10)));
+        assert_eq!(position(&breaker), "open");
+        assert_eq!(breaker.allow(start + secs(9)), Err(start + secs(10)));
+
+        // Failing when half-open doubles the timeout.
+        assert!(breaker.allow(start + secs(10)).is_ok());
+        assert_eq!(position(&breaker), "half-open");
+        assert_eq!(breaker.failure(start + secs(10)), Some(secs(20)));
+        assert!(breaker.allow(start + secs(29)).is_err());
+        assert!(breaker.allow(start + secs(30)).is_ok());
+        assert_eq!(breaker.failure(start + secs(30)), Some(secs(40)));
+
+        // The doubling is limited.
+        let mut now = start + secs(70);
+        for _ in 0..10 {
+            assert!(breaker.allow(now).is_ok());
+            let timeout = breaker.failure(now).unwrap();
+            assert!(timeout <= secs(640));
+            now += timeout;
+        }
+
+        // A success when half-open closes the breaker and resets it.
+        assert!(breaker.allow(now).is_ok());
+        breaker.success();
+        assert_eq!(breaker.failure(now), None);
+        assert_eq!(breaker.failure(now), None);
+        assert_eq!(breaker.failure(now), Some(secs(10)));
+    }
+
+    #[test]
+    fn failure_window() {
+        let secs = Duration::from_secs;
+        let breaker = CircuitBreaker {
+            failure_window: Some(60),
+            .. CircuitBreaker::new(3, 10)
+        };
+        let start = Instant::now();
+
+        // Failures spread out beyond the window don’t open the breaker.
+        assert_eq!(breaker.failure(start), None);
+        assert_eq!(breaker.failure(start + secs(61)), None);
+        assert_eq!(breaker.failure(start + secs(122)), None);
+        assert_eq!(position(&breaker), "closed");
+
+        // The window slides, so failures within it open the breaker even
+        // if an earlier one has expired in the meantime.
+        let breaker = CircuitBreaker {
+            failure_window: Some(60),
+            .. CircuitBreaker::new(3, 10)
+        };
+        assert_eq!(breaker.failure(start), None);
+        assert_eq!(breaker.failure(start + secs(30)), None);
+        assert_eq!(breaker.failure(start + secs(61)), None);
+        assert_eq!(position(&breaker), "closed");
+        assert_eq!(breaker.failure(start + secs(90)), Some(secs(10)));
+        assert_eq!(position(&breaker), "open");
+    }
+}
@@ -1,27 +1,38 @@
 //! Units that combine the updates from other units.
 
+use std::cmp;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 use crossbeam_utils::atomic::AtomicCell;
 use futures::future::{select, select_all, Either, FutureExt};
-use rand::{thread_rng, Rng};
+use log::{debug, info, warn};
+use rpki_rtr::Serial;
 use serde::Deserialize;
+use tokio::time::{timeout_at, Instant};
 use crate::metrics;
 use crate::metrics::{Metric, MetricType, MetricUnit};
 use crate::comms::{Gate, GateMetrics, Link, Terminated, UnitStatus};
 use crate::manager::Component;
 use crate::payload;
+use crate::random::Random;
 
 
 //------------ Any -----------------------------------------------------------
 
 /// A unit selecting updates from one working unit from a set.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Any {
     /// The set of units to choose from.
     sources: Vec<Link>,
 
     /// Whether to pick randomly from the sources.
     random: bool,
+
+    /// The source for random picks.
+    #[serde(skip)]
+    rng: Random,
 }
 
 impl Any {
@@ -32,6 +43,7 @@ impl Any {
             gate.update_status(UnitStatus::Gone).await;
             return Err(Terminated)
         }
+        self.rng = component.random().clone();
         let metrics = Arc::new(AnyMetrics::new(&gate));
         component.register_metrics(metrics.clone());
 
@@ -102,7 +114,7 @@ impl Any {
         // source at random and then loop around. That’s not truly random but
         // deterministic.
         let mut next = if self.random {
-            thread_rng().gen_range(0, self.sources.len())
+            self.rng.gen_range(0, self.sources.len())
         }
         else if let Some(curr) = curr {
             (curr + 1) % self.sources.len()
@@ -272,6 +284,463 @@ impl<'a> AnySource<'a> {
 */
 
 
+//------------ QuorumMerge ---------------------------------------------------
+
+/// A unit merging the data of a set of units as long as enough are healthy.
+///
+/// The unit publishes the union of the data sets of all its healthy sources.
+/// If fewer than `min_sources` sources are healthy, the unit considers the
+/// merged data unreliable. It goes stalled and doesn’t publish any updates
+/// until enough sources have been healthy again for `quorum_hold` seconds.
+///
+/// If `quarantine_after` is given, the data of a source that isn’t healthy
+/// is kept in the union until the source has been unhealthy for that many
+/// seconds. The source is then quarantined and its data removed. It is only
+/// re-admitted after it has delivered a fresh update and stayed healthy
+/// for `readmit_after` seconds.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuorumMerge {
+    /// The set of units to merge.
+    sources: Vec<Link>,
+
+    /// The minimum number of healthy sources.
+    #[serde(rename = "min-sources", default = "QuorumMerge::default_min")]
+    min_sources: usize,
+
+    /// How many seconds enough sources need to be healthy after a loss.
+    #[serde(rename = "quorum-hold", default = "QuorumMerge::default_hold")]
+    quorum_hold: u64,
+
+    /// How many seconds a source may be unhealthy before quarantining it.
+    ///
+    /// If this is `None`, the data of unhealthy sources is dropped right
+    /// away.
+    #[serde(rename = "quarantine-after", default)]
+    quarantine_after: Option<u64>,
+
+    /// How many seconds a quarantined source needs to be healthy again.
+    #[serde(
+        rename = "readmit-after", default = "QuorumMerge::default_readmit"
+    )]
+    readmit_after: u64,
+}
+
+impl QuorumMerge {
+    /// The default for the minimum number of healthy sources.
+    pub fn default_min() -> usize {
+        1
+    }
+
+    /// The default for the hold time after quorum has been regained.
+    pub fn default_hold() -> u64 {
+        30
+    }
+
+    /// The default for the time before re-admitting a quarantined source.
+    pub fn default_readmit() -> u64 {
+        600
+    }
+
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        if self.sources.is_empty() {
+            gate.update_status(UnitStatus::Gone).await;
+            return Err(Terminated)
+        }
+        let metrics = Arc::new(
+            QuorumMetrics::new(&gate, self.sources.len())
+        );
+        component.register_metrics(metrics.clone());
+        let name = component.name().clone();
+
+        let mut updates: Vec<Option<payload::Update>> = vec![
+            None; self.sources.len()
+        ];
+        let mut output = MergeOutput::default();
+        let mut quorum = QuorumState::Initial;
+        let mut admissions = vec![
+            Admission::Admitted; self.sources.len()
+        ];
+        let mut status = UnitStatus::Stalled;
+        gate.update_status(status).await;
+
+        loop {
+            // Wait for something to happen on our sources. If we are waiting
+            // out the hold time or for a source to change its admission, we
+            // also need to wake up at the end of that.
+            let quarantine_after = self.quarantine_after();
+            let readmit_after = self.readmit_after();
+            let query = gate.process_until(
+                select_all(
+                    self.sources.iter_mut().map(|link| link.query().boxed())
+                )
+            );
+            let hold = match quorum {
+                QuorumState::Regained(until) => Some(until),
+                _ => None
+            };
+            let deadline = admissions.iter().filter_map(|admission| {
+                admission.deadline(quarantine_after, readmit_after)
+            }).chain(hold).min();
+            let res = match deadline {
+                Some(until) => {
+                    match timeout_at(until, query).await {
+                        Ok(res) => Some(res?),
+                        Err(_) => None,
+                    }
+                }
+                None => Some(query.await?)
+            }.map(|(res, idx, _)| (res, idx));
+            let updated = match res {
+                Some((Ok(update), idx)) => {
+                    updates[idx] = Some(update);
+                    Some(idx)
+                }
+                _ => None
+            };
+
+            let healthy = self.healthy();
+            if self.quarantine_after.is_some() {
+                self.update_admissions(
+                    &mut admissions, updated, &name, &metrics
+                );
+            }
+            metrics.healthy_sources.store(healthy.len(), Ordering::Relaxed);
+            let next = quorum.next(
+                healthy.len() >= self.min_sources, Instant::now(),
+                Duration::from_secs(self.quorum_hold)
+            );
+            match (quorum, next) {
+                (QuorumState::Established, QuorumState::Lost) => {
+                    warn!(
+                        "Unit {}: only {} of the required {} sources are \
+                         healthy. Suspending updates.",
+                        name, healthy.len(), self.min_sources
+                    );
+                    metrics.quorum_losses.fetch_add(1, Ordering::Relaxed);
+                    if status == UnitStatus::Healthy {
+                        status = UnitStatus::Stalled;
+                        gate.update_status(status).await;
+                    }
+                }
+                (QuorumState::Lost, QuorumState::Regained(_)) => {
+                    info!(
+                        "Unit {}: {} sources are healthy again. Waiting {} \
+                         seconds before resuming updates.",
+                        name, healthy.len(), self.quorum_hold
+                    );
+                }
+                (QuorumState::Regained(_), QuorumState::Established) => {
+                    info!("Unit {}: quorum restored.", name);
+                }
+                _ => { }
+            }
+            quorum = next;
+            metrics.quorum.store(
+                quorum == QuorumState::Established, Ordering::Relaxed
+            );
+            if quorum == QuorumState::Established {
+                let contributing = if self.quarantine_after.is_some() {
+                    admissions.iter().enumerate().filter_map(|(idx, item)| {
+                        if item.contributes() { Some(idx) } else { None }
+                    }).collect()
+                }
+                else {
+                    healthy
+                };
+                let sources = contributing.into_iter().filter_map(|idx| {
+                    updates[idx].clone()
+                });
+                if output.publish(&name, sources, &mut gate).await
+                    && status != UnitStatus::Healthy
+                {
+                    status = UnitStatus::Healthy;
+                    gate.update_status(status).await;
+                }
+            }
+        }
+    }
+
+    /// Returns the time after which to quarantine an unhealthy source.
+    fn quarantine_after(&self) -> Duration {
+        Duration::from_secs(self.quarantine_after.unwrap_or(0))
+    }
+
+    /// Returns the time a quarantined source needs to be healthy.
+    fn readmit_after(&self) -> Duration {
+        Duration::from_secs(self.readmit_after)
+    }
+
+    /// Moves the admissions of all sources to their next state.
+    ///
+    /// If an update was received from a source, its index is given in
+    /// `updated`. Logs and counts all transitions.
+    fn update_admissions(
+        &self,
+        admissions: &mut [Admission],
+        updated: Option<usize>,
+        name: &str,
+        metrics: &QuorumMetrics,
+    ) {
+        let now = Instant::now();
+        for (idx, admission) in admissions.iter_mut().enumerate() {
+            let next = admission.next(
+                self.sources[idx].get_status() == UnitStatus::Healthy,
+                updated == Some(idx), now,
+                self.quarantine_after(), self.readmit_after()
+            );
+            match (*admission, next) {
+                (Admission::Admitted, Admission::Stale(_)) => {
+                    info!(
+                        "Unit {}: source {} is stale. Keeping its data for \
+                         {} seconds.",
+                        name, idx, self.quarantine_after().as_secs()
+                    );
+                }
+                (Admission::Stale(_), Admission::Admitted) => {
+                    info!("Unit {}: source {} has recovered.", name, idx);
+                }
+                (Admission::Stale(_), Admission::Quarantined) => {
+                    warn!(
+                        "Unit {}: source {} has been stale for too long. \
+                         Quarantining its data.",
+                        name, idx
+                    );
+                    metrics.quarantines.fetch_add(1, Ordering::Relaxed);
+                }
+                (Admission::Quarantined, Admission::Readmitting(_)) => {
+                    info!(
+                        "Unit {}: quarantined source {} delivered fresh \
+                         data. Re-admitting it after {} seconds.",
+                        name, idx, self.readmit_after
+                    );
+                }
+                (Admission::Readmitting(_), Admission::Quarantined) => {
+                    info!(
+                        "Unit {}: quarantined source {} is unhealthy \
+                         again.",
+                        name, idx
+                    );
+                }
+                (Admission::Readmitting(_), Admission::Admitted) => {
+                    info!("Unit {}: re-admitted source {}.", name, idx);
+                    metrics.readmissions.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => { }
+            }
+            *admission = next;
+            metrics.quarantined[idx].store(
+                !next.contributes(), Ordering::Relaxed
+            );
+        }
+    }
+
+    /// Returns the indexes of all healthy sources.
+    fn healthy(&self) -> Vec<usize> {
+        self.sources.iter().enumerate().filter_map(|(idx, link)| {
+            if link.get_status() == UnitStatus::Healthy {
+                Some(idx)
+            }
+            else {
+                None
+            }
+        }).collect()
+    }
+}
+
+
+//------------ QuorumState ---------------------------------------------------
+
+/// The quorum state of a quorum merge unit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum QuorumState {
+    /// We never had quorum yet.
+    ///
+    /// When we first get it, we publish right away.
+    Initial,
+
+    /// There are enough healthy sources.
+    Established,
+
+    /// There are not enough healthy sources.
+    Lost,
+
+    /// There are enough sources again but not yet for long enough.
+    ///
+    /// The value is the time when we are willing to publish again.
+    Regained(Instant),
+}
+
+impl QuorumState {
+    /// Returns the next state.
+    ///
+    /// Whether there currently are enough healthy sources is given via
+    /// `has_quorum`. After quorum has been lost, it needs to be there for
+    /// `hold` before it is considered established again.
+    fn next(self, has_quorum: bool, now: Instant, hold: Duration) -> Self {
+        match (self, has_quorum) {
+            (QuorumState::Initial, true) => QuorumState::Established,
+            (QuorumState::Established, false) => QuorumState::Lost,
+            (QuorumState::Lost, true) => QuorumState::Regained(now + hold),
+            (QuorumState::Regained(until), true) if now >= until => {
+                QuorumState::Established
+            }
+            (QuorumState::Regained(_), false) => QuorumState::Lost,
+            (state, _) => state,
+        }
+    }
+}
+
+
+//------------ Admission -----------------------------------------------------
+
+/// Whether the data of a source is admitted into the union.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Admission {
+    /// The source is healthy and its data is used.
+    Admitted,
+
+    /// The source is unhealthy since the given time but its data is used.
+    Stale(Instant),
+
+    /// The source’s data is not used.
+    Quarantined,
+
+    /// The source has delivered fresh data and is healthy since the given
+    /// time but its data is not used yet.
+    Readmitting(Instant),
+}
+
+impl Admission {
+    /// Returns the next state.
+    ///
+    /// Whether the source is currently healthy is given via `healthy`,
+    /// whether it just delivered an update via `updated`.
+    fn next(
+        self, healthy: bool, updated: bool, now: Instant,
+        quarantine: Duration, readmit: Duration,
+    ) -> Self {
+        match self {
+            Admission::Admitted if !healthy => Admission::Stale(now),
+            Admission::Stale(_) if healthy => Admission::Admitted,
+            Admission::Stale(since) if now >= since + quarantine => {
+                Admission::Quarantined
+            }
+            Admission::Quarantined if healthy && updated => {
+                Admission::Readmitting(now)
+            }
+            Admission::Readmitting(_) if !healthy => Admission::Quarantined,
+            Admission::Readmitting(since) if now >= since + readmit => {
+                Admission::Admitted
+            }
+            state => state
+        }
+    }
+
+    /// Returns when the state will change unless the source does.
+    fn deadline(
+        self, quarantine: Duration, readmit: Duration
+    ) -> Option<Instant> {
+        match self {
+            Admission::Stale(since) => Some(since + quarantine),
+            Admission::Readmitting(since) => Some(since + readmit),
+            _ => None
+        }
+    }
+
+    /// Returns whether the source’s data is used.
+    fn contributes(self) -> bool {
+        matches!(self, Admission::Admitted | Admission::Stale(_))
+    }
+}
+
+
+//------------ MergeOutput ---------------------------------------------------
+
+/// The data a merging unit has published.
+#[derive(Debug, Default)]
+struct MergeOutput {
+    /// The last data set we published.
+    published: Option<Arc<payload::Set>>,
+
+    /// The serial number of the last update we published.
+    serial: Serial,
+
+    /// Whether the last update we published was for auditing only.
+    audit_only: bool,
+}
+
+impl MergeOutput {
+    /// Merges the sets of the given updates and publishes the result.
+    ///
+    /// Nothing is published if the result hasn’t changed. The published
+    /// update carries the range of IDs of all merged updates and the origin
+    /// of the most recent one.
+    ///
+    /// Updates for auditing only are left out as long as there are other
+    /// updates, so they don’t keep the data of the other sources from being
+    /// served. If all updates are for auditing only, they are merged and
+    /// the result is for auditing only, too.
+    ///
+    /// Returns whether there is published data.
+    async fn publish(
+        &mut self,
+        name: &str,
+        sources: impl Iterator<Item = payload::Update>,
+        gate: &mut Gate
+    ) -> bool {
+        let sources: Vec<_> = sources.collect();
+        let audit_only = sources.iter().all(|update| {
+            update.is_audit_only()
+        });
+        let mut builder = payload::SetBuilder::empty();
+        let mut ids: Option<payload::UpdateIds> = None;
+        let mut origin = None;
+        for update in sources {
+            if update.is_audit_only() != audit_only {
+                continue
+            }
+            builder.merge_set(&update.set());
+            ids = Some(match ids {
+                Some(ids) => ids.merge(update.ids()),
+                None => update.ids()
+            });
+            origin = cmp::max(origin, update.origin());
+        }
+        let ids = match ids {
+            Some(ids) => ids,
+            None => return self.published.is_some()
+        };
+        let set = Arc::new(builder.finalize());
+        let diff = match self.published {
+            Some(ref published) => {
+                let diff = payload::Diff::reconcile(published, &set);
+                if diff.is_empty() && audit_only == self.audit_only {
+                    return true
+                }
+                Some(Arc::new(diff))
+            }
+            None => None
+        };
+        self.serial = self.serial.add(1);
+        debug!(
+            "Unit {}: merged updates {} into serial {}.",
+            name, ids, self.serial
+        );
+        gate.update_data(
+            payload::Update::new(
+                self.serial, set.clone(), diff
+            ).with_ids(ids).with_origin(origin).with_audit_only(audit_only)
+        ).await;
+        self.published = Some(set);
+        self.audit_only = audit_only;
+        true
+    }
+}
+
+
 //------------ AnyMetrics ----------------------------------------------------
 
 #[derive(Debug, Default)]
@@ -306,3 +775,256 @@ impl metrics::Source for AnyMetrics {
     }
 }
 
+
+
+//------------ QuorumMetrics -------------------------------------------------
+
+#[derive(Debug, Default)]
+struct QuorumMetrics {
+    gate: Arc<GateMetrics>,
+
+    /// The number of currently healthy sources.
+    healthy_sources: AtomicUsize,
+
+    /// Whether there currently is quorum.
+    quorum: AtomicBool,
+
+    /// The number of times quorum was lost.
+    quorum_losses: AtomicU64,
+
+    /// The number of times a source was quarantined.
+    quarantines: AtomicU64,
+
+    /// The number of times a quarantined source was re-admitted.
+    readmissions: AtomicU64,
+
+    /// Whether each source is currently quarantined.
+    quarantined: Vec<AtomicBool>,
+}
+
+impl QuorumMetrics {
+    const HEALTHY_SOURCES_METRIC: Metric = Metric::new(
+        "healthy_sources", "the number of currently healthy sources",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const QUORUM_METRIC: Metric = Metric::new(
+        "quorum", "whether enough sources are healthy",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const QUORUM_LOSS_METRIC: Metric = Metric::new(
+        "quorum_loss_count", "the number of times quorum was lost",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const QUARANTINES_METRIC: Metric = Metric::new(
+        "quarantine_count", "the number of times a source was quarantined",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const READMISSIONS_METRIC: Metric = Metric::new(
+        "readmission_count",
+        "the number of times a quarantined source was re-admitted",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const QUARANTINED_METRIC: Metric = Metric::new(
+        "source_quarantined", "whether a source is currently quarantined",
+        MetricType::Gauge, MetricUnit::Info
+    );
+}
+
+impl QuorumMetrics {
+    fn new(gate: &Gate, sources: usize) -> Self {
+        QuorumMetrics {
+            gate: gate.metrics(),
+            quarantined: (0..sources).map(|_| {
+                AtomicBool::new(false)
+            }).collect(),
+            .. Default::default()
+        }
+    }
+}
+
+impl metrics::Source for QuorumMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append_simple(
+            &Self::HEALTHY_SOURCES_METRIC, Some(unit_name),
+            self.healthy_sources.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::QUORUM_METRIC, Some(unit_name),
+            self.quorum.load(Ordering::Relaxed) as u8
+        );
+        target.append_simple(
+            &Self::QUORUM_LOSS_METRIC, Some(unit_name),
+            self.quorum_losses.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::QUARANTINES_METRIC, Some(unit_name),
+            self.quarantines.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::READMISSIONS_METRIC, Some(unit_name),
+            self.readmissions.load(Ordering::Relaxed)
+        );
+        target.append(&Self::QUARANTINED_METRIC, Some(unit_name), |records| {
+            for (idx, value) in self.quarantined.iter().enumerate() {
+                records.label_value(
+                    &[("source", &idx.to_string())],
+                    value.load(Ordering::Relaxed) as u8
+                );
+            }
+        });
+        self.gate.append(unit_name, target);
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use crate::payload::testing::v4;
+    use super::*;
+
+    #[test]
+    fn quorum_hysteresis() {
+        let start = Instant::now();
+        let hold = Duration::from_secs(30);
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let state = QuorumState::Initial.next(false, at(0), hold);
+        assert_eq!(state, QuorumState::Initial);
+        let state = state.next(true, at(0), hold);
+        assert_eq!(state, QuorumState::Established);
+        let state = state.next(false, at(10), hold);
+        assert_eq!(state, QuorumState::Lost);
+
+        // Quickly flapping sources don’t restore quorum.
+        let state = state.next(true, at(20), hold);
+        assert_eq!(state, QuorumState::Regained(at(50)));
+        let state = state.next(false, at(40), hold);
+        assert_eq!(state, QuorumState::Lost);
+        let state = state.next(true, at(45), hold);
+        assert_eq!(state, QuorumState::Regained(at(75)));
+        let state = state.next(true, at(60), hold);
+        assert_eq!(state, QuorumState::Regained(at(75)));
+        let state = state.next(true, at(75), hold);
+        assert_eq!(state, QuorumState::Established);
+    }
+
+    #[test]
+    fn admission() {
+        let start = Instant::now();
+        let quarantine = Duration::from_secs(60);
+        let readmit = Duration::from_secs(30);
+        let at = |secs| start + Duration::from_secs(secs);
+        let next = |state: Admission, healthy, updated, secs| {
+            state.next(healthy, updated, at(secs), quarantine, readmit)
+        };
+
+        // A short outage keeps the data.
+        let state = next(Admission::Admitted, false, false, 0);
+        assert_eq!(state, Admission::Stale(at(0)));
+        assert!(state.contributes());
+        assert_eq!(state.deadline(quarantine, readmit), Some(at(60)));
+        let state = next(state, true, false, 30);
+        assert_eq!(state, Admission::Admitted);
+
+        // A long one doesn’t.
+        let state = next(state, false, false, 40);
+        let state = next(state, false, false, 100);
+        assert_eq!(state, Admission::Quarantined);
+        assert!(!state.contributes());
+
+        // Becoming healthy without fresh data isn’t enough.
+        let state = next(state, true, false, 110);
+        assert_eq!(state, Admission::Quarantined);
+        let state = next(state, true, true, 120);
+        assert_eq!(state, Admission::Readmitting(at(120)));
+        assert!(!state.contributes());
+
+        // Flapping during re-admission starts over.
+        let state = next(state, false, false, 130);
+        assert_eq!(state, Admission::Quarantined);
+        let state = next(state, true, true, 140);
+        let state = next(state, true, false, 169);
+        assert_eq!(state, Admission::Readmitting(at(140)));
+        let state = next(state, true, false, 170);
+        assert_eq!(state, Admission::Admitted);
+    }
+
+    #[tokio::test]
+    async fn merge_publish() {
+        
+        
+
+        fn update(asns: &[u32]) -> payload::Update {
+            let mut set = payload::SetBuilder::empty();
+            for &asn in asns {
+                set.insert(v4([192, 0, 2, 0], 24, 24, asn)).unwrap();
+            }
+            payload::Update::new(
+                Serial::default(), Arc::new(set.finalize()), None
+            )
+        }
+
+        let (mut gate, _agent) = Gate::new();
+        let mut output = MergeOutput::default();
+        assert!(
+            !output.publish("merge", Vec::new().into_iter(), &mut gate).await
+        );
+        let (first, second) = (update(&[1, 2]), update(&[2, 3]));
+        assert!(
+            output.publish(
+                "merge", vec![second.clone(), first.clone()].into_iter(),
+                &mut gate
+            ).await
+        );
+        assert_eq!(output.published.as_ref().unwrap().len(), 3);
+        assert_eq!(output.serial, Serial::default().add(1));
+
+        // The published update carries the range of the merged IDs.
+        let ids = gate.metrics().update_ids().unwrap();
+        assert_eq!(ids, first.ids().merge(second.ids()));
+        assert_eq!(
+            (ids.first(), ids.last()),
+            (first.ids().first(), second.ids().last())
+        );
+
+        // Unchanged data isn’t published again.
+        assert!(
+            output.publish(
+                "merge", vec![update(&[3, 2, 1])].into_iter(), &mut gate
+            ).await
+        );
+        assert_eq!(output.serial, Serial::default().add(1));
+        assert!(!output.audit_only);
+
+        // Data for auditing only is left out if there is other data.
+        assert!(
+            output.publish(
+                "merge",
+                vec![
+                    update(&[1, 2]), update(&[3, 4]).with_audit_only(true)
+                ].into_iter(),
+                &mut gate
+            ).await
+        );
+        assert_eq!(output.published.as_ref().unwrap().len(), 2);
+        assert_eq!(output.serial, Serial::default().add(2));
+        assert!(!output.audit_only);
+
+        // If there’s only data for auditing only, the result is, too.
+        assert!(
+            output.publish(
+                "merge",
+                vec![
+                    update(&[1]).with_audit_only(true),
+                    update(&[3, 4]).with_audit_only(true)
+                ].into_iter(),
+                &mut gate
+            ).await
+        );
+        assert_eq!(output.published.as_ref().unwrap().len(), 3);
+        assert_eq!(output.serial, Serial::default().add(3));
+        assert!(output.audit_only);
+    }
+}
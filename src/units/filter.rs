@@ -0,0 +1,438 @@
+//! Units that filter the data of another unit.
+
+use std::{fmt, fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use reqwest::Url;
+use reqwest::blocking::Client as HttpClient;
+use rpki_rtr::Serial;
+use rpki_rtr::payload::Payload;
+use serde::Deserialize;
+use tokio::task::spawn_blocking;
+use tokio::time::{timeout_at, Instant};
+use crate::{net, payload};
+use crate::comms::{Gate, Link, Terminated, UnitStatus};
+use crate::manager::Component;
+use crate::payload::Prefix;
+use crate::siem::SiemEvent;
+use super::asn_policy::ReservedAsn;
+
+
+//------------ Filter --------------------------------------------------------
+
+/// A unit removing payload from the data set of another unit.
+///
+/// The rules for which payload to remove are kept in a separate file or
+/// fetched from a remote URL and are checked for changes regularly. When
+/// the rules change, the data set is filtered again right away and the
+/// resulting changes are published.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    /// The unit to filter the data of.
+    source: Link,
+
+    /// The path to the file with the filter rules.
+    #[serde(default)]
+    rules: Option<PathBuf>,
+
+    /// The URL to fetch the filter rules from.
+    #[serde(rename = "rules-url", default)]
+    rules_url: Option<Url>,
+
+    /// How many seconds to wait before checking the rules for changes.
+    #[serde(default = "Filter::default_refresh")]
+    refresh: u64,
+}
+
+impl Filter {
+    /// The default for the refresh interval.
+    pub fn default_refresh() -> u64 {
+        60
+    }
+
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        component.register_metrics(gate.metrics());
+        let name = component.name().clone();
+
+        let location = match (self.rules.take(), self.rules_url.take()) {
+            (Some(path), None) => RulesLocation::File(path),
+            (None, Some(url)) => RulesLocation::Remote(url),
+            _ => {
+                error!(
+                    "Unit {}: exactly one of 'rules' and 'rules-url' \
+                     must be given.",
+                    name
+                );
+                gate.update_status(UnitStatus::Gone).await;
+                return Err(Terminated)
+            }
+        };
+        let client = component.http_client().clone();
+        let outbound = component.outbound(None);
+
+        // A remote location may just be temporarily unavailable, so we
+        // don’t give up but keep trying without publishing anything.
+        let mut rules = match gate.process_until(
+            location.load(&client, &outbound)
+        ).await? {
+            Ok(rules) => Some(rules),
+            Err(err) => {
+                if let RulesLocation::File(_) = location {
+                    error!(
+                        "Unit {}: cannot load filter rules from {}: {}",
+                        name, location, err
+                    );
+                    gate.update_status(UnitStatus::Gone).await;
+                    return Err(Terminated)
+                }
+                warn!(
+                    "Unit {}: cannot load filter rules from {}: {}. \
+                     Trying again in {} seconds.",
+                    name, location, err, self.refresh
+                );
+                None
+            }
+        };
+        let refresh = Duration::from_secs(self.refresh);
+        let mut output = FilterOutput::default();
+        let mut next_refresh = Instant::now() + refresh;
+
+        loop {
+            match timeout_at(
+                next_refresh, gate.process_until(self.source.query())
+            ).await {
+                Ok(Ok(Ok(update))) => {
+                    debug!(
+                        "Unit {}: received update {}.", name, update.ids()
+                    );
+                    output.upstream = Some(update.set());
+                    output.ids = Some(update.ids());
+                    output.origin = update.origin();
+                    output.audit_only = update.is_audit_only();
+                    if let Some(ref rules) = rules {
+                        if output.publish(rules, &mut gate).await > 0 {
+                            output.report_dropped(&name, &component);
+                        }
+                    }
+                }
+                Ok(Ok(Err(status))) => {
+                    gate.update_status(status).await;
+                }
+                Ok(Err(_)) => return Err(Terminated),
+                Err(_) => {
+                    next_refresh = Instant::now() + refresh;
+                    let new_rules = match gate.process_until(
+                        location.load(&client, &outbound)
+                    ).await? {
+                        Ok(new_rules) => new_rules,
+                        Err(err) => {
+                            warn!(
+                                "Unit {}: cannot reload filter rules from \
+                                 {}: {}. Keeping the current rules.",
+                                name, location, err
+                            );
+                            continue
+                        }
+                    };
+                    if rules.as_ref() == Some(&new_rules) {
+                        continue
+                    }
+                    let changes = output.publish(&new_rules, &mut gate).await;
+                    rules = Some(new_rules);
+                    if changes > 0 {
+                        output.report_dropped(&name, &component);
+                    }
+                    info!(
+                        "Unit {}: filter rules changed, published {} \
+                         changes.",
+                        name, changes
+                    );
+                }
+            }
+        }
+    }
+}
+
+
+//------------ RulesLocation -------------------------------------------------
+
+/// Where the filter rules are loaded from.
+#[derive(Clone, Debug)]
+enum RulesLocation {
+    /// A local file in TOML format.
+    File(PathBuf),
+
+    /// A remote URL serving the rules in JSON format.
+    Remote(Url),
+}
+
+impl RulesLocation {
+    /// Loads the rules.
+    ///
+    /// Remote rules are fetched while holding a permit from `outbound`.
+    async fn load(
+        &self, client: &HttpClient, outbound: &net::Outbound
+    ) -> Result<Rules, RulesError> {
+        match *self {
+            RulesLocation::File(ref path) => Rules::load(path),
+            RulesLocation::Remote(ref url) => {
+                let _permit = outbound.permit().await;
+                let (url, client) = (url.clone(), client.clone());
+                spawn_blocking(move || {
+                    Rules::fetch(url, &client)
+                }).await.unwrap_or(Err(RulesError::Aborted))
+            }
+        }
+    }
+}
+
+impl fmt::Display for RulesLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RulesLocation::File(ref path) => path.display().fmt(f),
+            RulesLocation::Remote(ref url) => url.fmt(f),
+        }
+    }
+}
+
+
+//------------ FilterOutput --------------------------------------------------
+
+/// The data a filter unit has received and published.
+#[derive(Debug, Default)]
+struct FilterOutput {
+    /// The last data set received from the source.
+    upstream: Option<Arc<payload::Set>>,
+
+    /// The IDs of the last update received from the source.
+    ids: Option<payload::UpdateIds>,
+
+    /// The origin of the last update received from the source.
+    ///
+    /// This is only used for the first update published for it. Updates
+    /// caused by changed rules start a new origin.
+    origin: Option<std::time::Instant>,
+
+    /// The last data set we published.
+    published: Option<Arc<payload::Set>>,
+
+    /// The serial number of the last update we published.
+    serial: Serial,
+
+    /// Whether the data received from the source is for auditing only.
+    audit_only: bool,
+}
+
+impl FilterOutput {
+    /// Filters the upstream data and publishes the result.
+    ///
+    /// The update contains the diff against the previously published data
+    /// set, so that downstream only needs to apply the actual changes.
+    /// Nothing is published if nothing has changed.
+    ///
+    /// Returns the number of changes published.
+    async fn publish(&mut self, rules: &Rules, gate: &mut Gate) -> usize {
+        let upstream = match self.upstream {
+            Some(ref upstream) => upstream,
+            None => return 0
+        };
+        let set = Arc::new(upstream.filter(|item| rules.keep(item)));
+        let (diff, changes) = match self.published {
+            Some(ref published) => {
+                let diff = payload::Diff::reconcile(published, &set);
+                if diff.is_empty() {
+                    return 0
+                }
+                let changes = diff.len();
+                (Some(Arc::new(diff)), changes)
+            }
+            None => (None, set.len())
+        };
+        self.serial = self.serial.add(1);
+        let mut update = payload::Update::new(
+            self.serial, set.clone(), diff
+        ).with_audit_only(self.audit_only).with_origin(self.origin.take());
+        if let Some(ids) = self.ids {
+            update = update.with_ids(ids)
+        }
+        gate.update_data(update).await;
+        self.published = Some(set);
+        changes
+    }
+
+    /// Reports the items removed from the upstream data to the SIEM sink.
+    fn report_dropped(&self, name: &str, component: &Component) {
+        let siem = match component.siem() {
+            Some(siem) => siem,
+            None => return
+        };
+        if let (Some(upstream), Some(published)) = (
+            self.upstream.as_ref(), self.published.as_ref()
+        ) {
+            let dropped = upstream.len() - published.len();
+            if dropped > 0 {
+                siem.emit(&SiemEvent::filter_drop(name, dropped))
+            }
+        }
+    }
+}
+
+
+//------------ Rules ---------------------------------------------------------
+
+/// The rules determining which payload to remove.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+struct Rules {
+    /// Payload for these AS numbers is removed.
+    #[serde(rename = "exclude-asns", default)]
+    exclude_asns: Vec<u32>,
+
+    /// Payload for prefixes covered by these prefixes is removed.
+    #[serde(rename = "exclude-prefixes", default)]
+    exclude_prefixes: Vec<Prefix>,
+
+    /// Whether payload for reserved AS numbers is removed.
+    ///
+    /// This uses the same AS numbers as the `asn-policy` option of the
+    /// units receiving data from outside.
+    #[serde(rename = "exclude-reserved-asn", default)]
+    exclude_reserved_asn: bool,
+}
+
+impl Rules {
+    /// Loads the rules from a TOML file.
+    fn load(path: &Path) -> Result<Self, RulesError> {
+        let data = fs::read_to_string(path).map_err(RulesError::Io)?;
+        toml::from_str(&data).map_err(RulesError::Parse)
+    }
+
+    /// Fetches the rules in JSON format from a URL.
+    fn fetch(url: Url, client: &HttpClient) -> Result<Self, RulesError> {
+        let response = client.get(url).send().and_then(|response| {
+            response.error_for_status()
+        }).map_err(RulesError::Http)?;
+        serde_json::from_reader(response).map_err(RulesError::Json)
+    }
+
+    /// Returns whether the given payload passes the filter.
+    fn keep(&self, payload: &Payload) -> bool {
+        let asn = match *payload {
+            Payload::V4(ref prefix) => prefix.asn,
+            Payload::V6(ref prefix) => prefix.asn,
+        };
+        if self.exclude_reserved_asn && ReservedAsn::classify(asn).is_some() {
+            return false
+        }
+        !self.exclude_asns.contains(&asn)
+            && !self.exclude_prefixes.iter().any(|prefix| {
+                prefix.covers(payload)
+            })
+    }
+}
+
+
+//------------ RulesError ----------------------------------------------------
+
+/// Loading the filter rules failed.
+#[derive(Debug)]
+enum RulesError {
+    /// The rules file could not be read.
+    Io(io::Error),
+
+    /// The rules file could not be parsed.
+    Parse(toml::de::Error),
+
+    /// The remote rules could not be fetched.
+    Http(reqwest::Error),
+
+    /// The remote rules could not be parsed.
+    Json(serde_json::Error),
+
+    /// Fetching the remote rules was aborted.
+    Aborted,
+}
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RulesError::Io(ref err) => err.fmt(f),
+            RulesError::Parse(ref err) => err.fmt(f),
+            RulesError::Http(ref err) => err.fmt(f),
+            RulesError::Json(ref err) => err.fmt(f),
+            RulesError::Aborted => f.write_str("fetching aborted"),
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::payload::testing::v4;
+
+    #[test]
+    fn rules_keep() {
+        let rules: Rules = toml::from_str(
+            "exclude-asns = [64496]\n\
+             exclude-prefixes = [\"198.51.100.0/24\", \"2001:db8::/32\"]\n"
+        ).unwrap();
+        assert!(!rules.keep(&v4([192, 0, 2, 0], 24, 24, 64496)));
+        assert!(rules.keep(&v4([192, 0, 2, 0], 24, 24, 64497)));
+        assert!(!rules.keep(&v4([198, 51, 100, 128], 25, 25, 64497)));
+        assert!(rules.keep(&v4([198, 51, 0, 0], 16, 16, 64497)));
+        assert!(rules.keep(&v4([198, 51, 0, 0], 16, 16, 23456)));
+
+        let rules: Rules = toml::from_str(
+            "exclude-reserved-asn = true"
+        ).unwrap();
+        assert!(!rules.keep(&v4([192, 0, 2, 0], 24, 24, 64497)));
+        assert!(!rules.keep(&v4([192, 0, 2, 0], 24, 24, 23456)));
+        assert!(!rules.keep(&v4([192, 0, 2, 0], 24, 24, 4_200_000_000)));
+        assert!(rules.keep(&v4([192, 0, 2, 0], 24, 24, 65000)));
+    }
+
+    #[test]
+    fn rules_json() {
+        let rules: Rules = serde_json::from_str(
+            r#"{
+                "exclude-asns": [64496],
+                "exclude-prefixes": ["198.51.100.0/24"]
+            }"#
+        ).unwrap();
+        assert_eq!(
+            rules,
+            toml::from_str(
+                "exclude-asns = [64496]\n\
+                 exclude-prefixes = [\"198.51.100.0/24\"]\n"
+            ).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn rules_change_withdraws() {
+        let mut set = payload::SetBuilder::empty();
+        set.insert(v4([192, 0, 2, 0], 24, 24, 64496)).unwrap();
+        set.insert(v4([198, 51, 100, 0], 24, 24, 64497)).unwrap();
+        let mut output = FilterOutput {
+            upstream: Some(Arc::new(set.finalize())),
+            .. Default::default()
+        };
+        let (mut gate, _agent) = Gate::new();
+
+        assert_eq!(output.publish(&Rules::default(), &mut gate).await, 2);
+        assert_eq!(output.publish(&Rules::default(), &mut gate).await, 0);
+        let rules = Rules {
+            exclude_asns: vec![64496], .. Default::default()
+        };
+        assert_eq!(output.publish(&rules, &mut gate).await, 1);
+        assert_eq!(output.published.as_ref().unwrap().len(), 1);
+        assert_eq!(output.serial, Serial::default().add(2));
+    }
+}
@@ -0,0 +1,43 @@
+//! A unit publishing data given to it upfront.
+
+use std::sync::Arc;
+use rpki_rtr::Serial;
+use crate::payload;
+use crate::comms::{Gate, Terminated, UnitStatus};
+use crate::manager::Component;
+
+
+//------------ Injected ------------------------------------------------------
+
+/// A unit publishing a fixed data set.
+///
+/// The unit cannot be configured. Instead, it replaces units that fetch
+/// data from somewhere else when evaluating a configuration against given
+/// data via [`Unit::inject`](super::Unit::inject). It publishes the data
+/// set once and thereafter sends it to every new link.
+#[derive(Debug)]
+pub struct Injected {
+    /// The data set to publish.
+    set: Arc<payload::Set>,
+}
+
+impl Injected {
+    /// Creates a new unit publishing the given set.
+    pub fn new(set: Arc<payload::Set>) -> Self {
+        Injected { set }
+    }
+
+    pub async fn run(
+        self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        component.register_metrics(gate.metrics());
+        gate.enable_replay();
+        gate.update_status(UnitStatus::Healthy).await;
+        gate.update_data(
+            payload::Update::new(Serial::from(1), self.set, None)
+        ).await;
+        loop {
+            gate.process().await?;
+        }
+    }
+}
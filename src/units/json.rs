@@ -1,32 +1,93 @@
 //! JSON clients.
 
-use std::{io, thread};
+use std::{fmt, io, thread};
 use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
+use chrono::{DateTime, Utc};
 use log::{debug, error, warn};
 use reqwest::Url;
 use rpki_rtr::Serial;
+use rpki_rtr::client::VrpError;
 use serde::Deserialize;
 use tokio::sync::oneshot;
 use tokio::time::{Instant, timeout_at};
-use crate::payload;
-use crate::comms::{Gate, Terminated, UnitStatus};
+use crate::{metrics, net, payload};
+use crate::comms::{Gate, GateMetrics, Terminated, UnitStatus};
 use crate::formats::json::Set as JsonSet;
 use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use super::asn_policy::AsnPolicy;
+use super::stages::{Stage, StageMetrics, UpdateTimer};
 
 //------------ Json ----------------------------------------------------------
 
 /// An unit that regularly fetches a JSON-encoded VRP set.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Json {
     /// The URI of the JSON source.
     uri: Url,
 
     /// How many seconds to wait before refreshing the data.
     refresh: u64,
+
+    /// How many seconds a data set may claim to be from the future.
+    ///
+    /// If this is `None`, the generation time is not checked against the
+    /// future at all.
+    #[serde(rename = "max-future-skew", default)]
+    max_future_skew: Option<u64>,
+
+    /// What to do with data sets generated too far in the future.
+    #[serde(rename = "future-dated", default)]
+    future_dated: FuturePolicy,
+
+    /// How many seconds old a data set may be at most.
+    ///
+    /// If this is `None`, data sets of any age are accepted.
+    #[serde(rename = "max-age", default)]
+    max_age: Option<u64>,
+
+    /// The maximum number of concurrent connections of this unit.
+    #[serde(rename = "max-outbound-connections", default)]
+    max_outbound_connections: Option<usize>,
+
+    /// The policy for payload with reserved AS numbers.
+    ///
+    /// If this is `None`, all payload is kept.
+    #[serde(rename = "asn-policy", default)]
+    asn_policy: Option<AsnPolicy>,
 }
 
 impl Json {
+    /// Checks the generation time of a data set against the current time.
+    ///
+    /// Returns the generation time to use for the data set. This is the
+    /// current time if the data set is from the future and the policy is
+    /// to clamp it.
+    fn check_generated(
+        &self, generated: DateTime<Utc>, now: DateTime<Utc>
+    ) -> Result<DateTime<Utc>, JsonError> {
+        if let Some(skew) = self.max_future_skew {
+            if generated > now + chrono::Duration::seconds(skew as i64) {
+                match self.future_dated {
+                    FuturePolicy::Reject => {
+                        return Err(JsonError::FutureDated { generated, now })
+                    }
+                    FuturePolicy::Clamp => return Ok(now)
+                }
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            if generated + chrono::Duration::seconds(max_age as i64) < now {
+                return Err(JsonError::TooOld { generated, now })
+            }
+        }
+        Ok(generated)
+    }
+
     pub async fn run(
         self, component: Component, gate: Gate
     ) -> Result<(), Terminated> {
@@ -43,21 +104,31 @@ struct JsonRunner {
     gate: Gate,
     serial: Serial,
     status: UnitStatus,
+    metrics: Arc<JsonMetrics>,
+    policy: Option<Arc<AsnPolicy>>,
+    outbound: net::Outbound,
 }
 
 impl JsonRunner {
     fn new(
-        json: Json, component: Component, gate: Gate
+        mut json: Json, mut component: Component, gate: Gate
     ) -> Self {
+        let metrics = Arc::new(JsonMetrics::new(&gate));
+        let policy = json.asn_policy.take().map(Arc::new);
+        let outbound = component.outbound(json.max_outbound_connections);
         JsonRunner {
             json, component, gate,
             serial: Serial::default(),
             status: UnitStatus::Stalled,
+            metrics, policy, outbound,
         }
     }
 
     async fn run(mut self) -> Result<(), Terminated> {
-        self.component.register_metrics(self.gate.metrics());
+        self.component.register_metrics(self.metrics.clone());
+        if let Some(policy) = self.policy.clone() {
+            self.component.register_metrics(policy);
+        }
         self.gate.update_status(self.status).await;
         loop {
             self.step().await?;
@@ -89,15 +160,10 @@ impl JsonRunner {
             self.component.name(), self.json.uri
         );
         let uri = self.json.uri.clone();
-        if let Err(err) = self.step_generic(
-            move || File::open(uri.path())
-        ).await? {
-            warn!("{}: cannot open file '{}': {}",
-                self.component.name(),
-                self.json.uri.path(),
-                err
-            );
-        }
+        let res = self.step_generic(None, move || {
+            File::open(uri.path()).map_err(JsonError::Open)
+        }).await?;
+        self.step_failed(res).await;
         Ok(())
     }
 
@@ -106,26 +172,55 @@ impl JsonRunner {
             self.component.name(), self.json.uri
         );
         let request = self.component.http_client().get(self.json.uri.clone());
-        if let Err(err) = self.step_generic(move || request.send()).await? {
-            warn!("{}: failed to fetch from '{}': {}",
-                self.component.name(),
-                self.json.uri,
-                err
-            );
-        }
+        let permit = self.gate.process_until(self.outbound.permit()).await?;
+        let res = self.step_generic(Some(permit), move || {
+            request.send().and_then(|response| {
+                response.error_for_status()
+            }).map_err(JsonError::Fetch)
+        }).await?;
+        self.step_failed(res).await;
         Ok(())
     }
 
-    async fn step_generic<F, R, E>(
-        &mut self, op: F
-    ) -> Result<Result<(), E>, Terminated>
+    /// Processes the outcome of a step.
+    ///
+    /// Counts and logs the error if there was one. Only data that cannot
+    /// be parsed marks the unit as stalled.
+    async fn step_failed(&mut self, res: Result<(), JsonError>) {
+        let err = match res {
+            Ok(()) => return,
+            Err(err) => err
+        };
+        self.metrics.error(&err);
+        if err.is_stalling() && self.status != UnitStatus::Stalled {
+            self.status = UnitStatus::Stalled;
+            self.gate.update_status(self.status).await
+        }
+        warn!("{}: failed to update from '{}': {}",
+            self.component.name(),
+            self.json.uri,
+            err
+        );
+    }
+
+    /// Runs `op` and processes the data it returns.
+    ///
+    /// If a permit for an outbound connection is given, it is held until
+    /// all data has been read.
+    ///
+    /// Since the data is parsed while it is read, receiving only covers
+    /// opening the file or waiting for the response headers. Reading the
+    /// rest of the data is part of parsing.
+    async fn step_generic<F, R>(
+        &mut self, permit: Option<net::OutboundPermit>, op: F
+    ) -> Result<Result<(), JsonError>, Terminated>
     where
-        F: FnOnce() -> Result<R, E> + Send + 'static,
+        F: FnOnce() -> Result<R, JsonError> + Send + 'static,
         R: io::Read,
-        E: Send + 'static,
     {
         let (tx, rx) = oneshot::channel();
         let _ = thread::spawn(move || {
+            let mut timer = UpdateTimer::start();
             let reader = match op() {
                 Ok(reader) => reader,
                 Err(err)=> {
@@ -133,38 +228,75 @@ impl JsonRunner {
                     return;
                 }
             };
-            let res = serde_json::from_reader::<_, JsonSet>(reader);
-            let _ = tx.send(Ok(res));
+            timer.stage(Stage::Receive);
+            let res = serde_json::from_reader::<_, JsonSet>(
+                reader
+            ).map_err(JsonError::Parse);
+            drop(permit);
+            let _ = tx.send(res.map(|res| (res, timer)));
         });
 
         // XXX I think awaiting rx should never produce an error, so
         //     unwrapping is the right thing to do. But is it really?
-        let res = match self.gate.process_until(rx).await?.unwrap() {
-            Ok(Ok(res)) => res,
-            Ok(Err(err)) => {
-                if self.status != UnitStatus::Stalled {
-                    self.status = UnitStatus::Stalled;
-                    self.gate.update_status(self.status).await
-                }
+        let res = self.gate.process_until(rx).await?.unwrap();
+        let (res, mut timer) = match res {
+            Ok(res) => res,
+            Err(err) => return Ok(Err(err))
+        };
+
+        // Report all invalid entries in one go. They are skipped when
+        // converting the data.
+        let invalid = res.validate();
+        self.metrics.invalid.store(invalid.len(), Ordering::Relaxed);
+        for (idx, err) in invalid {
+            warn!(
+                "{}: ignoring entry {} in '{}': {}",
+                self.component.name(), idx, self.json.uri,
+                describe_vrp_error(err)
+            );
+        }
+
+        if let Some(generated) = res.generated() {
+            let now = Utc::now();
+            let accepted = match self.json.check_generated(generated, now) {
+                Ok(accepted) => accepted,
+                Err(err) => return Ok(Err(err))
+            };
+            if accepted != generated {
+                self.metrics.future_clamped.fetch_add(1, Ordering::Relaxed);
                 warn!(
-                    "{}: Failed reading source: {}",
-                    self.component.name(),
-                    err
+                    "{}: data generated at {} is in the future (local time \
+                     {}). Using local time instead.",
+                    self.component.name(), generated, now
                 );
-                return Ok(Ok(()))
             }
-            Err(err) => return Ok(Err(err))
-        };
-        
+            self.metrics.generated.store(
+                accepted.timestamp(), Ordering::Relaxed
+            );
+        }
+
         self.serial = self.serial.add(1);
         if self.status != UnitStatus::Healthy {
             self.status = UnitStatus::Healthy;
             self.gate.update_status(self.status).await
         }
-        self.gate.update_data(
-            payload::Update::new(self.serial, res.into_payload().into(), None)
-        ).await;
-        debug!("Unit {}: successfully updated.", self.component.name());
+        timer.stage(Stage::Parse);
+        let mut set = res.into_payload();
+        if let Some(ref policy) = self.policy {
+            set = policy.apply(set);
+        }
+        timer.stage(Stage::Finalize);
+        // We always publish the full set, so there is no diff.
+        timer.record(Stage::Diff, Duration::from_secs(0));
+        let update = payload::Update::new(self.serial, set.into(), None);
+        let ids = update.ids();
+        self.gate.update_data(update).await;
+        timer.stage(Stage::Publish);
+        timer.finish(&self.metrics.stages);
+        debug!(
+            "Unit {}: successfully updated (update {}).",
+            self.component.name(), ids
+        );
         Ok(Ok(()))
     }
 
@@ -185,3 +317,274 @@ impl JsonRunner {
 
 }
 
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns a description of why a VRP was considered invalid.
+fn describe_vrp_error(err: VrpError) -> &'static str {
+    match err {
+        VrpError::Corrupt => "invalid prefix length or max-length",
+        VrpError::DuplicateAnnounce => "duplicate entry",
+        _ => "invalid entry",
+    }
+}
+
+
+//------------ FuturePolicy --------------------------------------------------
+
+/// What to do with data sets that claim to be from the future.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum FuturePolicy {
+    /// Reject the data set as if it could not be parsed.
+    #[serde(rename = "reject")]
+    Reject,
+
+    /// Accept the data set but use the local time as its generation time.
+    #[serde(rename = "clamp")]
+    Clamp,
+}
+
+impl Default for FuturePolicy {
+    fn default() -> Self {
+        FuturePolicy::Reject
+    }
+}
+
+
+//------------ JsonError -----------------------------------------------------
+
+/// An error happened while updating the JSON unit.
+#[derive(Debug)]
+enum JsonError {
+    /// The file could not be opened.
+    Open(io::Error),
+
+    /// The data could not be fetched via HTTP.
+    Fetch(reqwest::Error),
+
+    /// The data could not be parsed.
+    Parse(serde_json::Error),
+
+    /// The data claims to have been generated too far in the future.
+    FutureDated {
+        generated: DateTime<Utc>,
+        now: DateTime<Utc>,
+    },
+
+    /// The data has been generated too long ago.
+    TooOld {
+        generated: DateTime<Utc>,
+        now: DateTime<Utc>,
+    },
+}
+
+impl JsonError {
+    /// The names of all error kinds as used in metrics.
+    ///
+    /// The order needs to match the one used by [`kind`](Self::kind).
+    const KINDS: [&'static str; 5] = [
+        "open", "fetch", "parse", "future-dated", "too-old"
+    ];
+
+    /// Returns the index of the error’s kind in [`KINDS`](Self::KINDS).
+    fn kind(&self) -> usize {
+        match *self {
+            JsonError::Open(_) => 0,
+            JsonError::Fetch(_) => 1,
+            JsonError::Parse(_) => 2,
+            JsonError::FutureDated { .. } => 3,
+            JsonError::TooOld { .. } => 4,
+        }
+    }
+
+    /// Returns whether the error should mark the unit as stalled.
+    ///
+    /// This is only true if the received data cannot be parsed. Data that
+    /// is from the future or too old is handled like data that could not
+    /// be received at all: the previous data is kept and we try again.
+    fn is_stalling(&self) -> bool {
+        matches!(*self, JsonError::Parse(_))
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Open(ref err) => {
+                write!(f, "cannot open file: {}", err)
+            }
+            JsonError::Fetch(ref err) => {
+                write!(f, "cannot fetch data: {}", err)
+            }
+            JsonError::Parse(ref err) => {
+                write!(f, "cannot parse data: {}", err)
+            }
+            JsonError::FutureDated { generated, now } => {
+                write!(f,
+                    "data generated at {} is too far in the future \
+                     (local time {})",
+                    generated, now
+                )
+            }
+            JsonError::TooOld { generated, now } => {
+                write!(f,
+                    "data generated at {} is too old (local time {})",
+                    generated, now
+                )
+            }
+        }
+    }
+}
+
+
+//------------ JsonMetrics ---------------------------------------------------
+
+#[derive(Debug, Default)]
+struct JsonMetrics {
+    gate: Arc<GateMetrics>,
+
+    /// The number of errors that happened by kind.
+    ///
+    /// The kinds are indexed as in `JsonError::KINDS`.
+    errors: [AtomicU64; 5],
+
+    /// The number of data sets from the future that were accepted anyway.
+    future_clamped: AtomicU64,
+
+    /// The generation time of the current data in seconds since the epoch.
+    ///
+    /// This is zero if the time is not known.
+    generated: AtomicI64,
+
+    /// The number of invalid entries in the last data set received.
+    invalid: AtomicUsize,
+
+    /// The durations of the stages of processing updates.
+    stages: StageMetrics,
+}
+
+impl JsonMetrics {
+    fn new(gate: &Gate) -> Self {
+        JsonMetrics {
+            gate: gate.metrics(),
+            errors: Default::default(),
+            future_clamped: Default::default(),
+            generated: Default::default(),
+            invalid: Default::default(),
+            stages: Default::default(),
+        }
+    }
+
+    /// Counts an error.
+    fn error(&self, err: &JsonError) {
+        self.errors[err.kind()].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl JsonMetrics {
+    const ERRORS_METRIC: Metric = Metric::new(
+        "json_errors", "the number of errors of the JSON client by kind",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FUTURE_CLAMPED_METRIC: Metric = Metric::new(
+        "json_future_clamped",
+        "the number of data sets from the future accepted with local time",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const GENERATED_METRIC: Metric = Metric::new(
+        "json_generated", "the generation time of the current data",
+        MetricType::Gauge, MetricUnit::Second
+    );
+    const INVALID_METRIC: Metric = Metric::new(
+        "json_invalid_entries",
+        "the number of entries ignored in the last data set received",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for JsonMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        self.gate.append(unit_name, target);
+        self.stages.append(unit_name, target);
+        target.append(&Self::ERRORS_METRIC, Some(unit_name), |records| {
+            for (kind, value) in JsonError::KINDS.iter().zip(&self.errors) {
+                records.label_value(
+                    &[("kind", kind)], value.load(Ordering::Relaxed)
+                );
+            }
+        });
+        target.append_simple(
+            &Self::FUTURE_CLAMPED_METRIC, Some(unit_name),
+            self.future_clamped.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::GENERATED_METRIC, Some(unit_name),
+            self.generated.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::INVALID_METRIC, Some(unit_name),
+            self.invalid.load(Ordering::Relaxed)
+        );
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn error_kinds() {
+        fn kind(err: JsonError) -> &'static str {
+            JsonError::KINDS[err.kind()]
+        }
+
+        assert_eq!(
+            kind(JsonError::Open(
+                File::open("/nonexistent/rtrtr/test.json").unwrap_err()
+            )),
+            "open"
+        );
+        assert_eq!(
+            kind(JsonError::Parse(
+                serde_json::from_str::<JsonSet>("{").unwrap_err()
+            )),
+            "parse"
+        );
+    }
+
+    #[test]
+    fn check_generated() {
+        fn json(toml: &str) -> Json {
+            toml::from_str(&format!(
+                "uri = \"file:///dev/null\"\nrefresh = 60\n{}", toml
+            )).unwrap()
+        }
+
+        let now = Utc.timestamp(1_600_000_000, 0);
+        let future = now + chrono::Duration::seconds(600);
+        let past = now - chrono::Duration::seconds(600);
+
+        // Nothing is checked by default.
+        assert_eq!(json("").check_generated(future, now).unwrap(), future);
+        assert_eq!(json("").check_generated(past, now).unwrap(), past);
+
+        let reject = json("max-future-skew = 300");
+        let err = reject.check_generated(future, now).unwrap_err();
+        assert_eq!(JsonError::KINDS[err.kind()], "future-dated");
+        assert!(!err.is_stalling());
+        assert_eq!(reject.check_generated(past, now).unwrap(), past);
+
+        let clamp = json("max-future-skew = 300\nfuture-dated = \"clamp\"");
+        assert_eq!(clamp.check_generated(future, now).unwrap(), now);
+
+        let max_age = json("max-age = 300");
+        let err = max_age.check_generated(past, now).unwrap_err();
+        assert_eq!(JsonError::KINDS[err.kind()], "too-old");
+        assert!(!err.is_stalling());
+        assert_eq!(max_age.check_generated(future, now).unwrap(), future);
+    }
+}
@@ -18,14 +18,25 @@
 //------------ Sub-modules ---------------------------------------------------
 //
 // These contain all the actual unit types grouped by shared functionality.
+mod aggregate;
+mod asn_policy;
+mod batch;
+mod circuit_breaker;
 mod combine;
+mod filter;
+mod injected;
 mod json;
+mod rtan_source;
 mod rtr;
+mod stages;
 
 //------------ Unit ----------------------------------------------------------
 
+use std::sync::Arc;
 use serde::Deserialize;
+use crate::payload;
 use crate::comms::Gate;
+use crate::log::ExitError;
 use crate::manager::Component;
 
 /// The fundamental entity for data processing.
@@ -35,14 +46,57 @@ pub enum Unit {
     #[serde(rename = "any")]
     Any(combine::Any),
 
-    #[serde(rename = "rtr")]
-    RtrTcp(rtr::Tcp),
+    /// The RTR unit is by far the largest, so it is boxed.
+    #[serde(rename = "rtr", deserialize_with = "rtr::Tcp::deserialize_unit")]
+    RtrTcp(Box<rtr::Tcp>),
 
     #[serde(rename = "json")]
     Json(json::Json),
+
+    #[serde(rename = "filter")]
+    Filter(filter::Filter),
+
+    #[serde(rename = "quorum-merge")]
+    QuorumMerge(combine::QuorumMerge),
+
+    #[serde(rename = "aggregate")]
+    Aggregate(aggregate::Aggregate),
+
+    #[serde(rename = "rtan")]
+    Rtan(rtan_source::RtanSource),
+
+    #[serde(rename = "batch")]
+    Batch(batch::Batch),
+
+    #[serde(skip_deserializing)]
+    Injected(injected::Injected),
 }
 
 impl Unit {
+    /// Replaces a unit fetching data from elsewhere with the given data.
+    ///
+    /// Units that get their data from outside of RTRTR, i.e., RTR, JSON,
+    /// and RTAN units, are replaced by a unit that only ever publishes
+    /// `set`. All other units are returned unchanged.
+    pub fn inject(self, set: Arc<payload::Set>) -> Self {
+        match self {
+            Unit::RtrTcp(_) | Unit::Json(_) | Unit::Rtan(_) => {
+                Unit::Injected(injected::Injected::new(set))
+            }
+            unit => unit
+        }
+    }
+
+    /// Takes over any file descriptors passed in for the unit.
+    ///
+    /// This happens at startup before the runtime is started.
+    pub fn adopt_fds(&mut self, name: &str) -> Result<(), ExitError> {
+        match *self {
+            Unit::RtrTcp(ref mut unit) => unit.adopt_fd(name),
+            _ => Ok(())
+        }
+    }
+
     pub async fn run(
         self, component: Component, gate: Gate
     )  {
@@ -50,7 +104,67 @@ impl Unit {
             Unit::Any(unit) => unit.run(component, gate).await,
             Unit::RtrTcp(unit) => unit.run(component, gate).await,
             Unit::Json(unit) => unit.run(component, gate).await,
+            Unit::Filter(unit) => unit.run(component, gate).await,
+            Unit::QuorumMerge(unit) => unit.run(component, gate).await,
+            Unit::Aggregate(unit) => unit.run(component, gate).await,
+            Unit::Rtan(unit) => unit.run(component, gate).await,
+            Unit::Batch(unit) => unit.run(component, gate).await,
+            Unit::Injected(unit) => unit.run(component, gate).await,
         };
     }
 }
 
+
+//------------ UnitConfig ----------------------------------------------------
+
+/// A unit together with the options common to all units.
+#[derive(Debug, Deserialize)]
+pub struct UnitConfig {
+    /// The unit itself.
+    #[serde(flatten)]
+    pub unit: Unit,
+
+    /// The maximum number of links the unit’s gate accepts.
+    ///
+    /// If this is `None`, the number is not limited.
+    #[serde(rename = "max-consumers", default)]
+    pub max_consumers: Option<usize>,
+
+    /// Whether the unit runs in shadow mode.
+    ///
+    /// In shadow mode, the unit’s updates are logged rather than published.
+    /// See [`Gate::enable_shadow_mode`] for details.
+    #[serde(rename = "shadow-mode", default)]
+    pub shadow_mode: bool,
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn max_consumers() {
+        let config: UnitConfig = toml::from_str(
+            "type = \"rtr\"\nremote = \"localhost:323\"\nmax-consumers = 4"
+        ).unwrap();
+        assert!(matches!(config.unit, Unit::RtrTcp(_)));
+        assert_eq!(config.max_consumers, Some(4));
+
+        let config: UnitConfig = toml::from_str(
+            "type = \"rtr\"\nremote = \"localhost:323\""
+        ).unwrap();
+        assert_eq!(config.max_consumers, None);
+        assert!(!config.shadow_mode);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let err = toml::from_str::<UnitConfig>(
+            "type = \"rtr\"\nremote = \"localhost:323\"\nrefresh = 600"
+        ).unwrap_err();
+        assert!(err.to_string().contains("refresh"), "{}", err);
+    }
+}
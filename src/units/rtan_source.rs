@@ -0,0 +1,602 @@
+//! A unit receiving ROA publications from an RTAN subscription feed.
+//!
+//! The RPKI Trust Anchor Manager (RTAN) offers access to newly published
+//! ROAs via a WebSocket subscription. Compared to polling an RTR cache,
+//! this reduces the time until new ROAs are available.
+//!
+//! All messages are JSON objects in text messages. After connecting, the
+//! unit subscribes with
+//!
+//! ```json
+//! { "type": "subscribe", "serial": 12 }
+//! ```
+//!
+//! where `serial` is the serial of the last publication received or `null`
+//! if there is none. The server answers with either a snapshot of all ROAs
+//! or the deltas since that serial and then keeps sending deltas as new
+//! ROAs are published:
+//!
+//! ```json
+//! { "type": "snapshot", "serial": 12, "roas": [ ... ] }
+//! { "type": "delta", "serial": 13,
+//!   "announced": [ ... ], "withdrawn": [ ... ] }
+//! ```
+//!
+//! The VRPs in the arrays use the same format as the `roas` array of the
+//! JSON unit. A delta must have the serial following the last one received
+//! and must apply cleanly to the current data. If that is not the case, the
+//! unit resubscribes without a serial to get a fresh snapshot.
+//!
+//! If the connection breaks, the unit reconnects after waiting for the
+//! configured retry time and resubscribes with the last serial. Only
+//! unencrypted connections via `ws://` URIs are currently supported.
+
+use std::{fmt, io};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use reqwest::Url;
+use rpki_rtr::Serial;
+use rpki_rtr::payload::Action;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as _;
+use tokio::net::TcpStream;
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_tungstenite::{client_async, WebSocketStream};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use crate::{metrics, net, payload};
+use crate::comms::{Gate, GateMetrics, Terminated, UnitStatus};
+use crate::formats::json::{Set as JsonSet, Vrp};
+use crate::manager::Component;
+use crate::metrics::{Metric, MetricType, MetricUnit};
+use super::asn_policy::AsnPolicy;
+
+
+//------------ RtanSource ----------------------------------------------------
+
+/// A unit subscribing to an RTAN feed.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RtanSource {
+    /// The WebSocket URI of the feed.
+    #[serde(deserialize_with = "RtanSource::deserialize_uri")]
+    uri: Url,
+
+    /// How many seconds to wait before reconnecting.
+    #[serde(default = "RtanSource::default_retry")]
+    retry: u64,
+
+    /// How many seconds connecting to the feed may take.
+    ///
+    /// This includes the WebSocket handshake.
+    #[serde(
+        rename = "connect-timeout",
+        default = "RtanSource::default_connect_timeout"
+    )]
+    connect_timeout: u64,
+
+    /// The maximum number of concurrent connections of this unit.
+    #[serde(rename = "max-outbound-connections", default)]
+    max_outbound_connections: Option<usize>,
+
+    /// The policy for payload with reserved AS numbers.
+    ///
+    /// If this is `None`, all payload is kept.
+    #[serde(rename = "asn-policy", default)]
+    asn_policy: Option<AsnPolicy>,
+}
+
+impl RtanSource {
+    pub fn default_retry() -> u64 {
+        60
+    }
+
+    pub fn default_connect_timeout() -> u64 {
+        30
+    }
+
+    /// Deserializes the `uri` option.
+    ///
+    /// Only accepts `ws` URIs with a host.
+    fn deserialize_uri<'de, D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Url, D::Error> {
+        let uri = Url::deserialize(deserializer)?;
+        if uri.scheme() != "ws" {
+            return Err(D::Error::custom(format!(
+                "invalid RTAN URI '{}': only 'ws' URIs are supported", uri
+            )))
+        }
+        if uri.host_str().is_none() {
+            return Err(D::Error::custom(format!(
+                "invalid RTAN URI '{}': missing host", uri
+            )))
+        }
+        Ok(uri)
+    }
+
+    pub async fn run(
+        mut self, mut component: Component, mut gate: Gate
+    ) -> Result<(), Terminated> {
+        let metrics = Arc::new(RtanMetrics::new(&gate));
+        component.register_metrics(metrics.clone());
+        let policy = self.asn_policy.take().map(|policy| {
+            let policy = Arc::new(policy);
+            component.register_metrics(policy.clone());
+            policy
+        });
+        let mut feed = Feed { policy, .. Default::default() };
+        let outbound = component.outbound(self.max_outbound_connections);
+        gate.update_status(UnitStatus::Stalled).await;
+
+        loop {
+            debug!(
+                "Unit {}: connecting to {} ...", component.name(), self.uri
+            );
+            let mut sock = match gate.process_until(
+                self.connect(&outbound)
+            ).await? {
+                Ok(sock) => sock,
+                Err(err) => {
+                    warn!(
+                        "Unit {}: failed to connect to RTAN feed {}: {}",
+                        component.name(), self.uri, err
+                    );
+                    self.retry_wait(&mut gate).await?;
+                    continue
+                }
+            };
+            info!("Unit {}: connected to {}.", component.name(), self.uri);
+            let res = self.session(
+                component.name(), &mut sock, &mut feed, &mut gate, &metrics
+            ).await?;
+            gate.update_status(UnitStatus::Stalled).await;
+            match res {
+                Ok(()) => {
+                    debug!(
+                        "Unit {}: RTAN feed closed the connection.",
+                        component.name()
+                    );
+                }
+                Err(err) => {
+                    warn!(
+                        "Unit {}: RTAN session with {} failed: {}",
+                        component.name(), self.uri, err
+                    );
+                }
+            }
+            self.retry_wait(&mut gate).await?;
+        }
+    }
+
+    /// Connects to the feed.
+    ///
+    /// Waits for a permit from `outbound` first. The connection including
+    /// the WebSocket handshake has to be established within the connect
+    /// timeout.
+    async fn connect(
+        &self, outbound: &net::Outbound
+    ) -> Result<Socket, RtanError> {
+        let permit = outbound.permit().await;
+        let connect = async {
+            // The URI has been checked to have a host when deserializing.
+            let host = self.uri.host_str().unwrap_or_default();
+            let port = self.uri.port_or_known_default().unwrap_or(80);
+            let sock = TcpStream::connect(
+                (host, port)
+            ).await.map_err(RtanError::Connect)?;
+            let (sock, _) = client_async(
+                self.uri.as_str(), net::Outgoing::new(sock, permit)
+            ).await.map_err(RtanError::WebSocket)?;
+            Ok(sock)
+        };
+        let limit = Duration::from_secs(self.connect_timeout);
+        match timeout(limit, connect).await {
+            Ok(res) => res,
+            Err(_) => {
+                Err(RtanError::Connect(io::Error::new(
+                    io::ErrorKind::TimedOut, "connection timed out"
+                )))
+            }
+        }
+    }
+
+    /// Runs a session on a connection until it ends.
+    ///
+    /// Returns `Ok(())` if the server closed the connection.
+    async fn session(
+        &self,
+        name: &str,
+        sock: &mut Socket,
+        feed: &mut Feed,
+        gate: &mut Gate,
+        metrics: &RtanMetrics,
+    ) -> Result<Result<(), RtanError>, Terminated> {
+        if let Err(err) = gate.process_until(
+            Self::subscribe(sock, feed.serial)
+        ).await? {
+            return Ok(Err(err))
+        }
+
+        // Whether we have requested a snapshot and ignore deltas until it
+        // arrives.
+        let mut resync = false;
+        loop {
+            let msg = match gate.process_until(sock.next()).await? {
+                Some(Ok(Message::Text(msg))) => msg,
+                Some(Ok(Message::Close(_))) | None => return Ok(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Ok(Err(RtanError::WebSocket(err))),
+            };
+            let msg = match serde_json::from_str::<FeedMessage>(&msg) {
+                Ok(msg) => msg,
+                Err(err) => return Ok(Err(RtanError::Parse(err))),
+            };
+            if resync {
+                if let FeedMessage::Delta { .. } = msg {
+                    continue
+                }
+                resync = false;
+            }
+            let update = match feed.apply(msg) {
+                Ok(update) => update,
+                Err(err) => {
+                    // Start over with a snapshot.
+                    metrics.resyncs.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Unit {}: {}. Requesting a snapshot.",
+                        name, err
+                    );
+                    if let Err(err) = gate.process_until(
+                        Self::subscribe(sock, None)
+                    ).await? {
+                        return Ok(Err(err))
+                    }
+                    resync = true;
+                    continue
+                }
+            };
+            if update.diff().is_some() {
+                metrics.deltas.fetch_add(1, Ordering::Relaxed);
+            }
+            else {
+                metrics.snapshots.fetch_add(1, Ordering::Relaxed);
+            }
+            info!(
+                "Unit {}: publishing update {} with serial {}: {}.",
+                name, update.ids(), update.serial(), update.summary()
+            );
+            gate.update_status(UnitStatus::Healthy).await;
+            gate.update_data(update).await;
+        }
+    }
+
+    /// Sends a subscription request.
+    async fn subscribe(
+        sock: &mut Socket, serial: Option<u32>
+    ) -> Result<(), RtanError> {
+        let msg = match serial {
+            Some(serial) => {
+                format!("{{\"type\":\"subscribe\",\"serial\":{}}}", serial)
+            }
+            None => String::from("{\"type\":\"subscribe\",\"serial\":null}")
+        };
+        sock.send(Message::Text(msg)).await.map_err(RtanError::WebSocket)
+    }
+
+    /// Waits for the retry time while processing the gate.
+    async fn retry_wait(&self, gate: &mut Gate) -> Result<(), Terminated> {
+        let end = Instant::now() + Duration::from_secs(self.retry);
+        while end > Instant::now() {
+            match timeout_at(end, gate.process()).await {
+                Ok(Ok(_)) => { }
+                Ok(Err(_)) => return Err(Terminated),
+                Err(_) => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The socket to the feed.
+type Socket = WebSocketStream<net::Outgoing<TcpStream>>;
+
+
+//------------ FeedMessage ---------------------------------------------------
+
+/// A message received from the feed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+enum FeedMessage {
+    /// The complete current data.
+    #[serde(rename = "snapshot")]
+    Snapshot {
+        serial: u32,
+        roas: Vec<Vrp>,
+    },
+
+    /// The changes since the previous serial.
+    #[serde(rename = "delta")]
+    Delta {
+        serial: u32,
+
+        #[serde(default)]
+        announced: Vec<Vrp>,
+
+        #[serde(default)]
+        withdrawn: Vec<Vrp>,
+    },
+}
+
+
+//------------ Feed ----------------------------------------------------------
+
+/// The state of the data received from the feed.
+#[derive(Debug, Default)]
+struct Feed {
+    /// The serial of the feed’s last publication.
+    ///
+    /// This is `None` if we haven’t received a snapshot yet.
+    serial: Option<u32>,
+
+    /// The current data.
+    current: Arc<payload::Set>,
+
+    /// Our own serial for the updates we publish.
+    unit_serial: Serial,
+
+    /// The policy for payload with reserved AS numbers.
+    policy: Option<Arc<AsnPolicy>>,
+}
+
+impl Feed {
+    /// Applies a message and returns the update to publish.
+    ///
+    /// If the message doesn’t fit the current data, the data is left alone
+    /// and an error is returned.
+    fn apply(
+        &mut self, msg: FeedMessage
+    ) -> Result<payload::Update, FeedError> {
+        let (serial, set, diff) = match msg {
+            FeedMessage::Snapshot { serial, roas } => {
+                let mut set = JsonSet::from_vrps(roas).into_payload();
+                if let Some(ref policy) = self.policy {
+                    set = policy.apply(set);
+                }
+                (serial, set, None)
+            }
+            FeedMessage::Delta { serial, announced, withdrawn } => {
+                let expected = match self.serial {
+                    Some(current) => current.wrapping_add(1),
+                    None => return Err(FeedError::NoSnapshot)
+                };
+                if serial != expected {
+                    return Err(FeedError::Serial { expected, serial })
+                }
+                let mut diff = payload::DiffBuilder::default();
+                let changes = announced.iter().map(|vrp| {
+                    (vrp, Action::Announce)
+                }).chain(withdrawn.iter().map(|vrp| {
+                    (vrp, Action::Withdraw)
+                }));
+                for (vrp, action) in changes {
+                    // Announced items must be new and withdrawn items
+                    // must exist.
+                    let item = vrp.to_payload();
+                    if let Some(ref policy) = self.policy {
+                        if !policy.keep(&item) {
+                            continue
+                        }
+                    }
+                    let known = self.current.contains(&item);
+                    if payload::SetBuilder::validate(&item).is_err()
+                        || known == action.is_announce()
+                        || diff.push(item, action).is_err()
+                    {
+                        return Err(FeedError::Delta(serial))
+                    }
+                }
+                let diff = diff.finalize();
+                let set = diff.apply(&self.current);
+                (serial, set, Some(Arc::new(diff)))
+            }
+        };
+        self.serial = Some(serial);
+        self.current = Arc::new(set);
+        self.unit_serial = self.unit_serial.add(1);
+        Ok(payload::Update::new(self.unit_serial, self.current.clone(), diff))
+    }
+}
+
+
+//------------ FeedError -----------------------------------------------------
+
+/// A message from the feed didn’t fit our data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FeedError {
+    /// A delta arrived before any snapshot.
+    NoSnapshot,
+
+    /// A delta had an unexpected serial.
+    Serial { expected: u32, serial: u32 },
+
+    /// The delta with the given serial doesn’t apply to our data.
+    Delta(u32),
+}
+
+impl fmt::Display for FeedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FeedError::NoSnapshot => {
+                f.write_str("received delta before snapshot")
+            }
+            FeedError::Serial { expected, serial } => {
+                write!(
+                    f, "received delta for serial {} instead of {}",
+                    serial, expected
+                )
+            }
+            FeedError::Delta(serial) => {
+                write!(f, "delta for serial {} doesn’t apply", serial)
+            }
+        }
+    }
+}
+
+
+//------------ RtanError -----------------------------------------------------
+
+/// An error happened while talking to the feed.
+#[derive(Debug)]
+enum RtanError {
+    /// Connecting to the server failed.
+    Connect(io::Error),
+
+    /// An error happened on the WebSocket connection.
+    WebSocket(WsError),
+
+    /// A message couldn’t be parsed.
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for RtanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RtanError::Connect(ref err) => err.fmt(f),
+            RtanError::WebSocket(ref err) => err.fmt(f),
+            RtanError::Parse(ref err) => {
+                write!(f, "invalid message: {}", err)
+            }
+        }
+    }
+}
+
+
+//------------ RtanMetrics ---------------------------------------------------
+
+/// The metrics of an RTAN unit.
+#[derive(Debug, Default)]
+struct RtanMetrics {
+    gate: Arc<GateMetrics>,
+
+    /// The number of snapshots received.
+    snapshots: AtomicU64,
+
+    /// The number of deltas received.
+    deltas: AtomicU64,
+
+    /// The number of times a snapshot had to be requested again.
+    resyncs: AtomicU64,
+}
+
+impl RtanMetrics {
+    fn new(gate: &Gate) -> Self {
+        RtanMetrics {
+            gate: gate.metrics(),
+            .. Default::default()
+        }
+    }
+}
+
+impl RtanMetrics {
+    const SNAPSHOTS_METRIC: Metric = Metric::new(
+        "rtan_snapshots", "the number of snapshots received from the feed",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const DELTAS_METRIC: Metric = Metric::new(
+        "rtan_deltas", "the number of deltas received from the feed",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const RESYNCS_METRIC: Metric = Metric::new(
+        "rtan_resyncs",
+        "the number of snapshots requested because a delta didn't fit",
+        MetricType::Counter, MetricUnit::Total
+    );
+}
+
+impl metrics::Source for RtanMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        self.gate.append(unit_name, target);
+        target.append_simple(
+            &Self::SNAPSHOTS_METRIC, Some(unit_name),
+            self.snapshots.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::DELTAS_METRIC, Some(unit_name),
+            self.deltas.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::RESYNCS_METRIC, Some(unit_name),
+            self.resyncs.load(Ordering::Relaxed)
+        );
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(json: &str) -> FeedMessage {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn feed() {
+        let mut feed = Feed::default();
+        assert_eq!(
+            feed.apply(msg(
+                r#"{"type": "delta", "serial": 4, "announced": []}"#
+            )).unwrap_err(),
+            FeedError::NoSnapshot
+        );
+
+        let update = feed.apply(msg(r#"{
+            "type": "snapshot", "serial": 4, "roas": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24, "ta": "test" }
+            ]
+        }"#)).unwrap();
+        assert_eq!(update.serial(), Serial::from(1));
+        assert_eq!(update.set().len(), 1);
+        assert!(update.diff().is_none());
+
+        let update = feed.apply(msg(r#"{
+            "type": "delta", "serial": 5,
+            "announced": [
+                { "asn": "AS64497", "prefix": "198.51.100.0/24",
+                  "maxLength": 24, "ta": "test" }
+            ],
+            "withdrawn": [
+                { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                  "maxLength": 24, "ta": "test" }
+            ]
+        }"#)).unwrap();
+        assert_eq!(update.serial(), Serial::from(2));
+        assert_eq!(update.set().len(), 1);
+        assert_eq!(update.diff().unwrap().action_counts(), (1, 1));
+
+        // Gaps and deltas that don’t apply leave the data alone.
+        assert_eq!(
+            feed.apply(msg(
+                r#"{"type": "delta", "serial": 7, "announced": []}"#
+            )).unwrap_err(),
+            FeedError::Serial { expected: 6, serial: 7 }
+        );
+        assert_eq!(
+            feed.apply(msg(r#"{
+                "type": "delta", "serial": 6, "withdrawn": [
+                    { "asn": "AS64496", "prefix": "192.0.2.0/24",
+                      "maxLength": 24, "ta": "test" }
+                ]
+            }"#)).unwrap_err(),
+            FeedError::Delta(6)
+        );
+        assert_eq!(feed.serial, Some(5));
+        assert_eq!(feed.current.len(), 1);
+    }
+}
@@ -1,35 +1,229 @@
 //! RTR Clients.
 
-use std::io;
-use std::sync::Arc;
+use std::{cmp, fmt, io};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
 use std::time::Duration;
+use chrono::{DateTime, Utc};
+use crossbeam_utils::atomic::AtomicCell;
 use futures::pin_mut;
-use futures::future::{select, Either};
-use log::{debug, warn};
+use futures::future::{join, select, Either};
+use hyper::{Body, Method, Request, Response};
+use log::{debug, error, info, warn};
 use rpki_rtr::client::{Client, VrpError, VrpTarget, VrpUpdate};
 use rpki_rtr::payload::{Action, Payload, Timing};
 use rpki_rtr::state::{Serial, State};
-use serde::Deserialize;
-use tokio::net::TcpStream;
-use tokio::time::{timeout_at, Instant};
-use crate::metrics;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as _;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+use tokio::time::{delay_for, timeout_at, Instant};
+use crate::{http, metrics, net};
+use crate::metrics::{Metric, MetricType, MetricUnit};
 use crate::comms::{Gate, GateMetrics, GateStatus, Terminated, UnitStatus};
+use crate::log::ExitError;
 use crate::manager::Component;
 use crate::payload;
+use crate::random::Random;
+use crate::siem::SiemEvent;
+use super::asn_policy::AsnPolicy;
+use super::circuit_breaker::CircuitBreaker;
+use super::stages::{Stage, StageMetrics, UpdateTimer};
 
 
 //------------ Tcp -----------------------------------------------------------
 
 /// An RTR client using an unencrypted plain TCP socket.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Tcp {
     /// The remote address to connect to.
-    remote: String,
+    remote: Remote,
+
+    /// A server to fetch data from while `remote` is unavailable.
+    ///
+    /// The fallback is only used to get data if we don’t have any or our
+    /// data has expired. We never stay connected to it.
+    #[serde(rename = "fallback-remote", default)]
+    fallback_remote: Option<Remote>,
+
+    /// After how many seconds without an update our data has expired.
+    #[serde(
+        rename = "fallback-expire",
+        default = "Tcp::default_fallback_expire"
+    )]
+    fallback_expire: u64,
 
     /// How long to wait before connecting again if the connection is closed.
     #[serde(default = "Tcp::default_retry")]
     retry: u64,
 
+    /// An identifier for this client.
+    ///
+    /// The RTR protocol currently provides no means to convey such an
+    /// identifier to the server, so it is never sent and only used in our
+    /// own log messages.
+    #[serde(rename = "client-id", default)]
+    client_id: Option<String>,
+
+    /// Whether our data is for auditing only.
+    ///
+    /// If this is `true`, our updates are marked so that RTR server targets
+    /// won’t serve them. Everything else works as usual.
+    #[serde(rename = "audit-only", default)]
+    audit_only: bool,
+
+    /// The address families of prefixes to accept from the server.
+    #[serde(rename = "prefix-family", default)]
+    prefix_family: PrefixFamily,
+
+    /// What to do about prefixes of a family we don’t accept.
+    #[serde(rename = "disabled-family", default)]
+    disabled_family: DisabledFamily,
+
+    /// Whether to limit the number of cache resets accepted from the server.
+    #[serde(rename = "limit-resets", default = "Tcp::default_limit_resets")]
+    limit_resets: bool,
+
+    /// The maximum number of cache resets per hour before backing off.
+    #[serde(
+        rename = "max-resets-per-hour",
+        default = "Tcp::default_max_resets"
+    )]
+    max_resets: usize,
+
+    /// How long to wait before a new full transfer after too many resets.
+    #[serde(rename = "reset-backoff", default = "Tcp::default_reset_backoff")]
+    reset_backoff: u64,
+
+    /// How many finalization tasks may run concurrently for this unit.
+    #[serde(
+        rename = "finalize-concurrency",
+        default = "Tcp::default_finalize_concurrency"
+    )]
+    finalize_concurrency: usize,
+
+    /// After how many seconds without an exchange to query the server.
+    ///
+    /// This keeps the state of NATs and firewalls on the path alive. If
+    /// this is `None`, we just wait for the server.
+    #[serde(rename = "idle-query", default)]
+    idle_query: Option<u64>,
+
+    /// How often to look for payload that could be aggregated.
+    ///
+    /// If this is `None`, we don’t look at all.
+    #[serde(rename = "aggregation-check", default)]
+    aggregation_check: Option<u64>,
+
+    /// How long the connection needs to be down to raise the alarm.
+    #[serde(
+        rename = "connection-alarm-raise",
+        default = "Tcp::default_connection_alarm_raise"
+    )]
+    connection_alarm_raise: u64,
+
+    /// How long the connection needs to be up to clear the alarm.
+    #[serde(
+        rename = "connection-alarm-clear",
+        default = "Tcp::default_connection_alarm_clear"
+    )]
+    connection_alarm_clear: u64,
+
+    /// The maximum number of concurrent connections of this unit.
+    #[serde(rename = "max-outbound-connections", default)]
+    max_outbound_connections: Option<usize>,
+
+    /// What to do if the server changes the protocol version mid-session.
+    #[serde(rename = "version-mismatch", default)]
+    version_mismatch: VersionMismatch,
+
+    /// Whether to log all received PDUs at debug level.
+    #[serde(rename = "debug-pdus", default)]
+    debug_pdus: bool,
+
+    /// The VRF to connect from.
+    ///
+    /// If this is `None`, the default routing table is used. VRFs are only
+    /// supported on Linux.
+    #[serde(default, deserialize_with = "Tcp::deserialize_vrf")]
+    vrf: Option<String>,
+
+    /// The network interface to connect via.
+    ///
+    /// If this is `None`, the interface is chosen by routing. This can’t
+    /// be combined with `vrf`. Interfaces are only supported on Linux and
+    /// macOS.
+    #[serde(default, deserialize_with = "Tcp::deserialize_interface")]
+    interface: Option<String>,
+
+    /// The file descriptor of a connected socket to use first.
+    ///
+    /// The socket is passed in by a supervisor and taken over at startup
+    /// via [`adopt_fd`](Self::adopt_fd). File descriptors are only
+    /// supported on Unix.
+    #[serde(default, deserialize_with = "Tcp::deserialize_fd")]
+    fd: Option<i32>,
+
+    /// What to do once the connection via `fd` has been closed.
+    #[serde(rename = "fd-disconnect", default)]
+    fd_disconnect: FdDisconnect,
+
+    /// Whether to publish updates with the server’s serial number.
+    ///
+    /// If this is `false`, we number our updates ourselves.
+    #[serde(rename = "preserve-serial", default)]
+    preserve_serial: bool,
+
+    /// Whether to publish serial advances without changes.
+    ///
+    /// If this is `false`, updates without changes are dropped.
+    #[serde(rename = "forward-empty-updates", default)]
+    forward_empty_updates: bool,
+
+    /// How to query the server after reconnecting.
+    #[serde(rename = "reconnect-serial", default)]
+    reconnect_serial: ReconnectSerial,
+
+    /// The largest serial gap accepted after reconnecting.
+    ///
+    /// This is only used with [`ReconnectSerial::ResetOnGap`].
+    #[serde(
+        rename = "max-serial-gap",
+        default = "Tcp::default_max_serial_gap"
+    )]
+    max_serial_gap: u32,
+
+    /// The circuit breaker for connecting to the server.
+    ///
+    /// If this is `None`, we keep trying regardless of failures.
+    #[serde(rename = "circuit-breaker", default)]
+    circuit_breaker: Option<CircuitBreaker>,
+
+    /// The policy for payload with reserved AS numbers.
+    ///
+    /// If this is `None`, all payload is kept. The policy is boxed to keep
+    /// the unit small.
+    #[serde(rename = "asn-policy", default)]
+    asn_policy: Option<Box<AsnPolicy>>,
+
+    /// The maximum size of a single update in bytes.
+    ///
+    /// The size is that of the prefix PDUs received for the update. If
+    /// this is `None`, updates can be of any size.
+    #[serde(rename = "max-update-bytes", default)]
+    max_update_bytes: Option<usize>,
+
+    /// The limits for our outbound connections.
+    #[serde(skip)]
+    outbound: net::Outbound,
+
     /// Our gate status.
     #[serde(skip)]
     status: GateStatus,
@@ -37,6 +231,22 @@ pub struct Tcp {
     /// Our current serial.
     #[serde(skip)]
     serial: Serial,
+
+    /// Whether we have published an update yet.
+    #[serde(skip)]
+    published: bool,
+
+    /// The cache resets we have recently received.
+    #[serde(skip)]
+    resets: ResetLimit,
+
+    /// The random numbers for jittering the retry time.
+    #[serde(skip)]
+    random: Random,
+
+    /// The socket taken over from `fd` if it hasn’t been used yet.
+    #[serde(skip)]
+    adopted: Option<std::net::TcpStream>,
 }
 
 impl Tcp {
@@ -44,45 +254,365 @@ impl Tcp {
         60
     }
 
+    pub fn default_fallback_expire() -> u64 {
+        7200
+    }
+
+    pub fn default_limit_resets() -> bool {
+        true
+    }
+
+    pub fn default_max_resets() -> usize {
+        4
+    }
+
+    pub fn default_reset_backoff() -> u64 {
+        1800
+    }
+
+    pub fn default_finalize_concurrency() -> usize {
+        1
+    }
+
+    pub fn default_connection_alarm_raise() -> u64 {
+        120
+    }
+
+    /// Deserializes the `vrf` option.
+    ///
+    /// Rejects the option on systems other than Linux and names that can’t
+    /// be the name of a VRF device.
+    fn deserialize_vrf<'de, D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Option<String>, D::Error> {
+        let vrf = match Option::<String>::deserialize(deserializer)? {
+            Some(vrf) => vrf,
+            None => return Ok(None)
+        };
+        if !cfg!(target_os = "linux") {
+            return Err(D::Error::custom(
+                "the 'vrf' option is only supported on Linux"
+            ))
+        }
+        if vrf.is_empty() || vrf.len() > net::MAX_VRF_LEN {
+            return Err(D::Error::custom(format!(
+                "invalid VRF name '{}': must be between 1 and {} bytes long",
+                vrf, net::MAX_VRF_LEN
+            )))
+        }
+        Ok(Some(vrf))
+    }
+
+    /// Deserializes the `interface` option.
+    ///
+    /// Rejects the option on systems where sockets can’t be bound to an
+    /// interface and names that can’t be the name of an interface.
+    fn deserialize_interface<'de, D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Option<String>, D::Error> {
+        let interface = match Option::<String>::deserialize(deserializer)? {
+            Some(interface) => interface,
+            None => return Ok(None)
+        };
+        if !net::INTERFACE_SUPPORTED {
+            return Err(D::Error::custom(
+                "the 'interface' option is only supported on Linux and macOS"
+            ))
+        }
+        if interface.is_empty() || interface.len() > net::MAX_INTERFACE_LEN {
+            return Err(D::Error::custom(format!(
+                "invalid interface name '{}': must be between 1 and {} \
+                 bytes long",
+                interface, net::MAX_INTERFACE_LEN
+            )))
+        }
+        Ok(Some(interface))
+    }
+
+    /// Deserializes the unit.
+    ///
+    /// This rejects combinations of options that exclude each other, which
+    /// the field deserializers can’t see.
+    pub fn deserialize_unit<'de, D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Box<Self>, D::Error> {
+        let res = Box::<Self>::deserialize(deserializer)?;
+        if res.vrf.is_some() && res.interface.is_some() {
+            return Err(D::Error::custom(
+                "the 'vrf' and 'interface' options can’t be used together"
+            ))
+        }
+        Ok(res)
+    }
+
+    pub fn default_connection_alarm_clear() -> u64 {
+        300
+    }
+
+    pub fn default_max_serial_gap() -> u32 {
+        100
+    }
+
+    /// Deserializes the `fd` option.
+    ///
+    /// Rejects the option on systems other than Unix and negative values.
+    fn deserialize_fd<'de, D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<Option<i32>, D::Error> {
+        let fd = match Option::<i32>::deserialize(deserializer)? {
+            Some(fd) => fd,
+            None => return Ok(None)
+        };
+        if !cfg!(unix) {
+            return Err(D::Error::custom(
+                "the 'fd' option is only supported on Unix"
+            ))
+        }
+        if fd < 0 {
+            return Err(D::Error::custom(format!(
+                "invalid file descriptor {}", fd
+            )))
+        }
+        Ok(Some(fd))
+    }
+
+    /// Takes over the socket given via the `fd` option.
+    ///
+    /// This needs to happen at startup before anything else could open a
+    /// file and end up with the descriptor if it wasn’t actually passed to
+    /// us. Fails if the descriptor isn’t a connected TCP socket.
+    pub fn adopt_fd(&mut self, name: &str) -> Result<(), ExitError> {
+        let fd = match self.fd {
+            Some(fd) => fd,
+            None => return Ok(())
+        };
+        match net::adopt_stream(fd) {
+            Ok(sock) => {
+                self.adopted = Some(sock);
+                Ok(())
+            }
+            Err(err) => {
+                error!(
+                    "Fatal: unit {}: file descriptor {} is not a \
+                     connected TCP socket: {}",
+                    name, fd, err
+                );
+                Err(ExitError)
+            }
+        }
+    }
+
     pub async fn run(
         mut self, mut component: Component, mut gate: Gate
     ) -> Result<(), Terminated> {
-        let mut target = Target::new(component.name().clone());
-        let metrics = Arc::new(RtrMetrics::new(&gate));
+        let mut target = Target::new(
+            component.name().clone(), self.prefix_family
+        );
+        target.policy = self.asn_policy.take().map(|policy| {
+            let policy: Arc<AsnPolicy> = policy.into();
+            component.register_metrics(policy.clone());
+            policy
+        });
+        target.max_bytes = self.max_update_bytes;
+        self.random = component.random().clone();
+        let metrics = Arc::new(RtrMetrics::new(
+            &gate,
+            ConnectionAlarm::new(
+                Duration::from_secs(self.connection_alarm_raise),
+                Duration::from_secs(self.connection_alarm_clear),
+            )
+        ));
         component.register_metrics(metrics.clone());
+        self.outbound = component.outbound(self.max_outbound_connections);
+        let finalizer = Finalizer::new(
+            self.finalize_concurrency, metrics.clone()
+        );
+        let aggregation = self.aggregation_check.map(|secs| {
+            let check = Arc::new(AggregationCheck::new(component.name()));
+            component.register_metrics(check.clone());
+            component.register_http_resource(check.clone());
+            tokio::spawn(AggregationCheck::run(
+                Arc::downgrade(&check), Duration::from_secs(secs)
+            ));
+            check
+        });
+        let breaker = self.circuit_breaker.take().map(|breaker| {
+            let breaker = Arc::new(breaker);
+            component.register_metrics(breaker.clone());
+            breaker
+        });
         gate.update_status(UnitStatus::Stalled).await;
+
+        // When we last received an update from any server.
+        let mut last_update = None;
+
+        // Whether the last connection was closed to send an idle query.
+        let mut idle_requery = false;
+
         loop {
-            debug!("Unit {}: Connecting ...", target.name);
-            let mut client = match self.connect(target, &mut gate).await {
-                Ok(client) => {
+            if let Some(ref breaker) = breaker {
+                if let Err(until) = breaker.allow(Instant::now()) {
+                    gate.update_status(UnitStatus::Stalled).await;
+                    // An open breaker is exactly when the fallback server
+                    // is needed, so try it before waiting.
+                    if self.fallback_due(last_update) {
+                        let (new_target, success) = self.fallback(
+                            target, &mut gate, &metrics, &finalizer,
+                            aggregation.as_deref()
+                        ).await?;
+                        target = new_target;
+                        if success {
+                            last_update = Some(Instant::now());
+                        }
+                    }
+                    self.wait_until(&mut gate, until).await?;
+                    continue;
+                }
+            }
+            // Whether this connection uses the socket passed in via `fd`.
+            let adopted = self.adopted.is_some();
+            let peer = if adopted {
+                format!("file descriptor {}", self.fd.unwrap_or_default())
+            }
+            else {
+                self.peer()
+            };
+            debug!("Unit {}: Connecting to {} ...", target.name, peer);
+            let sock = match self.connect(&mut gate, false).await? {
+                Ok(sock) => {
+                    match metrics.connected(Utc::now(), idle_requery) {
+                        Some((count, time)) => {
+                            info!(
+                                "Unit {}: reconnected to {} at {} \
+                                 (reconnect {}).",
+                                target.name, peer, time, count
+                            );
+                        }
+                        None if idle_requery => {
+                            debug!(
+                                "Unit {}: connected to {} for idle query.",
+                                target.name, peer
+                            );
+                        }
+                        None => {
+                            info!(
+                                "Unit {}: connected to {}.",
+                                target.name, peer
+                            );
+                        }
+                    }
+                    if let Some(siem) = component.siem() {
+                        siem.emit(&SiemEvent::connection_up(
+                            &target.name, &peer
+                        ));
+                    }
+                    metrics.connection_alarm.connected(true, Instant::now());
                     gate.update_status(UnitStatus::Healthy).await;
-                    client
+                    sock
                 }
-                Err(res) => {
+                Err(err) => {
+                    metrics.error(&err);
+                    metrics.connection_alarm.connected(false, Instant::now());
+                    warn!(
+                        "Unit {}: Failed to connect to RTR server {}: {}",
+                        target.name, peer, err
+                    );
+                    if adopted && self.fd_disconnect.is_fail() {
+                        return Err(
+                            self.fd_closed(&target.name, &mut gate).await
+                        )
+                    }
                     debug!(
                         "Unit {}: Connection failed. Awaiting reconnect.",
-                        res.name
+                        target.name
                     );
                     gate.update_status(UnitStatus::Stalled).await;
+                    if self.breaker_failure(
+                        &target.name, breaker.as_deref()
+                    ) {
+                        continue;
+                    }
+                    if self.fallback_due(last_update) {
+                        let (new_target, success) = self.fallback(
+                            target, &mut gate, &metrics, &finalizer,
+                            aggregation.as_deref()
+                        ).await?;
+                        target = new_target;
+                        if success {
+                            last_update = Some(Instant::now());
+                        }
+                    }
                     self.retry_wait(&mut gate).await?;
-                    target = res;
                     continue;
                 }
             };
+            let state = self.reconnect_serial.query_state(
+                target.state, idle_requery
+            );
+            if state.is_none() && target.state.is_some() {
+                debug!(
+                    "Unit {}: requesting a cache reset after reconnecting.",
+                    target.name
+                );
+            }
+            idle_requery = false;
+            let sock = PduCounter::new(
+                sock, target.name.clone(), self.version_mismatch,
+                metrics.clone()
+            ).with_pdu_log(self.debug_pdus);
+            let activity = sock.activity();
+            let mut client = Client::new(sock, target, state);
+
+            // Whether we are waiting for the first update on the connection.
+            let mut initial = true;
+            let mut backoff = false;
+            let mut idle = false;
+            let mut requery = false;
+            let mut gap_reset = false;
+            let mut upstream = state.map(|state| state.serial());
 
             loop {
-                let update = match self.update(&mut client, &mut gate).await {
-                    Ok(Ok(update)) => {
+                let update = match self.update(
+                    &mut client, &mut gate, &activity
+                ).await {
+                    Ok(Some(Ok(update))) => {
                         debug!(
                             "Unit {}: received update.", client.target().name
                         );
                         update
                     }
-                    Ok(Err(_)) => {
+                    Ok(None) => {
+                        metrics.idle_queries.fetch_add(1, Ordering::Relaxed);
                         debug!(
-                            "Unit {}: RTR client disconnected.",
+                            "Unit {}: connection idle, querying server.",
                             client.target().name
                         );
+                        idle = true;
+                        break;
+                    }
+                    Ok(Some(Err(err))) => {
+                        metrics.error(&err);
+                        if err.is_disconnect() {
+                            debug!(
+                                "Unit {}: RTR client disconnected.",
+                                client.target().name
+                            );
+                        }
+                        else {
+                            warn!(
+                                "Unit {}: RTR session with server {} \
+                                 failed: {}",
+                                client.target().name, self.peer(), err
+                            );
+                        }
+                        if err.is_version_mismatch() {
+                            if self.version_mismatch.is_fail() {
+                                return Err(self.fail(
+                                    &client.target().name, &mut gate
+                                ).await)
+                            }
+                            requery = true;
+                        }
                         break;
                     }
                     Err(_) => {
@@ -93,118 +623,911 @@ impl Tcp {
                         return Err(Terminated)
                     }
                 };
-                if !update.is_definitely_empty() {
-                    self.serial = self.serial.add(1);
-                    let update = update.into_update(self.serial);
-                    client.target_mut().current = update.set();
-                    gate.update_data(update).await;
+                if initial && !update.is_reset() && !self.accept_gap(
+                    &client.target().name, state,
+                    client.state()
+                ) {
+                    gap_reset = true;
+                    break;
+                }
+                last_update = Some(Instant::now());
+                if let Some(ref breaker) = breaker {
+                    breaker.success();
+                }
+                metrics.update_received(update.is_reset());
+                self.family_dropped(
+                    &client.target().name, update.dropped, &metrics
+                );
+                if update.is_reset() {
+                    // A full update only is what we asked for if we sent a
+                    // reset query, which only happens at the start of a
+                    // connection without a state. Every other one means
+                    // the server answered a serial query with a cache
+                    // reset and counts towards the reset limit.
+                    if !initial || state.is_some() {
+                        backoff = self.reset_received(
+                            &client.target().name, &metrics
+                        );
+                    }
+                }
+                else {
+                    self.incremental_received(
+                        &client.target().name, &metrics
+                    );
+                }
+                initial = false;
+                let previous = upstream;
+                upstream = client.state().map(|state| state.serial());
+                if self.publish_due(&update, previous, upstream) {
+                    self.publish(
+                        update, upstream, client.target_mut(), &mut gate,
+                        &finalizer, aggregation.as_deref()
+                    ).await?;
+                }
+                if backoff {
+                    break;
                 }
             }
 
+            // Keep the state so the next connection can continue with a
+            // serial query.
+            let state = client.state();
             target = client.into_target();
+            target.state = state;
+            if let Some(siem) = component.siem() {
+                siem.emit(&SiemEvent::connection_down(&target.name, &peer));
+            }
+            if requery || gap_reset {
+                // We can’t trust the data received in the broken session,
+                // so start over with a reset query.
+                target.state = None;
+            }
+            if adopted && self.fd_disconnect.is_fail() {
+                return Err(self.fd_closed(&target.name, &mut gate).await)
+            }
+            if idle || gap_reset {
+                // The client can only send a query when it starts, so we
+                // query over a new connection right away.
+                idle_requery = idle;
+                continue;
+            }
+            metrics.connection_alarm.connected(false, Instant::now());
             gate.update_status(UnitStatus::Stalled).await;
-            self.retry_wait(&mut gate).await?;
+            // A connection that ended without an update failed, too.
+            if initial && self.breaker_failure(
+                &target.name, breaker.as_deref()
+            ) {
+                continue;
+            }
+            if backoff {
+                self.wait(&mut gate, self.reset_backoff).await?;
+            }
+            else {
+                self.retry_wait(&mut gate).await?;
+            }
         }
     }
 
-    async fn connect(
-        &mut self, target: Target, gate: &mut Gate,
-    ) -> Result<Client<TcpStream, Target>, Target> {
-        let sock = {
-            let connect = TcpStream::connect(&self.remote);
-            pin_mut!(connect);
-            
-            loop {
-                let process = gate.process();
-                pin_mut!(process);
-                match select(process, connect).await {
-                    Either::Left((Err(_), _)) => {
-                        return Err(target)
-                    }
-                    Either::Left((Ok(status), next_fut)) => {
-                        self.status = status;
-                        connect = next_fut;
-                    }
-                    Either::Right((res, _)) => break res
-                }
-            }
+    /// Finalizes an update and hands it to the gate.
+    ///
+    /// The server’s serial number after the update is given in `upstream`
+    /// if known.
+    async fn publish(
+        &mut self,
+        update: TargetUpdate,
+        upstream: Option<Serial>,
+        target: &mut Target,
+        gate: &mut Gate,
+        finalizer: &Finalizer,
+        aggregation: Option<&AggregationCheck>,
+    ) -> Result<(), Terminated> {
+        // PDUs are parsed as they are received, so parsing can’t be told
+        // apart from receiving and isn’t recorded.
+        let mut timer = update.timer;
+        timer.stage(Stage::Receive);
+        if update.max_len_dropped > 0 {
+            finalizer.metrics.max_len_dropped.fetch_add(
+                update.max_len_dropped as u64, Ordering::Relaxed
+            );
+            error!(
+                "Unit {}: dropped {} VRPs with a max-length beyond the \
+                 address length.",
+                target.name, update.max_len_dropped
+            );
+        }
+        self.serial = self.next_serial(&target.name, upstream);
+        self.published = true;
+        let (update, finalize, diff) = gate.process_until(
+            finalizer.finalize(update, self.serial)
+        ).await?;
+        info!(
+            "Unit {}: publishing update {} with serial {}: {}.",
+            target.name, update.ids(), self.serial, update.summary()
+        );
+        target.current = update.set();
+        if let Some(check) = aggregation {
+            check.update(update.set());
+        }
+        timer.record(Stage::Finalize, finalize);
+        timer.record(Stage::Diff, diff);
+        gate.update_data(update.with_audit_only(self.audit_only)).await;
+        timer.stage(Stage::Publish);
+        timer.finish(&finalizer.metrics.stages);
+        Ok(())
+    }
+
+    /// Returns whether an update needs to be published.
+    ///
+    /// Updates with changes always are. Updates known to contain no
+    /// changes are only published if we forward empty updates and the
+    /// server’s serial has moved from `previous` to `upstream`.
+    fn publish_due(
+        &self,
+        update: &TargetUpdate,
+        previous: Option<Serial>,
+        upstream: Option<Serial>,
+    ) -> bool {
+        if !update.is_definitely_empty() {
+            return true
+        }
+        self.forward_empty_updates
+            && upstream.is_some() && upstream != previous
+    }
+
+    /// Returns the serial number for the next update.
+    ///
+    /// Normally, this is our current serial plus one. If we preserve the
+    /// server’s serial, it is `upstream` instead. Since our serials need to
+    /// keep increasing, this only works if `upstream` is newer than our
+    /// current serial. This may not be the case after a cache reset with a
+    /// new session or after switching to the fallback server. Then we fall
+    /// back to our current serial plus one until the server’s serial has
+    /// caught up again.
+    fn next_serial(&self, name: &str, upstream: Option<Serial>) -> Serial {
+        let upstream = match upstream {
+            Some(upstream) if self.preserve_serial => upstream,
+            _ => return self.serial.add(1)
         };
+        if !self.published || upstream > self.serial {
+            return upstream
+        }
+        warn!(
+            "Unit {}: server serial {} is not newer than our serial {}. \
+             Using serial {} instead.",
+            name, upstream, self.serial, self.serial.add(1)
+        );
+        self.serial.add(1)
+    }
+
+    /// Returns whether to accept the first update after reconnecting.
+    ///
+    /// The update moved the server’s state from `old` to `new`. If the
+    /// serial gap between the two is not acceptable, logs a warning.
+    fn accept_gap(
+        &self, name: &str, old: Option<State>, new: Option<State>
+    ) -> bool {
+        let (old, new) = match (old, new) {
+            (Some(old), Some(new)) => (old.serial(), new.serial()),
+            _ => return true
+        };
+        if self.reconnect_serial.accept_gap(old, new, self.max_serial_gap) {
+            return true
+        }
+        warn!(
+            "Unit {}: server serial moved from {} to {} since the last \
+             connection, more than the {} accepted. Requesting a cache \
+             reset.",
+            name, old, new, self.max_serial_gap
+        );
+        false
+    }
+
+    /// Returns whether we should get data from the fallback server.
+    ///
+    /// This is the case if there is a fallback server and we haven’t
+    /// received an update for longer than the expire interval.
+    fn fallback_due(&self, last_update: Option<Instant>) -> bool {
+        if self.fallback_remote.is_none() {
+            return false
+        }
+        match last_update {
+            Some(last) => {
+                last.elapsed() >= Duration::from_secs(self.fallback_expire)
+            }
+            None => true
+        }
+    }
 
-        let sock = match sock {
+    /// Fetches a full data set from the fallback server.
+    ///
+    /// Connects to the fallback server, receives one full update, publishes
+    /// it, and disconnects again. Returns the target and whether an update
+    /// was received.
+    async fn fallback(
+        &mut self,
+        target: Target,
+        gate: &mut Gate,
+        metrics: &Arc<RtrMetrics>,
+        finalizer: &Finalizer,
+        aggregation: Option<&AggregationCheck>,
+    ) -> Result<(Target, bool), Terminated> {
+        let remote = match self.fallback_remote {
+            Some(ref remote) => self.with_binding(remote.to_string()),
+            None => return Ok((target, false))
+        };
+        info!(
+            "Unit {}: fetching data from fallback server {}.",
+            target.name, remote
+        );
+        let sock = match self.connect(gate, true).await? {
             Ok(sock) => sock,
             Err(err) => {
+                metrics.error(&err);
+                warn!(
+                    "Unit {}: Failed to connect to fallback server {}: {}",
+                    target.name, remote, err
+                );
+                return Ok((target, false))
+            }
+        };
+        let sock = PduCounter::new(
+            sock, target.name.clone(), self.version_mismatch, metrics.clone()
+        ).with_pdu_log(self.debug_pdus);
+        let activity = sock.activity();
+
+        // Always start with a reset query: the fallback server most likely
+        // has a different session.
+        let mut client = Client::new(sock, target, None);
+        let update = match self.update(&mut client, gate, &activity).await? {
+            Some(Ok(update)) => update,
+            Some(Err(err)) => {
+                metrics.error(&err);
                 warn!(
-                    "Unit {}: Failed to connect to RTR server {}: {}",
-                    target.name, &self.remote, err
+                    "Unit {}: RTR session with fallback server {} \
+                     failed: {}",
+                    client.target().name, remote, err
                 );
-                return Err(target)
+                if
+                    err.is_version_mismatch()
+                    && self.version_mismatch.is_fail()
+                {
+                    return Err(self.fail(&client.target().name, gate).await)
+                }
+                return Ok((client.into_target(), false))
             }
+            None => return Ok((client.into_target(), false))
         };
+        metrics.fallback_updates.fetch_add(1, Ordering::Relaxed);
+        self.family_dropped(&client.target().name, update.dropped, metrics);
+        let upstream = client.state().map(|state| state.serial());
+        self.publish(
+            update, upstream, client.target_mut(), gate, finalizer,
+            aggregation
+        ).await?;
+        info!(
+            "Unit {}: received data from fallback server {}.",
+            client.target().name, remote
+        );
 
-        let state = target.state;
-        Ok(Client::new(sock, target, state))
+        // The state belongs to the fallback server, so the primary server
+        // needs to start over with a reset query, too.
+        let mut target = client.into_target();
+        target.state = None;
+        Ok((target, true))
     }
 
-    async fn update(
-        &mut self, client: &mut Client<TcpStream, Target>, gate: &mut Gate
-    ) -> Result<Result<TargetUpdate, io::Error>, Terminated> {
-        let update = client.update();
-        pin_mut!(update);
+    /// Permanently stops the unit after a protocol version mismatch.
+    async fn fail(&self, name: &str, gate: &mut Gate) -> Terminated {
+        error!(
+            "Unit {}: server changed the RTR version mid-session. \
+             Unit failed.",
+            name
+        );
+        gate.update_status(UnitStatus::Gone).await;
+        Terminated
+    }
+
+    /// Permanently stops the unit after the connection via `fd` closed.
+    async fn fd_closed(&self, name: &str, gate: &mut Gate) -> Terminated {
+        error!(
+            "Unit {}: connection via file descriptor closed. Unit failed.",
+            name
+        );
+        gate.update_status(UnitStatus::Gone).await;
+        Terminated
+    }
+
+    /// Processes a cache reset received from the server.
+    ///
+    /// Returns whether the unit should back off before the next full
+    /// transfer because the server has been resetting too often.
+    fn reset_received(&mut self, name: &str, metrics: &RtrMetrics) -> bool {
+        metrics.resets.fetch_add(1, Ordering::Relaxed);
+        if !self.limit_resets {
+            return false
+        }
+        if !self.resets.push(Instant::now(), self.max_resets) {
+            return false
+        }
+        error!(
+            "Unit {}: server {} sent more than {} cache resets in the last \
+             hour. Backing off for {} seconds.",
+            name, self.peer(), self.max_resets, self.reset_backoff
+        );
+        metrics.reset_alarm.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Processes a successful incremental update received from the server.
+    ///
+    /// This clears the reset limit.
+    fn incremental_received(&mut self, name: &str, metrics: &RtrMetrics) {
+        if metrics.reset_alarm.swap(false, Ordering::Relaxed) {
+            info!(
+                "Unit {}: server {} is providing incremental updates again.",
+                name, self.peer()
+            );
+        }
+        self.resets.clear();
+    }
+
+    /// Processes the payload of disabled families dropped from an update.
+    fn family_dropped(
+        &self, name: &str, dropped: usize, metrics: &RtrMetrics
+    ) {
+        if dropped == 0 {
+            return
+        }
+        match self.disabled_family {
+            DisabledFamily::Silent => { }
+            DisabledFamily::Count => {
+                metrics.family_dropped.fetch_add(
+                    dropped as u64, Ordering::Relaxed
+                );
+            }
+            DisabledFamily::Log => {
+                metrics.family_dropped.fetch_add(
+                    dropped as u64, Ordering::Relaxed
+                );
+                warn!(
+                    "Unit {}: server {} sent {} prefixes of a disabled \
+                     address family.",
+                    name, self.peer(), dropped
+                );
+            }
+        }
+    }
+
+    /// Returns a description of the server and our side for log messages.
+    fn peer(&self) -> String {
+        let res = self.with_binding(self.remote.to_string());
+        match self.client_id {
+            Some(ref id) => format!("{} as client '{}'", res, id),
+            None => res,
+        }
+    }
+
+    /// Adds the interface or VRF if there is one to a server for logging.
+    fn with_binding(&self, server: String) -> String {
+        match (self.interface.as_ref(), self.vrf.as_ref()) {
+            (Some(interface), _) => {
+                format!("{} via interface '{}'", server, interface)
+            }
+            (None, Some(vrf)) => format!("{} in VRF '{}'", server, vrf),
+            (None, None) => server,
+        }
+    }
+
+    /// Connects to the server or, if `fallback` is `true`, the fallback.
+    ///
+    /// If there is an unused socket taken over from `fd`, uses that
+    /// instead of connecting to the server. Keeps processing the gate while
+    /// connecting.
+    async fn connect(
+        &mut self, gate: &mut Gate, fallback: bool,
+    ) -> Result<Result<net::Outgoing<TcpStream>, RtrError>, Terminated> {
+        let remote = match (fallback, self.fallback_remote.as_ref()) {
+            (true, Some(remote)) => remote,
+            _ => &self.remote
+        };
+        let adopted = if fallback { None } else { self.adopted.take() };
+        let connect = match adopted {
+            Some(sock) => {
+                Either::Left(Self::connect_adopted(&self.outbound, sock))
+            }
+            None => {
+                Either::Right(Self::connect_addr(
+                    &self.outbound, remote.addr(),
+                    self.interface.as_deref(), self.vrf.as_deref()
+                ))
+            }
+        };
+        pin_mut!(connect);
 
         loop {
             let process = gate.process();
             pin_mut!(process);
-            match select(process, update).await {
+            match select(process, connect).await {
                 Either::Left((Err(_), _)) => {
                     return Err(Terminated)
                 }
                 Either::Left((Ok(status), next_fut)) => {
                     self.status = status;
-                    update = next_fut;
-                }
-                Either::Right((res, _)) => {
-                    return Ok(res)
+                    connect = next_fut;
                 }
+                Either::Right((res, _)) => return Ok(res)
             }
         }
     }
 
-    async fn retry_wait(
-        &mut self, gate: &mut Gate
-    ) -> Result<(), Terminated> {
-        let end = Instant::now() + Duration::from_secs(self.retry);
+    /// Uses a socket taken over from a file descriptor.
+    ///
+    /// Waits for a permit from `outbound` like a regular connection.
+    async fn connect_adopted(
+        outbound: &net::Outbound, sock: std::net::TcpStream
+    ) -> Result<net::Outgoing<TcpStream>, RtrError> {
+        let permit = outbound.permit().await;
+        match TcpStream::from_std(sock) {
+            Ok(sock) => Ok(net::Outgoing::new(sock, permit)),
+            Err(err) => Err(RtrError::connect(err)),
+        }
+    }
 
-        while end > Instant::now() {
-            match timeout_at(end, gate.process()).await {
-                Ok(Ok(status)) => {
-                    self.status = status
+    /// Resolves the given address and connects to it.
+    ///
+    /// If the address resolves to more than one socket address, they are
+    /// tried in turn until one succeeds. Before connecting, waits for a
+    /// permit from `outbound`. If `interface` is given, connects via this
+    /// interface. Otherwise, if `vrf` is given, connects from within this
+    /// VRF.
+    async fn connect_addr(
+        outbound: &net::Outbound, addr: &str,
+        interface: Option<&str>, vrf: Option<&str>,
+    ) -> Result<net::Outgoing<TcpStream>, RtrError> {
+        let addrs = lookup_host(addr).await.map_err(RtrError::ConnectDns)?;
+        let permit = outbound.permit().await;
+        let mut last_err = None;
+        for addr in addrs {
+            let res = match (interface, vrf) {
+                (Some(interface), _) => {
+                    net::connect_interface(addr, interface).await
                 }
-                Ok(Err(_)) => return Err(Terminated),
-                Err(_) => return Ok(()),
+                (None, Some(vrf)) => net::connect_vrf(addr, vrf).await,
+                (None, None) => TcpStream::connect(addr).await,
+            };
+            match res {
+                Ok(sock) => return Ok(net::Outgoing::new(sock, permit)),
+                Err(err) => last_err = Some(err),
             }
         }
-
-        Ok(())
+        Err(match last_err {
+            Some(err) => RtrError::connect(err),
+            None => {
+                RtrError::ConnectDns(io::Error::new(
+                    io::ErrorKind::NotFound, "no addresses found"
+                ))
+            }
+        })
     }
-}
-
-
-//------------ Target --------------------------------------------------------
-
-struct Target {
-    current: Arc<payload::Set>,
-
-    state: Option<State>,
 
-    name: Arc<str>,
-}
+    /// Receives the next update from the server.
+    ///
+    /// Keeps processing the gate while waiting for the update. If an idle
+    /// query interval is configured and nothing was exchanged with the
+    /// server for that long according to `activity`, returns `Ok(None)`.
+    async fn update(
+        &mut self,
+        client: &mut Client<PduCounter<net::Outgoing<TcpStream>>, Target>,
+        gate: &mut Gate,
+        activity: &AtomicCell<Instant>,
+    ) -> Result<Option<Result<TargetUpdate, RtrError>>, Terminated> {
+        let idle = self.idle_query.map(Duration::from_secs);
+        let res = {
+            let update = client.update();
+            pin_mut!(update);
 
-impl Target {
-    pub fn new(name: Arc<str>) -> Self {
-        Target {
-            current: Default::default(),
+            loop {
+                let process = gate.process();
+                pin_mut!(process);
+                let step = select(process, update.as_mut());
+                let res = match idle {
+                    Some(idle) => {
+                        match timeout_at(activity.load() + idle, step).await {
+                            Ok(res) => res,
+                            Err(_) => {
+                                if activity.load() + idle <= Instant::now() {
+                                    return Ok(None)
+                                }
+                                continue
+                            }
+                        }
+                    }
+                    None => step.await
+                };
+                match res {
+                    Either::Left((Err(_), _)) => {
+                        return Err(Terminated)
+                    }
+                    Either::Left((Ok(status), _)) => {
+                        self.status = status;
+                    }
+                    Either::Right((res, _)) => break res
+                }
+            }
+        };
+        Ok(Some(res.map_err(|err| {
+            RtrError::session(err, client.target().failure.take())
+        })))
+    }
+
+    async fn retry_wait(
+        &mut self, gate: &mut Gate
+    ) -> Result<(), Terminated> {
+        let end = Instant::now() + self.retry_delay();
+        self.wait_until(gate, end).await
+    }
+
+    /// Returns the time to wait before reconnecting.
+    ///
+    /// This is the retry time plus a random jitter of up to a tenth of it,
+    /// so that units failing at the same time don’t all reconnect at the
+    /// same time, too.
+    fn retry_delay(&self) -> Duration {
+        let retry = Duration::from_secs(self.retry);
+        let jitter = self.retry.saturating_mul(100);
+        if jitter == 0 {
+            return retry
+        }
+        retry + Duration::from_millis(self.random.gen_range(0, jitter))
+    }
+
+    /// Records a failure with the circuit breaker if there is one.
+    ///
+    /// Returns whether this opened the breaker.
+    fn breaker_failure(
+        &self, name: &str, breaker: Option<&CircuitBreaker>
+    ) -> bool {
+        let breaker = match breaker {
+            Some(breaker) => breaker,
+            None => return false
+        };
+        match breaker.failure(Instant::now()) {
+            Some(timeout) => {
+                warn!(
+                    "Unit {}: too many failures with server {}. Circuit \
+                     breaker open, not connecting for {} seconds.",
+                    name, self.peer(), timeout.as_secs()
+                );
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Waits for the given number of seconds while processing the gate.
+    async fn wait(
+        &mut self, gate: &mut Gate, secs: u64
+    ) -> Result<(), Terminated> {
+        let end = Instant::now() + Duration::from_secs(secs);
+        self.wait_until(gate, end).await
+    }
+
+    /// Waits until `end` while processing the gate.
+    async fn wait_until(
+        &mut self, gate: &mut Gate, end: Instant
+    ) -> Result<(), Terminated> {
+        while end > Instant::now() {
+            match timeout_at(end, gate.process()).await {
+                Ok(Ok(status)) => {
+                    self.status = status
+                }
+                Ok(Err(_)) => return Err(Terminated),
+                Err(_) => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+//------------ Remote --------------------------------------------------------
+
+/// The address of an RTR server.
+///
+/// The address can be given as a plain `host:port` pair or with a scheme
+/// describing the transport to use as in `rtr://host:port`. In the latter
+/// case, the port can be left out and the default port for the transport
+/// is used.
+///
+/// Currently, only the plain TCP transport with scheme `rtr` is supported.
+/// The scheme `rtrs` for RTR over TLS is recognized but rejected.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct Remote {
+    /// The address in `host:port` form.
+    addr: String,
+}
+
+impl Remote {
+    /// The default port for RTR over plain TCP.
+    const RTR_PORT: u16 = 323;
+
+    /// Returns the `host:port` pair to connect to.
+    fn addr(&self) -> &str {
+        &self.addr
+    }
+}
+
+impl TryFrom<String> for Remote {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let pos = match value.find("://") {
+            Some(pos) => pos,
+            None => return Ok(Remote { addr: value })
+        };
+        let port = match &value[..pos] {
+            "rtr" => Self::RTR_PORT,
+            "rtrs" => {
+                return Err(format!(
+                    "invalid remote '{}': RTR over TLS is not supported",
+                    value
+                ))
+            }
+            scheme => {
+                return Err(format!(
+                    "invalid remote '{}': unknown scheme '{}'", value, scheme
+                ))
+            }
+        };
+        let addr = value[pos + 3..].trim_end_matches('/');
+        if addr.is_empty() || addr.contains('/') {
+            return Err(format!("invalid remote '{}'", value))
+        }
+        let has_port = if addr.starts_with('[') {
+            addr.contains("]:")
+        }
+        else {
+            addr.contains(':')
+        };
+        if has_port {
+            Ok(Remote { addr: addr.into() })
+        }
+        else {
+            Ok(Remote { addr: format!("{}:{}", addr, port) })
+        }
+    }
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.addr)
+    }
+}
+
+
+//------------ PrefixFamily --------------------------------------------------
+
+/// The address families of prefixes accepted by a unit.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum PrefixFamily {
+    /// Both IPv4 and IPv6 prefixes are accepted.
+    #[serde(rename = "both")]
+    Both,
+
+    /// Only IPv4 prefixes are accepted.
+    #[serde(rename = "ipv4")]
+    Ipv4,
+
+    /// Only IPv6 prefixes are accepted.
+    #[serde(rename = "ipv6")]
+    Ipv6,
+}
+
+impl PrefixFamily {
+    /// Returns whether the given payload is accepted.
+    fn allows(self, payload: &Payload) -> bool {
+        matches!(
+            (self, payload),
+            (PrefixFamily::Both, _)
+            | (PrefixFamily::Ipv4, Payload::V4(_))
+            | (PrefixFamily::Ipv6, Payload::V6(_))
+        )
+    }
+}
+
+impl Default for PrefixFamily {
+    fn default() -> Self {
+        PrefixFamily::Both
+    }
+}
+
+
+//------------ DisabledFamily ------------------------------------------------
+
+/// How to deal with prefixes of a disabled address family.
+///
+/// Such prefixes are always dropped. This only determines whether we make
+/// any fuss about it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum DisabledFamily {
+    /// Drop them silently.
+    #[serde(rename = "silent")]
+    Silent,
+
+    /// Count them in the metrics.
+    #[serde(rename = "count")]
+    Count,
+
+    /// Count them and log a warning for each update containing them.
+    #[serde(rename = "log")]
+    Log,
+}
+
+impl Default for DisabledFamily {
+    fn default() -> Self {
+        DisabledFamily::Count
+    }
+}
+
+
+//------------ VersionMismatch -----------------------------------------------
+
+/// What to do if the server changes the protocol version mid-session.
+///
+/// The version of a session is the version of the first PDU received from
+/// the server. Any later PDU with a different version is a server bug.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum VersionMismatch {
+    /// Drop the connection and start over with a reset query.
+    #[serde(rename = "reset")]
+    Reset,
+
+    /// Skip the offending PDU and carry on.
+    #[serde(rename = "ignore")]
+    Ignore,
+
+    /// Drop the connection and permanently stop the unit.
+    #[serde(rename = "fail")]
+    Fail,
+}
+
+impl VersionMismatch {
+    /// Returns whether the unit should fail.
+    fn is_fail(self) -> bool {
+        matches!(self, VersionMismatch::Fail)
+    }
+}
+
+impl Default for VersionMismatch {
+    fn default() -> Self {
+        VersionMismatch::Reset
+    }
+}
+
+
+//------------ ReconnectSerial -----------------------------------------------
+
+/// How to query the server after reconnecting.
+///
+/// After a reconnect, we still have the session ID and serial number of
+/// the server’s data we had last. Normally, we ask the server for the
+/// changes since then via a serial query. Whether this is a good idea
+/// depends on how much we trust the server’s history.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum ReconnectSerial {
+    /// Always start over with a reset query.
+    #[serde(rename = "always-reset")]
+    AlwaysReset,
+
+    /// Send a serial query and accept whatever the server responds.
+    #[serde(rename = "trust-serial")]
+    TrustSerial,
+
+    /// Send a serial query but ask again with a reset query if the serial
+    /// moved by more than the `max-serial-gap`.
+    #[serde(rename = "reset-on-gap")]
+    ResetOnGap,
+}
+
+impl ReconnectSerial {
+    /// Returns the state to start a new connection with.
+    ///
+    /// Connections made to send an idle query, as indicated by `idle`,
+    /// continue the previous connection and always keep the state.
+    fn query_state(self, state: Option<State>, idle: bool) -> Option<State> {
+        match self {
+            ReconnectSerial::AlwaysReset if !idle => None,
+            _ => state
+        }
+    }
+
+    /// Returns whether to accept an update from `old` to `new`.
+    ///
+    /// Only the first update after reconnecting is checked. A serial going
+    /// backwards counts as a very large gap.
+    fn accept_gap(self, old: Serial, new: Serial, max_gap: u32) -> bool {
+        match self {
+            ReconnectSerial::ResetOnGap => {
+                u32::from(new).wrapping_sub(u32::from(old)) <= max_gap
+            }
+            _ => true
+        }
+    }
+}
+
+impl Default for ReconnectSerial {
+    fn default() -> Self {
+        ReconnectSerial::TrustSerial
+    }
+}
+
+
+//------------ FdDisconnect --------------------------------------------------
+
+/// What to do once the connection via a passed-in socket has been closed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+enum FdDisconnect {
+    /// Connect to the configured remote address from now on.
+    #[serde(rename = "reconnect")]
+    Reconnect,
+
+    /// Permanently stop the unit.
+    #[serde(rename = "fail")]
+    Fail,
+}
+
+impl FdDisconnect {
+    /// Returns whether the unit should fail.
+    fn is_fail(self) -> bool {
+        matches!(self, FdDisconnect::Fail)
+    }
+}
+
+impl Default for FdDisconnect {
+    fn default() -> Self {
+        FdDisconnect::Reconnect
+    }
+}
+
+
+//------------ Target --------------------------------------------------------
+
+struct Target {
+    current: Arc<payload::Set>,
+
+    state: Option<State>,
+
+    name: Arc<str>,
+
+    /// The address families of prefixes we accept.
+    family: PrefixFamily,
+
+    /// The policy for payload with reserved AS numbers.
+    policy: Option<Arc<AsnPolicy>>,
+
+    /// The maximum size of an update in bytes.
+    max_bytes: Option<usize>,
+
+    /// The reason why processing the last update failed.
+    ///
+    /// The RTR client reports all errors as `io::Error`s. In order to be
+    /// able to tell apart the errors we caused ourselves while processing
+    /// the data, the update will leave its reason here.
+    failure: Arc<AtomicCell<Option<UpdateFailure>>>,
+}
+
+impl Target {
+    pub fn new(name: Arc<str>, family: PrefixFamily) -> Self {
+        Target {
+            current: Default::default(),
             state: None,
-            name
+            name,
+            family,
+            policy: None,
+            max_bytes: None,
+            failure: Default::default(),
         }
     }
 }
@@ -214,16 +1537,40 @@ impl VrpTarget for Target {
 
     fn start(&mut self, reset: bool) -> Self::Update {
         debug!("Unit {}: starting update (reset={})", self.name, reset);
+        self.failure.store(None);
         if reset {
             TargetUpdate {
                 set: Default::default(),
-                diff: None
+                diff: None,
+                previous: if self.current.is_empty() {
+                    None
+                }
+                else {
+                    Some(self.current.clone())
+                },
+                family: self.family,
+                policy: self.policy.clone(),
+                dropped: 0,
+                max_len_dropped: 0,
+                max_bytes: self.max_bytes,
+                bytes: 0,
+                failure: self.failure.clone(),
+                timer: UpdateTimer::start(),
             }
         }
         else {
             TargetUpdate {
                 set: self.current.as_ref().into(),
-                diff: Some(Default::default())
+                diff: Some(Default::default()),
+                previous: None,
+                family: self.family,
+                policy: self.policy.clone(),
+                dropped: 0,
+                max_len_dropped: 0,
+                max_bytes: self.max_bytes,
+                bytes: 0,
+                failure: self.failure.clone(),
+                timer: UpdateTimer::start(),
             }
         }
     }
@@ -249,9 +1596,58 @@ struct TargetUpdate {
     ///
     /// If this is `None` we are processing a reset query.
     diff: Option<payload::DiffBuilder>,
+
+    /// The set we had before a cache reset.
+    ///
+    /// If we already had data when the server sent a cache reset, we keep
+    /// the old set so we can reconcile the new set with it and only pass
+    /// on what has actually changed.
+    previous: Option<Arc<payload::Set>>,
+
+    /// The address families of prefixes we accept.
+    family: PrefixFamily,
+
+    /// The policy for payload with reserved AS numbers.
+    policy: Option<Arc<AsnPolicy>>,
+
+    /// The number of prefixes dropped because of their address family.
+    dropped: usize,
+
+    /// The number of prefixes dropped because of their max-length.
+    ///
+    /// These have a max-length beyond the address length which can never
+    /// match a route and indicates a broken server.
+    max_len_dropped: usize,
+
+    /// The maximum size of the update in bytes.
+    max_bytes: Option<usize>,
+
+    /// The size of the prefix PDUs received so far in bytes.
+    bytes: usize,
+
+    /// Where to leave the reason if processing fails.
+    failure: Arc<AtomicCell<Option<UpdateFailure>>>,
+
+    /// The timer for the stages of processing the update.
+    timer: UpdateTimer,
 }
 
 impl TargetUpdate {
+    /// The length of an IPv4 Prefix PDU in bytes.
+    const V4_PDU_LEN: usize = 20;
+
+    /// The length of an IPv6 Prefix PDU in bytes.
+    const V6_PDU_LEN: usize = 32;
+
+    /// Returns whether the update is the result of a cache reset.
+    fn is_reset(&self) -> bool {
+        self.diff.is_none()
+    }
+
+    /// Returns whether the update is known to contain no changes.
+    ///
+    /// This is the case for a serial query answered with an empty
+    /// response, such as the ones to idle queries.
     fn is_definitely_empty(&self) -> bool {
         if let Some(diff) = self.diff.as_ref() {
             diff.is_empty()
@@ -261,13 +1657,6 @@ impl TargetUpdate {
         }
     }
 
-    fn into_update(self, serial: Serial) -> payload::Update {
-        payload::Update::new(
-            serial,
-            Arc::new(self.set.finalize()),
-            self.diff.map(|diff| Arc::new(diff.finalize()))
-        )
-    }
 }
 
 impl VrpUpdate for TargetUpdate {
@@ -275,6 +1664,46 @@ impl VrpUpdate for TargetUpdate {
         &mut self, 
         action: Action, 
         payload: Payload
+    ) -> Result<(), VrpError> {
+        // Everything received counts towards the size, even if we drop it
+        // right away.
+        self.bytes += match payload {
+            Payload::V4(_) => Self::V4_PDU_LEN,
+            Payload::V6(_) => Self::V6_PDU_LEN,
+        };
+        if let Some(max) = self.max_bytes {
+            if self.bytes > max {
+                self.failure.store(Some(UpdateFailure::Oversize));
+                return Err(VrpError::Corrupt)
+            }
+        }
+        if !self.family.allows(&payload) {
+            self.dropped += 1;
+            return Ok(())
+        }
+        if payload::Set::has_redundant_max_len(&payload) {
+            self.max_len_dropped += 1;
+            return Ok(())
+        }
+        if let Some(ref policy) = self.policy {
+            if !policy.keep(&payload) {
+                return Ok(())
+            }
+        }
+        let res = self.apply_vrp(action, payload);
+        if res.is_err() {
+            self.failure.store(Some(UpdateFailure::Validation));
+        }
+        res
+    }
+}
+
+impl TargetUpdate {
+    /// Applies a single change to the update.
+    fn apply_vrp(
+        &mut self, 
+        action: Action, 
+        payload: Payload
     ) -> Result<(), VrpError> {
         match self.diff {
             Some(ref mut diff) => {
@@ -301,24 +1730,1917 @@ impl VrpUpdate for TargetUpdate {
 }
 
 
-//------------ RtrMetrics ----------------------------------------------------
+//------------ RtrError ------------------------------------------------------
 
-#[derive(Debug, Default)]
-struct RtrMetrics {
-    gate: Arc<GateMetrics>,
+/// An error that caused the RTR client to fail.
+#[derive(Debug)]
+enum RtrError {
+    /// The server’s address could not be resolved.
+    ConnectDns(io::Error),
+
+    /// Connecting to the server timed out.
+    ConnectTimeout(io::Error),
+
+    /// The server refused the connection.
+    ConnectRefused(io::Error),
+
+    /// Connecting to the server failed for some other reason.
+    ConnectIo(io::Error),
+
+    /// Reading from or writing to the server failed.
+    SessionIo(io::Error),
+
+    /// The server sent data that violated the RTR protocol.
+    ProtocolCorrupt(io::Error),
+
+    /// The server closed the connection.
+    SessionTerminated(io::Error),
+
+    /// The server sent data that was inconsistent with our data set.
+    Validation(io::Error),
+
+    /// The server changed the protocol version mid-session.
+    VersionMismatch(io::Error),
+
+    /// The server sent an update larger than allowed.
+    Oversize(io::Error),
 }
 
-impl RtrMetrics {
-    fn new(gate: &Gate) -> Self {
-        RtrMetrics {
-            gate: gate.metrics(),
+impl RtrError {
+    /// The names of all error kinds as used in metrics.
+    ///
+    /// The order needs to match the one used by [`kind`](Self::kind).
+    const KINDS: [&'static str; 10] = [
+        "connect-dns", "connect-timeout", "connect-refused", "connect-io",
+        "session-io", "protocol-corrupt", "session-terminated", "validation",
+        "version-mismatch", "oversize",
+    ];
+
+    /// Creates an error from an error that happened during connect.
+    fn connect(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => RtrError::ConnectTimeout(err),
+            io::ErrorKind::ConnectionRefused => RtrError::ConnectRefused(err),
+            _ => RtrError::ConnectIo(err),
+        }
+    }
+
+    /// Creates an error from an error that happened during a session.
+    ///
+    /// The `failure` is the reason left by the update if it caused the
+    /// error itself.
+    fn session(err: io::Error, failure: Option<UpdateFailure>) -> Self {
+        match failure {
+            Some(UpdateFailure::Validation) => {
+                return RtrError::Validation(err)
+            }
+            Some(UpdateFailure::Oversize) => {
+                return RtrError::Oversize(err)
+            }
+            None => { }
+        }
+        if matches!(
+            err.get_ref(), Some(inner) if inner.is::<VersionError>()
+        ) {
+            return RtrError::VersionMismatch(err)
+        }
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => RtrError::SessionTerminated(err),
+            io::ErrorKind::InvalidData => RtrError::ProtocolCorrupt(err),
+            _ => RtrError::SessionIo(err),
+        }
+    }
+
+    /// Returns the index of the error’s kind in [`KINDS`](Self::KINDS).
+    fn kind(&self) -> usize {
+        match *self {
+            RtrError::ConnectDns(_) => 0,
+            RtrError::ConnectTimeout(_) => 1,
+            RtrError::ConnectRefused(_) => 2,
+            RtrError::ConnectIo(_) => 3,
+            RtrError::SessionIo(_) => 4,
+            RtrError::ProtocolCorrupt(_) => 5,
+            RtrError::SessionTerminated(_) => 6,
+            RtrError::Validation(_) => 7,
+            RtrError::VersionMismatch(_) => 8,
+            RtrError::Oversize(_) => 9,
         }
     }
+
+    /// Returns whether the error is a regular disconnect by the server.
+    fn is_disconnect(&self) -> bool {
+        matches!(*self, RtrError::SessionTerminated(_))
+    }
+
+    /// Returns whether the server changed the protocol version.
+    fn is_version_mismatch(&self) -> bool {
+        matches!(*self, RtrError::VersionMismatch(_))
+    }
 }
 
-impl metrics::Source for RtrMetrics {
-    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
-        self.gate.append(unit_name, target);
+impl fmt::Display for RtrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RtrError::ConnectDns(ref err) => {
+                write!(f, "cannot resolve address: {}", err)
+            }
+            RtrError::ConnectTimeout(ref err) => {
+                write!(f, "connection timed out: {}", err)
+            }
+            RtrError::ConnectRefused(ref err) => {
+                write!(f, "connection refused: {}", err)
+            }
+            RtrError::ConnectIo(ref err) => {
+                write!(f, "cannot connect: {}", err)
+            }
+            RtrError::SessionIo(ref err) => {
+                write!(f, "connection failed: {}", err)
+            }
+            RtrError::ProtocolCorrupt(ref err) => {
+                write!(f, "protocol error: {}", err)
+            }
+            RtrError::SessionTerminated(ref err) => {
+                write!(f, "connection closed: {}", err)
+            }
+            RtrError::Validation(ref err) => {
+                write!(f, "invalid data: {}", err)
+            }
+            RtrError::VersionMismatch(ref err) => {
+                write!(f, "version mismatch: {}", err)
+            }
+            RtrError::Oversize(ref err) => {
+                write!(f, "update too large: {}", err)
+            }
+        }
+    }
+}
+
+
+//------------ VersionError --------------------------------------------------
+
+/// The server sent a PDU with an unexpected protocol version.
+#[derive(Clone, Copy, Debug)]
+struct VersionError {
+    /// The version of the session.
+    expected: u8,
+
+    /// The version of the offending PDU.
+    received: u8,
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "server switched from RTR version {} to {} mid-session",
+            self.expected, self.received
+        )
+    }
+}
+
+impl std::error::Error for VersionError { }
+
+
+//------------ UpdateFailure -------------------------------------------------
+
+/// The reason why an update itself failed to process the received data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum UpdateFailure {
+    /// The data was inconsistent with the current data set.
+    Validation,
+
+    /// The update exceeded the maximum size.
+    Oversize,
+}
+
+
+//------------ Finalizer -----------------------------------------------------
+
+/// Finalizes updates on the blocking thread pool.
+///
+/// Converting the builders of an update into the final set and diff can be
+/// expensive for large data sets, so this happens on the blocking thread
+/// pool. In order to not let a single unit with a huge data set starve all
+/// the others, the number of finalization tasks a unit can have running at
+/// the same time is limited.
+struct Finalizer {
+    /// The permits for running finalization tasks.
+    permits: Arc<Semaphore>,
+
+    /// The metrics of the unit.
+    metrics: Arc<RtrMetrics>,
+}
+
+impl Finalizer {
+    /// Creates a new finalizer allowing `concurrency` concurrent tasks.
+    fn new(concurrency: usize, metrics: Arc<RtrMetrics>) -> Self {
+        Finalizer {
+            permits: Arc::new(Semaphore::new(cmp::max(concurrency, 1))),
+            metrics
+        }
+    }
+
+    /// Finalizes an update.
+    ///
+    /// The set and the diff are finalized as separate tasks. For a cache
+    /// reset with a previous set, the diff is reconciled from the two sets
+    /// after the new set has been finalized.
+    ///
+    /// Returns the update and how long finalizing the set and the diff
+    /// took. Without a diff, the latter is zero.
+    async fn finalize(
+        &self, update: TargetUpdate, serial: Serial
+    ) -> (payload::Update, Duration, Duration) {
+        let TargetUpdate { set, diff, previous, .. } = update;
+        if let Some(previous) = previous {
+            let (set, diff, durations) = self.spawn(move || {
+                let start = Instant::now();
+                let set = set.finalize();
+                let finalized = Instant::now();
+                let diff = payload::Diff::reconcile(&previous, &set);
+                (set, diff, (finalized - start, finalized.elapsed()))
+            }).await;
+            return (
+                payload::Update::new(
+                    serial, Arc::new(set), Some(Arc::new(diff))
+                ),
+                durations.0, durations.1
+            )
+        }
+        let set = self.spawn(move || timed(|| set.finalize()));
+        let diff = async {
+            match diff {
+                Some(diff) => {
+                    Some(self.spawn(move || timed(|| diff.finalize())).await)
+                }
+                None => None
+            }
+        };
+        let ((set, set_duration), diff) = join(set, diff).await;
+        let (diff, diff_duration) = match diff {
+            Some((diff, duration)) => (Some(Arc::new(diff)), duration),
+            None => (None, Duration::from_secs(0))
+        };
+        (
+            payload::Update::new(serial, Arc::new(set), diff),
+            set_duration, diff_duration
+        )
+    }
+
+    /// Runs a closure on the blocking thread pool once a permit is available.
+    async fn spawn<F, T>(&self, op: F) -> T
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+        self.metrics.finalize_queued.fetch_add(1, Ordering::Relaxed);
+        let permit = self.permits.clone().acquire_owned().await;
+        self.metrics.finalize_queued.fetch_sub(1, Ordering::Relaxed);
+
+        // The task only fails if the closure panics in which case we
+        // should panic, too.
+        let res = spawn_blocking(op).await.unwrap();
+        drop(permit);
+        res
+    }
+}
+
+
+/// Runs a closure and returns its result and how long it took.
+fn timed<T>(op: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let res = op();
+    (res, start.elapsed())
+}
+
+
+//------------ AggregationCheck ----------------------------------------------
+
+/// Regularly looks for payload of the unit that could be aggregated.
+///
+/// The check runs over the unit’s current data set once per period if the
+/// set has changed since the last run. The number of candidates is
+/// available as a metric, the candidates themselves under
+/// `/aggregation/<unit-name>` on the HTTP server.
+#[derive(Debug)]
+struct AggregationCheck {
+    /// The path of the HTTP resource.
+    path: String,
+
+    /// The current data set of the unit.
+    current: Mutex<Option<Arc<payload::Set>>>,
+
+    /// The result of the last run.
+    ///
+    /// This is the number of candidates and the first few of them or
+    /// `None` if the check hasn’t run yet.
+    report: Mutex<Option<(usize, Vec<payload::Aggregation>)>>,
+}
+
+impl AggregationCheck {
+    /// The maximum number of candidates kept for the HTTP resource.
+    const MAX_EXAMPLES: usize = 100;
+
+    fn new(name: &str) -> Self {
+        AggregationCheck {
+            path: format!("/aggregation/{}", name),
+            current: Default::default(),
+            report: Default::default(),
+        }
+    }
+
+    /// Sets the current data set of the unit.
+    fn update(&self, set: Arc<payload::Set>) {
+        *self.current.lock().unwrap() = Some(set);
+    }
+
+    /// Runs the check every `period` for as long as the check is alive.
+    async fn run(this: Weak<Self>, period: Duration) {
+        let mut last = Weak::new();
+        loop {
+            delay_for(period).await;
+            let this = match this.upgrade() {
+                Some(this) => this,
+                None => return
+            };
+            let set = match this.current.lock().unwrap().clone() {
+                Some(set) => set,
+                None => continue
+            };
+            let weak = Arc::downgrade(&set);
+            if Weak::ptr_eq(&last, &weak) {
+                continue
+            }
+            last = weak;
+
+            // The task only fails if the closure panics in which case we
+            // should panic, too.
+            let report = spawn_blocking(move || {
+                let all = set.aggregations();
+                let count = all.len();
+                (
+                    count,
+                    all.into_iter().take(Self::MAX_EXAMPLES).collect()
+                )
+            }).await.unwrap();
+            *this.report.lock().unwrap() = Some(report);
+        }
+    }
+}
+
+impl metrics::Source for AggregationCheck {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        if let Some((count, _)) = *self.report.lock().unwrap() {
+            target.append_simple(
+                &Self::CANDIDATES_METRIC, Some(unit_name), count
+            );
+        }
+    }
+}
+
+impl AggregationCheck {
+    const CANDIDATES_METRIC: Metric = Metric::new(
+        "aggregation_candidates",
+        "the number of pairs of VRPs that could be aggregated",
+        MetricType::Gauge, MetricUnit::Total
+    );
+}
+
+impl http::ProcessRequest for AggregationCheck {
+    fn process_request(
+        &self, request: &mut Request<Body>
+    ) -> Option<Response<Body>> {
+        if
+            request.method() != Method::GET
+            || request.uri().path() != self.path
+        {
+            return None
+        }
+        let body = match *self.report.lock().unwrap() {
+            Some((count, ref examples)) => {
+                let mut body = format!("candidates: {}\n", count);
+                for item in examples {
+                    body.push_str(&format!("{}\n", item));
+                }
+                if count > examples.len() {
+                    body.push_str("...\n");
+                }
+                body
+            }
+            None => "candidates: N/A\n".into()
+        };
+        Some(
+            Response::builder()
+            .header("Content-Type", "text/plain")
+            .body(body.into())
+            .unwrap()
+        )
+    }
+}
+
+
+//------------ ResetLimit ----------------------------------------------------
+
+/// Keeps track of the cache resets received during the last hour.
+#[derive(Debug, Default)]
+struct ResetLimit {
+    /// The times when resets were received, oldest first.
+    resets: VecDeque<Instant>,
+}
+
+impl ResetLimit {
+    /// The period over which resets are counted.
+    const PERIOD: Duration = Duration::from_secs(3600);
+
+    /// Records a reset received at `now`.
+    ///
+    /// Returns whether more than `limit` resets have been received during
+    /// the last hour.
+    fn push(&mut self, now: Instant, limit: usize) -> bool {
+        while let Some(&first) = self.resets.front() {
+            if now.duration_since(first) < Self::PERIOD {
+                break
+            }
+            self.resets.pop_front();
+        }
+        self.resets.push_back(now);
+        self.resets.len() > limit
+    }
+
+    /// Forgets about all resets.
+    fn clear(&mut self) {
+        self.resets.clear()
+    }
+}
+
+
+//------------ PduCounter ----------------------------------------------------
+
+/// A socket wrapper counting the PDUs received by type.
+///
+/// The wrapper follows the stream of received data and looks at the header
+/// of each PDU as it passes by. The PDU is counted towards the metrics of
+/// the unit once its header is complete. Since only the header is looked at,
+/// this works regardless of whether the client understands the PDU.
+///
+/// The wrapper also keeps an eye on the protocol version of the PDUs and
+/// applies the configured policy if it changes mid-session. If the policy
+/// is to ignore such PDUs, they are removed from the received data before
+/// the client sees them.
+///
+/// Finally, the RTR client expects a Cache Reset PDU to carry the type of
+/// an End of Data PDU. Since a Cache Reset is otherwise rejected as
+/// corrupt, the wrapper changes its type before the client sees it.
+#[derive(Debug)]
+struct PduCounter<Sock> {
+    /// The actual socket.
+    sock: Sock,
+
+    /// The name of the unit for logging.
+    name: Arc<str>,
+
+    /// What to do about PDUs with an unexpected version.
+    policy: VersionMismatch,
+
+    /// The metrics to count the PDUs in.
+    metrics: Arc<RtrMetrics>,
+
+    /// The version of the session.
+    ///
+    /// This is the version of the first PDU received.
+    version: Option<u8>,
+
+    /// Whether the current PDU is to be dropped.
+    skip: bool,
+
+    /// The part of the current PDU header received so far.
+    header: [u8; 8],
+
+    /// The number of bytes of the current header received so far.
+    header_len: usize,
+
+    /// The number of bytes of the current PDU’s body still to come.
+    remaining: usize,
+
+    /// The log for received PDUs if enabled.
+    pdu_log: Option<PduLog>,
+
+    /// When data was last sent or received.
+    activity: Arc<AtomicCell<Instant>>,
+}
+
+/// The PDU type of a Cache Reset.
+const CACHE_RESET_PDU: u8 = 8;
+
+/// The PDU type the RTR client expects for a Cache Reset.
+const CLIENT_CACHE_RESET_PDU: u8 = 7;
+
+impl<Sock> PduCounter<Sock> {
+    fn new(
+        sock: Sock, name: Arc<str>, policy: VersionMismatch,
+        metrics: Arc<RtrMetrics>
+    ) -> Self {
+        PduCounter {
+            sock, name, policy, metrics,
+            version: None,
+            skip: false,
+            header: [0; 8],
+            header_len: 0,
+            remaining: 0,
+            pdu_log: None,
+            activity: Arc::new(AtomicCell::new(Instant::now())),
+        }
+    }
+
+    /// Enables or disables logging of all received PDUs.
+    fn with_pdu_log(mut self, enabled: bool) -> Self {
+        self.pdu_log = if enabled { Some(Default::default()) } else { None };
+        self
+    }
+
+    /// Returns a shared handle to the time of the last activity.
+    fn activity(&self) -> Arc<AtomicCell<Instant>> {
+        self.activity.clone()
+    }
+
+    /// Processes received data.
+    ///
+    /// PDUs to be ignored are removed from `data` in place. Returns the
+    /// length of the remaining data or an error if the session should end
+    /// because of an unexpected version.
+    fn received(&mut self, data: &mut [u8]) -> Result<usize, io::Error> {
+        let mut read = 0;
+        let mut write = 0;
+        while read < data.len() {
+            if self.remaining > 0 {
+                let len = cmp::min(self.remaining, data.len() - read);
+                if let Some(ref mut log) = self.pdu_log {
+                    log.push(&data[read..read + len]);
+                }
+                if !self.skip {
+                    data.copy_within(read..read + len, write);
+                    write += len;
+                }
+                self.remaining -= len;
+                read += len;
+                if self.remaining == 0 {
+                    self.pdu_done();
+                }
+                continue
+            }
+            if self.header_len == 0 {
+                if let Some(ref mut log) = self.pdu_log {
+                    log.start(&self.name, Instant::now());
+                }
+                self.skip = self.check_version(data[read])?;
+            }
+            let start = self.header_len;
+            let len = cmp::min(self.header.len() - start, data.len() - read);
+            self.header[start..start + len].copy_from_slice(
+                &data[read..read + len]
+            );
+            if let Some(ref mut log) = self.pdu_log {
+                log.push(&data[read..read + len]);
+            }
+            if !self.skip {
+                if start < 2 && start + len > 1 {
+                    let pos = read + 1 - start;
+                    if data[pos] == CACHE_RESET_PDU {
+                        data[pos] = CLIENT_CACHE_RESET_PDU;
+                    }
+                }
+                data.copy_within(read..read + len, write);
+                write += len;
+            }
+            self.header_len += len;
+            read += len;
+            if self.header_len == self.header.len() {
+                self.metrics.pdu(self.header[1]);
+                let pdu_len = u32::from_be_bytes([
+                    self.header[4], self.header[5],
+                    self.header[6], self.header[7]
+                ]) as usize;
+                self.remaining = pdu_len.saturating_sub(self.header.len());
+                self.header_len = 0;
+                if self.remaining == 0 {
+                    self.pdu_done();
+                }
+            }
+        }
+        Ok(write)
+    }
+
+    /// Processes the end of a PDU.
+    fn pdu_done(&mut self) {
+        if let Some(ref mut log) = self.pdu_log {
+            log.finish(&self.name);
+        }
+    }
+
+    /// Checks the version of a new PDU.
+    ///
+    /// Returns whether the PDU should be dropped.
+    fn check_version(&mut self, version: u8) -> Result<bool, io::Error> {
+        let expected = match self.version {
+            Some(expected) => expected,
+            None => {
+                self.version = Some(version);
+                return Ok(false)
+            }
+        };
+        if version == expected {
+            return Ok(false)
+        }
+        let err = VersionError { expected, received: version };
+        if self.policy == VersionMismatch::Ignore {
+            self.metrics.ignored_pdus.fetch_add(1, Ordering::Relaxed);
+            warn!("Unit {}: {}. Ignoring PDU.", self.name, err);
+            Ok(true)
+        }
+        else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+    }
+}
+
+impl<Sock: AsyncRead + Unpin> AsyncRead for PduCounter<Sock> {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        loop {
+            let len = match Pin::new(&mut this.sock).poll_read(cx, buf) {
+                Poll::Ready(Ok(len)) => len,
+                res => return res
+            };
+            if len == 0 {
+                return Poll::Ready(Ok(0))
+            }
+            this.activity.store(Instant::now());
+            match this.received(&mut buf[..len]) {
+                // Everything was dropped. Returning zero would signal the
+                // end of the stream, so we need to read more.
+                Ok(0) => continue,
+                res => return Poll::Ready(res)
+            }
+        }
+    }
+}
+
+impl<Sock: AsyncWrite + Unpin> AsyncWrite for PduCounter<Sock> {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.sock).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = res {
+            if len > 0 {
+                this.activity.store(Instant::now());
+            }
+        }
+        res
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>, cx: &mut Context
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().sock).poll_shutdown(cx)
+    }
+}
+
+
+//------------ PduLog --------------------------------------------------------
+
+/// Logs received PDUs at debug level.
+///
+/// In order to not flood the log, only a limited number of PDUs is logged
+/// per minute. Any further PDUs are suppressed with a warning.
+#[derive(Debug, Default)]
+struct PduLog {
+    /// The part of the current PDU received so far.
+    ///
+    /// This is only collected if the PDU is to be logged.
+    pdu: Vec<u8>,
+
+    /// Whether the current PDU is to be logged.
+    active: bool,
+
+    /// When the most recent PDUs were logged, oldest first.
+    logged: VecDeque<Instant>,
+
+    /// Whether we are currently suppressing PDUs.
+    suppressed: bool,
+}
+
+impl PduLog {
+    /// The maximum number of PDUs logged per period.
+    const LIMIT: usize = 100;
+
+    /// The period over which logged PDUs are counted.
+    const PERIOD: Duration = Duration::from_secs(60);
+
+    /// The maximum number of bytes of a PDU to log.
+    const MAX_LEN: usize = 65_536;
+
+    /// Starts a new PDU received at `now`.
+    fn start(&mut self, name: &str, now: Instant) {
+        self.pdu.clear();
+        self.active = self.allow(name, now);
+    }
+
+    /// Adds data to the current PDU.
+    fn push(&mut self, data: &[u8]) {
+        if self.active {
+            let len = cmp::min(
+                Self::MAX_LEN.saturating_sub(self.pdu.len()), data.len()
+            );
+            self.pdu.extend_from_slice(&data[..len]);
+        }
+    }
+
+    /// Finishes the current PDU and logs it if appropriate.
+    fn finish(&mut self, name: &str) {
+        if self.active {
+            debug!("Unit {}: received {}", name, Self::format(&self.pdu));
+            self.active = false;
+        }
+    }
+
+    /// Returns whether a PDU received at `now` may be logged.
+    fn allow(&mut self, name: &str, now: Instant) -> bool {
+        while let Some(&first) = self.logged.front() {
+            if now.duration_since(first) < Self::PERIOD {
+                break
+            }
+            self.logged.pop_front();
+        }
+        if self.logged.len() < Self::LIMIT {
+            self.logged.push_back(now);
+            self.suppressed = false;
+            true
+        }
+        else {
+            if !self.suppressed {
+                warn!(
+                    "Unit {}: more than {} PDUs per minute received. \
+                     Suppressing PDU debug logging.",
+                    name, Self::LIMIT
+                );
+                self.suppressed = true;
+            }
+            false
+        }
+    }
+
+    /// Formats a PDU for logging.
+    ///
+    /// The session ID and serial number are only given for PDU types that
+    /// have them.
+    fn format(pdu: &[u8]) -> String {
+        use std::fmt::Write;
+
+        let field = |range: std::ops::Range<usize>| {
+            pdu.get(range).map(|data| {
+                data.iter().fold(0u32, |res, &ch| res << 8 | u32::from(ch))
+            })
+        };
+        let pdu_type = pdu.get(1).copied().unwrap_or(0);
+        let mut res = format!(
+            "PDU type={} version={}",
+            pdu_type, pdu.first().copied().unwrap_or(0)
+        );
+        // Serial Notify, Serial Query, Cache Response, and End of Data
+        // carry a session ID.
+        match (pdu_type, field(2..4)) {
+            (0, Some(id)) | (1, Some(id)) | (3, Some(id)) | (7, Some(id)) => {
+                write!(res, " session_id={}", id).unwrap()
+            }
+            _ => res.push_str(" session_id=-")
+        }
+        // Serial Notify, Serial Query, and End of Data carry a serial.
+        match (pdu_type, field(8..12)) {
+            (0, Some(serial)) | (1, Some(serial)) | (7, Some(serial)) => {
+                write!(res, " serial={}", serial).unwrap()
+            }
+            _ => res.push_str(" serial=-")
+        }
+        write!(
+            res, " length={} payload=", field(4..8).unwrap_or(0)
+        ).unwrap();
+        for ch in pdu.get(8..).unwrap_or(&[]) {
+            write!(res, "{:02x}", ch).unwrap()
+        }
+        res
+    }
+}
+
+
+//------------ ConnectionAlarm -----------------------------------------------
+
+/// A debounced alarm for the connection being down.
+///
+/// The alarm is raised once the connection has been down for the raise
+/// duration and cleared once it has been up for the clear duration. Any
+/// shorter change of the connection state leaves the alarm as it is.
+///
+/// Instead of running timers, the alarm is evaluated whenever the
+/// connection state changes and whenever it is looked at.
+#[derive(Debug)]
+struct ConnectionAlarm {
+    /// How long the connection needs to be down to raise the alarm.
+    raise: Duration,
+
+    /// How long the connection needs to be up to clear the alarm.
+    clear: Duration,
+
+    /// The current state of the connection and the alarm.
+    state: Mutex<AlarmState>,
+}
+
+/// The state of the connection alarm.
+#[derive(Clone, Copy, Debug)]
+struct AlarmState {
+    /// Whether the connection is currently up.
+    connected: bool,
+
+    /// Since when the connection is in its current state.
+    since: Instant,
+
+    /// Whether the alarm was raised when the state last changed.
+    raised: bool,
+}
+
+impl ConnectionAlarm {
+    /// Creates a new alarm.
+    ///
+    /// The connection starts out as down and the alarm as cleared.
+    fn new(raise: Duration, clear: Duration) -> Self {
+        ConnectionAlarm {
+            raise, clear,
+            state: Mutex::new(AlarmState {
+                connected: false,
+                since: Instant::now(),
+                raised: false,
+            })
+        }
+    }
+
+    /// Records the state of the connection at `now`.
+    fn connected(&self, connected: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.connected == connected {
+            return
+        }
+        *state = AlarmState {
+            connected,
+            since: now,
+            raised: self.evaluate(*state, now),
+        };
+    }
+
+    /// Returns whether the alarm is raised at `now`.
+    fn is_raised(&self, now: Instant) -> bool {
+        self.evaluate(*self.state.lock().unwrap(), now)
+    }
+
+    /// Returns whether the alarm is raised at `now` given `state`.
+    fn evaluate(&self, state: AlarmState, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(state.since);
+        if state.connected {
+            state.raised && elapsed < self.clear
+        }
+        else {
+            state.raised || elapsed >= self.raise
+        }
+    }
+}
+
+impl Default for ConnectionAlarm {
+    fn default() -> Self {
+        Self::new(
+            Duration::from_secs(Tcp::default_connection_alarm_raise()),
+            Duration::from_secs(Tcp::default_connection_alarm_clear()),
+        )
+    }
+}
+
+
+//------------ RtrMetrics ----------------------------------------------------
+
+#[derive(Debug, Default)]
+struct RtrMetrics {
+    gate: Arc<GateMetrics>,
+
+    /// The number of cache resets received after the initial transfer.
+    resets: AtomicU64,
+
+    /// The number of full updates received, i.e., cache responses to reset
+    /// queries.
+    updates_full: AtomicU64,
+
+    /// The number of incremental updates received.
+    updates_incremental: AtomicU64,
+
+    /// Whether the last update was full or incremental.
+    ///
+    /// This is `None` if we haven’t received an update yet.
+    last_update_full: AtomicCell<Option<bool>>,
+
+    /// The number of prefixes dropped because of their address family.
+    family_dropped: AtomicU64,
+
+    /// The number of prefixes dropped because of their max-length.
+    max_len_dropped: AtomicU64,
+
+    /// The number of queries sent because the connection was idle.
+    idle_queries: AtomicU64,
+
+    /// The number of updates received from the fallback server.
+    fallback_updates: AtomicU64,
+
+    /// Is the server currently resetting too often?
+    reset_alarm: AtomicBool,
+
+    /// Has the connection been down for too long?
+    connection_alarm: ConnectionAlarm,
+
+    /// Have we ever been connected to the server?
+    was_connected: AtomicBool,
+
+    /// The number of connections established after the first one.
+    reconnects: AtomicU64,
+
+    /// The date and time of the last reconnect if there was one.
+    last_reconnect: AtomicCell<Option<DateTime<Utc>>>,
+
+    /// The number of finalization tasks waiting to be run.
+    finalize_queued: AtomicUsize,
+
+    /// The number of errors that happened by kind.
+    ///
+    /// The kinds are indexed as in `RtrError::KINDS`.
+    errors: [AtomicU64; 10],
+
+    /// The number of PDUs received by type.
+    ///
+    /// The types are indexed as in `Self::PDU_TYPES` with unknown types
+    /// counted in the last element.
+    pdus: [AtomicU64; 9],
+
+    /// The number of PDUs ignored because of their version.
+    ignored_pdus: AtomicU64,
+
+    /// The number of updates aborted because they were too large.
+    size_exceeded: AtomicU64,
+
+    /// The durations of the stages of processing updates.
+    stages: StageMetrics,
+}
+
+impl RtrMetrics {
+    fn new(gate: &Gate, connection_alarm: ConnectionAlarm) -> Self {
+        RtrMetrics {
+            gate: gate.metrics(),
+            resets: Default::default(),
+            updates_full: Default::default(),
+            updates_incremental: Default::default(),
+            last_update_full: Default::default(),
+            family_dropped: Default::default(),
+            max_len_dropped: Default::default(),
+            idle_queries: Default::default(),
+            fallback_updates: Default::default(),
+            reset_alarm: Default::default(),
+            connection_alarm,
+            was_connected: Default::default(),
+            reconnects: Default::default(),
+            last_reconnect: Default::default(),
+            finalize_queued: Default::default(),
+            errors: Default::default(),
+            pdus: Default::default(),
+            ignored_pdus: Default::default(),
+            size_exceeded: Default::default(),
+            stages: Default::default(),
+        }
+    }
+
+    /// Counts an error.
+    fn error(&self, err: &RtrError) {
+        self.errors[err.kind()].fetch_add(1, Ordering::Relaxed);
+        if let RtrError::Oversize(_) = *err {
+            self.size_exceeded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a successfully received update.
+    ///
+    /// If `reset` is `true`, the update was a full one.
+    fn update_received(&self, reset: bool) {
+        if reset {
+            self.updates_full.fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self.updates_incremental.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_update_full.store(Some(reset));
+    }
+
+    /// Records a connection to the server established at `now`.
+    ///
+    /// If this was a reconnect, returns the number of reconnects so far and
+    /// the time of this one. Connections only opened to send an idle query,
+    /// as indicated by `idle`, are not reconnects.
+    fn connected(
+        &self, now: DateTime<Utc>, idle: bool
+    ) -> Option<(u64, DateTime<Utc>)> {
+        self.gate.connected(now);
+        if !self.was_connected.swap(true, Ordering::Relaxed) || idle {
+            return None
+        }
+        let count = self.reconnects.fetch_add(1, Ordering::Relaxed) + 1;
+        self.last_reconnect.store(Some(now));
+        Some((count, now))
+    }
+
+    /// Counts a received PDU of the given type.
+    fn pdu(&self, pdu_type: u8) {
+        let idx = Self::PDU_TYPES.iter().position(|item| {
+            item.0 == pdu_type
+        }).unwrap_or(Self::PDU_TYPES.len());
+        self.pdus[idx].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl RtrMetrics {
+    const RESETS_METRIC: Metric = Metric::new(
+        "cache_resets", "the number of cache resets sent by the server",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const UPDATES_FULL_METRIC: Metric = Metric::new(
+        "updates_full", "the number of full updates received",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const UPDATES_INCREMENTAL_METRIC: Metric = Metric::new(
+        "updates_incremental", "the number of incremental updates received",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const LAST_UPDATE_KIND_METRIC: Metric = Metric::new(
+        "last_update_kind",
+        "whether the last update was full or incremental",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const FAMILY_DROPPED_METRIC: Metric = Metric::new(
+        "family_dropped",
+        "the number of prefixes dropped because of their address family",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const MAX_LEN_DROPPED_METRIC: Metric = Metric::new(
+        "max_len_dropped",
+        "the number of prefixes dropped because of a max-length beyond the \
+         address length",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const IDLE_QUERIES_METRIC: Metric = Metric::new(
+        "rtr_idle_queries",
+        "the number of queries sent because the connection was idle",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const FALLBACK_UPDATES_METRIC: Metric = Metric::new(
+        "fallback_updates",
+        "the number of updates received from the fallback server",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const RESET_ALARM_METRIC: Metric = Metric::new(
+        "reset_alarm", "whether the server is sending too many cache resets",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const CONNECTION_ALARM_METRIC: Metric = Metric::new(
+        "connection_alarm",
+        "whether the connection to the server has been down for too long",
+        MetricType::Gauge, MetricUnit::Info
+    );
+    const RECONNECTS_METRIC: Metric = Metric::new(
+        "rtr_reconnects",
+        "the number of reconnects to the server, excluding idle queries",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const LAST_RECONNECT_METRIC: Metric = Metric::new(
+        "rtr_last_reconnect",
+        "the Unix time of the last reconnect to the server",
+        MetricType::Gauge, MetricUnit::Second
+    );
+    const FINALIZE_QUEUED_METRIC: Metric = Metric::new(
+        "finalize_queued", "the number of finalization tasks waiting to run",
+        MetricType::Gauge, MetricUnit::Total
+    );
+    const ERRORS_METRIC: Metric = Metric::new(
+        "rtr_errors", "the number of errors of the RTR client by kind",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const PDUS_METRIC: Metric = Metric::new(
+        "rtr_pdus", "the number of PDUs received from the server by type",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const IGNORED_PDUS_METRIC: Metric = Metric::new(
+        "rtr_ignored_pdus",
+        "the number of PDUs ignored because of an unexpected version",
+        MetricType::Counter, MetricUnit::Total
+    );
+    const UPDATE_SIZE_EXCEEDED_METRIC: Metric = Metric::new(
+        "rtr_update_size_exceeded",
+        "the number of updates aborted for exceeding the maximum size",
+        MetricType::Counter, MetricUnit::Total
+    );
+
+    /// The PDU types a server may send and their label values.
+    const PDU_TYPES: [(u8, &'static str); 8] = [
+        (0, "serial-notify"),
+        (3, "cache-response"),
+        (4, "ipv4-prefix"),
+        (6, "ipv6-prefix"),
+        (7, "end-of-data"),
+        (8, "cache-reset"),
+        (9, "router-key"),
+        (10, "error-report"),
+    ];
+}
+
+impl metrics::Source for RtrMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        self.gate.append(unit_name, target);
+        self.stages.append(unit_name, target);
+        target.append_simple(
+            &Self::RESETS_METRIC, Some(unit_name),
+            self.resets.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::UPDATES_FULL_METRIC, Some(unit_name),
+            self.updates_full.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::UPDATES_INCREMENTAL_METRIC, Some(unit_name),
+            self.updates_incremental.load(Ordering::Relaxed)
+        );
+        let last_full = self.last_update_full.load();
+        target.append(
+            &Self::LAST_UPDATE_KIND_METRIC, Some(unit_name), |records| {
+                records.label_value(
+                    &[("kind", "full")], (last_full == Some(true)) as u8
+                );
+                records.label_value(
+                    &[("kind", "incremental")],
+                    (last_full == Some(false)) as u8
+                );
+            }
+        );
+        target.append_simple(
+            &Self::FAMILY_DROPPED_METRIC, Some(unit_name),
+            self.family_dropped.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::MAX_LEN_DROPPED_METRIC, Some(unit_name),
+            self.max_len_dropped.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::IDLE_QUERIES_METRIC, Some(unit_name),
+            self.idle_queries.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::FALLBACK_UPDATES_METRIC, Some(unit_name),
+            self.fallback_updates.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::RESET_ALARM_METRIC, Some(unit_name),
+            self.reset_alarm.load(Ordering::Relaxed) as u8
+        );
+        target.append_simple(
+            &Self::CONNECTION_ALARM_METRIC, Some(unit_name),
+            self.connection_alarm.is_raised(Instant::now()) as u8
+        );
+        target.append_simple(
+            &Self::RECONNECTS_METRIC, Some(unit_name),
+            self.reconnects.load(Ordering::Relaxed)
+        );
+        // Milliseconds allow spotting reconnects in the same second across
+        // many instances. If there wasn’t any reconnect yet, we use -1.
+        match self.last_reconnect.load() {
+            Some(time) => {
+                target.append_simple(
+                    &Self::LAST_RECONNECT_METRIC, Some(unit_name),
+                    (time.timestamp_millis() as f64) / 1000.
+                );
+            }
+            None => {
+                target.append_simple(
+                    &Self::LAST_RECONNECT_METRIC, Some(unit_name), -1
+                );
+            }
+        }
+        target.append_simple(
+            &Self::FINALIZE_QUEUED_METRIC, Some(unit_name),
+            self.finalize_queued.load(Ordering::Relaxed)
+        );
+        target.append(&Self::ERRORS_METRIC, Some(unit_name), |records| {
+            for (kind, value) in RtrError::KINDS.iter().zip(&self.errors) {
+                records.label_value(
+                    &[("kind", kind)], value.load(Ordering::Relaxed)
+                );
+            }
+        });
+        target.append(&Self::PDUS_METRIC, Some(unit_name), |records| {
+            let types = Self::PDU_TYPES.iter().map(|item| item.1).chain(
+                Some("other")
+            );
+            for (pdu_type, value) in types.zip(&self.pdus) {
+                records.label_value(
+                    &[("type", pdu_type)], value.load(Ordering::Relaxed)
+                );
+            }
+        });
+        target.append_simple(
+            &Self::IGNORED_PDUS_METRIC, Some(unit_name),
+            self.ignored_pdus.load(Ordering::Relaxed)
+        );
+        target.append_simple(
+            &Self::UPDATE_SIZE_EXCEEDED_METRIC, Some(unit_name),
+            self.size_exceeded.load(Ordering::Relaxed)
+        );
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use crate::units::Unit;
+    use crate::payload::testing::{v4, v6};
+    use super::*;
+
+    #[test]
+    fn reset_limit() {
+        let start = Instant::now();
+        let mut limit = ResetLimit::default();
+
+        // A reset-happy server resetting every ten minutes.
+        for i in 0..4 {
+            assert!(!limit.push(start + Duration::from_secs(i * 600), 4));
+        }
+        assert!(limit.push(start + Duration::from_secs(2400), 4));
+
+        // Resets older than an hour are forgotten.
+        assert!(!limit.push(start + Duration::from_secs(7200), 4));
+
+        // An incremental update clears everything.
+        limit.clear();
+        for i in 0..4 {
+            assert!(!limit.push(start + Duration::from_secs(i), 4));
+        }
+    }
+
+    #[test]
+    fn remote() {
+        fn addr(s: &str) -> String {
+            Remote::try_from(String::from(s)).unwrap().addr
+        }
+
+        assert_eq!(addr("localhost:3323"), "localhost:3323");
+        assert_eq!(addr("rtr://localhost:3323"), "localhost:3323");
+        assert_eq!(addr("rtr://localhost"), "localhost:323");
+        assert_eq!(addr("rtr://192.0.2.1/"), "192.0.2.1:323");
+        assert_eq!(addr("rtr://[2001:db8::1]"), "[2001:db8::1]:323");
+        assert_eq!(addr("rtr://[2001:db8::1]:3323"), "[2001:db8::1]:3323");
+        assert!(Remote::try_from(String::from("rtrs://localhost")).is_err());
+        assert!(Remote::try_from(String::from("http://localhost")).is_err());
+        assert!(Remote::try_from(String::from("rtr://")).is_err());
+    }
+
+    #[test]
+    fn next_serial() {
+        let mut tcp = toml::from_str::<Tcp>(
+            "remote = \"localhost:323\"\npreserve-serial = true"
+        ).unwrap();
+        let mut publish = |upstream: Option<u32>| {
+            tcp.serial = tcp.next_serial("test", upstream.map(Serial::from));
+            tcp.published = true;
+            u32::from(tcp.serial)
+        };
+
+        // The first update can have any serial.
+        assert_eq!(publish(Some(0)), 0);
+        assert_eq!(publish(Some(1)), 1);
+        assert_eq!(publish(Some(5)), 5);
+
+        // A server starting over is overtaken until it catches up.
+        assert_eq!(publish(Some(1)), 6);
+        assert_eq!(publish(Some(6)), 7);
+        assert_eq!(publish(None), 8);
+        assert_eq!(publish(Some(9)), 9);
+        assert_eq!(publish(Some(u32::max_value())), 10);
+
+        let mut tcp = toml::from_str::<Tcp>(
+            "remote = \"localhost:323\""
+        ).unwrap();
+        assert_eq!(
+            tcp.next_serial("test", Some(Serial::from(12))), Serial::from(1)
+        );
+        tcp.serial = Serial::from(7);
+        assert_eq!(
+            tcp.next_serial("test", Some(Serial::from(12))), Serial::from(8)
+        );
+    }
+
+    #[test]
+    fn forward_empty_updates() {
+        let tcp = |forward: bool| toml::from_str::<Tcp>(&format!(
+            "remote = \"localhost:323\"\nforward-empty-updates = {}",
+            forward
+        )).unwrap();
+        let serial = |serial| Some(Serial::from(serial));
+        let mut target = Target::new("test".into(), PrefixFamily::Both);
+
+        // Without forwarding, empty updates are dropped.
+        let off = tcp(false);
+        assert!(off.publish_due(&target.start(true), serial(1), serial(1)));
+        assert!(!off.publish_due(&target.start(false), serial(1), serial(2)));
+
+        // Empty updates only if the server’s serial has advanced.
+        let on = tcp(true);
+        assert!(on.publish_due(&target.start(false), serial(1), serial(2)));
+        assert!(!on.publish_due(&target.start(false), serial(2), serial(2)));
+        assert!(!on.publish_due(&target.start(false), None, None));
+    }
+
+    #[test]
+    fn unique_metric_names() {
+        use metrics::Source as _;
+
+        // A metric without labels must be the only one of its name, or
+        // the plain output can’t be told apart.
+        let mut output = metrics::Target::new(metrics::OutputFormat::Plain);
+        RtrMetrics::default().append("rtr", &mut output);
+        let output = output.into_string();
+        fn name(line: &str) -> &str {
+            line.split(&[' ', ':'][..]).nth(1).unwrap_or("")
+        }
+        for line in output.lines() {
+            if line.starts_with(&format!("rtr {}:", name(line))) {
+                assert_eq!(
+                    output.lines().filter(|other| {
+                        name(other) == name(line)
+                    }).count(),
+                    1, "{}", name(line)
+                );
+            }
+        }
+        assert!(output.contains("rtr last_update_kind kind=full: 0\n"));
+    }
+
+    #[tokio::test]
+    async fn update_stages() {
+        use metrics::Source as _;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        // A server answering a reset query with a single VRP.
+        let mut listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut query = [0u8; 8];
+            sock.read_exact(&mut query).await.unwrap();
+            let version = query[0];
+            let mut response = vec![version, 3, 0, 7, 0, 0, 0, 8];
+            response.extend_from_slice(&[
+                version, 4, 0, 0, 0, 0, 0, 20,
+                1, 24, 24, 0, 192, 0, 2, 0, 0, 0, 0xfb, 0xf0
+            ]);
+            if version == 0 {
+                response.extend_from_slice(&[0, 7, 0, 7, 0, 0, 0, 12]);
+                response.extend_from_slice(&[0, 0, 0, 1]);
+            }
+            else {
+                response.extend_from_slice(&[version, 7, 0, 7, 0, 0, 0, 24]);
+                response.extend_from_slice(&[
+                    0, 0, 0, 1, 0, 0, 14, 16, 0, 0, 2, 88, 0, 0, 28, 32
+                ]);
+            }
+            sock.write_all(&response).await.unwrap();
+            let _ = sock.read(&mut query).await;
+        });
+
+        let mut tcp = toml::from_str::<Tcp>(
+            &format!("remote = \"{}\"", addr)
+        ).unwrap();
+        let (mut gate, _agent) = Gate::new();
+        let metrics = Arc::new(RtrMetrics::default());
+        let finalizer = Finalizer::new(1, metrics.clone());
+        let permit = net::Outbound::new(None).permit().await;
+        let sock = PduCounter::new(
+            net::Outgoing::new(
+                TcpStream::connect(addr).await.unwrap(), permit
+            ),
+            "rtr".into(), VersionMismatch::default(), metrics.clone()
+        );
+        let activity = sock.activity();
+        let mut client = Client::new(
+            sock, Target::new("rtr".into(), PrefixFamily::Both), None
+        );
+        let update = match tcp.update(
+            &mut client, &mut gate, &activity
+        ).await {
+            Ok(Some(Ok(update))) => update,
+            _ => panic!("no update received")
+        };
+        let mut target = client.into_target();
+        tcp.publish(
+            update, None, &mut target, &mut gate, &finalizer, None
+        ).await.unwrap();
+        assert_eq!(target.current.len(), 1);
+
+        // Parsing happens while receiving and isn’t recorded at all.
+        let mut output = metrics::Target::new(metrics::OutputFormat::Plain);
+        metrics.append("rtr", &mut output);
+        let output = output.into_string();
+        for stage in &["receive", "parse", "finalize", "diff", "publish"] {
+            let (count, last) = match *stage {
+                "parse" => (0, false),
+                _ => (1, true)
+            };
+            assert!(
+                output.contains(
+                    &format!("rtr update_{}_duration count: {}", stage, count)
+                ),
+                "{}", output
+            );
+            assert_eq!(
+                output.contains(
+                    &format!("rtr update_stage_duration stage={}: ", stage)
+                ),
+                last, "{}", output
+            );
+        }
+    }
+
+    #[test]
+    fn seeded_retry_delay() {
+        let delays = |seed: u64| {
+            let mut tcp = toml::from_str::<Tcp>(
+                "remote = \"localhost:323\"\nretry = 60"
+            ).unwrap();
+            tcp.random = Random::new(Some(seed)).for_component("rtr");
+            (0..8).map(|_| tcp.retry_delay()).collect::<Vec<_>>()
+        };
+        let first = delays(1);
+        assert_eq!(first, delays(1));
+        assert_ne!(first, delays(2));
+        for delay in first {
+            assert!(delay >= Duration::from_secs(60));
+            assert!(delay < Duration::from_secs(66));
+        }
+    }
+
+    #[test]
+    fn reconnect_serial() {
+        let tcp = |policy: &str| toml::from_str::<Tcp>(&format!(
+            "remote = \"localhost:323\"\n\
+             reconnect-serial = \"{}\"\n\
+             max-serial-gap = 10",
+            policy
+        )).unwrap();
+        let state = |serial| Some(State::from_parts(7, Serial::from(serial)));
+        let parts = |state: Option<State>| {
+            state.map(|state| (state.session(), state.serial()))
+        };
+
+        // The previous connection ended at serial 100. The server is now at
+        // 105 (small gap), 200 (large gap), or 90 (went backwards).
+        let old = state(100);
+        let cases = [
+            ("always-reset", None, [true, true, true]),
+            ("trust-serial", old, [true, true, true]),
+            ("reset-on-gap", old, [true, false, false]),
+        ];
+        for &(policy, query, accept) in &cases {
+            let tcp = tcp(policy);
+            assert_eq!(
+                parts(tcp.reconnect_serial.query_state(old, false)),
+                parts(query), "{}", policy
+            );
+            assert_eq!(
+                parts(tcp.reconnect_serial.query_state(old, true)),
+                parts(old), "{}", policy
+            );
+            for (&new, &accept) in [105, 200, 90].iter().zip(&accept) {
+                assert_eq!(
+                    tcp.accept_gap("test", old, state(new)), accept,
+                    "{} {}", policy, new
+                );
+            }
+            // Without a previous state, there is nothing to compare.
+            assert!(tcp.accept_gap("test", None, state(200)));
+        }
+
+        // The default keeps the previous behaviour.
+        let tcp = toml::from_str::<Tcp>(
+            "remote = \"localhost:323\""
+        ).unwrap();
+        assert_eq!(tcp.reconnect_serial, ReconnectSerial::TrustSerial);
+        assert_eq!(tcp.max_serial_gap, 100);
+    }
+
+    #[test]
+    fn vrf_option() {
+        let tcp = |vrf: &str| toml::from_str::<Tcp>(&format!(
+            "remote = \"localhost:323\"\nvrf = \"{}\"", vrf
+        ));
+        assert!(tcp("").is_err());
+        assert!(tcp("a-very-long-vrf-name").is_err());
+        assert_eq!(
+            tcp("blue").map(|tcp| tcp.peer()).ok(),
+            if cfg!(target_os = "linux") {
+                Some("localhost:323 in VRF 'blue'".into())
+            }
+            else {
+                None
+            }
+        );
+    }
+
+    #[test]
+    fn interface_option() {
+        let tcp = |options: &str| toml::from_str::<Tcp>(&format!(
+            "remote = \"localhost:323\"\n{}", options
+        ));
+        assert!(tcp("interface = \"\"").is_err());
+        assert!(tcp("interface = \"a-very-long-if-name\"").is_err());
+        assert_eq!(
+            tcp("interface = \"eth0\"").map(|tcp| tcp.peer()).ok(),
+            if net::INTERFACE_SUPPORTED {
+                Some("localhost:323 via interface 'eth0'".into())
+            }
+            else {
+                None
+            }
+        );
+        if cfg!(target_os = "linux") {
+            let unit = |options: &str| toml::from_str::<Unit>(&format!(
+                "type = \"rtr\"\nremote = \"localhost:323\"\n{}", options
+            ));
+            assert!(unit("interface = \"eth0\"").is_ok());
+            assert!(unit("vrf = \"blue\"").is_ok());
+            let err = unit(
+                "interface = \"eth0\"\nvrf = \"blue\""
+            ).unwrap_err();
+            assert!(err.to_string().contains("can’t be used together"));
+        }
+    }
+
+    #[test]
+    fn error_kinds() {
+        fn kind(err: RtrError) -> &'static str {
+            RtrError::KINDS[err.kind()]
+        }
+        fn io(kind: io::ErrorKind) -> io::Error {
+            io::Error::new(kind, "test")
+        }
+
+        assert_eq!(
+            kind(RtrError::connect(io(io::ErrorKind::TimedOut))),
+            "connect-timeout"
+        );
+        assert_eq!(
+            kind(RtrError::connect(io(io::ErrorKind::ConnectionRefused))),
+            "connect-refused"
+        );
+        assert_eq!(
+            kind(RtrError::connect(io(io::ErrorKind::PermissionDenied))),
+            "connect-io"
+        );
+        assert_eq!(
+            kind(RtrError::session(io(io::ErrorKind::UnexpectedEof), None)),
+            "session-terminated"
+        );
+        assert_eq!(
+            kind(RtrError::session(io(io::ErrorKind::ConnectionReset), None)),
+            "session-terminated"
+        );
+        assert_eq!(
+            kind(RtrError::session(io(io::ErrorKind::InvalidData), None)),
+            "protocol-corrupt"
+        );
+        assert_eq!(
+            kind(RtrError::session(io(io::ErrorKind::TimedOut), None)),
+            "session-io"
+        );
+        assert_eq!(
+            kind(RtrError::session(
+                io(io::ErrorKind::Other), Some(UpdateFailure::Validation)
+            )),
+            "validation"
+        );
+        assert_eq!(
+            kind(RtrError::session(
+                io(io::ErrorKind::Other), Some(UpdateFailure::Oversize)
+            )),
+            "oversize"
+        );
+    }
+
+    #[test]
+    fn max_update_bytes() {
+        let v4 = |octet| v4([192, 0, octet, 0], 24, 24, 64496);
+        let mut target = Target::new("test".into(), PrefixFamily::Both);
+        target.max_bytes = Some(50);
+
+        // Two IPv4 prefixes are 40 bytes, the third one is too much.
+        let mut update = target.start(true);
+        update.push_vrp(Action::Announce, v4(1)).unwrap();
+        update.push_vrp(Action::Announce, v4(2)).unwrap();
+        assert!(update.push_vrp(Action::Announce, v4(3)).is_err());
+        assert_eq!(
+            target.failure.take(), Some(UpdateFailure::Oversize)
+        );
+
+        // The count starts over with each update.
+        let mut update = target.start(false);
+        update.push_vrp(Action::Announce, v4(1)).unwrap();
+        update.push_vrp(Action::Announce, v4(2)).unwrap();
+        assert_eq!(target.failure.take(), None);
+    }
+
+    #[test]
+    fn ipv4_only() {
+        
+        
+
+        let v4 = |asn| v4([192, 0, 2, 0], 24, 24, asn);
+        let v6 = |asn| v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32, 32, asn);
+        let mut target = Target::new("test".into(), PrefixFamily::Ipv4);
+
+        let mut update = target.start(true);
+        update.push_vrp(Action::Announce, v4(64496)).unwrap();
+        update.push_vrp(Action::Announce, v6(64496)).unwrap();
+        assert_eq!(update.dropped, 1);
+        let set = update.set.finalize();
+        assert_eq!(set.len(), 1);
+        assert!(set.iter().all(|item| matches!(item, Payload::V4(_))));
+        target.current = Arc::new(set);
+
+        // Withdrawals of a disabled family don’t fail and don’t show up in
+        // the diff either.
+        let mut update = target.start(false);
+        update.push_vrp(Action::Announce, v4(64497)).unwrap();
+        update.push_vrp(Action::Announce, v6(64497)).unwrap();
+        update.push_vrp(Action::Withdraw, v6(64496)).unwrap();
+        assert_eq!(update.dropped, 2);
+        assert_eq!(update.diff.unwrap().len(), 1);
+        let set = update.set.finalize();
+        assert_eq!(set.len(), 2);
+        assert!(set.iter().all(|item| matches!(item, Payload::V4(_))));
+    }
+
+    #[test]
+    fn max_len_dropped() {
+        
+        
+
+        let v4 = |max_len| v4([192, 0, 2, 0], 24, max_len, 64496);
+        let mut target = Target::new("test".into(), PrefixFamily::Both);
+        let mut update = target.start(true);
+        update.push_vrp(Action::Announce, v4(24)).unwrap();
+        update.push_vrp(Action::Announce, v4(33)).unwrap();
+        assert_eq!(update.max_len_dropped, 1);
+        target.current = Arc::new(update.set.finalize());
+        assert_eq!(target.current.len(), 1);
+
+        // Withdrawing a dropped item neither fails nor shows up in the diff.
+        let mut update = target.start(false);
+        update.push_vrp(Action::Withdraw, v4(33)).unwrap();
+        assert_eq!(update.max_len_dropped, 1);
+        assert!(update.is_definitely_empty());
+    }
+
+    #[test]
+    fn empty_serial_response() {
+        let mut target = Target::new("test".into(), PrefixFamily::Both);
+        assert!(target.start(false).is_definitely_empty());
+        assert!(!target.start(true).is_definitely_empty());
+
+        let mut update = target.start(false);
+        update.push_vrp(
+            Action::Announce, v4([192, 0, 2, 0], 24, 24, 64496)
+        ).unwrap();
+        assert!(!update.is_definitely_empty());
+    }
+
+    #[test]
+    fn aggregation_check() {
+        
+        use http::ProcessRequest;
+        
+
+        let check = AggregationCheck::new("rtr");
+        let mut request = Request::get("/aggregation/rtr").body(
+            Body::empty()
+        ).unwrap();
+        assert!(check.process_request(&mut request).is_some());
+
+        let v4 = |addr| v4([192, 0, addr, 0], 24, 24, 64496);
+        let mut builder = payload::SetBuilder::empty();
+        builder.insert(v4(2)).unwrap();
+        builder.insert(v4(3)).unwrap();
+        let set = builder.finalize();
+        *check.report.lock().unwrap() = Some((1, set.aggregations()));
+        let mut target = metrics::Target::new(metrics::OutputFormat::Plain);
+        metrics::Source::append(&check, "rtr", &mut target);
+        assert!(
+            target.into_string().contains("rtr aggregation_candidates: 1")
+        );
+    }
+
+    #[test]
+    fn fallback_due() {
+        let tcp: Tcp = toml::from_str(
+            "remote = \"localhost:3323\"\n\
+             fallback-remote = \"rtr://backup\"\n\
+             fallback-expire = 60\n"
+        ).unwrap();
+        let now = Instant::now();
+        assert!(tcp.fallback_due(None));
+        assert!(!tcp.fallback_due(Some(now)));
+        assert!(tcp.fallback_due(Some(now - Duration::from_secs(60))));
+
+        let tcp: Tcp = toml::from_str(
+            "remote = \"localhost:3323\"\n"
+        ).unwrap();
+        assert!(!tcp.fallback_due(None));
+    }
+
+    #[test]
+    fn connection_alarm() {
+        let secs = Duration::from_secs;
+        let alarm = ConnectionAlarm::new(secs(10), secs(20));
+        let start = Instant::now();
+        assert!(!alarm.is_raised(start + secs(9)));
+        assert!(alarm.is_raised(start + secs(10)));
+
+        // A short reconnect doesn’t clear the alarm.
+        alarm.connected(true, start + secs(30));
+        assert!(alarm.is_raised(start + secs(49)));
+        alarm.connected(false, start + secs(49));
+        assert!(alarm.is_raised(start + secs(50)));
+
+        // Being up long enough does.
+        alarm.connected(true, start + secs(50));
+        assert!(!alarm.is_raised(start + secs(70)));
+
+        // A short disconnect doesn’t raise it again.
+        alarm.connected(false, start + secs(80));
+        alarm.connected(true, start + secs(89));
+        assert!(!alarm.is_raised(start + secs(100)));
+    }
+
+    #[test]
+    fn reconnects() {
+        use chrono::TimeZone;
+
+        let metrics = RtrMetrics::default();
+        let time = Utc.timestamp_millis(1_600_000_000_250);
+        assert_eq!(metrics.connected(time, false), None);
+        assert!(metrics.last_reconnect.load().is_none());
+        assert_eq!(metrics.connected(time, false), Some((1, time)));
+        assert_eq!(metrics.connected(time, true), None);
+        assert_eq!(metrics.connected(time, false), Some((2, time)));
+        assert_eq!(metrics.reconnects.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.last_reconnect.load(), Some(time));
+    }
+
+    #[test]
+    fn pdu_counter() {
+        let metrics = Arc::new(RtrMetrics::default());
+        let mut counter = PduCounter::new(
+            (), "rtr".into(), VersionMismatch::Reset, metrics.clone()
+        );
+        let mut data = vec![1, 3, 0, 0, 0, 0, 0, 8];
+        data.extend_from_slice(&[1, 4, 0, 0, 0, 0, 0, 20]);
+        data.extend_from_slice(&[0; 12]);
+        data.extend_from_slice(&[1, 4, 0, 0, 0, 0, 0, 20]);
+        data.extend_from_slice(&[0; 12]);
+        data.extend_from_slice(&[1, 7, 0, 0, 0, 0, 0, 24]);
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&[1, 42, 0, 0, 0, 0, 0, 8]);
+
+        // Feed the data in odd chunks so headers get split.
+        for chunk in data.chunks_mut(5) {
+            assert_eq!(counter.received(chunk).unwrap(), chunk.len());
+        }
+        let count = |idx: usize| metrics.pdus[idx].load(Ordering::Relaxed);
+        assert_eq!(count(1), 1);
+        assert_eq!(count(2), 2);
+        assert_eq!(count(4), 1);
+        assert_eq!(count(8), 1);
+        assert_eq!(metrics.pdus.iter().map(|item| {
+            item.load(Ordering::Relaxed)
+        }).sum::<u64>(), 5);
+
+        // A Cache Reset is counted as such but passed on with the type the
+        // client expects.
+        let mut data = [1, 8, 0, 0, 0, 0, 0, 8];
+        for chunk in data.chunks_mut(1) {
+            assert_eq!(counter.received(chunk).unwrap(), chunk.len());
+        }
+        assert_eq!(data, [1, 7, 0, 0, 0, 0, 0, 8]);
+        assert_eq!(count(5), 1);
+    }
+
+    #[test]
+    fn pdu_log() {
+        // End of Data, version 1, session 12, serial 7.
+        let mut data = vec![1, 7, 0, 12, 0, 0, 0, 24, 0, 0, 0, 7];
+        data.extend_from_slice(&[0, 0, 14, 16, 0, 0, 2, 88, 0, 0, 28, 32]);
+        assert_eq!(
+            PduLog::format(&data),
+            "PDU type=7 version=1 session_id=12 serial=7 length=24 \
+             payload=0000000700000e100000025800001c20"
+        );
+        assert_eq!(
+            PduLog::format(&[1, 4, 0, 0, 0, 0, 0, 20, 0, 24, 24, 0]),
+            "PDU type=4 version=1 session_id=- serial=- length=20 \
+             payload=00181800"
+        );
+
+        let mut log = PduLog::default();
+        let start = Instant::now();
+        for _ in 0..PduLog::LIMIT {
+            assert!(log.allow("rtr", start));
+        }
+        assert!(!log.allow("rtr", start + Duration::from_secs(59)));
+        assert!(log.allow("rtr", start + Duration::from_secs(60)));
+
+        // Logging doesn’t interfere with the data.
+        let mut counter = PduCounter::new(
+            (), "rtr".into(), VersionMismatch::Reset, Default::default()
+        ).with_pdu_log(true);
+        for chunk in data.chunks_mut(5) {
+            assert_eq!(counter.received(chunk).unwrap(), chunk.len());
+        }
+        assert!(!counter.pdu_log.unwrap().active);
+    }
+
+    #[tokio::test]
+    async fn version_mismatch() {
+        use tokio::io::AsyncReadExt;
+
+        // A version 1 session where the server sends one prefix PDU with
+        // version 0 midway.
+        let mut data = vec![1, 3, 0, 0, 0, 0, 0, 8];
+        data.extend_from_slice(&[1, 4, 0, 0, 0, 0, 0, 20]);
+        data.extend_from_slice(&[1; 12]);
+        data.extend_from_slice(&[0, 4, 0, 0, 0, 0, 0, 20]);
+        data.extend_from_slice(&[2; 12]);
+        data.extend_from_slice(&[1, 7, 0, 0, 0, 0, 0, 24]);
+        data.extend_from_slice(&[3; 16]);
+
+        let counter = |policy| {
+            PduCounter::new(
+                &data[..], "rtr".into(), policy, Default::default()
+            )
+        };
+
+        let mut sock = counter(VersionMismatch::Ignore);
+        let mut received = Vec::new();
+        sock.read_to_end(&mut received).await.unwrap();
+        let mut expected = data[..28].to_vec();
+        expected.extend_from_slice(&data[48..]);
+        assert_eq!(received, expected);
+        assert_eq!(sock.metrics.ignored_pdus.load(Ordering::Relaxed), 1);
+
+        for &policy in &[VersionMismatch::Reset, VersionMismatch::Fail] {
+            let mut sock = counter(policy);
+            let mut received = Vec::new();
+            let err = RtrError::session(
+                sock.read_to_end(&mut received).await.unwrap_err(), None
+            );
+            assert!(err.is_version_mismatch());
+            assert_eq!(
+                err.to_string(),
+                "version mismatch: server switched from RTR version 1 to 0 \
+                 mid-session"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_dns_error() {
+        let err = Tcp::connect_addr(
+            &Default::default(), "no-port-given", None, None
+        ).await.unwrap_err();
+        assert_eq!(RtrError::KINDS[err.kind()], "connect-dns");
     }
 }
 
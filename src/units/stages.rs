@@ -0,0 +1,293 @@
+//! Timing the stages of processing an update.
+//!
+//! Units producing data from external input go through the same stages for
+//! every update: the data is received, parsed, turned into a finalized
+//! set, compared with the previous set, and published through the gate.
+//! An [`UpdateTimer`] measures how long each of these stages took for a
+//! single update and hands the result to [`StageMetrics`] which provides
+//! the duration of the last update as well as histograms for each stage.
+//!
+//! Not every unit can separate all stages. A stage that is part of another
+//! stage isn’t recorded at all, so its histogram stays empty and it is left
+//! out of the durations of the last update instead of appearing to take no
+//! time. A stage a unit simply skips, such as determining the changes for a
+//! unit that always publishes full sets, is recorded with a duration of
+//! zero.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::metrics;
+use crate::metrics::{Histogram, Metric, MetricType, MetricUnit};
+
+
+//------------ Stage ---------------------------------------------------------
+
+/// A stage of processing an update.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stage {
+    /// Receiving the data from the server.
+    Receive,
+
+    /// Parsing the received data.
+    Parse,
+
+    /// Finalizing the new data set.
+    Finalize,
+
+    /// Determining the changes to the previous data set.
+    Diff,
+
+    /// Handing the update to the gate.
+    Publish,
+}
+
+impl Stage {
+    /// All stages in the order they happen.
+    pub const ALL: [Stage; 5] = [
+        Stage::Receive, Stage::Parse, Stage::Finalize, Stage::Diff,
+        Stage::Publish,
+    ];
+
+    /// Returns the name of the stage as used in the metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Receive => "receive",
+            Stage::Parse => "parse",
+            Stage::Finalize => "finalize",
+            Stage::Diff => "diff",
+            Stage::Publish => "publish",
+        }
+    }
+
+    /// Returns the index of the stage in `Stage::ALL`.
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+
+//------------ UpdateTimer ---------------------------------------------------
+
+/// Measures the stages of processing a single update.
+///
+/// The timer is started when processing begins. Each call to
+/// [`stage`](Self::stage) ends a stage and starts the next one. Stages
+/// measured elsewhere, e.g., because they run concurrently, can be added
+/// via [`record`](Self::record).
+#[derive(Clone, Copy, Debug)]
+pub struct UpdateTimer {
+    /// When the current stage started.
+    last: Instant,
+
+    /// The durations of the stages measured so far.
+    durations: [Option<Duration>; 5],
+}
+
+impl UpdateTimer {
+    /// Starts a new timer.
+    pub fn start() -> Self {
+        UpdateTimer {
+            last: Instant::now(),
+            durations: Default::default(),
+        }
+    }
+
+    /// Ends `stage` now.
+    ///
+    /// The stage is taken to have started when the previous stage ended or
+    /// the timer was started.
+    pub fn stage(&mut self, stage: Stage) {
+        let now = Instant::now();
+        self.durations[stage.index()] = Some(
+            now.saturating_duration_since(self.last)
+        );
+        self.last = now;
+    }
+
+    /// Records the duration of a stage measured elsewhere.
+    ///
+    /// The next stage starts now.
+    pub fn record(&mut self, stage: Stage, duration: Duration) {
+        self.durations[stage.index()] = Some(duration);
+        self.last = Instant::now();
+    }
+
+    /// Returns the duration of a stage if it has been measured.
+    pub fn duration(&self, stage: Stage) -> Option<Duration> {
+        self.durations[stage.index()]
+    }
+
+    /// Hands the measured durations to the metrics.
+    ///
+    /// Stages that haven’t been measured are left out.
+    pub fn finish(self, metrics: &StageMetrics) {
+        for &stage in &Stage::ALL {
+            match self.duration(stage) {
+                Some(duration) => metrics.observe(stage, duration),
+                None => metrics.skip(stage),
+            }
+        }
+    }
+}
+
+
+//------------ StageMetrics --------------------------------------------------
+
+/// The metrics for the stages of processing updates.
+#[derive(Debug)]
+pub struct StageMetrics {
+    /// The duration of each stage of the last update in microseconds.
+    ///
+    /// Stages that weren’t measured for the last update are
+    /// [`NOT_MEASURED`](Self::NOT_MEASURED).
+    last: [AtomicU64; 5],
+
+    /// The histogram of the durations of each stage.
+    histograms: [Histogram; 5],
+}
+
+impl Default for StageMetrics {
+    fn default() -> Self {
+        StageMetrics {
+            last: [
+                AtomicU64::new(Self::NOT_MEASURED),
+                AtomicU64::new(Self::NOT_MEASURED),
+                AtomicU64::new(Self::NOT_MEASURED),
+                AtomicU64::new(Self::NOT_MEASURED),
+                AtomicU64::new(Self::NOT_MEASURED),
+            ],
+            histograms: [
+                Histogram::new(Self::BUCKETS),
+                Histogram::new(Self::BUCKETS),
+                Histogram::new(Self::BUCKETS),
+                Histogram::new(Self::BUCKETS),
+                Histogram::new(Self::BUCKETS),
+            ],
+        }
+    }
+}
+
+impl StageMetrics {
+    /// The upper bounds of the histogram buckets in seconds.
+    const BUCKETS: &'static [f64] = &[
+        0.001, 0.01, 0.1, 0.5, 1., 5., 10., 30., 60.
+    ];
+
+    /// The value of `last` for a stage that wasn’t measured.
+    const NOT_MEASURED: u64 = u64::MAX;
+
+    /// Adds the duration of a stage.
+    fn observe(&self, stage: Stage, duration: Duration) {
+        self.last[stage.index()].store(
+            duration.as_micros() as u64, Ordering::Relaxed
+        );
+        self.histograms[stage.index()].observe(duration);
+    }
+
+    /// Notes that a stage wasn’t measured for the last update.
+    fn skip(&self, stage: Stage) {
+        self.last[stage.index()].store(
+            Self::NOT_MEASURED, Ordering::Relaxed
+        );
+    }
+}
+
+impl StageMetrics {
+    const LAST_METRIC: Metric = Metric::new(
+        "update_stage_duration",
+        "the duration of each stage of processing the last update",
+        MetricType::Gauge, MetricUnit::Second
+    );
+    const HISTOGRAM_METRICS: [Metric; 5] = [
+        Metric::new(
+            "update_receive_duration",
+            "the time it took to receive an update",
+            MetricType::Histogram, MetricUnit::Second
+        ),
+        Metric::new(
+            "update_parse_duration",
+            "the time it took to parse an update",
+            MetricType::Histogram, MetricUnit::Second
+        ),
+        Metric::new(
+            "update_finalize_duration",
+            "the time it took to finalize the data set of an update",
+            MetricType::Histogram, MetricUnit::Second
+        ),
+        Metric::new(
+            "update_diff_duration",
+            "the time it took to determine the changes of an update",
+            MetricType::Histogram, MetricUnit::Second
+        ),
+        Metric::new(
+            "update_publish_duration",
+            "the time it took to publish an update",
+            MetricType::Histogram, MetricUnit::Second
+        ),
+    ];
+}
+
+impl metrics::Source for StageMetrics {
+    fn append(&self, unit_name: &str, target: &mut metrics::Target)  {
+        target.append(&Self::LAST_METRIC, Some(unit_name), |records| {
+            for (stage, last) in Stage::ALL.iter().zip(&self.last) {
+                let last = last.load(Ordering::Relaxed);
+                if last == Self::NOT_MEASURED {
+                    continue
+                }
+                records.label_value(
+                    &[("stage", stage.name())], last as f64 / 1_000_000.
+                );
+            }
+        });
+        for (metric, histogram) in Self::HISTOGRAM_METRICS.iter().zip(
+            &self.histograms
+        ) {
+            target.append(metric, Some(unit_name), |records| {
+                records.histogram(histogram)
+            });
+        }
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timer() {
+        let metrics = StageMetrics::default();
+        let mut timer = UpdateTimer::start();
+        timer.stage(Stage::Receive);
+        timer.stage(Stage::Parse);
+        timer.record(Stage::Finalize, Duration::from_millis(20));
+        assert_eq!(timer.duration(Stage::Diff), None);
+        timer.finish(&metrics);
+
+        assert_eq!(metrics.last[Stage::Finalize.index()].load(
+            Ordering::Relaxed
+        ), 20_000);
+        for &stage in &Stage::ALL {
+            let expected = match stage {
+                Stage::Diff | Stage::Publish => 0,
+                _ => 1
+            };
+            assert_eq!(
+                metrics.histograms[stage.index()].count(), expected
+            );
+        }
+
+        // Stages not measured for the last update are left out of its
+        // durations.
+        let mut output = metrics::Target::new(metrics::OutputFormat::Plain);
+        metrics::Source::append(&metrics, "rtr", &mut output);
+        let output = output.into_string();
+        assert!(output.contains("rtr update_stage_duration stage=parse: "));
+        assert!(!output.contains("rtr update_stage_duration stage=diff: "));
+        assert!(output.contains("rtr update_diff_duration count: 0\n"));
+    }
+}
@@ -0,0 +1,315 @@
+//! Tracing changes to a configured list of prefixes.
+//!
+//! If the `watch` option lists any prefixes, the manager creates a
+//! [`WatchList`] and registers a prefix watch for each of them with every
+//! unit. Whenever a unit publishes an update that announces or withdraws a
+//! payload item covered by a watched prefix, the change is logged at info
+//! level and kept in a ring buffer available as JSON under
+//! `/api/v1/watch-events` on the HTTP server.
+//!
+//! Only the changes of each update are looked at, so this is cheap even
+//! for large data sets. To avoid flooding the log when a large watched
+//! prefix churns, at most [`RATE_MAX`] events are reported per watched
+//! prefix within [`RATE_WINDOW`]. Further events within the window are
+//! only counted and the count is logged once the next window starts.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use hyper::{Body, Method, Request, Response};
+use log::info;
+use rpki_rtr::payload::{Action, Payload};
+use rpki_rtr::state::Serial;
+use crate::http;
+use crate::comms::{GateAgent, VrpChangeEvent, WatchHandle};
+use crate::payload::{DisplayPayload, Prefix};
+
+
+//------------ Configuration -------------------------------------------------
+
+/// The path of the HTTP resource.
+const PATH: &str = "/api/v1/watch-events";
+
+/// The number of events kept in the ring buffer.
+pub const RING_LEN: usize = 1000;
+
+/// The time window for rate limiting events per watched prefix.
+pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// The number of events reported per watched prefix within the window.
+pub const RATE_MAX: usize = 20;
+
+
+//------------ WatchList -----------------------------------------------------
+
+/// The list of watched prefixes and the events recorded for them.
+#[derive(Debug)]
+pub struct WatchList {
+    /// The watched prefixes.
+    prefixes: Vec<Prefix>,
+
+    /// The recorded events and rate limits.
+    state: Mutex<WatchState>,
+
+    /// The handles of the prefix watches registered with the units.
+    ///
+    /// The watches are removed when the list is dropped.
+    handles: Mutex<Vec<WatchHandle>>,
+}
+
+/// The mutable state of a watch list.
+#[derive(Debug)]
+struct WatchState {
+    /// The ring buffer of the most recent events.
+    events: VecDeque<WatchEvent>,
+
+    /// The rate limit for each watched prefix in the order of the list.
+    limits: Vec<RateLimit>,
+}
+
+impl WatchList {
+    /// Creates a new watch list for the given prefixes.
+    pub fn new(prefixes: Vec<Prefix>) -> Self {
+        WatchList {
+            state: Mutex::new(WatchState {
+                events: VecDeque::new(),
+                limits: vec![RateLimit::default(); prefixes.len()],
+            }),
+            prefixes,
+            handles: Default::default(),
+        }
+    }
+
+    /// Watches the updates published by the unit behind `agent`.
+    pub fn watch(self: &Arc<Self>, unit: &str, agent: &GateAgent) {
+        let unit: Arc<str> = unit.into();
+        let mut handles = self.handles.lock().unwrap();
+        for (index, &prefix) in self.prefixes.iter().enumerate() {
+            let list = Arc::downgrade(self);
+            let unit = unit.clone();
+            handles.push(agent.watch_prefix(
+                prefix,
+                Arc::new(move |event: VrpChangeEvent| {
+                    if let Some(list) = Weak::upgrade(&list) {
+                        list.record(index, &unit, event)
+                    }
+                })
+            ));
+        }
+    }
+
+    /// Records an event for the prefix with the given index.
+    fn record(&self, index: usize, unit: &Arc<str>, event: VrpChangeEvent) {
+        let prefix = self.prefixes[index];
+        let mut state = self.state.lock().unwrap();
+        if !state.limits[index].check(prefix, event.timestamp) {
+            return
+        }
+        info!(
+            "Watched prefix {}: unit {} {} {} with serial {}.",
+            prefix, unit,
+            match event.action {
+                Action::Announce => "announced",
+                Action::Withdraw => "withdrew",
+            },
+            DisplayPayload(&event.payload), event.serial
+        );
+        if state.events.len() >= RING_LEN {
+            state.events.pop_front();
+        }
+        state.events.push_back(WatchEvent {
+            time: Utc::now(),
+            unit: unit.clone(),
+            serial: event.serial,
+            action: event.action,
+            payload: event.payload,
+        });
+    }
+
+    /// Returns the recorded events, oldest first.
+    pub fn events(&self) -> Vec<WatchEvent> {
+        self.state.lock().unwrap().events.iter().cloned().collect()
+    }
+}
+
+impl http::ProcessRequest for WatchList {
+    fn process_request(
+        &self, request: &mut Request<Body>
+    ) -> Option<Response<Body>> {
+        if request.method() != Method::GET || request.uri().path() != PATH {
+            return None
+        }
+        let mut body = String::from("[");
+        for (index, event) in self.events().iter().enumerate() {
+            if index > 0 {
+                body.push(',');
+            }
+            body.push_str("\n  ");
+            event.write_json(&mut body);
+        }
+        body.push_str("\n]\n");
+        Some(
+            Response::builder()
+            .header("Content-Type", "application/json")
+            .body(body.into())
+            .unwrap()
+        )
+    }
+}
+
+
+//------------ WatchEvent ----------------------------------------------------
+
+/// A change to a watched prefix.
+#[derive(Clone, Debug)]
+pub struct WatchEvent {
+    /// The time the event was recorded.
+    pub time: DateTime<Utc>,
+
+    /// The name of the unit that published the change.
+    pub unit: Arc<str>,
+
+    /// The serial number of the update containing the change.
+    pub serial: Serial,
+
+    /// Whether the payload was announced or withdrawn.
+    pub action: Action,
+
+    /// The payload that changed.
+    pub payload: Payload,
+}
+
+impl WatchEvent {
+    /// Appends the event as a JSON object to `target`.
+    fn write_json(&self, target: &mut String) {
+        let (addr, prefix_len, max_len, asn) = match self.payload {
+            Payload::V4(ref item) => (
+                IpAddr::V4(item.prefix), item.prefix_len, item.max_len,
+                item.asn
+            ),
+            Payload::V6(ref item) => (
+                IpAddr::V6(item.prefix), item.prefix_len, item.max_len,
+                item.asn
+            ),
+        };
+        write!(
+            target,
+            "{{ \"time\": \"{}\", \"unit\": {}, \"serial\": {}, \
+             \"action\": \"{}\", \"prefix\": \"{}/{}\", \
+             \"maxLength\": {}, \"asn\": \"AS{}\" }}",
+            self.time.to_rfc3339(),
+            serde_json::to_string(self.unit.as_ref()).unwrap(),
+            self.serial,
+            match self.action {
+                Action::Announce => "announce",
+                Action::Withdraw => "withdraw",
+            },
+            addr, prefix_len, max_len, asn
+        ).unwrap();
+    }
+}
+
+
+//------------ RateLimit -----------------------------------------------------
+
+/// The rate limit for the events of a single watched prefix.
+#[derive(Clone, Debug, Default)]
+struct RateLimit {
+    /// The start of the current window.
+    start: Option<Instant>,
+
+    /// The number of events reported in the current window.
+    reported: usize,
+
+    /// The number of events suppressed in the current window.
+    suppressed: usize,
+}
+
+impl RateLimit {
+    /// Returns whether an event at `now` should be reported.
+    fn check(&mut self, prefix: Prefix, now: Instant) -> bool {
+        let expired = match self.start {
+            Some(start) => {
+                now.saturating_duration_since(start) >= RATE_WINDOW
+            }
+            None => true
+        };
+        if expired {
+            if self.suppressed > 0 {
+                info!(
+                    "Watched prefix {}: {} events were suppressed.",
+                    prefix, self.suppressed
+                );
+            }
+            *self = RateLimit {
+                start: Some(now), reported: 0, suppressed: 0
+            };
+        }
+        if self.reported < RATE_MAX {
+            self.reported += 1;
+            return true
+        }
+        if self.suppressed == 0 {
+            info!(
+                "Watched prefix {}: more than {} events within {} seconds. \
+                 Suppressing further events for now.",
+                prefix, RATE_MAX, RATE_WINDOW.as_secs()
+            );
+        }
+        self.suppressed += 1;
+        false
+    }
+}
+
+
+//============ Testing =======================================================
+
+#[cfg(test)]
+mod test {
+    
+    use std::str::FromStr;
+    
+    use crate::comms::Gate;
+    use crate::payload;
+    use crate::payload::testing::v4;
+    use http::ProcessRequest;
+    use super::*;
+
+    fn update(serial: u32, octets: &[u8]) -> payload::Update {
+        let mut set = payload::SetBuilder::empty();
+        for &octet in octets {
+            set.insert(v4([10, octet, 0, 0], 16, 16, 64496)).unwrap();
+        }
+        payload::Update::new(
+            Serial::from(serial), Arc::new(set.finalize()), None
+        )
+    }
+
+    #[tokio::test]
+    async fn watch_events() {
+        let list = Arc::new(WatchList::new(vec![
+            Prefix::from_str("10.0.0.0/8").unwrap()
+        ]));
+        let (mut gate, agent) = Gate::new();
+        list.watch("rtr", &agent);
+
+        gate.update_data(update(1, &[1])).await;
+        gate.update_data(update(2, &[2])).await;
+        let events = list.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].action, Action::Announce);
+        assert_eq!(events[2].serial, Serial::from(2));
+        assert_eq!(events[2].unit.as_ref(), "rtr");
+
+        let mut request = Request::get(PATH).body(Body::empty()).unwrap();
+        assert!(list.process_request(&mut request).is_some());
+
+        // Everything beyond the rate limit is dropped.
+        let octets: Vec<_> = (0..2 * RATE_MAX as u8).collect();
+        gate.update_data(update(3, &octets)).await;
+        assert_eq!(list.events().len(), RATE_MAX);
+    }
+}